@@ -0,0 +1,291 @@
+use crate::error::{ContainerError, ContainerResult};
+use flate2::read::GzDecoder;
+use nix::sys::stat::{Mode, SFlag, makedev, mknod};
+use std::collections::HashSet;
+use std::fs::{self, DirBuilder, File};
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt, lchown};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType};
+use xz2::read::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0];
+// Image layers mark deletions of lower-layer files with these.
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+// A --rootfs-tar archive or the layers of an --image unpacked to
+// <state-dir>/<name>.rootfs. Like the overlay layers, the runtime extracts it
+// before the init is cloned and removes it once the container has exited,
+// unless `keep` is set.
+#[derive(Debug)]
+pub struct ExtractedRootfs {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl ExtractedRootfs {
+    pub fn extract(
+        archive: &Path,
+        state_dir: &Path,
+        name: &str,
+        keep: bool,
+    ) -> ContainerResult<Self> {
+        let file = File::open(archive).map_err(|e| {
+            ContainerError::invalid_configuration(format!(
+                "Cannot open rootfs archive {}: {e}",
+                archive.display()
+            ))
+        })?;
+        let rootfs = Self::create(state_dir, name, keep)?;
+        log::info!(
+            "Extracting {} to {}",
+            archive.display(),
+            rootfs.path.display()
+        );
+        rootfs.unpack(file, false, &archive.display().to_string())?;
+        Ok(rootfs)
+    }
+
+    // An empty directory to unpack into; it must not exist yet.
+    pub fn create(state_dir: &Path, name: &str, keep: bool) -> ContainerResult<Self> {
+        fs::create_dir_all(state_dir).map_err(|e| {
+            ContainerError::filesystem_setup(format!(
+                "Failed to create state directory {state_dir:?}: {e}"
+            ))
+        })?;
+        let path = state_dir.join(format!("{name}.rootfs"));
+        DirBuilder::new().mode(0o755).create(&path).map_err(|e| {
+            ContainerError::filesystem_setup(format!(
+                "Failed to create rootfs directory {path:?}: {e}"
+            ))
+        })?;
+        // From here on, Drop removes the partial tree unless asked to keep it.
+        Ok(Self { path, keep })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Unpacks a plain, gzip or xz compressed tar stream over what is already
+    // there. With `whiteouts`, .wh. entries delete files of earlier layers
+    // instead of being unpacked.
+    pub fn unpack(
+        &self,
+        reader: impl Read + 'static,
+        whiteouts: bool,
+        source: &str,
+    ) -> ContainerResult<()> {
+        let archive = Archive::new(decompress(reader)?);
+        self.unpack_entries(archive, whiteouts)
+            .map_err(|e| match e {
+                ContainerError::InvalidConfiguration { .. } => e,
+                e => ContainerError::filesystem_setup(format!("Failed to extract {source}: {e}")),
+            })
+    }
+
+    fn unpack_entries(
+        &self,
+        mut archive: Archive<Box<dyn Read>>,
+        whiteouts: bool,
+    ) -> ContainerResult<()> {
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_ownerships(true);
+        archive.set_preserve_mtime(true);
+        archive.set_overwrite(true);
+        // What this layer itself brought, which an opaque whiteout keeps.
+        let mut unpacked = HashSet::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_path = entry.path()?.into_owned();
+            // unpack_in skips `..` entries silently; an archive that has any
+            // is refused outright. Hard links are checked the same way, as
+            // they name a file inside the archive.
+            check_entry_path(&entry_path)?;
+            if entry.header().entry_type() == EntryType::Link
+                && let Some(target) = entry.link_name()?
+            {
+                check_entry_path(&target)?;
+            }
+            let relative = relative_path(&entry_path);
+            if whiteouts
+                && let Some(file_name) = relative.file_name().and_then(|name| name.to_str())
+                && let Some(whited_out) = file_name.strip_prefix(WHITEOUT_PREFIX)
+            {
+                let dir = relative.parent().unwrap_or(Path::new(""));
+                if file_name == OPAQUE_WHITEOUT {
+                    self.clear_dir(dir, &unpacked)?;
+                } else {
+                    self.remove_lower(&dir.join(whited_out))?;
+                }
+                continue;
+            }
+            match entry.header().entry_type() {
+                EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                    self.make_node(&entry_path, entry.header())?;
+                }
+                _ => {
+                    entry.unpack_in(&self.path)?;
+                }
+            }
+            if whiteouts {
+                unpacked.insert(relative);
+            }
+        }
+        Ok(())
+    }
+
+    // The host path of `relative`, once its parent is known to resolve inside
+    // the rootfs: a symlink from an earlier entry must not lead outside.
+    fn inside(&self, relative: &Path, entry_path: &Path) -> ContainerResult<PathBuf> {
+        self.check_dir(relative.parent().unwrap_or(Path::new("")), entry_path)?;
+        Ok(self.path.join(relative))
+    }
+
+    fn check_dir(&self, relative: &Path, entry_path: &Path) -> ContainerResult<()> {
+        let dir = fs::canonicalize(self.path.join(relative))?;
+        if !dir.starts_with(fs::canonicalize(&self.path)?) {
+            return Err(ContainerError::invalid_configuration(format!(
+                "Archive entry {} points outside the rootfs",
+                entry_path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    fn remove_lower(&self, relative: &Path) -> ContainerResult<()> {
+        if relative.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let path = match self.inside(relative, relative) {
+            Ok(path) => path,
+            // Whiting out something under a directory that is not there.
+            Err(ContainerError::Io { source, .. }) if source.kind() == ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&path)?,
+            Ok(_) => fs::remove_file(&path)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    fn clear_dir(&self, relative: &Path, keep: &HashSet<PathBuf>) -> ContainerResult<()> {
+        let dir = self.path.join(relative);
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        self.check_dir(relative, relative)?;
+        for child in fs::read_dir(&dir)? {
+            let child = relative.join(child?.file_name());
+            if !keep.contains(&child) {
+                self.remove_lower(&child)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The tar crate would write device nodes and FIFOs as empty regular
+    // files. Device nodes need CAP_MKNOD, so they are skipped where the
+    // runtime lacks it; the container's /dev is populated separately anyway.
+    fn make_node(&self, entry_path: &Path, header: &tar::Header) -> ContainerResult<()> {
+        let relative = relative_path(entry_path);
+        if relative.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let parent = self.path.join(relative.parent().unwrap_or(Path::new("")));
+        fs::create_dir_all(&parent)?;
+        let target = self.inside(&relative, entry_path)?;
+        let mode = Mode::from_bits_truncate(header.mode()?);
+        let (kind, dev) = match header.entry_type() {
+            EntryType::Fifo => (SFlag::S_IFIFO, 0),
+            kind => (
+                if kind == EntryType::Char {
+                    SFlag::S_IFCHR
+                } else {
+                    SFlag::S_IFBLK
+                },
+                makedev(
+                    header.device_major()?.unwrap_or(0).into(),
+                    header.device_minor()?.unwrap_or(0).into(),
+                ),
+            ),
+        };
+        match fs::remove_file(&target) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        match mknod(&target, kind, mode, dev) {
+            Ok(()) => {}
+            Err(nix::errno::Errno::EPERM) => {
+                log::debug!("Not allowed to create {}, skipping", relative.display());
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        lchown(
+            &target,
+            Some(header.uid()? as u32),
+            Some(header.gid()? as u32),
+        )?;
+        // mknod applies the umask.
+        fs::set_permissions(&target, fs::Permissions::from_mode(mode.bits()))?;
+        Ok(())
+    }
+
+    pub fn cleanup(&self) {
+        match fs::remove_dir_all(&self.path) {
+            Ok(()) => log::info!("Removed extracted rootfs {:?}", self.path),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to remove extracted rootfs {:?}: {e}", self.path),
+        }
+    }
+}
+
+impl Drop for ExtractedRootfs {
+    fn drop(&mut self) {
+        if self.keep {
+            log::info!("Extracted rootfs kept in {:?}", self.path);
+        } else {
+            self.cleanup();
+        }
+    }
+}
+
+fn check_entry_path(path: &Path) -> ContainerResult<()> {
+    if path
+        .components()
+        .any(|part| matches!(part, Component::ParentDir))
+    {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Refusing archive entry {} that escapes the rootfs",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+// The entry path as unpack_in places it: leading '/' and '.' dropped.
+fn relative_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|part| matches!(part, Component::Normal(_)))
+        .collect()
+}
+
+fn decompress(reader: impl Read + 'static) -> ContainerResult<Box<dyn Read>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+    Ok(if magic.starts_with(GZIP_MAGIC) {
+        Box::new(GzDecoder::new(reader))
+    } else if magic.starts_with(XZ_MAGIC) {
+        Box::new(XzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    })
+}
@@ -0,0 +1,224 @@
+use crate::error::{ContainerError, ContainerResult};
+use caps::{CapSet, Capability, CapsHashSet};
+use std::str::FromStr;
+
+// What the workload keeps when neither --cap-drop nor --cap-add is given;
+// the same set Docker grants by default.
+pub const DEFAULT_CAPABILITIES: [Capability; 14] = [
+    Capability::CAP_AUDIT_WRITE,
+    Capability::CAP_CHOWN,
+    Capability::CAP_DAC_OVERRIDE,
+    Capability::CAP_FOWNER,
+    Capability::CAP_FSETID,
+    Capability::CAP_KILL,
+    Capability::CAP_MKNOD,
+    Capability::CAP_NET_BIND_SERVICE,
+    Capability::CAP_NET_RAW,
+    Capability::CAP_SETFCAP,
+    Capability::CAP_SETGID,
+    Capability::CAP_SETPCAP,
+    Capability::CAP_SETUID,
+    Capability::CAP_SYS_CHROOT,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilitySpec {
+    All,
+    Cap(Capability),
+}
+
+// Accepts `ALL` and capability names with or without the CAP_ prefix, in any
+// case: `net_admin`, `NET_ADMIN` and `CAP_NET_ADMIN` are the same capability.
+impl FromStr for CapabilitySpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("all") {
+            return Ok(CapabilitySpec::All);
+        }
+        Capability::from_str(&caps::to_canonical(s))
+            .map(CapabilitySpec::Cap)
+            .map_err(|_| format!("unknown capability '{s}'"))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CapabilityConfig {
+    pub drop: Vec<CapabilitySpec>,
+    pub add: Vec<CapabilitySpec>,
+    // Raised into the ambient set so they survive execve for a program
+    // without file capabilities; ALL is every capability kept.
+    pub ambient: Vec<CapabilitySpec>,
+}
+
+impl CapabilityConfig {
+    // Drops are applied before adds, so `--cap-drop ALL --cap-add X` keeps
+    // exactly X. Both start from DEFAULT_CAPABILITIES; ALL expands to every
+    // capability the running kernel knows.
+    pub fn resolve(&self, supported: &CapsHashSet) -> CapsHashSet {
+        let mut keep: CapsHashSet = DEFAULT_CAPABILITIES
+            .iter()
+            .filter(|cap| supported.contains(cap))
+            .copied()
+            .collect();
+        for spec in &self.drop {
+            match spec {
+                CapabilitySpec::All => keep.clear(),
+                CapabilitySpec::Cap(cap) => {
+                    keep.remove(cap);
+                }
+            }
+        }
+        for spec in &self.add {
+            match spec {
+                CapabilitySpec::All => keep.extend(supported.iter().copied()),
+                CapabilitySpec::Cap(cap) if supported.contains(cap) => {
+                    keep.insert(*cap);
+                }
+                CapabilitySpec::Cap(cap) => {
+                    log::warn!("{cap} is not supported by this kernel, not adding it");
+                }
+            }
+        }
+        keep
+    }
+
+    // An ambient capability has to be permitted, and so kept, as well.
+    pub fn ambient(&self, keep: &CapsHashSet) -> ContainerResult<CapsHashSet> {
+        let mut ambient = CapsHashSet::new();
+        for spec in &self.ambient {
+            match spec {
+                CapabilitySpec::All => ambient.extend(keep.iter().copied()),
+                CapabilitySpec::Cap(cap) if keep.contains(cap) => {
+                    ambient.insert(*cap);
+                }
+                CapabilitySpec::Cap(cap) => {
+                    return Err(ContainerError::capability(format!(
+                        "Cannot raise {cap} as ambient: the container does not keep it; \
+                         add it with --cap-add {cap}"
+                    )));
+                }
+            }
+        }
+        Ok(ambient)
+    }
+
+    // The same resolution apply does, for failing before anything starts.
+    pub fn validate(&self) -> ContainerResult<()> {
+        let supported = caps::runtime::thread_all_supported();
+        self.ambient(&self.resolve(&supported)).map(drop)
+    }
+}
+
+#[derive(Debug)]
+pub struct CapabilityManager;
+
+impl CapabilityManager {
+    // Root regains its bounding set across execve, so the bounding set is what
+    // actually limits the workload; it is trimmed first, while CAP_SETPCAP is
+    // still held, and the process sets follow.
+    pub fn apply(config: &CapabilityConfig) -> ContainerResult<()> {
+        let supported = caps::runtime::thread_all_supported();
+        let keep = config.resolve(&supported);
+        let ambient = config.ambient(&keep)?;
+        for cap in supported.difference(&keep) {
+            caps::drop(None, CapSet::Bounding, *cap).map_err(|e| {
+                ContainerError::capability(format!("Failed to drop {cap} from bounding set: {e}"))
+            })?;
+        }
+        for set in [CapSet::Inheritable, CapSet::Effective, CapSet::Permitted] {
+            let current = caps::read(None, set).map_err(|e| {
+                ContainerError::capability(format!("Failed to read {set:?} set: {e}"))
+            })?;
+            let target: CapsHashSet = current.intersection(&keep).copied().collect();
+            caps::set(None, set, &target).map_err(|e| {
+                ContainerError::capability(format!("Failed to set {set:?} set: {e}"))
+            })?;
+        }
+        // An ambient capability must be inheritable too, and the ambient set
+        // is emptied by the kernel whenever either set loses it.
+        for cap in &ambient {
+            caps::raise(None, CapSet::Inheritable, *cap).map_err(|e| {
+                ContainerError::capability(format!("Failed to make {cap} inheritable: {e}"))
+            })?;
+            caps::raise(None, CapSet::Ambient, *cap).map_err(|e| {
+                ContainerError::capability(format!("Failed to raise {cap} as ambient: {e}"))
+            })?;
+        }
+        if !ambient.is_empty() {
+            let mut raised: Vec<String> = ambient.iter().map(|cap| cap.to_string()).collect();
+            raised.sort();
+            log::debug!("Ambient capabilities: {}", raised.join(","));
+        }
+        let mut kept: Vec<String> = keep.iter().map(|cap| cap.to_string()).collect();
+        kept.sort();
+        log::debug!("Capabilities kept: {}", kept.join(","));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn specs(names: &[&str]) -> Vec<CapabilitySpec> {
+        names.iter().map(|name| name.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn names_are_accepted_with_or_without_the_prefix() {
+        let net_admin = CapabilitySpec::Cap(Capability::CAP_NET_ADMIN);
+        for name in ["net_admin", "NET_ADMIN", "CAP_NET_ADMIN", "cap_net_admin"] {
+            assert_eq!(name.parse(), Ok(net_admin), "{name}");
+        }
+        assert_eq!("all".parse(), Ok(CapabilitySpec::All));
+        assert_eq!("ALL".parse(), Ok(CapabilitySpec::All));
+        assert_eq!(
+            "net_bogus".parse::<CapabilitySpec>(),
+            Err("unknown capability 'net_bogus'".to_string())
+        );
+    }
+
+    #[test]
+    fn drops_apply_before_adds() {
+        let supported: CapsHashSet = caps::all();
+        let config = CapabilityConfig {
+            drop: specs(&["ALL"]),
+            add: specs(&["net_admin"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.resolve(&supported),
+            CapsHashSet::from([Capability::CAP_NET_ADMIN])
+        );
+        let config = CapabilityConfig {
+            drop: specs(&["chown"]),
+            ..Default::default()
+        };
+        let keep = config.resolve(&supported);
+        assert_eq!(keep.len(), DEFAULT_CAPABILITIES.len() - 1);
+        assert!(!keep.contains(&Capability::CAP_CHOWN));
+    }
+
+    #[test]
+    fn ambient_capabilities_must_be_kept() {
+        let supported: CapsHashSet = caps::all();
+        let config = CapabilityConfig {
+            ambient: specs(&["net_bind_service"]),
+            ..Default::default()
+        };
+        let keep = config.resolve(&supported);
+        assert_eq!(
+            config.ambient(&keep).unwrap(),
+            CapsHashSet::from([Capability::CAP_NET_BIND_SERVICE])
+        );
+        let config = CapabilityConfig {
+            ambient: specs(&["sys_admin"]),
+            ..Default::default()
+        };
+        let error = config.ambient(&keep).unwrap_err();
+        assert!(
+            error.to_string().contains("--cap-add CAP_SYS_ADMIN"),
+            "{error}"
+        );
+    }
+}
@@ -1,34 +1,101 @@
-use crate::error::{ContainerError, ContainerResult, Context};
+use crate::devices::{self, DeviceRule};
+use crate::error::{ContainerError, ContainerResult, ErrorDetail};
+use crate::systemd::SystemdScope;
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::{Gid, Pid, Uid, chown, getpid};
+use serde::Serialize;
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
 use std::io::ErrorKind;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::thread;
-use std::time::Duration;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+const KILL_WAIT_PERIOD: Duration = Duration::from_secs(2);
+const REMOVE_ATTEMPTS: u32 = 8;
+// How long freezing may take before we give up on it.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(5);
+// On cgroup v2 the container's processes live in this leaf below the
+// container cgroup, which keeps controllers of its own for the leaf and
+// whatever a nested manager creates next to it.
+const INIT_CGROUP: &str = "init";
+// The kernel's vm.swappiness default, the point below which swapping is
+// discouraged.
+const DEFAULT_SWAPPINESS: u64 = 60;
+const CONTROLLERS: [&str; 4] = ["cpu", "memory", "pids", "io"];
+
+// Who creates the container's cgroup: the runtime writing to the cgroupfs
+// directly, or the host's systemd as a transient scope unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CgroupDriver {
+    #[default]
+    Cgroupfs,
+    Systemd,
+}
+
+impl FromStr for CgroupDriver {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cgroupfs" => Ok(CgroupDriver::Cgroupfs),
+            "systemd" => Ok(CgroupDriver::Systemd),
+            other => Err(format!(
+                "unknown cgroup driver '{other}', expected cgroupfs or systemd"
+            )),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 
 pub struct CgroupConfig {
     pub name: String,
     pub memory_limit: Option<u64>,
+    pub memory_high: Option<u64>,
     pub memory_swap_limit: Option<u64>,
+    pub swappiness: Option<u64>,
     pub cpu_weight: Option<u64>,
     pub cpu_quota: Option<u64>,
     pub cpu_period: Option<u64>,
     pub pids_limit: Option<u64>,
+    pub rt_runtime: Option<u64>,
+    pub rt_period: Option<u64>,
+    pub oom_group: bool,
+    pub force_kill: bool,
+    pub reuse: bool,
+    pub best_effort: bool,
+    pub delegate: bool,
+    pub driver: CgroupDriver,
+    pub strict: bool,
+    pub devices: Option<Vec<DeviceRule>>,
+    pub root: PathBuf,
 }
 impl Default for CgroupConfig {
     fn default() -> Self {
         Self {
             name: format!("container-{}", std::process::id()),
             memory_limit: None,
+            memory_high: None,
             memory_swap_limit: None,
+            swappiness: None,
             cpu_weight: None,
             cpu_quota: None,
             cpu_period: Some(100000),
             pids_limit: None,
+            rt_runtime: None,
+            rt_period: None,
+            oom_group: false,
+            force_kill: false,
+            reuse: false,
+            best_effort: false,
+            delegate: false,
+            driver: CgroupDriver::default(),
+            strict: false,
+            devices: None,
+            root: PathBuf::from(CGROUP_ROOT),
         }
     }
 }
@@ -43,9 +110,32 @@ impl CgroupConfig {
         self.memory_limit = Some(mb * 1024 * 1024);
         self
     }
+    pub fn with_memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+    pub fn with_memory_high_mb(mut self, mb: u64) -> Self {
+        self.memory_high = Some(mb * 1024 * 1024);
+        self
+    }
+    #[allow(dead_code)]
     pub fn with_cpu_percent(mut self, cpu_percent: u64) -> Self {
         let period = self.cpu_period.unwrap_or(100000);
-        self.cpu_quota = Some((period * cpu_percent / 100) as u64);
+        self.cpu_quota = Some(period * cpu_percent / 100);
+        self
+    }
+    pub fn with_swappiness(mut self, swappiness: u64) -> Self {
+        self.swappiness = Some(swappiness);
+        self
+    }
+    // Fail on settings the cgroup version can only approximate.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+    // The only devices the container may create and open.
+    pub fn with_devices(mut self, rules: Vec<DeviceRule>) -> Self {
+        self.devices = Some(rules);
         self
     }
     pub fn with_pids_limit(mut self, limit: u64) -> Self {
@@ -56,12 +146,475 @@ impl CgroupConfig {
         self.cpu_weight = Some(weight);
         self
     }
+    pub fn with_cpu_quota(mut self, quota: u64, period: Option<u64>) -> Self {
+        self.cpu_quota = Some(quota);
+        if period.is_some() {
+            self.cpu_period = period;
+        }
+        self
+    }
+    pub fn with_rt_runtime(mut self, runtime: u64, period: Option<u64>) -> Self {
+        self.rt_runtime = Some(runtime);
+        self.rt_period = period;
+        self
+    }
+    pub fn with_oom_group(mut self, oom_group: bool) -> Self {
+        self.oom_group = oom_group;
+        self
+    }
+    pub fn with_force_kill(mut self, force_kill: bool) -> Self {
+        self.force_kill = force_kill;
+        self
+    }
+    // Keep an empty leftover cgroup of the same name (with its limits reset)
+    // instead of removing and recreating it.
+    pub fn with_reuse(mut self, reuse: bool) -> Self {
+        self.reuse = reuse;
+        self
+    }
+    // Skip the limits of controllers that are not delegated to the cgroup
+    // instead of failing.
+    pub fn with_best_effort(mut self, best_effort: bool) -> Self {
+        self.best_effort = best_effort;
+        self
+    }
+    // Hand the init's cgroup to the container's root, for a manager inside to
+    // create and control sub-cgroups.
+    pub fn with_delegate(mut self, delegate: bool) -> Self {
+        self.delegate = delegate;
+        self
+    }
+    pub fn with_driver(mut self, driver: CgroupDriver) -> Self {
+        self.driver = driver;
+        self
+    }
+    // Where the cgroup filesystem is mounted, CGROUP_ROOT unless a test
+    // brings a tree of its own.
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+    // cgroup v2 has no per-cgroup swappiness. What comes closest: no swap at
+    // all for 0, and for a low value keeping swapped pages compressed in
+    // zswap rather than written out to the swap device.
+    pub fn swappiness_v2(&self) -> Option<(&'static str, &'static str)> {
+        match self.swappiness? {
+            0 if self.memory_swap_limit.is_none() => Some(("memory.swap.max", "0")),
+            swappiness if swappiness < DEFAULT_SWAPPINESS => Some(("memory.zswap.writeback", "0")),
+            _ => None,
+        }
+    }
+    // Each controller the limits need, with the options that need it.
+    pub fn controllers(&self) -> Vec<(&'static str, &'static str)> {
+        [
+            (
+                "memory",
+                self.memory_limit.is_some()
+                    || self.memory_high.is_some()
+                    || self.memory_swap_limit.is_some()
+                    || self.swappiness.is_some()
+                    || self.oom_group,
+                "--memory, --memory-high-mb, --memory-swappiness and --memory-oom-group",
+            ),
+            (
+                "cpu",
+                self.cpu_weight.is_some() || self.cpu_quota.is_some(),
+                "--cpu-weight and --cpu-quota",
+            ),
+            ("pids", self.pids_limit.is_some(), "--pids-limit"),
+        ]
+        .into_iter()
+        .filter(|(_, used, _)| *used)
+        .map(|(controller, _, options)| (controller, options))
+        .collect()
+    }
+    pub fn validate(&self) -> ContainerResult<()> {
+        if let (Some(high), Some(max)) = (self.memory_high, self.memory_limit)
+            && high > max
+        {
+            return Err(ContainerError::invalid_configuration(format!(
+                "memory.high ({} MB) must not exceed memory.max ({} MB)",
+                high / 1024 / 1024,
+                max / 1024 / 1024
+            )));
+        }
+        Ok(())
+    }
+}
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UsageSummary {
+    pub memory_peak_bytes: Option<u64>,
+    pub memory_current_bytes: Option<u64>,
+    pub cpu_usage_usec: Option<u64>,
+    pub cpu_throttled_usec: Option<u64>,
+    pub pids_peak: Option<u64>,
+}
+impl fmt::Display for UsageSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn field(value: Option<u64>, scale: u64, unit: &str) -> String {
+            value
+                .map(|v| format!("{}{}", v / scale, unit))
+                .unwrap_or_else(|| "n/a".to_string())
+        }
+        write!(
+            f,
+            "memory peak={} current={}, cpu usage={} throttled={}, pids peak={}",
+            field(self.memory_peak_bytes, 1024, "KB"),
+            field(self.memory_current_bytes, 1024, "KB"),
+            field(self.cpu_usage_usec, 1000, "ms"),
+            field(self.cpu_throttled_usec, 1000, "ms"),
+            field(self.pids_peak, 1, ""),
+        )
+    }
+}
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatsSample {
+    pub memory_current_bytes: Option<u64>,
+    pub cpu_usage_usec: Option<u64>,
+    pub pids_current: Option<u64>,
+    pub io_read_bytes: Option<u64>,
+    pub io_write_bytes: Option<u64>,
+}
+impl fmt::Display for StatsSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn field(value: Option<u64>, scale: u64, unit: &str) -> String {
+            value
+                .map(|v| format!("{}{}", v / scale, unit))
+                .unwrap_or_else(|| "n/a".to_string())
+        }
+        write!(
+            f,
+            "mem={} cpu={} pids={} io_read={} io_write={}",
+            field(self.memory_current_bytes, 1024, "KB"),
+            field(self.cpu_usage_usec, 1000, "ms"),
+            field(self.pids_current, 1, ""),
+            field(self.io_read_bytes, 1024, "KB"),
+            field(self.io_write_bytes, 1024, "KB"),
+        )
+    }
+}
+#[derive(Debug, Clone, Copy)]
+pub struct PidsUsage {
+    pub current: u64,
+    // u64::MAX for no limit.
+    pub max: u64,
+    pub limit_hits: u64,
+}
+#[derive(Debug, Clone)]
+pub struct StatsReader {
+    cgroup_path: PathBuf,
+    cgroup_version: CgroupVersion,
+}
+impl StatsReader {
+    pub fn sample(&self) -> StatsSample {
+        match self.cgroup_version {
+            CgroupVersion::V1 => StatsSample {
+                memory_current_bytes: self.read_value("memory.usage_in_bytes"),
+                ..Default::default()
+            },
+            CgroupVersion::V2 => {
+                let (io_read_bytes, io_write_bytes) = self.read_io_totals();
+                StatsSample {
+                    memory_current_bytes: self.read_value("memory.current"),
+                    cpu_usage_usec: self.read_keyed("cpu.stat", "usage_usec"),
+                    pids_current: self.read_value("pids.current"),
+                    io_read_bytes,
+                    io_write_bytes,
+                }
+            }
+        }
+    }
+    pub fn summary(&self) -> UsageSummary {
+        match self.cgroup_version {
+            CgroupVersion::V1 => UsageSummary {
+                memory_peak_bytes: self.read_value("memory.max_usage_in_bytes"),
+                memory_current_bytes: self.read_value("memory.usage_in_bytes"),
+                ..Default::default()
+            },
+            CgroupVersion::V2 => UsageSummary {
+                memory_peak_bytes: self.read_value("memory.peak"),
+                memory_current_bytes: self.read_value("memory.current"),
+                cpu_usage_usec: self.read_keyed("cpu.stat", "usage_usec"),
+                cpu_throttled_usec: self.read_keyed("cpu.stat", "throttled_usec"),
+                pids_peak: self.read_value("pids.peak"),
+            },
+        }
+    }
+    // pids.current against pids.max, and how often a fork has failed on the
+    // limit. Only cgroup v2 has a pids controller here.
+    pub fn pids(&self) -> Option<PidsUsage> {
+        if self.cgroup_version == CgroupVersion::V1 {
+            return None;
+        }
+        Some(PidsUsage {
+            current: self.read_value("pids.current")?,
+            max: self.read_value("pids.max")?,
+            limit_hits: self.read_keyed("pids.events", "max").unwrap_or(0),
+        })
+    }
+    // Every process in the container, nested cgroups included.
+    pub fn processes(&self) -> Vec<i32> {
+        subtree_processes(&self.cgroup_path).unwrap_or_default()
+    }
+    fn read_value(&self, file: &str) -> Option<u64> {
+        read_counter(&self.cgroup_path.join(file))
+    }
+    fn read_keyed(&self, file: &str, key: &str) -> Option<u64> {
+        read_keyed_counter(&self.cgroup_path.join(file), key)
+    }
+    // io.stat has one line per device: "8:0 rbytes=1 wbytes=2 rios=3 ..."
+    fn read_io_totals(&self) -> (Option<u64>, Option<u64>) {
+        let Ok(content) = fs::read_to_string(self.cgroup_path.join("io.stat")) else {
+            return (None, None);
+        };
+        let (mut read, mut write) = (0u64, 0u64);
+        for field in content.split_whitespace() {
+            if let Some((key, value)) = field.split_once('=') {
+                let value = value.parse::<u64>().unwrap_or(0);
+                match key {
+                    "rbytes" => read += value,
+                    "wbytes" => write += value,
+                    _ => {}
+                }
+            }
+        }
+        (Some(read), Some(write))
+    }
+}
+// Pauses and resumes every process in a container cgroup: cgroup.freeze on
+// v2, the freezer controller (mounted next to the memory one) on v1. Usable
+// from any process that knows the cgroup path, e.g. `container_rs pause`.
+#[derive(Debug, Clone)]
+pub struct Freezer {
+    path: PathBuf,
+    cgroup_version: CgroupVersion,
+}
+impl Freezer {
+    // `cgroup_path` is what CgroupManager::path returns.
+    pub fn for_cgroup(cgroup_path: &Path) -> ContainerResult<Self> {
+        let cgroup_version = CgroupManager::detect_cgroup_version(Path::new(CGROUP_ROOT))?;
+        Ok(Self::new(cgroup_path, cgroup_version))
+    }
+    fn new(cgroup_path: &Path, cgroup_version: CgroupVersion) -> Self {
+        let path = match cgroup_version {
+            CgroupVersion::V1 => freezer_path_v1(cgroup_path),
+            CgroupVersion::V2 => cgroup_path.to_path_buf(),
+        };
+        Self {
+            path,
+            cgroup_version,
+        }
+    }
+    pub fn freeze(&self) -> ContainerResult<()> {
+        self.set_frozen(true)
+    }
+    pub fn thaw(&self) -> ContainerResult<()> {
+        self.set_frozen(false)
+    }
+    pub fn is_frozen(&self) -> bool {
+        match self.cgroup_version {
+            CgroupVersion::V1 => fs::read_to_string(self.path.join("freezer.state"))
+                .is_ok_and(|state| state.trim() == "FROZEN"),
+            CgroupVersion::V2 => {
+                fs::read_to_string(self.path.join("cgroup.events"))
+                    .ok()
+                    .and_then(|events| parse_keyed_value(&events, "frozen"))
+                    == Some(1)
+            }
+        }
+    }
+    // The write only starts freezing; wait until every task has stopped.
+    fn set_frozen(&self, frozen: bool) -> ContainerResult<()> {
+        self.write_state(frozen)?;
+        let start = Instant::now();
+        while self.is_frozen() != frozen {
+            if start.elapsed() > FREEZE_TIMEOUT {
+                // Do not leave the cgroup half frozen.
+                if frozen {
+                    let _ = self.write_state(false);
+                }
+                return Err(ContainerError::Cgroup {
+                    detail: ErrorDetail::new(format!(
+                        "Cgroup {:?} did not {} within {:?}",
+                        self.path,
+                        if frozen { "freeze" } else { "thaw" },
+                        FREEZE_TIMEOUT
+                    )),
+                });
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+    fn write_state(&self, frozen: bool) -> ContainerResult<()> {
+        let (file, value) = match (self.cgroup_version, frozen) {
+            (CgroupVersion::V1, true) => ("freezer.state", "FROZEN"),
+            (CgroupVersion::V1, false) => ("freezer.state", "THAWED"),
+            (CgroupVersion::V2, true) => ("cgroup.freeze", "1"),
+            (CgroupVersion::V2, false) => ("cgroup.freeze", "0"),
+        };
+        let path = self.path.join(file);
+        fs::write(&path, value).map_err(|e| ContainerError::Cgroup {
+            detail: ErrorDetail::new("Failed to write").path(&path).source(e),
+        })
+    }
+}
+// On v1 the container's cgroup lives in the memory hierarchy, ROOT/memory/NAME;
+// its freezer cgroup has the same name in the freezer one.
+fn freezer_path_v1(cgroup_path: &Path) -> PathBuf {
+    sibling_path_v1(cgroup_path, "freezer")
+}
+// Likewise in the devices hierarchy.
+fn devices_path_v1(cgroup_path: &Path) -> PathBuf {
+    sibling_path_v1(cgroup_path, "devices")
+}
+// And in the cpu one, for realtime bandwidth.
+fn cpu_path_v1(cgroup_path: &Path) -> PathBuf {
+    sibling_path_v1(cgroup_path, "cpu")
+}
+fn sibling_path_v1(cgroup_path: &Path, controller: &str) -> PathBuf {
+    let name = cgroup_path.file_name().unwrap_or_default();
+    let root = cgroup_path
+        .parent()
+        .and_then(Path::parent)
+        .unwrap_or(Path::new(CGROUP_ROOT));
+    root.join(controller).join(name)
+}
+// The container's cgroups besides the memory one.
+fn hierarchies_v1(cgroup_path: &Path) -> [PathBuf; 3] {
+    [
+        freezer_path_v1(cgroup_path),
+        devices_path_v1(cgroup_path),
+        cpu_path_v1(cgroup_path),
+    ]
+}
+// A single-value interface file such as pids.current; "max" reads as
+// u64::MAX.
+pub fn read_counter(path: &Path) -> Option<u64> {
+    match fs::read_to_string(path).ok()?.trim() {
+        "max" => Some(u64::MAX),
+        value => value.parse().ok(),
+    }
+}
+
+// One key of a flat-keyed interface file such as pids.events.
+pub fn read_keyed_counter(path: &Path, key: &str) -> Option<u64> {
+    parse_keyed_value(&fs::read_to_string(path).ok()?, key)
+}
+
+// The cgroup v2 cgroup of a process, as a path under `root`.
+pub fn process_cgroup(root: &Path, pid: u32) -> Option<PathBuf> {
+    let content = fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| root.join(path.trim_start_matches('/')))
+}
+
+// The cgroup v1 cgroups of a process, one per controller, as paths under
+// `root`.
+pub fn process_cgroups_v1(root: &Path, pid: u32) -> Vec<PathBuf> {
+    let content = fs::read_to_string(format!("/proc/{pid}/cgroup")).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+            Some((controllers, path.trim_start_matches('/')))
+        })
+        .flat_map(|(controllers, path)| {
+            controllers
+                .split(',')
+                .filter(|c| !c.is_empty() && !c.starts_with("name="))
+                .map(move |c| root.join(c).join(path))
+        })
+        .collect()
+}
+
+// The processes in a cgroup and every cgroup below it.
+fn subtree_processes(cgroup: &Path) -> ContainerResult<Vec<i32>> {
+    let procs = cgroup.join("cgroup.procs");
+    let content = match fs::read_to_string(&procs) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(ContainerError::Cgroup {
+                detail: ErrorDetail::new("Failed to read").path(procs).source(e),
+            });
+        }
+    };
+    let mut pids: Vec<i32> = content
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .collect();
+    if let Ok(entries) = fs::read_dir(cgroup) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pids.extend(subtree_processes(&entry.path())?);
+            }
+        }
+    }
+    Ok(pids)
+}
+
+// Cgroup directories can only be removed with rmdir (their interface files
+// cannot be unlinked), and rmdir returns EBUSY for a short while after the
+// last task exits, so retry with a capped exponential backoff.
+fn delete_with_retry(
+    path: &Path,
+    attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+) -> ContainerResult<()> {
+    let mut delay = initial_delay.min(max_delay);
+    let mut last_error = None;
+    for attempt in 1..=attempts.max(1) {
+        match fs::remove_dir(path) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log::debug!("Cgroup {:?} already gone (ENOENT)", path);
+                return Ok(());
+            }
+            Err(e) => {
+                log::debug!(
+                    "Attempt {}/{} to remove {:?} failed: {}",
+                    attempt,
+                    attempts,
+                    path,
+                    e
+                );
+                last_error = Some(e);
+            }
+        }
+        if attempt < attempts {
+            std::thread::sleep(delay);
+            delay = delay.saturating_mul(2).min(max_delay);
+        }
+    }
+    let mut detail = ErrorDetail::new(format!(
+        "Failed to remove cgroup after {} attempts:",
+        attempts.max(1)
+    ))
+    .path(path);
+    detail.source = last_error.map(Into::into);
+    Err(ContainerError::Cgroup { detail })
+}
+fn parse_keyed_value(content: &str, key: &str) -> Option<u64> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(name, _)| *name == key)
+        .and_then(|(_, value)| value.trim().parse::<u64>().ok())
 }
 #[derive(Debug)]
 pub struct CgroupManager {
     cgroup_path: PathBuf,
     config: CgroupConfig,
     cgroup_version: CgroupVersion,
+    scope: Option<SystemdScope>,
+    // The runtime's own cgroups before it joined the container's, to go
+    // back to.
+    origin: Vec<PathBuf>,
 }
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum CgroupVersion {
@@ -71,21 +624,295 @@ enum CgroupVersion {
 
 impl CgroupManager {
     pub fn new(config: CgroupConfig) -> ContainerResult<Self> {
-        let cgroup_version = Self::detect_cgroup_version()?;
+        let cgroup_version = Self::detect_cgroup_version(&config.root)?;
         log::info!("Detected cgroup version: {:?}", cgroup_version);
-        let cgroup_path = match cgroup_version {
-            CgroupVersion::V1 => PathBuf::from(CGROUP_ROOT),
-            CgroupVersion::V2 => PathBuf::from(CGROUP_ROOT).join(&config.name),
+        if config.driver == CgroupDriver::Cgroupfs && SystemdScope::is_running() {
+            log::debug!(
+                "systemd manages this host's cgroups; --cgroup-driver systemd leaves the \
+                 container's to it as well"
+            );
+        }
+        let cgroup_path = Self::cgroup_path(cgroup_version, &config);
+        // Checked before Self exists: Drop must never clean up a cgroup that
+        // is not ours.
+        let mut cgroups = vec![cgroup_path.clone()];
+        if cgroup_version == CgroupVersion::V1 {
+            cgroups.extend(hierarchies_v1(&cgroup_path));
+        }
+        for cgroup in &cgroups {
+            Self::check_leftover(cgroup)?;
+        }
+        if config.driver == CgroupDriver::Systemd {
+            if cgroup_version == CgroupVersion::V1 {
+                Self::check_v1_support(&config)?;
+            }
+            // systemd creates the cgroup with the runtime in it, in place of
+            // the add_process that would follow setup.
+            let scope = SystemdScope::start(&config, std::process::id())?;
+            return Ok(Self {
+                cgroup_path,
+                config,
+                cgroup_version,
+                scope: Some(scope),
+                origin: Vec::new(),
+            });
+        }
+        let own_pid = std::process::id();
+        let origin = match cgroup_version {
+            CgroupVersion::V1 => process_cgroups_v1(&config.root, own_pid),
+            CgroupVersion::V2 => process_cgroup(&config.root, own_pid).into_iter().collect(),
         };
-
-        Ok(Self {
+        let manager = Self {
             cgroup_path,
             config,
             cgroup_version,
-        })
+            scope: None,
+            origin,
+        };
+        if cgroups.iter().any(|cgroup| cgroup.exists()) {
+            if manager.config.reuse {
+                log::warn!(
+                    "Reusing leftover cgroup {:?}, resetting its limits",
+                    manager.cgroup_path
+                );
+                manager.reset_limits();
+            } else {
+                log::warn!(
+                    "Removing leftover cgroup {:?} before recreating it",
+                    manager.cgroup_path
+                );
+                for cgroup in &cgroups {
+                    Self::remove_subtree(cgroup)?;
+                }
+            }
+        }
+        Ok(manager)
+    }
+    // A cgroup of the same name that still has processes belongs to someone
+    // else (e.g. a concurrent run with the same --name).
+    fn check_leftover(cgroup: &Path) -> ContainerResult<()> {
+        let pids = subtree_processes(cgroup)?;
+        if !pids.is_empty() {
+            let pids: Vec<String> = pids.iter().map(i32::to_string).collect();
+            return Err(ContainerError::cgroup_setup(format!(
+                "Cgroup {:?} already exists and is in use by PID(s) {}; is another container running under the same name?",
+                cgroup,
+                pids.join(", ")
+            )));
+        }
+        Ok(())
+    }
+    // Back to what a new cgroup starts with, so a reused one only has the
+    // limits setup() writes. Files of controllers that are not enabled are
+    // missing and skipped.
+    fn reset_limits(&self) {
+        let defaults: &[(&str, &str)] = match self.cgroup_version {
+            CgroupVersion::V1 => &[("memory.limit_in_bytes", "-1")],
+            CgroupVersion::V2 => &[
+                ("memory.max", "max"),
+                ("memory.high", "max"),
+                ("memory.swap.max", "max"),
+                ("memory.zswap.writeback", "1"),
+                ("memory.oom.group", "0"),
+                ("cpu.weight", "100"),
+                ("cpu.max", "max"),
+                ("pids.max", "max"),
+                ("cgroup.freeze", "0"),
+            ],
+        };
+        for (file, value) in defaults {
+            let path = self.cgroup_path.join(file);
+            if path.exists()
+                && let Err(e) = self.write_file(&path, value)
+            {
+                log::warn!("Failed to reset {:?}: {}", path, e);
+            }
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.cgroup_path
+    }
+
+    // What new() and setup() would run into, without creating or removing
+    // anything: limits the cgroup version cannot express, controllers the
+    // kernel does not offer, and a cgroup of the same name still in use.
+    pub fn check(config: &CgroupConfig) -> ContainerResult<()> {
+        config.validate()?;
+        let cgroup_version = Self::detect_cgroup_version(&config.root)?;
+        let cgroup_path = Self::cgroup_path(cgroup_version, config);
+        match cgroup_version {
+            CgroupVersion::V1 => {
+                Self::check_v1_support(config)?;
+                // Only the memory hierarchy is used; the rest is not applied.
+                if !cgroup_path.parent().is_some_and(Path::exists) {
+                    return Err(ContainerError::invalid_configuration(
+                        "Neither cgroup v2 nor the cgroup v1 memory hierarchy is mounted at \
+                         /sys/fs/cgroup; drop the resource limit options",
+                    ));
+                }
+                if config.devices.is_some()
+                    && !config.best_effort
+                    && !devices_path_v1(&cgroup_path)
+                        .parent()
+                        .is_some_and(Path::exists)
+                {
+                    return Err(ContainerError::invalid_configuration(
+                        "The cgroup v1 devices hierarchy is not mounted; pass --no-device-cgroup \
+                         to run without device access control",
+                    ));
+                }
+                if config.rt_runtime.is_some()
+                    && !config.best_effort
+                    && !cpu_path_v1(&cgroup_path).parent().is_some_and(Path::exists)
+                {
+                    return Err(ContainerError::invalid_configuration(
+                        "The cgroup v1 cpu hierarchy is not mounted; drop --cpu-rt-runtime or \
+                         pass --cgroup-best-effort",
+                    ));
+                }
+            }
+            CgroupVersion::V2 => {
+                if config.driver == CgroupDriver::Systemd && !SystemdScope::is_running() {
+                    return Err(ContainerError::invalid_configuration(
+                        "--cgroup-driver systemd needs systemd to manage this host's cgroups, \
+                         and it does not; use --cgroup-driver cgroupfs",
+                    ));
+                }
+                Self::check_v2_support(config)?;
+                let controllers = config.root.join("cgroup.controllers");
+                let available =
+                    fs::read_to_string(&controllers).map_err(|e| ContainerError::Cgroup {
+                        detail: ErrorDetail::new("Failed to read")
+                            .path(&controllers)
+                            .source(e),
+                    })?;
+                let missing = config.controllers().into_iter().filter(|(controller, _)| {
+                    !available.split_whitespace().any(|c| c == *controller)
+                });
+                for (controller, options) in missing {
+                    if !config.best_effort {
+                        return Err(ContainerError::invalid_configuration(format!(
+                            "The cgroup {controller} controller is not available in {}; \
+                             drop {options} or pass --cgroup-best-effort",
+                            config.root.display()
+                        )));
+                    }
+                    log::warn!(
+                        "The cgroup {controller} controller is not available; \
+                         {options} will not be applied"
+                    );
+                }
+            }
+        }
+        let mut cgroups = vec![cgroup_path.clone()];
+        if cgroup_version == CgroupVersion::V1 {
+            cgroups.extend(hierarchies_v1(&cgroup_path));
+        }
+        for cgroup in &cgroups {
+            Self::check_leftover(cgroup)?;
+        }
+        Ok(())
     }
-    fn detect_cgroup_version() -> ContainerResult<CgroupVersion> {
-        let cgroup_controllers = Path::new(CGROUP_ROOT).join("cgroup.controllers");
+
+    // The cgroup and the interface files setup() would write, with values.
+    pub fn plan(config: &CgroupConfig) -> ContainerResult<(PathBuf, Vec<(&'static str, String)>)> {
+        let cgroup_version = Self::detect_cgroup_version(&config.root)?;
+        let mut files = Vec::new();
+        match cgroup_version {
+            CgroupVersion::V1 => {
+                if let Some(limit) = config.memory_limit {
+                    files.push(("memory.limit_in_bytes", limit.to_string()));
+                }
+                if let Some(swappiness) = config.swappiness {
+                    files.push(("memory.swappiness", swappiness.to_string()));
+                }
+                if let Some(period) = config.rt_period {
+                    files.push(("cpu.rt_period_us", period.to_string()));
+                }
+                if let Some(runtime) = config.rt_runtime {
+                    files.push(("cpu.rt_runtime_us", runtime.to_string()));
+                }
+                if let Some(rules) = &config.devices {
+                    files.push(("devices.deny", "a".to_string()));
+                    files.extend(rules.iter().map(|rule| ("devices.allow", rule.to_string())));
+                }
+            }
+            // The limits are the unit's properties, for systemd to write.
+            CgroupVersion::V2 if config.driver == CgroupDriver::Systemd => {
+                files.push((
+                    "cgroup.subtree_control",
+                    CONTROLLERS.map(|c| format!("+{c}")).join(" "),
+                ));
+                files.extend(
+                    SystemdScope::properties(config)
+                        .into_iter()
+                        .map(|(property, value)| (property, value.to_string())),
+                );
+            }
+            CgroupVersion::V2 => {
+                // For the init's leaf, created below the container cgroup.
+                files.push((
+                    "cgroup.subtree_control",
+                    CONTROLLERS.map(|c| format!("+{c}")).join(" "),
+                ));
+                if let Some(limit) = config.memory_limit {
+                    files.push(("memory.max", limit.to_string()));
+                }
+                if let Some(limit) = config.memory_high {
+                    files.push(("memory.high", limit.to_string()));
+                }
+                if config.oom_group {
+                    files.push(("memory.oom.group", "1".to_string()));
+                }
+                if let Some(limit) = config.memory_swap_limit {
+                    files.push(("memory.swap.max", limit.to_string()));
+                }
+                if let Some((file, value)) = config.swappiness_v2() {
+                    files.push((file, value.to_string()));
+                }
+                if let Some(weight) = config.cpu_weight {
+                    files.push(("cpu.weight", weight.to_string()));
+                }
+                match (config.cpu_quota, config.cpu_period) {
+                    (Some(u64::MAX), Some(_)) => files.push(("cpu.max", "max".to_string())),
+                    (Some(quota), Some(period)) => {
+                        files.push(("cpu.max", format!("{quota} {period}")))
+                    }
+                    _ => {}
+                }
+                match config.pids_limit {
+                    Some(u64::MAX) => files.push(("pids.max", "max".to_string())),
+                    Some(limit) => files.push(("pids.max", limit.to_string())),
+                    None => {}
+                }
+            }
+        }
+        // Not a file: a BPF program attached to the init's leaf.
+        if cgroup_version == CgroupVersion::V2
+            && let Some(rules) = &config.devices
+        {
+            files.extend(
+                rules
+                    .iter()
+                    .map(|rule| ("device program allow", rule.to_string())),
+            );
+        }
+        Ok((Self::cgroup_path(cgroup_version, config), files))
+    }
+
+    fn cgroup_path(cgroup_version: CgroupVersion, config: &CgroupConfig) -> PathBuf {
+        let name = &config.name;
+        match (cgroup_version, config.driver) {
+            (CgroupVersion::V1, _) => config.root.join("memory").join(name),
+            (CgroupVersion::V2, CgroupDriver::Cgroupfs) => config.root.join(name),
+            (CgroupVersion::V2, CgroupDriver::Systemd) => {
+                SystemdScope::scope_path(&SystemdScope::unit_name(config))
+            }
+        }
+    }
+
+    fn detect_cgroup_version(root: &Path) -> ContainerResult<CgroupVersion> {
+        let cgroup_controllers = root.join("cgroup.controllers");
         if cgroup_controllers.exists() {
             log::debug!("Detected cgroup v2");
             Ok(CgroupVersion::V2)
@@ -96,112 +923,74 @@ impl CgroupManager {
     }
     pub fn setup(&self) -> ContainerResult<()> {
         log::info!("Setting up cgroups for container: {}", self.config.name);
+        self.config.validate()?;
         match self.cgroup_version {
             CgroupVersion::V1 => self.setup_v1(),
             CgroupVersion::V2 => self.setup_v2(),
-        };
-        Ok(())
+        }
     }
     pub fn add_process(&self, pid: i32) -> ContainerResult<()> {
         log::info!("Adding process {} to cgroup", pid);
         match self.cgroup_version {
             CgroupVersion::V1 => self.add_process_v1(pid),
             CgroupVersion::V2 => self.add_process_v2(pid),
+        }
+    }
+    pub fn check_oom_events(&self) -> ContainerResult<()> {
+        let kills = match self.cgroup_version {
+            CgroupVersion::V1 => self.read_event_counter("memory.oom_control", "oom_kill"),
+            CgroupVersion::V2 => self.read_event_counter("memory.events", "oom_kill"),
+        };
+        let kills = match kills {
+            Ok(kills) => kills.unwrap_or(0),
+            Err(e) => {
+                log::debug!("Could not read OOM events: {}", e);
+                return Ok(());
+            }
         };
+        if kills > 0 {
+            let limit = self.config.memory_limit.unwrap_or(0);
+            log::error!(
+                "Container cgroup {:?} recorded {} OOM kill(s) (memory limit: {} bytes)",
+                self.cgroup_path,
+                kills,
+                limit
+            );
+            return Err(ContainerError::OutOfMemory { kills, limit });
+        }
         Ok(())
     }
-    //pub fn cleanup(&self) -> ContainerResult<()> {
-    //    log::info!("Cleaning up cgroup: {}", self.config.name);
-    //    if self.cgroup_path.exists() {
-    //        fs::remove_dir(&self.cgroup_path).map_err(|e| {
-    //            log::warn!("Failed to remove cgroup directory: {}", e);
-    //            ContainerError::Cgroup {
-    //                message: format!("Failed to cleanup cgroup: {}", e),
-    //            }
-    //        })?;
-    //        log::info!("Successfully cleaned up cgroup");
-    //    } else {
-    //        log::debug!("Cgroup directory doesn't exist, skipping cleanup");
-    //    }
-    //    Ok(())
-    //}
-    // fn cleanup(&self) -> ContainerResult<()> {
-    //     if !self.cgroup_path.exists() {
-    //         log::info!("Cgroup {:#?} already removed", self.cgroup_path);
-    //         return Ok(());
-    //     }
-    //     let reclaim_path = self.cgroup_path.join("memory.reclaim");
-    //     if reclaim_path.exists() {
-    //         if let Err(e) = fs::write(&reclaim_path, b"1") {
-    //             log::warn!(
-    //                 "Failed to write memory.reclaim for {:#?}: {}",
-    //                 self.cgroup_path,
-    //                 e
-    //             )
-    //         } else {
-    //             log::info!("Triggered memory reclaim for {:#?}", self.cgroup_path);
-    //         }
-    //     }
-    //     if let Ok(entries) = fs::read_dir(&self.cgroup_path) {
-    //         for entry in entries.flatten() {
-    //             let path = entry.path();
-    //             if path.is_dir() {
-    //                 let child = CgroupManager {
-    //                     cgroup_path: path,
-    //                     cgroup_version: self.cgroup_version,
-    //                     config: self.config.clone(),
-    //                 };
-    //                 let _ = child.cleanup();
-    //             }
-    //         }
-    //     }
-    //     let timeout = Duration::from_secs(2);
-    //     let start = std::time::Instant::now();
-    //     loop {
-    //         let mem_current = fs::read_to_string(&self.cgroup_path.join("memory.current"))
-    //             .ok()
-    //             .and_then(|s| s.trim().parse::<u64>().ok())
-    //             .unwrap_or(0);
-    //         let kmem_usage =
-    //             fs::read_to_string(&self.cgroup_path.join("memory.kmem.usage_in_bytes"))
-    //                 .ok()
-    //                 .and_then(|s| s.trim().parse::<u64>().ok())
-    //                 .unwrap_or(0);
-    //         if mem_current == 0 && kmem_usage == 0 {
-    //             match fs::read_dir(&self.cgroup_path) {
-    //                 Ok(_) => {
-    //                     log::info!("Successfully removed cgroup: {:#?}", self.cgroup_path);
-    //                     break;
-    //                 }
-    //                 Err(e) => {
-    //                     if start.elapsed() > timeout {
-    //                         log::error!(
-    //                             "Failed to remove cgroup {:#?} after retries: {:#?}",
-    //                             self.cgroup_path,
-    //                             e
-    //                         );
-    //                         break;
-    //                     }
-    //                 }
-    //             }
-    //         }
-    //         if start.elapsed() > timeout {
-    //             log::warn!(
-    //                 "Timeout reached waiting for memory to be released in {:#?}",
-    //                 self.cgroup_path
-    //             );
-    //             break;
-    //         }
-    //         thread::sleep(Duration::from_millis(50));
-    //     }
-    //     Ok(())
-    // }
+    pub fn usage_summary(&self) -> UsageSummary {
+        self.stats_reader().summary()
+    }
+    pub fn stats_reader(&self) -> StatsReader {
+        StatsReader {
+            cgroup_path: self.cgroup_path.clone(),
+            cgroup_version: self.cgroup_version,
+        }
+    }
+    pub fn freezer(&self) -> ContainerResult<Freezer> {
+        Ok(Freezer::new(&self.cgroup_path, self.cgroup_version))
+    }
+    fn read_event_counter(&self, file: &str, key: &str) -> ContainerResult<Option<u64>> {
+        let content = self.read_file(&self.cgroup_path.join(file))?;
+        Ok(parse_keyed_value(&content, key))
+    }
     fn cleanup(&self) -> ContainerResult<()> {
-        use std::{fs, thread, time::Duration};
-
         let path = &self.cgroup_path;
 
-        // 1️⃣ Trigger memory reclaim if possible
+        // Frozen tasks never act on the signals below.
+        if let Ok(freezer) = self.freezer()
+            && freezer.is_frozen()
+            && let Err(e) = freezer.thaw()
+        {
+            log::warn!("Failed to thaw cgroup {:?}: {}", path, e);
+        }
+        if let Err(e) = self.stop_processes() {
+            log::warn!("Failed to stop processes in cgroup {:?}: {}", path, e);
+        }
+
+        // Reclaim memory first, where the kernel supports it.
         let reclaim_path = path.join("memory.reclaim");
         if reclaim_path.exists() {
             if let Err(e) = fs::write(&reclaim_path, b"1") {
@@ -211,105 +1000,339 @@ impl CgroupManager {
             }
         }
 
-        // 2️⃣ Clean child cgroups first (recursive)
+        // Child cgroups are removed bottom-up, then the cgroup itself.
+        if let Some(scope) = &self.scope {
+            // The scope's own cgroup goes with the unit.
+            if let Err(e) = Self::remove_subtree(&self.leaf()) {
+                log::warn!("{}", e);
+            }
+            // An emptied scope stops on its own, and is gone then.
+            if path.exists()
+                && let Err(e) = scope.stop()
+            {
+                log::warn!("{}", e);
+            }
+            return Ok(());
+        }
+        match Self::remove_subtree(path) {
+            Ok(()) => log::info!("Removed cgroup {:?}", path),
+            Err(e) => log::warn!("{}", e),
+        }
+        if self.cgroup_version == CgroupVersion::V1 {
+            for cgroup in hierarchies_v1(path) {
+                if let Err(e) = Self::remove_subtree(&cgroup) {
+                    log::warn!("{}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_subtree(path: &Path) -> ContainerResult<()> {
         if let Ok(entries) = fs::read_dir(path) {
             for entry in entries.flatten() {
                 let sub = entry.path();
                 if sub.is_dir() {
-                    let child = CgroupManager {
-                        cgroup_path: sub,
-                        cgroup_version: self.cgroup_version,
-                        config: self.config.clone(),
-                    };
-                    let _ = child.cleanup();
+                    Self::remove_subtree(&sub)?;
                 }
             }
         }
+        delete_with_retry(
+            path,
+            REMOVE_ATTEMPTS,
+            Duration::from_millis(10),
+            Duration::from_millis(500),
+        )
+    }
 
-        // 3️⃣ Wait for memory release before removing
-        let timeout = Duration::from_secs(2);
-        let start = std::time::Instant::now();
-
-        loop {
-            let mem_current = fs::read_to_string(path.join("memory.current"))
-                .ok()
-                .and_then(|s| s.trim().parse::<u64>().ok())
-                .unwrap_or(0);
-            let kmem_usage = fs::read_to_string(path.join("memory.kmem.usage_in_bytes"))
-                .ok()
-                .and_then(|s| s.trim().parse::<u64>().ok())
-                .unwrap_or(0);
-
-            if mem_current == 0 && kmem_usage == 0 {
-                thread::sleep(Duration::from_secs(1)); // mimic your manual `sleep 1`
+    fn stop_processes(&self) -> ContainerResult<()> {
+        if !self.cgroup_path.join("cgroup.procs").exists() {
+            return Ok(());
+        }
+        self.evacuate_self()?;
+        if self.list_processes()?.is_empty() {
+            return Ok(());
+        }
+        if self.config.force_kill {
+            log::info!("Force-killing processes in cgroup {:?}", self.cgroup_path);
+            self.kill_all()?;
+        } else {
+            log::info!(
+                "Sending SIGTERM to processes in cgroup {:?}",
+                self.cgroup_path
+            );
+            self.signal_all(Signal::SIGTERM)?;
+            if self.wait_until_empty(STOP_GRACE_PERIOD)? {
+                return Ok(());
             }
-
-            if start.elapsed() > timeout {
-                log::warn!(
-                    "Timeout waiting for memory release in {:?} (mem={}, kmem={})",
-                    path,
-                    mem_current,
-                    kmem_usage
-                );
-                break;
+            log::warn!(
+                "Processes still running after {:?}, escalating to SIGKILL",
+                STOP_GRACE_PERIOD
+            );
+            self.kill_all()?;
+        }
+        if !self.wait_until_empty(KILL_WAIT_PERIOD)? {
+            log::warn!(
+                "Processes still present in cgroup {:?} after SIGKILL",
+                self.cgroup_path
+            );
+        }
+        Ok(())
+    }
+    fn kill_all(&self) -> ContainerResult<()> {
+        let cgroup_kill = self.cgroup_path.join("cgroup.kill");
+        if cgroup_kill.exists() {
+            self.write_file(&cgroup_kill, "1")?;
+            log::debug!("Wrote cgroup.kill for {:?}", self.cgroup_path);
+            Ok(())
+        } else {
+            self.signal_all(Signal::SIGKILL)
+        }
+    }
+    fn signal_all(&self, signal: Signal) -> ContainerResult<()> {
+        for pid in self.list_processes()? {
+            if let Err(e) = kill(Pid::from_raw(pid), signal) {
+                log::debug!("Failed to send {} to {}: {}", signal, pid, e);
             }
-
-            // thread::sleep(Duration::from_millis(50));
         }
-        match fs::remove_dir_all(path) {
-            Ok(_) => log::info!("Removed cgroup {:?}", path),
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                log::info!("Cgroup {:?} already gone (ENOENT)", path)
+        Ok(())
+    }
+    fn wait_until_empty(&self, timeout: Duration) -> ContainerResult<bool> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if self.list_processes()?.is_empty() {
+                return Ok(true);
             }
-            Err(e) => log::warn!("Failed to remove cgroup {:?}: {}", path, e),
+            std::thread::sleep(Duration::from_millis(100));
         }
-
+        Ok(self.list_processes()?.is_empty())
+    }
+    fn list_processes(&self) -> ContainerResult<Vec<i32>> {
+        let own_pid = getpid().as_raw();
+        Ok(subtree_processes(&self.cgroup_path)?
+            .into_iter()
+            .filter(|pid| *pid != own_pid)
+            .collect())
+    }
+    // systemd starts the scope with the runtime in it, so the runtime has to
+    // step out again before anything acts on the whole cgroup: signalling,
+    // cgroup.kill or freezing.
+    pub fn evacuate_self(&self) -> ContainerResult<()> {
+        // Each cgroup the runtime was added to, with the container cgroup it
+        // belongs to: the runtime goes back to where it was in that
+        // hierarchy, or else to the latter's parent.
+        let mut cgroups = vec![(self.leaf(), self.cgroup_path.clone())];
+        if self.cgroup_version == CgroupVersion::V1 {
+            cgroups
+                .extend(hierarchies_v1(&self.cgroup_path).map(|cgroup| (cgroup.clone(), cgroup)));
+        }
+        let own_pid = getpid().as_raw();
+        for (cgroup, container) in cgroups {
+            let procs = fs::read_to_string(cgroup.join("cgroup.procs")).unwrap_or_default();
+            if !procs.lines().any(|line| line.trim() == own_pid.to_string()) {
+                continue;
+            }
+            let parent = container.parent().unwrap_or(&self.config.root);
+            // A slice only has other units in it, so under systemd the
+            // runtime goes back to where it came from too.
+            let origin = self
+                .scope
+                .as_ref()
+                .and_then(SystemdScope::origin)
+                .or_else(|| {
+                    self.origin
+                        .iter()
+                        .find(|origin| origin.starts_with(parent) && origin.exists())
+                        .map(PathBuf::as_path)
+                });
+            // The origin may have enabled controllers for children since,
+            // which keeps processes out of it on cgroup v2.
+            let home = match origin {
+                Some(origin)
+                    if self
+                        .write_file(&origin.join("cgroup.procs"), &own_pid.to_string())
+                        .map_err(|e| log::debug!("Could not return to {:?}: {}", origin, e))
+                        .is_ok() =>
+                {
+                    origin
+                }
+                _ => {
+                    self.write_file(&parent.join("cgroup.procs"), &own_pid.to_string())?;
+                    parent
+                }
+            };
+            log::debug!(
+                "Moved runtime process {} out of {:?} into {:?}",
+                own_pid,
+                cgroup,
+                home
+            );
+        }
+        Ok(())
+    }
+    // Where the container's processes are placed.
+    fn leaf(&self) -> PathBuf {
+        match self.cgroup_version {
+            CgroupVersion::V1 => self.cgroup_path.clone(),
+            CgroupVersion::V2 => self.cgroup_path.join(INIT_CGROUP),
+        }
+    }
+    // Makes the init's cgroup the container root's, as a systemd unit with
+    // Delegate=yes would: the directory and the files a manager writes to
+    // create, populate and enable controllers in sub-cgroups. The limits in
+    // the container cgroup above stay out of reach.
+    pub fn delegate(&self, uid: u32, gid: u32) -> ContainerResult<()> {
+        let leaf = self.leaf();
+        let owner = (Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)));
+        for path in [
+            leaf.clone(),
+            leaf.join("cgroup.procs"),
+            leaf.join("cgroup.threads"),
+            leaf.join("cgroup.subtree_control"),
+        ] {
+            chown(&path, owner.0, owner.1).map_err(|e| ContainerError::Cgroup {
+                detail: ErrorDetail::new("Failed to chown").path(&path).source(e),
+            })?;
+        }
+        log::info!("Delegated cgroup {:?} to {}:{}", leaf, uid, gid);
         Ok(())
     }
 
     fn setup_v2(&self) -> ContainerResult<()> {
+        Self::check_v2_support(&self.config)?;
         fs::create_dir_all(&self.cgroup_path).map_err(|e| ContainerError::Cgroup {
-            message: format!("Failed to create cgroup directory: {}", e),
+            detail: ErrorDetail::new("Failed to create cgroup directory")
+                .path(&self.cgroup_path)
+                .source(e),
         })?;
-        log::debug!("Created cgroup directory: {:?}", self.cgroup_path);
-        self.enable_controllers_v2()?;
-        if let Some(memory_limit) = self.config.memory_limit {
-            self.set_memory_limit_v2(memory_limit)?;
-        };
-        if let Some(swap_limit) = self.config.memory_swap_limit {
-            self.set_memory_swap_v2(swap_limit)?;
-        };
-        if let Some(cpu_weight) = self.config.cpu_weight {
-            self.set_cpu_weight_v2(cpu_weight)?;
-        };
-        if let Some(cpu_quota) = self.config.cpu_quota {
-            if let Some(cpu_period) = self.config.cpu_period {
-                self.set_cpu_max_v2(cpu_quota, cpu_period)?;
+        let leaf = self.leaf();
+        fs::create_dir_all(&leaf).map_err(|e| ContainerError::Cgroup {
+            detail: ErrorDetail::new("Failed to create cgroup directory")
+                .path(&leaf)
+                .source(e),
+        })?;
+        log::debug!("Created cgroup directories: {:?}", leaf);
+        if self.scope.is_some() {
+            // systemd started the scope with the runtime in it, which has to
+            // move down to the leaf before the scope can enable controllers.
+            self.add_process_v2(getpid().as_raw())?;
+        }
+        self.enable_controllers_v2();
+        let missing = self.missing_controllers_v2()?;
+        if !missing.is_empty() {
+            let names: Vec<&str> = missing.iter().map(|(controller, _)| *controller).collect();
+            let parent = self.cgroup_path.parent().unwrap_or(&self.config.root);
+            if !self.config.best_effort {
+                return Err(ContainerError::cgroup_setup(format!(
+                    "Cgroup controller(s) {} not delegated to {:?}: enable them in {:?} \
+                     and each cgroup above it (under systemd, run from a unit with Delegate=yes), \
+                     or pass --cgroup-best-effort to run without the limits that need them",
+                    names.join(", "),
+                    self.cgroup_path,
+                    parent.join("cgroup.subtree_control")
+                )));
             }
-        };
-        if let Some(pids_limit) = self.config.pids_limit {
+            for (controller, options) in &missing {
+                log::warn!(
+                    "Cgroup {} controller not delegated to {:?}; skipping {}",
+                    controller,
+                    self.cgroup_path,
+                    options
+                );
+            }
+        }
+        if let Some(rules) = &self.config.devices {
+            devices::attach_device_program(&leaf, rules)?;
+        }
+        if let Some(scope) = &self.scope {
+            log::info!("Limits are applied by systemd through {}", scope.unit());
+            return Ok(());
+        }
+        let enabled = |controller| !missing.iter().any(|(c, _)| *c == controller);
+        if enabled("memory") {
+            if let Some(memory_limit) = self.config.memory_limit {
+                self.set_memory_limit_v2(memory_limit)?;
+            };
+            if let Some(memory_high) = self.config.memory_high {
+                self.set_memory_high_v2(memory_high)?;
+            };
+            if self.config.oom_group {
+                self.set_oom_group_v2()?;
+            }
+            if let Some(swap_limit) = self.config.memory_swap_limit {
+                self.set_memory_swap_v2(swap_limit)?;
+            };
+            if let Some(swappiness) = self.config.swappiness {
+                self.set_swappiness_v2(swappiness);
+            }
+        }
+        if enabled("cpu") {
+            if let Some(cpu_weight) = self.config.cpu_weight {
+                self.set_cpu_weight_v2(cpu_weight)?;
+            };
+            if let (Some(cpu_quota), Some(cpu_period)) =
+                (self.config.cpu_quota, self.config.cpu_period)
+            {
+                self.set_cpu_max_v2(cpu_quota, cpu_period)?;
+            };
+        }
+        if enabled("pids")
+            && let Some(pids_limit) = self.config.pids_limit
+        {
             self.set_pids_limit_v2(pids_limit)?;
         };
 
         log::info!("Cgroup v2 setup completed successfully");
         Ok(())
     }
-    fn enable_controllers_v2(&self) -> ContainerResult<()> {
-        let parent_subtree = Path::new(CGROUP_ROOT).join("cgroup.subtree_control");
-        let controllers = vec!["cpu", "memory", "pids", "io"];
-        for controller in controllers {
-            let enable_cmd = format!("+{}", controller);
-            if let Err(e) = self.write_file(&parent_subtree, &enable_cmd) {
-                log::warn!(
-                    "Failed to enable {} controller: {} (may already be enabled)",
-                    controller,
-                    e
-                );
-            } else {
-                log::debug!("Enabled {} controller", controller);
+    // A controller reaches a cgroup only if every cgroup above it has it in
+    // cgroup.subtree_control, so it is enabled top-down from the root down to
+    // the container cgroup, for the init's leaf. Under
+    // systemd the cgroups above may belong to a unit that was not given
+    // delegation, in which case this fails and missing_controllers_v2 reports
+    // what is left out.
+    fn enable_controllers_v2(&self) {
+        // The cgroups above a scope are systemd's to configure.
+        let root = match self.scope {
+            Some(_) => self.cgroup_path.as_path(),
+            None => self.config.root.as_path(),
+        };
+        let leaf = self.leaf();
+        let mut ancestors: Vec<&Path> = leaf
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(root))
+            .collect();
+        ancestors.reverse();
+        for dir in ancestors {
+            let subtree_control = dir.join("cgroup.subtree_control");
+            let enabled = fs::read_to_string(&subtree_control).unwrap_or_default();
+            for controller in CONTROLLERS {
+                if enabled.split_whitespace().any(|c| c == controller) {
+                    continue;
+                }
+                match self.write_file(&subtree_control, &format!("+{}", controller)) {
+                    Ok(()) => log::debug!("Enabled {} controller in {:?}", controller, dir),
+                    Err(e) => log::debug!(
+                        "Failed to enable {} controller in {:?}: {}",
+                        controller,
+                        dir,
+                        e
+                    ),
+                }
             }
         }
-        Ok(())
+    }
+    // The controllers the limits need that did not make it to the cgroup.
+    fn missing_controllers_v2(&self) -> ContainerResult<Vec<(&'static str, &'static str)>> {
+        let available = self.read_file(&self.cgroup_path.join("cgroup.controllers"))?;
+        Ok(self
+            .config
+            .controllers()
+            .into_iter()
+            .filter(|(controller, _)| !available.split_whitespace().any(|c| c == *controller))
+            .collect())
     }
 
     fn write_file(&self, path: &Path, content: &str) -> ContainerResult<()> {
@@ -318,16 +1341,16 @@ impl CgroupManager {
             .truncate(true)
             .open(path)
             .map_err(|e| ContainerError::Cgroup {
-                message: format!("Failed to open {:?}: {}", path, e),
+                detail: ErrorDetail::new("Failed to open").path(path).source(e),
             })?;
         file.write_all(content.as_bytes())
             .map_err(|e| ContainerError::Cgroup {
-                message: format!("Failed to write to {:?}: {}", path, e),
+                detail: ErrorDetail::new("Failed to write to").path(path).source(e),
             })?;
         Ok(())
     }
     fn set_memory_limit_v2(&self, limit: u64) -> ContainerResult<()> {
-        let memory_max = self.cgroup_path.join("memory_max");
+        let memory_max = self.cgroup_path.join("memory.max");
         self.write_file(&memory_max, &limit.to_string())?;
         log::info!(
             "Set memory limit: {} bytes ({} MB)",
@@ -336,15 +1359,54 @@ impl CgroupManager {
         );
         Ok(())
     }
+    fn set_memory_high_v2(&self, limit: u64) -> ContainerResult<()> {
+        let memory_high = self.cgroup_path.join("memory.high");
+        self.write_file(&memory_high, &limit.to_string())?;
+        log::info!(
+            "Set memory high watermark: {} bytes ({} MB)",
+            limit,
+            limit / 1024 / 1024
+        );
+        Ok(())
+    }
+    fn set_oom_group_v2(&self) -> ContainerResult<()> {
+        let oom_group = self.cgroup_path.join("memory.oom.group");
+        self.write_file(&oom_group, "1")?;
+        log::info!("Enabled memory.oom.group: the whole cgroup is OOM-killed together");
+        Ok(())
+    }
     fn set_memory_swap_v2(&self, limit: u64) -> ContainerResult<()> {
         let swap_max = self.cgroup_path.join("memory.swap.max");
-        let _ = self.write_file(&swap_max, &limit.to_string())?;
+        self.write_file(&swap_max, &limit.to_string())?;
         log::info!("Set swap limit: {} bytes", limit);
         Ok(())
     }
+    // Approximated (see CgroupConfig::swappiness_v2); on a kernel without the
+    // file, or for a value at or above the default, nothing is written.
+    fn set_swappiness_v2(&self, swappiness: u64) {
+        let Some((file, value)) = self.config.swappiness_v2() else {
+            log::debug!("Swappiness {} needs nothing on cgroup v2", swappiness);
+            return;
+        };
+        let path = self.cgroup_path.join(file);
+        match self.write_file(&path, value) {
+            Ok(()) => log::info!(
+                "Approximated swappiness {} on cgroup v2 with {}={}",
+                swappiness,
+                file,
+                value
+            ),
+            Err(e) => log::warn!(
+                "Failed to approximate swappiness {} with {}: {}",
+                swappiness,
+                file,
+                e
+            ),
+        }
+    }
     fn set_cpu_weight_v2(&self, weight: u64) -> ContainerResult<()> {
         let cpu_weight = self.cgroup_path.join("cpu.weight");
-        let _ = self.write_file(&cpu_weight, &weight.to_string())?;
+        self.write_file(&cpu_weight, &weight.to_string())?;
         log::info!("Set CPU weight: {}", weight);
         Ok(())
     }
@@ -355,7 +1417,7 @@ impl CgroupManager {
         } else {
             format!("{} {}", quota, period)
         };
-        let _ = self.write_file(&cpu_max, &value)?;
+        self.write_file(&cpu_max, &value)?;
         log::info!(
             "Set CPU quota: {} us / {} us ({:.1}%)",
             quota,
@@ -371,12 +1433,12 @@ impl CgroupManager {
         } else {
             limit.to_string()
         };
-        let _ = self.write_file(&pids_max, &value);
+        self.write_file(&pids_max, &value)?;
         log::info!("Set PIDs limit: {}", value);
         Ok(())
     }
     fn add_process_v2(&self, pid: i32) -> ContainerResult<()> {
-        let cgroup_process = self.cgroup_path.join("cgroup.procs");
+        let cgroup_process = self.leaf().join("cgroup.procs");
         self.write_file(&cgroup_process, &pid.to_string())?;
         log::debug!("Added process {} to cgroup", pid);
         Ok(())
@@ -384,23 +1446,164 @@ impl CgroupManager {
 
     // ==================== Cgroup V1 Implementation ====================
     fn setup_v1(&self) -> ContainerResult<()> {
+        Self::check_v1_support(&self.config)?;
+        self.setup_memory_v1()?;
+        let freezer = freezer_path_v1(&self.cgroup_path);
+        if freezer.parent().is_some_and(Path::exists) {
+            fs::create_dir_all(&freezer).map_err(|e| ContainerError::Cgroup {
+                detail: ErrorDetail::new("Failed to create freezer cgroup")
+                    .path(&freezer)
+                    .source(e),
+            })?;
+        }
+        if let Some(rules) = &self.config.devices {
+            self.setup_devices_v1(rules)?;
+        }
+        if let Some(runtime) = self.config.rt_runtime {
+            self.setup_realtime_v1(runtime)?;
+        }
+        log::info!("Cgroup v1 setup completed successfully");
+        Ok(())
+    }
+    // Everything denied, then the allow list let through.
+    fn setup_devices_v1(&self, rules: &[DeviceRule]) -> ContainerResult<()> {
+        let cgroup = devices_path_v1(&self.cgroup_path);
+        if !cgroup.parent().is_some_and(Path::exists) {
+            if !self.config.best_effort {
+                return Err(ContainerError::cgroup_setup(
+                    "The cgroup v1 devices hierarchy is not mounted; pass --no-device-cgroup \
+                     to run without device access control",
+                ));
+            }
+            log::warn!(
+                "The cgroup v1 devices hierarchy is not mounted; device access is not restricted"
+            );
+            return Ok(());
+        }
+        fs::create_dir_all(&cgroup).map_err(|e| ContainerError::Cgroup {
+            detail: ErrorDetail::new("Failed to create devices cgroup")
+                .path(&cgroup)
+                .source(e),
+        })?;
+        self.write_file(&cgroup.join("devices.deny"), "a")?;
+        for rule in rules {
+            self.write_file(&cgroup.join("devices.allow"), &rule.to_string())?;
+        }
+        log::info!("Restricted device access to {} rule(s)", rules.len());
+        Ok(())
+    }
+    // A new cpu cgroup has no realtime CPU time, so realtime tasks cannot be
+    // moved into it: only created when the time is given. The period goes
+    // first, as the runtime may not exceed it.
+    fn setup_realtime_v1(&self, runtime: u64) -> ContainerResult<()> {
+        let cgroup = cpu_path_v1(&self.cgroup_path);
+        if !cgroup.parent().is_some_and(Path::exists) {
+            if !self.config.best_effort {
+                return Err(ContainerError::cgroup_setup(
+                    "The cgroup v1 cpu hierarchy is not mounted; drop --cpu-rt-runtime or pass \
+                     --cgroup-best-effort",
+                ));
+            }
+            log::warn!(
+                "The cgroup v1 cpu hierarchy is not mounted; --cpu-rt-runtime is not applied"
+            );
+            return Ok(());
+        }
+        fs::create_dir_all(&cgroup).map_err(|e| ContainerError::Cgroup {
+            detail: ErrorDetail::new("Failed to create cpu cgroup")
+                .path(&cgroup)
+                .source(e),
+        })?;
+        if let Some(period) = self.config.rt_period {
+            self.write_file(&cgroup.join("cpu.rt_period_us"), &period.to_string())?;
+        }
+        // EINVAL: more than the period, or than what the parent has left.
+        self.write_file(&cgroup.join("cpu.rt_runtime_us"), &runtime.to_string())?;
+        log::info!("Set realtime runtime: {}us", runtime);
+        Ok(())
+    }
+    fn check_v1_support(config: &CgroupConfig) -> ContainerResult<()> {
+        if config.memory_high.is_some() {
+            return Err(ContainerError::invalid_configuration(
+                "--memory-high-mb (memory.high) is not supported on cgroup v1",
+            ));
+        }
+        if config.oom_group {
+            return Err(ContainerError::invalid_configuration(
+                "--memory-oom-group (memory.oom.group) is not supported on cgroup v1",
+            ));
+        }
+        if config.delegate {
+            return Err(ContainerError::invalid_configuration(
+                "--cgroup-delegate is not supported on cgroup v1",
+            ));
+        }
+        if config.driver == CgroupDriver::Systemd {
+            return Err(ContainerError::invalid_configuration(
+                "--cgroup-driver systemd is not supported on cgroup v1",
+            ));
+        }
+        Ok(())
+    }
+    fn check_v2_support(config: &CgroupConfig) -> ContainerResult<()> {
+        if config.rt_runtime.is_some() {
+            return Err(ContainerError::invalid_configuration(
+                "--cpu-rt-runtime is not supported on cgroup v2, which has no per-cgroup realtime \
+                 bandwidth; realtime tasks are throttled kernel-wide by kernel.sched_rt_runtime_us",
+            ));
+        }
+        if config.strict && config.swappiness.is_some() {
+            return Err(ContainerError::invalid_configuration(
+                "--memory-swappiness is unsupported on cgroup v2, which has no per-cgroup \
+                 swappiness; limit the container's swap instead, or drop --strict to have it \
+                 approximated",
+            ));
+        }
         Ok(())
     }
     fn setup_memory_v1(&self) -> ContainerResult<()> {
+        fs::create_dir_all(&self.cgroup_path).map_err(|e| ContainerError::Cgroup {
+            detail: ErrorDetail::new("Failed to create cgroup directory")
+                .path(&self.cgroup_path)
+                .source(e),
+        })?;
+        log::debug!("Created cgroup directory: {:?}", self.cgroup_path);
+        if let Some(limit) = self.config.memory_limit {
+            let limit_path = self.cgroup_path.join("memory.limit_in_bytes");
+            self.write_file(&limit_path, &limit.to_string())?;
+            log::info!(
+                "Set memory limit: {} bytes ({} MB)",
+                limit,
+                limit / 1024 / 1024
+            );
+        }
+        if let Some(swappiness) = self.config.swappiness {
+            let swappiness_path = self.cgroup_path.join("memory.swappiness");
+            self.write_file(&swappiness_path, &swappiness.to_string())?;
+            log::info!("Set swappiness: {}", swappiness);
+        }
         Ok(())
     }
     fn add_process_v1(&self, pid: i32) -> ContainerResult<()> {
+        let cgroup_process = self.cgroup_path.join("cgroup.procs");
+        self.write_file(&cgroup_process, &pid.to_string())?;
+        for cgroup in hierarchies_v1(&self.cgroup_path) {
+            if cgroup.exists() {
+                self.write_file(&cgroup.join("cgroup.procs"), &pid.to_string())?;
+            }
+        }
+        log::debug!("Added process {} to cgroup", pid);
         Ok(())
     }
     fn read_file(&self, path: &Path) -> ContainerResult<String> {
         let mut file = File::open(path).map_err(|e| ContainerError::Cgroup {
-            message: format!("Failed to open {:?}: {}", path, e),
+            detail: ErrorDetail::new("Failed to open").path(path).source(e),
         })?;
 
         let mut content = String::new();
         file.read_to_string(&mut content)
             .map_err(|e| ContainerError::Cgroup {
-                message: format!("Failed to read {:?}: {}", path, e),
+                detail: ErrorDetail::new("Failed to read").path(path).source(e),
             })?;
 
         Ok(content)
@@ -412,9 +1615,310 @@ impl Drop for CgroupManager {
         if let Err(e) = self.cleanup() {
             log::warn!(
                 "Cgroup cleanup failed in Drop for {:#?}: {:#?}",
-                self.cleanup(),
+                self.cgroup_path,
                 e
             )
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const NAME: &str = "container-test";
+
+    // An empty cgroup filesystem of either version. Interface files are
+    // plain files here, written where a test needs them.
+    fn fake_root(version: CgroupVersion) -> TempDir {
+        let root = tempfile::tempdir().unwrap();
+        match version {
+            CgroupVersion::V1 => {
+                for controller in ["memory", "freezer", "devices", "cpu"] {
+                    fs::create_dir(root.path().join(controller)).unwrap();
+                }
+            }
+            CgroupVersion::V2 => {
+                fs::write(root.path().join("cgroup.controllers"), "cpu memory pids io").unwrap()
+            }
+        }
+        root
+    }
+
+    fn config(root: &TempDir) -> CgroupConfig {
+        CgroupConfig::new(NAME.to_string()).with_root(root.path())
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    // Unlike a cgroupfs directory, one with files in it cannot be removed:
+    // clears them for the manager's Drop to clean up.
+    fn teardown(manager: CgroupManager, root: &Path) {
+        fn clear(dir: &Path) {
+            for entry in fs::read_dir(dir).unwrap().flatten() {
+                if entry.file_type().unwrap().is_dir() {
+                    clear(&entry.path());
+                } else {
+                    fs::remove_file(entry.path()).unwrap();
+                }
+            }
+        }
+        clear(root);
+        drop(manager);
+    }
+
+    #[test]
+    fn leftover_in_use_is_refused() {
+        let root = fake_root(CgroupVersion::V2);
+        write(&root.path().join(NAME).join("cgroup.procs"), "");
+        write(&root.path().join(NAME).join("init/cgroup.procs"), "4242\n");
+        let error = CgroupManager::new(config(&root)).unwrap_err();
+        assert!(
+            error.to_string().contains("in use by PID(s) 4242"),
+            "{error}"
+        );
+        assert!(root.path().join(NAME).join("init").exists());
+    }
+
+    #[test]
+    fn empty_leftover_is_removed() {
+        let root = fake_root(CgroupVersion::V2);
+        fs::create_dir_all(root.path().join(NAME).join("init")).unwrap();
+        let manager = CgroupManager::new(config(&root)).unwrap();
+        assert!(!root.path().join(NAME).exists());
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn empty_leftover_is_reused_with_default_limits() {
+        let root = fake_root(CgroupVersion::V2);
+        let cgroup = root.path().join(NAME);
+        write(&cgroup.join("cgroup.procs"), "");
+        write(&cgroup.join("memory.max"), "1048576");
+        write(&cgroup.join("pids.max"), "10");
+        let manager = CgroupManager::new(config(&root).with_reuse(true)).unwrap();
+        assert_eq!(manager.path(), cgroup);
+        assert_eq!(
+            fs::read_to_string(cgroup.join("memory.max")).unwrap(),
+            "max"
+        );
+        assert_eq!(fs::read_to_string(cgroup.join("pids.max")).unwrap(), "max");
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn leftover_in_another_v1_hierarchy_is_refused() {
+        let root = fake_root(CgroupVersion::V1);
+        write(
+            &root.path().join("freezer").join(NAME).join("cgroup.procs"),
+            "4242\n",
+        );
+        let error = CgroupManager::new(config(&root)).unwrap_err();
+        assert!(
+            error.to_string().contains("in use by PID(s) 4242"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn version_is_detected_from_the_root() {
+        let v1 = fake_root(CgroupVersion::V1);
+        let manager = CgroupManager::new(config(&v1)).unwrap();
+        assert_eq!(manager.cgroup_version, CgroupVersion::V1);
+        assert_eq!(manager.path(), v1.path().join("memory").join(NAME));
+        assert_eq!(
+            hierarchies_v1(manager.path()),
+            ["freezer", "devices", "cpu"].map(|c| v1.path().join(c).join(NAME))
+        );
+        teardown(manager, v1.path());
+
+        let v2 = fake_root(CgroupVersion::V2);
+        let manager = CgroupManager::new(config(&v2)).unwrap();
+        assert_eq!(manager.cgroup_version, CgroupVersion::V2);
+        assert_eq!(manager.path(), v2.path().join(NAME));
+        teardown(manager, v2.path());
+    }
+
+    fn procs(cgroup: &Path) -> String {
+        fs::read_to_string(cgroup.join("cgroup.procs")).unwrap()
+    }
+
+    #[test]
+    fn evacuate_self_returns_to_the_origin_v2() {
+        let root = fake_root(CgroupVersion::V2);
+        let own_pid = getpid().as_raw().to_string();
+        let mut manager = CgroupManager::new(config(&root)).unwrap();
+        let origin = root.path().join("user.slice");
+        write(&origin.join("cgroup.procs"), "");
+        manager.origin = vec![origin.clone()];
+        write(&manager.leaf().join("cgroup.procs"), &own_pid);
+        manager.evacuate_self().unwrap();
+        assert_eq!(procs(&origin), own_pid);
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn evacuate_self_falls_back_to_the_parent_v2() {
+        let root = fake_root(CgroupVersion::V2);
+        let own_pid = getpid().as_raw().to_string();
+        let mut manager = CgroupManager::new(config(&root)).unwrap();
+        // Gone since the runtime left it.
+        manager.origin = vec![root.path().join("user.slice")];
+        write(&root.path().join("cgroup.procs"), "");
+        write(&manager.leaf().join("cgroup.procs"), &own_pid);
+        manager.evacuate_self().unwrap();
+        assert_eq!(procs(root.path()), own_pid);
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn evacuate_self_leaves_every_v1_hierarchy() {
+        let root = fake_root(CgroupVersion::V1);
+        let own_pid = getpid().as_raw().to_string();
+        let mut manager = CgroupManager::new(config(&root)).unwrap();
+        let origin = root.path().join("memory/user.slice");
+        write(&origin.join("cgroup.procs"), "");
+        manager.origin = vec![origin.clone()];
+        for controller in ["memory", "freezer", "devices"] {
+            write(&root.path().join(controller).join("cgroup.procs"), "");
+            write(
+                &root.path().join(controller).join(NAME).join("cgroup.procs"),
+                &own_pid,
+            );
+        }
+        manager.evacuate_self().unwrap();
+        // Back home in the memory hierarchy, to the top of the others.
+        assert_eq!(procs(&origin), own_pid);
+        assert_eq!(procs(&root.path().join("memory")), "");
+        assert_eq!(procs(&root.path().join("freezer")), own_pid);
+        assert_eq!(procs(&root.path().join("devices")), own_pid);
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn swappiness_on_v1_is_written() {
+        let root = fake_root(CgroupVersion::V1);
+        let manager = CgroupManager::new(config(&root).with_swappiness(10)).unwrap();
+        write(&manager.path().join("memory.swappiness"), "60");
+        manager.setup().unwrap();
+        assert_eq!(
+            fs::read_to_string(manager.path().join("memory.swappiness")).unwrap(),
+            "10"
+        );
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn swappiness_on_v2_is_approximated_or_refused() {
+        let root = fake_root(CgroupVersion::V2);
+        let planned = |config: CgroupConfig| {
+            let (_, files) = CgroupManager::plan(&config).unwrap();
+            files
+                .into_iter()
+                .filter(|(file, _)| file.starts_with("memory."))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            planned(config(&root).with_swappiness(0)),
+            [("memory.swap.max", "0".to_string())]
+        );
+        assert_eq!(
+            planned(config(&root).with_swappiness(10)),
+            [("memory.zswap.writeback", "0".to_string())]
+        );
+        assert_eq!(planned(config(&root).with_swappiness(60)), []);
+        let strict = config(&root).with_swappiness(10).with_strict(true);
+        let error = CgroupManager::check(&strict).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("--memory-swappiness is unsupported"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn failed_pids_limit_fails_the_setup() {
+        let root = fake_root(CgroupVersion::V2);
+        let manager = CgroupManager::new(config(&root).with_pids_limit(64)).unwrap();
+        // A directory where pids.max should be cannot be written to.
+        fs::create_dir_all(manager.path().join("pids.max")).unwrap();
+        write(&manager.path().join("cgroup.controllers"), "pids");
+        let error = manager.setup().unwrap_err();
+        assert!(error.to_string().contains("pids.max"), "{error}");
+        fs::remove_dir(manager.path().join("pids.max")).unwrap();
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn usage_summary_reads_v1_memory_files() {
+        let root = fake_root(CgroupVersion::V1);
+        let manager = CgroupManager::new(config(&root)).unwrap();
+        write(&manager.path().join("memory.max_usage_in_bytes"), "8192\n");
+        write(&manager.path().join("memory.usage_in_bytes"), "4096\n");
+        let usage = manager.usage_summary();
+        assert_eq!(usage.memory_peak_bytes, Some(8192));
+        assert_eq!(usage.memory_current_bytes, Some(4096));
+        assert_eq!(usage.cpu_usage_usec, None);
+        assert_eq!(usage.pids_peak, None);
+        assert_eq!(
+            usage.to_string(),
+            "memory peak=8KB current=4KB, cpu usage=n/a throttled=n/a, pids peak=n/a"
+        );
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn usage_summary_reads_v2_files() {
+        let root = fake_root(CgroupVersion::V2);
+        let manager = CgroupManager::new(config(&root)).unwrap();
+        let cgroup = manager.path().to_path_buf();
+        write(&cgroup.join("memory.peak"), "2097152\n");
+        write(&cgroup.join("memory.current"), "1048576\n");
+        write(
+            &cgroup.join("cpu.stat"),
+            "usage_usec 25000\nuser_usec 20000\nthrottled_usec 3000\n",
+        );
+        write(&cgroup.join("pids.peak"), "7\n");
+        let usage = manager.usage_summary();
+        assert_eq!(
+            serde_json::to_string(&usage).unwrap(),
+            r#"{"memory_peak_bytes":2097152,"memory_current_bytes":1048576,"cpu_usage_usec":25000,"cpu_throttled_usec":3000,"pids_peak":7}"#
+        );
+        // Files the kernel does not provide are reported as missing.
+        fs::remove_file(cgroup.join("pids.peak")).unwrap();
+        assert_eq!(manager.usage_summary().pids_peak, None);
+        teardown(manager, root.path());
+    }
+
+    #[test]
+    fn read_counter_parses_interface_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, content: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            path
+        };
+        assert_eq!(read_counter(&file("pids.current", "42\n")), Some(42));
+        assert_eq!(read_counter(&file("pids.max", "max\n")), Some(u64::MAX));
+        assert_eq!(read_counter(&dir.path().join("missing")), None);
+        assert_eq!(read_counter(&file("garbage", "12ab\n")), None);
+        assert_eq!(read_counter(&file("negative", "-1\n")), None);
+        assert_eq!(read_counter(&file("empty", "")), None);
+    }
+
+    #[test]
+    fn read_keyed_counter_finds_its_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = dir.path().join("pids.events");
+        fs::write(&events, "max 3\nmax.imposed 1\n").unwrap();
+        assert_eq!(read_keyed_counter(&events, "max"), Some(3));
+        assert_eq!(read_keyed_counter(&events, "max.imposed"), Some(1));
+        assert_eq!(read_keyed_counter(&events, "oom_kill"), None);
+        assert_eq!(read_keyed_counter(&dir.path().join("missing"), "max"), None);
+    }
+}
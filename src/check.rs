@@ -0,0 +1,303 @@
+use crate::cgroup::CgroupManager;
+use crate::cli::ContainerConfig;
+use crate::container;
+use crate::error::{ContainerError, ContainerResult, ErrorDetail};
+use crate::filesystem::FilesystemManager;
+use crate::namespace::{NamespaceConfig, NamespaceManager};
+use crate::network::NetworkMode;
+use crate::preflight;
+use crate::process::ProcessManager;
+use crate::seccomp::SeccompProgram;
+use caps::{CapSet, Capability};
+use nix::errno::Errno;
+use nix::unistd::Uid;
+use std::fs;
+use std::path::Path;
+
+// Capabilities the runtime uses on every start: namespaces and mounts, and
+// pivot_root or chroot.
+const REQUIRED_CAPS: [Capability; 2] = [Capability::CAP_SYS_ADMIN, Capability::CAP_SYS_CHROOT];
+
+// What `--check` found: the setup a run would perform, section by section,
+// and every problem that would stop it, rather than only the first.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    sections: Vec<(&'static str, Vec<String>)>,
+    problems: Vec<ContainerError>,
+}
+
+impl CheckReport {
+    fn section(&mut self, title: &'static str, lines: Vec<String>) {
+        self.sections.push((title, lines));
+    }
+
+    // Keeps the value of a passing check and records a failing one.
+    fn check<T>(&mut self, result: ContainerResult<T>) -> Option<T> {
+        result.map_err(|e| self.problems.push(e)).ok()
+    }
+
+    pub fn problems(&self) -> &[ContainerError] {
+        &self.problems
+    }
+
+    // 0 when the container would start; otherwise the code the first problem
+    // would have made a real run exit with.
+    pub fn exit_code(&self) -> i32 {
+        self.problems.first().map_or(0, ContainerError::exit_code)
+    }
+
+    pub fn print(&self) {
+        for (title, lines) in &self.sections {
+            println!("{title}:");
+            for line in lines {
+                println!("  {line}");
+            }
+        }
+        if self.problems.is_empty() {
+            println!("OK: the container would start");
+        } else {
+            println!("Problems:");
+            for problem in &self.problems {
+                println!("  - {problem}");
+            }
+        }
+    }
+}
+
+// Runs every check a start would, without unsharing, mounting, extracting or
+// creating cgroups.
+pub fn run(mut config: ContainerConfig) -> CheckReport {
+    let mut report = CheckReport::default();
+    if !Uid::current().is_root() {
+        report.problems.push(ContainerError::RootRequired);
+    }
+    for cap in REQUIRED_CAPS {
+        if !caps::has_cap(None, CapSet::Effective, cap).unwrap_or(false) {
+            report.problems.push(ContainerError::capability(format!(
+                "The runtime lacks {cap}"
+            )));
+        }
+    }
+    if config.network.mode == NetworkMode::Bridge
+        && !caps::has_cap(None, CapSet::Effective, Capability::CAP_NET_ADMIN).unwrap_or(false)
+    {
+        report.problems.push(ContainerError::capability(
+            "--network bridge needs CAP_NET_ADMIN, which the runtime lacks",
+        ));
+    }
+    let ns_config = report
+        .check(container::plan(&mut config))
+        .unwrap_or_else(|| config.namespace_config());
+    report.check(SeccompProgram::from_mode(
+        &config.seccomp,
+        &config.capabilities,
+    ));
+    if let Some(log_file) = &config.log_file {
+        report.check(check_parent(log_file, "--log-file"));
+    }
+    if let Some(pid_file) = &config.pid_file {
+        report.check(check_parent(pid_file, "--pid-file"));
+    }
+
+    // A real run generates an id when neither is given.
+    let base = config.name.clone().or_else(|| config.id.clone());
+    let name = format!("container-{}", base.as_deref().unwrap_or("<id>"));
+    check_rootfs(&mut report, &config);
+    check_namespaces(&mut report, &config, &ns_config);
+
+    let mut command = vec![config.command.to_string_lossy().into_owned()];
+    command.extend(
+        config
+            .args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned()),
+    );
+    let mut process = vec![format!("argv: {command:?}")];
+    if let Some(workdir) = &config.workdir {
+        process.push(format!("workdir: {}", workdir.display()));
+    }
+    if let Some(nice) = config.nice {
+        process.push(format!("nice: {nice}"));
+    }
+    if let Some(ionice) = config.ionice {
+        process.push(format!("ionice: {ionice}"));
+    }
+    if let Some((policy, priority)) = config.sched {
+        process.push(format!("sched: {policy} priority {priority}"));
+    }
+    if let Ok(env) = ProcessManager::build_environment(&config.env) {
+        process.extend(
+            env.iter()
+                .map(|var| format!("env: {}", var.to_string_lossy())),
+        );
+    }
+    report.section("Process", process);
+
+    // Defaulted as spawn and run_init do.
+    let hostname = ns_config
+        .isolate_uts
+        .then(|| match (&config.hostname, &base) {
+            (Some(hostname), _) => hostname.clone(),
+            (None, None) => "<id>".to_string(),
+            (None, Some(base)) if NamespaceManager::validate_hostname(base, true).is_ok() => {
+                base.clone()
+            }
+            (None, Some(_)) => "rust-container".to_string(),
+        });
+    let fs_config = container::filesystem_config(&config, &ns_config, hostname);
+    report.section("Mounts", fs_config.plan(config.overlay));
+    for copy in &config.copies {
+        if let Err(e) = fs::symlink_metadata(&copy.source) {
+            report
+                .problems
+                .push(ContainerError::invalid_configuration(format!(
+                    "--copy source {}: {e}",
+                    copy.source.display()
+                )));
+        }
+    }
+    if config.overlay {
+        report.check(preflight::check_overlay());
+    }
+    report.check(preflight::check_devices(&config));
+    report.check(preflight::check_realtime(&config));
+
+    if !config.needs_cgroup() {
+        report.section(
+            "Cgroup",
+            vec!["none (--no-device-cgroup and no resource limits)".to_string()],
+        );
+    } else if let Ok(cgroup_config) = config.cgroup_config(name) {
+        // Otherwise a --device did not resolve, which check_devices reported.
+        if let Some((path, files)) = report.check(CgroupManager::plan(&cgroup_config)) {
+            let mut lines = vec![format!("path: {}", path.display())];
+            lines.extend(
+                files
+                    .iter()
+                    .map(|(file, value)| format!("{file} = {value}")),
+            );
+            report.section("Cgroup", lines);
+        }
+        report.check(CgroupManager::check(&cgroup_config));
+    }
+    report
+}
+
+fn check_rootfs(report: &mut CheckReport, config: &ContainerConfig) {
+    let mut lines = Vec::new();
+    let source = if let Some(archive) = &config.rootfs_tar {
+        if let Err(e) = fs::File::open(archive) {
+            report
+                .problems
+                .push(ContainerError::invalid_configuration(format!(
+                    "Cannot open rootfs archive {}: {e}",
+                    archive.display()
+                )));
+        }
+        format!("extracted from {}", archive.display())
+    } else if let Some(image) = &config.image {
+        format!("extracted from image {image}")
+    } else {
+        let rootfs = &config.rootfs;
+        if report
+            .check(FilesystemManager::validate_rootfs(rootfs))
+            .is_some()
+        {
+            match FilesystemManager::find_command(
+                rootfs,
+                &config.command,
+                config.workdir.as_deref(),
+                &config.env,
+            ) {
+                Ok(Some((path, _))) => lines.push(format!("command: {}", path.display())),
+                // Unless a --copy puts it there.
+                Ok(None)
+                    if !config
+                        .copies
+                        .iter()
+                        .any(|copy| copy.destination == Path::new(&config.command)) =>
+                {
+                    report.problems.push(ContainerError::ProcessExecution {
+                        detail: ErrorDetail::new("Command not found in the rootfs:")
+                            .path(&config.command)
+                            .source(Errno::ENOENT),
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => report.problems.push(e),
+            }
+            if !config.skip_preflight {
+                report.check(preflight::check_rootfs(config));
+            }
+            // Unless a --copy creates it.
+            if let Some(workdir) = &config.workdir
+                && !config
+                    .copies
+                    .iter()
+                    .any(|copy| copy.destination.starts_with(workdir))
+            {
+                report.check(FilesystemManager::validate_workdir(rootfs, workdir));
+            }
+        }
+        rootfs.display().to_string()
+    };
+    lines.insert(0, source);
+    if config.rootfs_tar.is_some() || config.image.is_some() {
+        // Nothing is there to look at until extraction.
+        lines.push("command preflight runs after extraction".to_string());
+    }
+    report.section("Rootfs", lines);
+}
+
+fn check_namespaces(
+    report: &mut CheckReport,
+    config: &ContainerConfig,
+    ns_config: &NamespaceConfig,
+) {
+    let kinds = [
+        ("pid", ns_config.isolate_pid),
+        ("net", ns_config.isolate_net),
+        ("mnt", ns_config.isolate_mount),
+        ("uts", ns_config.isolate_uts),
+        ("ipc", ns_config.isolate_ipc),
+        ("user", ns_config.isolate_user),
+        ("cgroup", ns_config.isolate_cgroup),
+        ("time", ns_config.isolate_time),
+    ];
+    let mut lines = Vec::new();
+    for (kind, isolate) in kinds {
+        let joined = config
+            .join_ns
+            .iter()
+            .find(|join| join.kind.to_string() == kind);
+        let line = match joined {
+            Some(join) => format!("{kind}: join {}", join.path.display()),
+            None if isolate => format!("{kind}: new"),
+            None => format!("{kind}: host"),
+        };
+        lines.push(line);
+    }
+    report.section("Namespaces", lines);
+    report
+        .problems
+        .extend(preflight::check_namespaces(ns_config));
+    // Opened and closed again, one by one so each bad path is reported.
+    for join in &config.join_ns {
+        report.check(NamespaceManager::open_namespaces(std::slice::from_ref(
+            join,
+        )));
+    }
+}
+
+fn check_parent(path: &Path, option: &str) -> ContainerResult<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+            Err(ContainerError::invalid_configuration(format!(
+                "{option} {}: {} is not a directory",
+                path.display(),
+                dir.display()
+            )))
+        }
+        _ => Ok(()),
+    }
+}
@@ -1,25 +1,937 @@
-use clap::{Arg, Command};
+use crate::capabilities::{CapabilityConfig, CapabilitySpec};
+use crate::cgroup::{CgroupConfig, CgroupDriver};
+use crate::commands::attach::{DEFAULT_DETACH_KEYS, parse_detach_keys};
+use crate::config_file::ConfigFile;
+use crate::devices::{DeviceRule, DeviceSpec};
+use crate::error::{ContainerError, ContainerResult};
+use crate::filesystem::{
+    CopySpec, DEFAULT_MASKED_PATHS, DEFAULT_READONLY_PATHS, Propagation, TmpfsMount, parse_size,
+};
+use crate::health::{HealthAction, HealthConfig};
+use crate::image::{Image, ImageRef};
+use crate::log_file::LogDriver;
+use crate::namespace::{JoinNamespace, NamespaceConfig, NamespaceType, TimeOffsets};
+use crate::network::{DnsConfig, NetworkConfig, NetworkMode, Subnet};
+use crate::process::{IoPriority, NofileLimit, OutputBuffering, Rlimit, SchedPolicy};
+use crate::publish::PortMapping;
+use crate::restart::RestartPolicy;
+use crate::seccomp::SeccompMode;
+use crate::spec::Spec;
+use crate::state::{ContainerState, Status, validate_id};
+use crate::sysctl::SysctlManager;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use log::LevelFilter;
+use nix::sys::signal::Signal;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_STATE_DIR: &str = "/var/lib/container_rs";
+const DEFAULT_SHM_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_STOP_GRACE: u64 = 10;
+
+// Left to the container by --env-host-all; naming one in --env-host still
+// copies it.
+const HOST_ENV_DENYLIST: [&str; 3] = ["PATH", "HOME", "HOSTNAME"];
 
 #[derive(Debug, Clone)]
 pub struct ContainerConfig {
-    pub rootfs: String,
-    pub command: String,
-    pub args: Vec<String>,
+    // Set by create and `run -d` to the state id; foreground runs get a
+    // generated one when they spawn.
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub rootfs: PathBuf,
+    pub rootfs_tar: Option<PathBuf>,
+    pub rootfs_keep: bool,
+    pub image: Option<Image>,
+    pub command: OsString,
+    pub args: Vec<OsString>,
     pub hostname: Option<String>,
-    pub memory_limit_mb: Option<u64>,
+    pub hostname_strict: bool,
+    pub extra_hosts: Vec<(String, IpAddr)>,
+    pub workdir: Option<PathBuf>,
+    pub umask: Option<u32>,
+    pub skip_preflight: bool,
+    pub env: Vec<(String, String)>,
+    pub env_host: Vec<String>,
+    pub env_host_all: bool,
+    pub env_files: Vec<PathBuf>,
+    // --setsid/--no-setsid; by default only a PTY gets a new session.
+    pub setsid: Option<bool>,
+    pub init: bool,
+    pub tty: bool,
+    pub interactive: bool,
+    pub output_buffering: OutputBuffering,
+    pub host_proc_sys_ro: bool,
+    pub overlay: bool,
+    pub state_dir: PathBuf,
+    pub rm: bool,
+    pub read_only: bool,
+    pub tmpfs: Vec<TmpfsMount>,
+    pub copies: Vec<CopySpec>,
+    pub copy_chown: Option<(u32, u32)>,
+    pub shm_size: u64,
+    pub mask_paths: Vec<PathBuf>,
+    pub no_default_masks: bool,
+    pub readonly_paths: Vec<PathBuf>,
+    pub cgroup_rw: bool,
+    pub cgroup_reuse: bool,
+    pub cgroup_best_effort: bool,
+    pub cgroup_delegate: bool,
+    pub cgroup_driver: CgroupDriver,
+    pub device_cgroup_rules: Vec<DeviceRule>,
+    pub no_device_cgroup: bool,
+    pub devices: Vec<DeviceSpec>,
+    pub no_pivot: bool,
+    pub rootfs_propagation: Propagation,
+    pub keep_old_root: bool,
+    // In bytes: --memory is given in MB, a bundle's limit to the byte.
+    pub memory_limit: Option<u64>,
+    pub memory_high_mb: Option<u64>,
+    pub cpu_weight: Option<u64>,
+    pub cpu_quota: Option<u64>,
+    pub cpu_period: Option<u64>,
+    pub pids_limit: Option<u64>,
+    pub oom_score_adj: Option<i32>,
+    pub memory_oom_group: bool,
+    pub memory_swappiness: Option<u64>,
+    // Realtime CPU time per period, in microseconds (cgroup v1 only).
+    pub cpu_rt_runtime: Option<u64>,
+    pub cpu_rt_period: Option<u64>,
+    pub strict: bool,
+    pub force_kill: bool,
+    pub stats_json: Option<PathBuf>,
+    pub stats_interval: Option<u64>,
+    pub pids_watch: bool,
+    pub timeout: Option<u64>,
+    pub stop_grace: u64,
+    pub restart: RestartPolicy,
+    pub health: Option<HealthConfig>,
+    pub stats_format_json: bool,
+    pub events_fd: Option<i32>,
+    pub sysctls: Vec<(String, String)>,
+    pub capabilities: CapabilityConfig,
+    pub rlimit_nofile: Option<NofileLimit>,
+    pub ulimits: Vec<Rlimit>,
+    pub nice: Option<i32>,
+    pub ionice: Option<IoPriority>,
+    pub sched: Option<(SchedPolicy, i32)>,
+    pub seccomp: SeccompMode,
+    pub no_new_privileges: bool,
+    pub group_add: Vec<String>,
+    pub network: NetworkConfig,
+    pub no_pid_ns: bool,
+    pub no_net_ns: bool,
+    pub no_ipc_ns: bool,
+    pub no_uts_ns: bool,
+    pub no_cgroup_ns: bool,
+    pub time_offsets: Option<TimeOffsets>,
+    pub join_ns: Vec<JoinNamespace>,
+    pub no_mount_ns: bool,
+    pub userns: bool,
+    pub publish: Vec<PortMapping>,
+    pub labels: Vec<(String, String)>,
+    pub label_files: Vec<PathBuf>,
+    pub bundle: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+    pub log_max_size: Option<u64>,
+    pub log_driver: LogDriver,
+    pub pid_file: Option<PathBuf>,
 }
 
-pub fn parse_args() -> ContainerConfig {
-    let matches = Command::new("container-runtime")
+impl ContainerConfig {
+    // The same defaults the command line starts from.
+    pub fn new(rootfs: PathBuf, command: OsString) -> Self {
+        Self {
+            id: None,
+            name: None,
+            rootfs,
+            rootfs_tar: None,
+            rootfs_keep: false,
+            image: None,
+            command,
+            args: Vec::new(),
+            hostname: None,
+            hostname_strict: true,
+            extra_hosts: Vec::new(),
+            workdir: None,
+            umask: None,
+            skip_preflight: false,
+            env: Vec::new(),
+            env_host: Vec::new(),
+            env_host_all: false,
+            env_files: Vec::new(),
+            setsid: None,
+            init: false,
+            tty: false,
+            interactive: false,
+            output_buffering: OutputBuffering::default(),
+            host_proc_sys_ro: false,
+            overlay: false,
+            state_dir: PathBuf::from(DEFAULT_STATE_DIR),
+            rm: false,
+            read_only: false,
+            tmpfs: Vec::new(),
+            copies: Vec::new(),
+            copy_chown: None,
+            shm_size: DEFAULT_SHM_SIZE,
+            mask_paths: Vec::new(),
+            no_default_masks: false,
+            readonly_paths: Vec::new(),
+            cgroup_rw: false,
+            cgroup_reuse: false,
+            cgroup_best_effort: false,
+            cgroup_delegate: false,
+            cgroup_driver: CgroupDriver::default(),
+            device_cgroup_rules: Vec::new(),
+            no_device_cgroup: false,
+            devices: Vec::new(),
+            no_pivot: false,
+            rootfs_propagation: Propagation::default(),
+            keep_old_root: false,
+            memory_limit: None,
+            memory_high_mb: None,
+            cpu_weight: None,
+            cpu_quota: None,
+            cpu_period: None,
+            pids_limit: None,
+            oom_score_adj: None,
+            memory_oom_group: false,
+            memory_swappiness: None,
+            cpu_rt_runtime: None,
+            cpu_rt_period: None,
+            strict: false,
+            force_kill: false,
+            stats_json: None,
+            stats_interval: None,
+            pids_watch: false,
+            timeout: None,
+            stop_grace: DEFAULT_STOP_GRACE,
+            restart: RestartPolicy::default(),
+            health: None,
+            stats_format_json: false,
+            events_fd: None,
+            sysctls: Vec::new(),
+            capabilities: CapabilityConfig::default(),
+            rlimit_nofile: None,
+            ulimits: Vec::new(),
+            nice: None,
+            ionice: None,
+            sched: None,
+            seccomp: SeccompMode::default(),
+            no_new_privileges: false,
+            group_add: Vec::new(),
+            network: NetworkConfig::default(),
+            no_pid_ns: false,
+            no_net_ns: false,
+            no_ipc_ns: false,
+            no_uts_ns: false,
+            no_cgroup_ns: false,
+            time_offsets: None,
+            join_ns: Vec::new(),
+            no_mount_ns: false,
+            userns: false,
+            publish: Vec::new(),
+            labels: Vec::new(),
+            label_files: Vec::new(),
+            bundle: None,
+            log_file: None,
+            log_max_size: None,
+            log_driver: LogDriver::default(),
+            pid_file: None,
+        }
+    }
+    pub fn needs_cgroup(&self) -> bool {
+        self.memory_limit.is_some()
+            || self.memory_high_mb.is_some()
+            || self.cpu_weight.is_some()
+            || self.cpu_quota.is_some()
+            || self.pids_limit.is_some()
+            || self.memory_oom_group
+            || self.memory_swappiness.is_some()
+            || self.cpu_rt_runtime.is_some()
+            || self.cgroup_delegate
+            || !self.no_device_cgroup
+            || self.stats_json.is_some()
+            || self.stats_interval.is_some()
+            || self.pids_watch
+    }
+    pub fn cgroup_config(&self, name: String) -> ContainerResult<CgroupConfig> {
+        let mut cgroup_config = CgroupConfig::new(name)
+            .with_oom_group(self.memory_oom_group)
+            .with_force_kill(self.force_kill)
+            .with_reuse(self.cgroup_reuse)
+            .with_best_effort(self.cgroup_best_effort)
+            .with_delegate(self.cgroup_delegate)
+            .with_driver(self.cgroup_driver)
+            .with_strict(self.strict);
+        if let Some(limit) = self.memory_limit {
+            cgroup_config = cgroup_config.with_memory_limit(limit);
+        }
+        if let Some(high) = self.memory_high_mb {
+            cgroup_config = cgroup_config.with_memory_high_mb(high);
+        }
+        if let Some(weight) = self.cpu_weight {
+            cgroup_config = cgroup_config.with_cpu_weight(weight);
+        }
+        if let Some(quota) = self.cpu_quota {
+            cgroup_config = cgroup_config.with_cpu_quota(quota, self.cpu_period);
+        }
+        if let Some(limit) = self.pids_limit {
+            cgroup_config = cgroup_config.with_pids_limit(limit);
+        }
+        if let Some(swappiness) = self.memory_swappiness {
+            cgroup_config = cgroup_config.with_swappiness(swappiness);
+        }
+        if let Some(runtime) = self.cpu_rt_runtime {
+            cgroup_config = cgroup_config.with_rt_runtime(runtime, self.cpu_rt_period);
+        }
+        if !self.no_device_cgroup {
+            let mut rules = DeviceRule::defaults();
+            rules.extend(self.device_cgroup_rules.iter().cloned());
+            for spec in &self.devices {
+                rules.push(spec.resolve()?.rule());
+            }
+            cgroup_config = cgroup_config.with_devices(rules);
+        }
+        Ok(cgroup_config)
+    }
+    pub fn namespace_config(&self) -> NamespaceConfig {
+        // A joined namespace is not created again.
+        let own = |kind| !self.joins(kind);
+        NamespaceConfig {
+            isolate_pid: !self.no_pid_ns && own(NamespaceType::Pid),
+            isolate_net: !self.no_net_ns
+                && self.network.mode != NetworkMode::Host
+                && own(NamespaceType::Net),
+            isolate_mount: !self.no_mount_ns,
+            isolate_uts: !self.no_uts_ns && own(NamespaceType::Uts),
+            isolate_ipc: !self.no_ipc_ns && own(NamespaceType::Ipc),
+            isolate_user: self.userns,
+            isolate_cgroup: !self.no_cgroup_ns && own(NamespaceType::Cgroup),
+            isolate_time: self.time_offsets.is_some(),
+        }
+    }
+    pub fn joins(&self, kind: NamespaceType) -> bool {
+        self.join_ns.iter().any(|join| join.kind == kind)
+    }
+    pub fn masked_paths(&self) -> Vec<PathBuf> {
+        let defaults = if self.no_default_masks {
+            &[][..]
+        } else {
+            &DEFAULT_MASKED_PATHS[..]
+        };
+        defaults
+            .iter()
+            .map(PathBuf::from)
+            .chain(self.mask_paths.iter().cloned())
+            .collect()
+    }
+    pub fn readonly_paths(&self) -> Vec<PathBuf> {
+        DEFAULT_READONLY_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .chain(self.readonly_paths.iter().cloned())
+            .collect()
+    }
+    // The container's own variables on top of the runtime defaults: the host's
+    // (--env-host-all, then --env-host), the env files in order, then the
+    // bundle's and --env.
+    pub fn resolve_env(&self) -> ContainerResult<Vec<(String, String)>> {
+        let mut env = Vec::new();
+        if self.env_host_all {
+            env.extend(std::env::vars_os().filter_map(|(key, value)| {
+                let key = key.into_string().ok()?;
+                let value = value.into_string().ok()?;
+                (!HOST_ENV_DENYLIST.contains(&key.as_str())).then_some((key, value))
+            }));
+        }
+        for key in &self.env_host {
+            match std::env::var(key) {
+                Ok(value) => env.push((key.clone(), value)),
+                Err(_) => log::debug!("--env-host {key}: not set in the host environment"),
+            }
+        }
+        for file in &self.env_files {
+            env.extend(parse_key_value_file(file)?);
+        }
+        env.extend(self.env.iter().cloned());
+        Ok(env)
+    }
+
+    // Label files are applied in order, then inline --label flags on top.
+    pub fn resolve_labels(&self) -> ContainerResult<BTreeMap<String, String>> {
+        let mut labels = BTreeMap::new();
+        for file in &self.label_files {
+            labels.extend(parse_key_value_file(file)?);
+        }
+        labels.extend(self.labels.iter().cloned());
+        Ok(labels)
+    }
+}
+
+// What the command line asked for. Without a subcommand the arguments are
+// those of `run`, so `container_rs --rootfs DIR CMD` keeps working.
+#[derive(Debug, Clone)]
+pub enum CliCommand {
+    Run {
+        config: Box<ContainerConfig>,
+        detach: bool,
+    },
+    // --check: validate and report, but start nothing.
+    Check {
+        config: Box<ContainerConfig>,
+    },
+    Create {
+        id: String,
+        config: Box<ContainerConfig>,
+    },
+    Start {
+        id: String,
+    },
+    Kill {
+        id: String,
+        signal: Signal,
+    },
+    Pause {
+        id: String,
+    },
+    Unpause {
+        id: String,
+    },
+    Delete {
+        id: String,
+        force: bool,
+    },
+    State {
+        id: String,
+    },
+    List {
+        json: bool,
+        prune: bool,
+        // KEY or KEY=VALUE, each of which a listed container's labels match.
+        label_filters: Vec<(String, Option<String>)>,
+    },
+    Wait {
+        id: String,
+    },
+    Attach {
+        id: String,
+        detach_keys: Vec<u8>,
+    },
+}
+
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  N        the command's own exit status, or 128+N if it was killed by signal N
+  2        invalid arguments or configuration
+  124      the command was stopped by --timeout
+  125      the runtime itself failed
+  126      the command was found but could not be executed
+  127      the command was not found in the container";
+
+// The parsed command line. The log level can be read before the command is
+// built, which may already log.
+#[derive(Debug)]
+pub struct Cli {
+    matches: ArgMatches,
+}
+
+pub fn parse_args() -> Cli {
+    let command = cli_command();
+    let args = hoist_verbosity(&command, std::env::args_os().collect());
+    Cli {
+        matches: command.get_matches_from(args),
+    }
+}
+
+// With args_conflicts_with_subcommands any argument ahead of a subcommand,
+// even a global one, turns the command line into a bare run. Leading -v/-q
+// are moved behind the subcommand name, where they parse the same.
+fn hoist_verbosity(command: &Command, mut args: Vec<OsString>) -> Vec<OsString> {
+    let is_verbosity = |arg: &OsString| match arg.to_str() {
+        Some("--verbose" | "--quiet") => true,
+        Some(arg) => {
+            arg.len() > 1
+                && arg.starts_with('-')
+                && (arg[1..].bytes().all(|b| b == b'v') || arg[1..].bytes().all(|b| b == b'q'))
+        }
+        None => false,
+    };
+    let flags = args
+        .iter()
+        .skip(1)
+        .take_while(|arg| is_verbosity(arg))
+        .count();
+    let subcommand = args.get(1 + flags).and_then(|arg| arg.to_str());
+    if flags > 0
+        && subcommand
+            .is_some_and(|name| command.get_subcommands().any(|sub| sub.get_name() == name))
+    {
+        args[1..=1 + flags].rotate_left(flags);
+    }
+    args
+}
+
+fn cli_command() -> Command {
+    container_args(Command::new("container-runtime"))
+        .arg(detach_arg())
+        .arg(check_arg())
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .short('v')
+                .help("Log more: -v for debug, -vv for trace output (overrides RUST_LOG)")
+                .action(ArgAction::Count)
+                .global(true),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .help("Log less: -q for warnings and errors, -qq for errors only (overrides RUST_LOG)")
+                .action(ArgAction::Count)
+                .conflicts_with("verbose")
+                .global(true),
+        )
         .version("0.1.0")
         .about("A simple container runtime in Rust")
+        .after_help(EXIT_STATUS_HELP)
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            container_args(
+                Command::new("run")
+                    .about("Create and start a container and wait for it to exit (the default)"),
+            )
+            .arg(detach_arg())
+            .arg(check_arg()),
+        )
+        .subcommand(container_args(
+            Command::new("create")
+                .about("Set up a container and park it before running its command")
+                .arg(id_arg()),
+        ))
+        .subcommand(
+            Command::new("start")
+                .about("Run the command of a created container")
+                .arg(id_arg()),
+        )
+        .subcommand(
+            Command::new("kill")
+                .about("Send a signal to a container's init")
+                .arg(id_arg())
+                .arg(
+                    Arg::new("signal")
+                        .help("Signal name or number")
+                        .default_value("SIGTERM")
+                        .value_parser(parse_signal),
+                ),
+        )
+        .subcommand(
+            Command::new("pause")
+                .about("Freeze every process in a running container")
+                .arg(id_arg()),
+        )
+        .subcommand(
+            Command::new("unpause")
+                .about("Resume a paused container")
+                .arg(id_arg()),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Remove a stopped container's state")
+                .arg(id_arg())
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .short('f')
+                        .help("Kill the container first if it is still running")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("state")
+                .about("Print a container's state as JSON")
+                .arg(id_arg()),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("List containers")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .value_parser(["table", "json"])
+                        .default_value("table"),
+                )
+                .arg(
+                    Arg::new("prune")
+                        .long("prune")
+                        .help("Remove the state of stopped containers")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("filter")
+                        .long("filter")
+                        .value_name("label=KEY[=VALUE]")
+                        .help("Only list containers with the label KEY (set to VALUE); repeatable, all must match")
+                        .action(ArgAction::Append)
+                        .value_parser(parse_label_filter),
+                ),
+        )
+        .subcommand(
+            Command::new("attach")
+                .about("Connect the terminal to a running container's console")
+                .arg(id_arg())
+                .arg(
+                    Arg::new("detach-keys")
+                        .long("detach-keys")
+                        .value_name("KEYS")
+                        .help("Key sequence that detaches, leaving the container running")
+                        .default_value(DEFAULT_DETACH_KEYS)
+                        .value_parser(parse_detach_keys),
+                ),
+        )
+        .subcommand(
+            Command::new("wait")
+                .about("Wait for a container to stop and exit with its exit code")
+                .arg(id_arg()),
+        )
+}
+
+impl Cli {
+    // None when neither -v nor -q is given, leaving it to RUST_LOG.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        let matches = self
+            .matches
+            .subcommand()
+            .map_or(&self.matches, |(_, sub)| sub);
+        match (matches.get_count("verbose"), matches.get_count("quiet")) {
+            (0, 0) => None,
+            (1, _) => Some(LevelFilter::Debug),
+            (_, 0) => Some(LevelFilter::Trace),
+            (_, 1) => Some(LevelFilter::Warn),
+            _ => Some(LevelFilter::Error),
+        }
+    }
+
+    pub fn command(&self) -> ContainerResult<CliCommand> {
+        command_from_matches(&self.matches)
+    }
+}
+
+fn command_from_matches(matches: &ArgMatches) -> ContainerResult<CliCommand> {
+    let id = |sub: &ArgMatches| sub.get_one::<String>("id").expect("id is required").clone();
+    Ok(match matches.subcommand() {
+        Some(("run", sub)) if sub.get_flag("check") => CliCommand::Check {
+            config: Box::new(config_from_matches(sub)?),
+        },
+        Some(("run", sub)) => CliCommand::Run {
+            config: Box::new(config_from_matches(sub)?),
+            detach: sub.get_flag("detach"),
+        },
+        Some(("create", sub)) => CliCommand::Create {
+            id: id(sub),
+            config: Box::new(config_from_matches(sub)?),
+        },
+        Some(("start", sub)) => CliCommand::Start { id: id(sub) },
+        Some(("kill", sub)) => CliCommand::Kill {
+            id: id(sub),
+            signal: *sub
+                .get_one::<Signal>("signal")
+                .expect("signal has a default"),
+        },
+        Some(("pause", sub)) => CliCommand::Pause { id: id(sub) },
+        Some(("unpause", sub)) => CliCommand::Unpause { id: id(sub) },
+        Some(("delete", sub)) => CliCommand::Delete {
+            id: id(sub),
+            force: sub.get_flag("force"),
+        },
+        Some(("state", sub)) => CliCommand::State { id: id(sub) },
+        Some(("list", sub)) => CliCommand::List {
+            json: sub
+                .get_one::<String>("format")
+                .is_some_and(|format| format == "json"),
+            prune: sub.get_flag("prune"),
+            label_filters: sub
+                .get_many::<(String, Option<String>)>("filter")
+                .map(|filters| filters.cloned().collect())
+                .unwrap_or_default(),
+        },
+        Some(("wait", sub)) => CliCommand::Wait { id: id(sub) },
+        Some(("attach", sub)) => CliCommand::Attach {
+            id: id(sub),
+            detach_keys: sub
+                .get_one::<Vec<u8>>("detach-keys")
+                .expect("detach-keys has a default")
+                .clone(),
+        },
+        _ if matches.get_flag("check") => CliCommand::Check {
+            config: Box::new(config_from_matches(matches)?),
+        },
+        _ => CliCommand::Run {
+            config: Box::new(config_from_matches(matches)?),
+            detach: matches.get_flag("detach"),
+        },
+    })
+}
+
+fn id_arg() -> Arg {
+    Arg::new("id")
+        .help("Container id")
+        .required(true)
+        .value_parser(validate_id)
+}
+
+fn check_arg() -> Arg {
+    Arg::new("check")
+        .long("check")
+        .help("Validate the container and print what would be set up, without starting anything")
+        .conflicts_with("detach")
+        .action(ArgAction::SetTrue)
+}
+
+fn detach_arg() -> Arg {
+    Arg::new("detach")
+        .long("detach")
+        .short('d')
+        .help("Run the container in the background and print its id")
+        .action(ArgAction::SetTrue)
+}
+
+// Accepts SIGTERM, TERM or 15.
+fn parse_signal(s: &str) -> Result<Signal, String> {
+    if let Ok(number) = s.parse::<i32>() {
+        return Signal::try_from(number).map_err(|_| format!("unknown signal number {number}"));
+    }
+    let name = s.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+    Signal::from_str(&name).map_err(|_| format!("unknown signal '{s}'"))
+}
+
+// Every option that describes a container, shared by the top level, `run`
+// and `create`.
+fn container_args(command: Command) -> Command {
+    command
+        .arg(
+            Arg::new("name")
+                .long("name")
+                .value_name("NAME")
+                .help("Name to refer to the container by; also used for its cgroup and default hostname")
+                .value_parser(validate_id),
+        )
         .arg(
             Arg::new("rootfs")
                 .long("rootfs")
                 .value_name("PATH")
-                .required(true)
+                .required_unless_present_any(["bundle", "rootfs-tar", "image", "config"])
                 .help("Path to root filesystem")
-                .value_parser(clap::value_parser!(String)),
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("rootfs-tar")
+                .long("rootfs-tar")
+                .value_name("FILE")
+                .conflicts_with_all(["rootfs", "bundle"])
+                .help("Extract a rootfs tarball (plain, gzip or xz) under --state-dir and run on that")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("image")
+                .long("image")
+                .value_name("TRANSPORT:PATH[:TAG]")
+                .conflicts_with_all(["rootfs", "bundle", "rootfs-tar"])
+                .help("Extract an image (oci:LAYOUT_DIR or docker-archive:FILE from `docker save`) under --state-dir and run on that, taking its Env, Cmd, Entrypoint and WorkingDir as defaults")
+                .value_parser(clap::value_parser!(ImageRef)),
+        )
+        .arg(
+            Arg::new("rootfs-keep")
+                .long("rootfs-keep")
+                .help("Keep the rootfs extracted from --rootfs-tar or --image when the container exits")
+                .requires("extracted-rootfs")
+                .action(ArgAction::SetTrue),
+        )
+        .group(ArgGroup::new("extracted-rootfs").args(["rootfs-tar", "image"]))
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .conflicts_with("bundle")
+                .help("Read the container definition from a TOML file; options on the command line override it")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("bundle")
+                .long("bundle")
+                .value_name("DIR")
+                .help("Run an OCI bundle: take the rootfs, command and limits from DIR/config.json")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("overlay")
+                .long("overlay")
+                .help("Run on an overlayfs over the rootfs so the rootfs itself is never modified")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("state-dir")
+                .long("state-dir")
+                .value_name("DIR")
+                .help("Directory holding per-container state such as the --overlay upper layer")
+                .default_value(DEFAULT_STATE_DIR)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("rm")
+                .long("rm")
+                .help("Remove the container's overlay layers when it exits instead of keeping them for inspection")
+                .requires("overlay")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .help("Mount the container's root read-only, with writable tmpfs at /tmp, /run and /dev/shm")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("tmpfs")
+                .long("tmpfs")
+                .value_name("PATH[:OPTIONS]")
+                .help("Mount a tmpfs in the container, e.g. /tmp:size=64m,mode=1777 (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(TmpfsMount)),
+        )
+        .arg(
+            Arg::new("copy")
+                .long("copy")
+                .value_name("SRC:DEST")
+                .help("Copy a host file or directory into the rootfs at DEST before the container starts (repeatable, applied in order)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(CopySpec)),
+        )
+        .arg(
+            Arg::new("copy-chown")
+                .long("copy-chown")
+                .value_name("UID:GID")
+                .help("Owner to give the files written by --copy")
+                .requires("copy")
+                .value_parser(parse_owner),
+        )
+        .arg(
+            Arg::new("shm-size")
+                .long("shm-size")
+                .value_name("SIZE")
+                .help("Size of the container's private /dev/shm, with an optional k/m/g suffix")
+                .default_value("64m")
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("mask-path")
+                .long("mask-path")
+                .value_name("PATH")
+                .help("Hide a path inside the container in addition to the default masked /proc and /sys entries (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(parse_container_path),
+        )
+        .arg(
+            Arg::new("no-default-masks")
+                .long("no-default-masks")
+                .help("Leave the default sensitive /proc and /sys paths visible")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("readonly-path")
+                .long("readonly-path")
+                .value_name("PATH")
+                .help("Make a path read-only inside the container, on top of the default /proc entries (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(parse_container_path),
+        )
+        .arg(
+            Arg::new("cgroup-reuse")
+                .long("cgroup-reuse")
+                .help("Reuse an empty leftover cgroup of the same name, resetting its limits, instead of recreating it")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("cgroup-best-effort")
+                .long("cgroup-best-effort")
+                .help("Skip, with a warning, the limits whose cgroup controller is not delegated to the container instead of failing")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("cgroup-delegate")
+                .long("cgroup-delegate")
+                .help("Hand the container's cgroup to its root user, mounted read-write, so a manager inside can create sub-cgroups (cgroup v2)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("cgroup-driver")
+                .long("cgroup-driver")
+                .value_name("DRIVER")
+                .help("Who creates the container's cgroup: cgroupfs (the runtime, directly) or systemd (a transient scope unit, cgroup v2)")
+                .default_value("cgroupfs")
+                .value_parser(CgroupDriver::from_str),
+        )
+        .arg(
+            Arg::new("device-cgroup-rule")
+                .long("device-cgroup-rule")
+                .value_name("RULE")
+                .help("Also allow the container the devices matching RULE, as in devices.allow: 'c 10:200 rwm' (type a/b/c, MAJOR:MINOR with * for any, access r/w/m); repeatable")
+                .action(ArgAction::Append)
+                .value_parser(DeviceRule::from_str),
+        )
+        .arg(
+            Arg::new("device")
+                .long("device")
+                .value_name("HOST[:CONTAINER][:rwm]")
+                .help("Create the host device node HOST in the container, at CONTAINER (default: the same path), and allow it the access rwm (default: rwm); repeatable")
+                .action(ArgAction::Append)
+                .value_parser(DeviceSpec::from_str),
+        )
+        .arg(
+            Arg::new("no-device-cgroup")
+                .long("no-device-cgroup")
+                .help("Let the container create and open any device node, instead of only null, zero, full, random, urandom, tty, console, ptmx and the PTYs")
+                .conflicts_with("device-cgroup-rule")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("cgroup-rw")
+                .long("cgroup-rw")
+                .help("Mount /sys/fs/cgroup read-write so the workload can manage its own sub-cgroups")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("rootfs-propagation")
+                .long("rootfs-propagation")
+                .value_name("MODE")
+                .help("Mount propagation of the container's root: private, rslave (mounts the host makes under the rootfs show up inside) or rshared (also the other way, through mounts under the rootfs the host shares)")
+                .default_value("private")
+                .value_parser(Propagation::from_str),
+        )
+        .arg(
+            Arg::new("no-pivot")
+                .long("no-pivot")
+                .help("Enter the rootfs with chroot instead of pivot_root (weaker isolation; for rootfs on initramfs)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("keep-old-root")
+                .long("keep-old-root")
+                .help("Debugging: leave the host's root mounted at /.oldroot inside the container")
+                .action(ArgAction::SetTrue),
         )
         .arg(
             Arg::new("memory")
@@ -29,6 +941,469 @@ pub fn parse_args() -> ContainerConfig {
                 .help("Memory limit in megabytes (e.g., 512)")
                 .value_parser(clap::value_parser!(u64)),
         )
+        .arg(
+            Arg::new("memory-high")
+                .long("memory-high-mb")
+                .value_name("MB")
+                .help("Soft memory limit (memory.high) in megabytes")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("oom-score-adj")
+                .long("oom-score-adj")
+                .value_name("N")
+                .help("OOM score adjustment for container processes (-1000 to 1000)")
+                .allow_negative_numbers(true)
+                .value_parser(clap::value_parser!(i32).range(-1000..=1000)),
+        )
+        .arg(
+            Arg::new("memory-oom-group")
+                .long("memory-oom-group")
+                .help(
+                    "Kill the whole container together when the cgroup hits OOM (memory.oom.group)",
+                )
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("memory-swappiness")
+                .long("memory-swappiness")
+                .value_name("N")
+                .help("How readily the container's memory is swapped out, 0-100 (memory.swappiness on cgroup v1; approximated on v2)")
+                .value_parser(clap::value_parser!(u64).range(0..=100)),
+        )
+        .arg(
+            Arg::new("cpu-rt-runtime")
+                .long("cpu-rt-runtime")
+                .value_name("US")
+                .help("Realtime CPU time the container may use per period, in microseconds (cpu.rt_runtime_us; cgroup v1 only)")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("cpu-rt-period")
+                .long("cpu-rt-period")
+                .value_name("US")
+                .help("Period --cpu-rt-runtime is measured over, in microseconds (cpu.rt_period_us)")
+                .requires("cpu-rt-runtime")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .help("Fail on settings this host can only approximate, such as --memory-swappiness on cgroup v2")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("force-kill")
+                .long("force-kill")
+                .help("Kill container processes immediately on cleanup instead of SIGTERM first")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stats-json")
+                .long("stats-json")
+                .value_name("PATH")
+                .help("Write the resource usage summary as JSON to PATH (or stdout if omitted)")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("-")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Stop the container SECONDS after its command starts and exit with 124")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("stop-grace")
+                .long("stop-grace")
+                .value_name("SECONDS")
+                .help("How long --timeout waits after SIGTERM before sending SIGKILL")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("restart")
+                .long("restart")
+                .value_name("POLICY")
+                .help("Run the container again when it exits: no, on-failure[:MAX] (non-zero exits, at most MAX times) or always")
+                .default_value("no")
+                .value_parser(RestartPolicy::from_str),
+        )
+        .arg(
+            Arg::new("health-cmd")
+                .long("health-cmd")
+                .value_name("COMMAND")
+                .help("Periodically run COMMAND with /bin/sh inside the container to check its health"),
+        )
+        .arg(
+            Arg::new("health-interval")
+                .long("health-interval")
+                .value_name("DURATION")
+                .help("Time between health checks, e.g. 500ms, 10s or 1m")
+                .default_value("30s")
+                .value_parser(parse_duration),
+        )
+        .arg(
+            Arg::new("health-timeout")
+                .long("health-timeout")
+                .value_name("DURATION")
+                .help("Kill a health check that runs longer than DURATION and count it as failed")
+                .default_value("30s")
+                .value_parser(parse_duration),
+        )
+        .arg(
+            Arg::new("health-retries")
+                .long("health-retries")
+                .value_name("N")
+                .help("Consecutive failed health checks before the container is unhealthy")
+                .default_value("3")
+                .value_parser(clap::value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("health-start-period")
+                .long("health-start-period")
+                .value_name("DURATION")
+                .help("Do not run health checks until DURATION after the command starts")
+                .default_value("0s")
+                .value_parser(parse_duration),
+        )
+        .arg(
+            Arg::new("health-on-failure")
+                .long("health-on-failure")
+                .value_name("ACTION")
+                .help("What to do once the container is unhealthy: none or kill")
+                .default_value("none")
+                .value_parser(HealthAction::from_str),
+        )
+        .arg(
+            Arg::new("stats-interval")
+                .long("stats-interval")
+                .value_name("SECONDS")
+                .help("Print container resource usage to stderr every SECONDS while it runs")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("pids-watch")
+                .long("pids-watch")
+                .help("Warn when the container nears its pids limit or forks fail on it (on by default with --stats-interval and a pids limit)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stats-format")
+                .long("stats-format")
+                .value_name("FORMAT")
+                .help("Format of --stats-interval samples")
+                .value_parser(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("pid-file")
+                .long("pid-file")
+                .value_name("PATH")
+                .help("Write the host PID of the container init to PATH, removed again when it exits")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("events-fd")
+                .long("events-fd")
+                .value_name("FD")
+                .help("Write created/running/exited/oom lifecycle events as JSON lines to this inherited fd")
+                .value_parser(clap::value_parser!(i32).range(3..)),
+        )
+        .arg(
+            Arg::new("sysctl")
+                .long("sysctl")
+                .value_name("KEY=VALUE")
+                .help("Set a namespaced kernel parameter inside the container (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(parse_sysctl),
+        )
+        .arg(
+            Arg::new("cap-drop")
+                .long("cap-drop")
+                .value_name("CAP")
+                .help("Drop a capability from the default set, or ALL of them (repeatable, applied before --cap-add)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(CapabilitySpec)),
+        )
+        .arg(
+            Arg::new("cap-add")
+                .long("cap-add")
+                .value_name("CAP")
+                .help("Grant a capability on top of the default set, e.g. NET_ADMIN, or ALL (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(CapabilitySpec)),
+        )
+        .arg(
+            Arg::new("cap-ambient")
+                .long("cap-ambient")
+                .value_name("CAP")
+                .help("Raise a kept capability into the ambient set, so the command keeps it across execve without file capabilities, or ALL of them (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(CapabilitySpec)),
+        )
+        .arg(
+            Arg::new("rlimit-nofile")
+                .long("rlimit-nofile")
+                .value_name("max|N")
+                .help("Open file limit for the command; max raises the soft limit to the hard limit")
+                .value_parser(clap::value_parser!(NofileLimit)),
+        )
+        .arg(
+            Arg::new("ulimit")
+                .long("ulimit")
+                .value_name("NAME=SOFT[:HARD]")
+                .help("Resource limit for the command, e.g. nofile=1024:2048 or core=0 (repeatable, --rlimit-nofile takes precedence)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(Rlimit)),
+        )
+        .arg(
+            Arg::new("nice")
+                .long("nice")
+                .value_name("N")
+                .help("Scheduling niceness of the container's processes, -20 (favoured) to 19; below 0 needs CAP_SYS_NICE")
+                .allow_negative_numbers(true)
+                .value_parser(clap::value_parser!(i32).range(-20..=19)),
+        )
+        .arg(
+            Arg::new("ionice")
+                .long("ionice")
+                .value_name("CLASS[:LEVEL]")
+                .help("IO scheduling of the container's processes: best-effort[:0-7] or idle")
+                .value_parser(clap::value_parser!(IoPriority)),
+        )
+        .arg(
+            Arg::new("sched-policy")
+                .long("sched-policy")
+                .value_name("POLICY")
+                .help("Realtime scheduling of the container's processes: fifo or rr; needs CAP_SYS_NICE")
+                .value_parser(SchedPolicy::from_str),
+        )
+        .arg(
+            Arg::new("sched-priority")
+                .long("sched-priority")
+                .value_name("N")
+                .help("Realtime priority for --sched-policy, 1 (lowest) to 99; defaults to the lowest")
+                .requires("sched-policy")
+                .value_parser(clap::value_parser!(i32)),
+        )
+        .arg(
+            Arg::new("seccomp")
+                .long("seccomp")
+                .value_name("MODE")
+                .help("Syscall filtering: default (deny-list of host-level syscalls) or unconfined")
+                .value_parser(["default", "unconfined"])
+                .default_value("default"),
+        )
+        .arg(
+            Arg::new("seccomp-profile")
+                .long("seccomp-profile")
+                .value_name("FILE")
+                .help("Load a Docker-style JSON seccomp profile instead of the default one")
+                .conflicts_with("seccomp")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("group-add")
+                .long("group-add")
+                .value_name("NAME|GID")
+                .help("Add the command to a supplementary group, by name from the rootfs's /etc/group or by number (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(clap::builder::NonEmptyStringValueParser::new()),
+        )
+        .arg(
+            Arg::new("no-new-privileges")
+                .long("no-new-privileges")
+                .help("Stop setuid/setgid binaries and file capabilities from granting privileges")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("network")
+                .long("network")
+                .value_name("MODE")
+                .help("Container networking: none (empty namespace), host (share the host's) or bridge (veth on a host bridge)")
+                .value_parser(["none", "host", "bridge"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::new("bridge")
+                .long("bridge")
+                .value_name("NAME")
+                .help("Host bridge used with --network bridge (created if missing)")
+                .default_value("crs0")
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("subnet")
+                .long("subnet")
+                .value_name("CIDR")
+                .help("Subnet to allocate container addresses from with --network bridge")
+                .default_value("10.88.0.0/24")
+                .value_parser(clap::value_parser!(Subnet)),
+        )
+        .arg(
+            Arg::new("dns")
+                .long("dns")
+                .value_name("IP")
+                .help("Nameserver to write into the container's /etc/resolv.conf (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(IpAddr)),
+        )
+        .arg(
+            Arg::new("dns-search")
+                .long("dns-search")
+                .value_name("DOMAIN")
+                .help("Search domain for the container's /etc/resolv.conf (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(parse_search_domain),
+        )
+        .arg(
+            Arg::new("resolv-conf")
+                .long("resolv-conf")
+                .value_name("MODE")
+                .help("Where a --dns resolv.conf lives: persistent (written to the rootfs) or ephemeral (in-memory bind mount)")
+                .value_parser(["persistent", "ephemeral"])
+                .default_value("persistent"),
+        )
+        .arg(
+            Arg::new("no-pid-ns")
+                .long("no-pid-ns")
+                .help("Share the host's PID namespace")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-net-ns")
+                .long("no-net-ns")
+                .help("Share the host's network namespace (same as --network host)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-ipc-ns")
+                .long("no-ipc-ns")
+                .help("Share the host's IPC namespace (same as --ipc host)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ipc")
+                .long("ipc")
+                .value_name("MODE")
+                .help("IPC namespace: private (its own, with a fresh /dev/shm) or host (the host's, with the host's /dev/shm)")
+                .value_parser(["private", "host"])
+                .default_value("private"),
+        )
+        .arg(
+            Arg::new("no-uts-ns")
+                .long("no-uts-ns")
+                .help("Share the host's UTS namespace; the hostname is left untouched (same as --uts host)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("uts")
+                .long("uts")
+                .value_name("MODE")
+                .help("UTS namespace: private (its own hostname) or host (the host's, which --hostname may not change)")
+                .value_parser(["private", "host"])
+                .default_value("private"),
+        )
+        .arg(
+            Arg::new("no-cgroup-ns")
+                .long("no-cgroup-ns")
+                .help("Share the host's cgroup namespace; /proc/self/cgroup shows full host paths")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("time-offset")
+                .long("time-offset")
+                .value_name("CLOCK=SECONDS,...")
+                .help("Run in a time namespace with CLOCK_MONOTONIC and CLOCK_BOOTTIME shifted, e.g. boottime=86400,monotonic=0")
+                .value_parser(TimeOffsets::from_str),
+        )
+        .arg(
+            Arg::new("join-ns")
+                .long("join-ns")
+                .value_name("TYPE=PATH")
+                .help("Join an existing namespace instead of creating one, e.g. net=/proc/1234/ns/net (pid, net, ipc, uts, cgroup or time; repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(JoinNamespace::from_str),
+        )
+        .arg(
+            Arg::new("join-container")
+                .long("join-container")
+                .num_args(2)
+                .value_names(["ID", "TYPES"])
+                .help("Join these namespaces of a running container, e.g. --join-container web net,ipc"),
+        )
+        .arg(
+            Arg::new("no-mount-ns")
+                .long("no-mount-ns")
+                .help("Share the host's mount namespace and chroot into the rootfs as it is; requires --no-pivot")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("userns")
+                .long("userns")
+                .help("Run the container in a new user namespace with an identity ID mapping")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("publish")
+                .long("publish")
+                .short('p')
+                .value_name("[IP:]HOST:CONTAINER[/PROTO]")
+                .help("Forward a host port to the container, tcp or udp (repeatable, needs --network bridge)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(PortMapping)),
+        )
+        .arg(
+            Arg::new("env")
+                .long("env")
+                .short('e')
+                .value_name("KEY=VALUE")
+                .help("Set an environment variable in the container (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(parse_label),
+        )
+        .arg(
+            Arg::new("env-host")
+                .long("env-host")
+                .value_name("KEY")
+                .help("Copy KEY from this process's environment into the container (repeatable, --env wins)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("env-host-all")
+                .long("env-host-all")
+                .help("Copy this process's whole environment into the container, except PATH, HOME and HOSTNAME")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("env-file")
+                .long("env-file")
+                .value_name("PATH")
+                .help("Read KEY=VALUE variables from a dotenv-style file; values cannot span lines (repeatable, applied in order, --env wins)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("label")
+                .long("label")
+                .short('l')
+                .value_name("KEY=VALUE")
+                .help("Attach a metadata label to the container (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(parse_label),
+        )
+        .arg(
+            Arg::new("label-file")
+                .long("label-file")
+                .value_name("PATH")
+                .help("Read KEY=VALUE labels from a file (repeatable, --label wins)")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
         .arg(
             Arg::new("hostname")
                 .long("hostname")
@@ -36,40 +1411,814 @@ pub fn parse_args() -> ContainerConfig {
                 .help("container hostname")
                 .value_parser(clap::value_parser!(String)),
         )
+        .arg(
+            Arg::new("hostname-strict")
+                .long("hostname-strict")
+                .value_name("BOOL")
+                .help("Require an RFC 1123 hostname; =false only enforces the kernel's limits")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_value("true")
+                .default_missing_value("true")
+                .value_parser(clap::value_parser!(bool)),
+        )
+        .arg(
+            Arg::new("add-host")
+                .long("add-host")
+                .value_name("NAME:IP")
+                .help("Add an entry to the container's /etc/hosts (repeatable)")
+                .action(ArgAction::Append)
+                .value_parser(parse_add_host),
+        )
+        .arg(
+            Arg::new("workdir")
+                .long("workdir")
+                .short('w')
+                .value_name("DIR")
+                .help("Working directory inside the container")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("umask")
+                .long("umask")
+                .value_name("OCTAL")
+                .help("File mode creation mask for the command, e.g. 027 (default: the runtime's own)")
+                .value_parser(parse_umask),
+        )
+        .arg(
+            Arg::new("skip-preflight")
+                .long("skip-preflight")
+                .help("Skip probing kernel features and checking the command's binary before starting")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("host-proc-sys-ro")
+                .long("host-proc-sys-ro")
+                .help("Expose the host's /proc/sys read-only at /host/proc-sys for diagnostics")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("output-buffering")
+                .long("output-buffering")
+                .value_name("MODE")
+                .help("How PTY output is flushed: none (every read), line (on newlines) or block (large buffer, best throughput)")
+                .value_parser(["line", "block", "none"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Also write the container's console output to PATH (default for detached containers: its console.log)")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("log-max-size")
+                .long("log-max-size")
+                .value_name("SIZE")
+                .help("Rotate the log file to PATH.1 once it would grow past SIZE (k/m/g suffix)")
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("log-driver")
+                .long("log-driver")
+                .value_name("DRIVER")
+                .help("Log file format: raw (output as is) or json-file (one JSON object per line, with stdout/stderr separated when there is no PTY)")
+                .default_value("raw")
+                .value_parser(LogDriver::from_str),
+        )
+        .arg(
+            Arg::new("setsid")
+                .long("setsid")
+                .help("Without a PTY, still start the command in a new session, away from this terminal: Ctrl-C and Ctrl-Z then only reach it through the runtime, and job control no longer applies to it")
+                .conflicts_with("no-setsid")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-setsid")
+                .long("no-setsid")
+                .help("Keep the command in this terminal session (the default without a PTY) so job control works; terminal signals then reach it directly as well as through the runtime. A PTY always gets a new session")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("tty")
+                .short('t')
+                .long("tty")
+                .help("Run the command on a pseudo-terminal")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("interactive")
+                .short('i')
+                .long("interactive")
+                .help("Keep stdin open and pass it on to the command; otherwise it reads /dev/null")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("init")
+                .long("init")
+                .help("Reap orphaned processes even when the command is an init (tini, dumb-init, ...) itself")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("command")
                 .help("Command to execute inside container")
-                .required(true)
-                .index(1)
-                .value_parser(clap::value_parser!(String)),
+                .required_unless_present_any(["bundle", "image", "config"])
+                .value_parser(clap::value_parser!(OsString)),
         )
         .arg(
             Arg::new("args")
                 .help("Arguments for the command")
                 .num_args(0..)
-                .index(2)
-                .value_parser(clap::value_parser!(String)),
+                .value_parser(clap::value_parser!(OsString)),
         )
-        .get_matches();
+}
+
+fn config_from_matches(matches: &ArgMatches) -> ContainerResult<ContainerConfig> {
     let rootfs = matches
-        .get_one::<String>("rootfs")
-        .expect("rootfs is required")
-        .clone();
+        .get_one::<PathBuf>("rootfs")
+        .cloned()
+        .unwrap_or_default();
+    let rootfs_tar = matches.get_one::<PathBuf>("rootfs-tar").cloned();
+    let rootfs_keep = matches.get_flag("rootfs-keep");
     let command = matches
-        .get_one::<String>("command")
-        .expect("command is required")
-        .clone();
-    let args: Vec<String> = matches
-        .get_many::<String>("args")
+        .get_one::<OsString>("command")
+        .cloned()
+        .unwrap_or_default();
+    let args: Vec<OsString> = matches
+        .get_many::<OsString>("args")
         .map(|vals| vals.cloned().collect())
         .unwrap_or_default();
     let hostname = matches.get_one::<String>("hostname").cloned();
-    let memory_limit_mb = matches.get_one::<u64>("memory").copied();
-    ContainerConfig {
+    let hostname_strict = matches
+        .get_one::<bool>("hostname-strict")
+        .copied()
+        .unwrap_or(true);
+    let extra_hosts: Vec<(String, IpAddr)> = matches
+        .get_many::<(String, IpAddr)>("add-host")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let workdir = matches.get_one::<PathBuf>("workdir").cloned();
+    let umask = matches.get_one::<u32>("umask").copied();
+    let skip_preflight = matches.get_flag("skip-preflight");
+    let env_host: Vec<String> = matches
+        .get_many::<String>("env-host")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let env_files: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("env-file")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let setsid = if matches.get_flag("setsid") {
+        Some(true)
+    } else if matches.get_flag("no-setsid") {
+        Some(false)
+    } else {
+        None
+    };
+    let init = matches.get_flag("init");
+    let output_buffering = matches
+        .get_one::<String>("output-buffering")
+        .and_then(|mode| mode.parse::<OutputBuffering>().ok())
+        .unwrap_or_default();
+    let host_proc_sys_ro = matches.get_flag("host-proc-sys-ro");
+    let overlay = matches.get_flag("overlay");
+    let state_dir = matches
+        .get_one::<PathBuf>("state-dir")
+        .expect("state-dir has a default")
+        .clone();
+    let rm = matches.get_flag("rm");
+    let read_only = matches.get_flag("read-only");
+    let tmpfs: Vec<TmpfsMount> = matches
+        .get_many::<TmpfsMount>("tmpfs")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let copies: Vec<CopySpec> = matches
+        .get_many::<CopySpec>("copy")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let copy_chown = matches.get_one::<(u32, u32)>("copy-chown").copied();
+    let shm_size = *matches
+        .get_one::<u64>("shm-size")
+        .expect("shm-size has a default");
+    let mask_paths: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("mask-path")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let no_default_masks = matches.get_flag("no-default-masks");
+    let readonly_paths: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("readonly-path")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let cgroup_rw = matches.get_flag("cgroup-rw");
+    let cgroup_reuse = matches.get_flag("cgroup-reuse");
+    let cgroup_best_effort = matches.get_flag("cgroup-best-effort");
+    let cgroup_delegate = matches.get_flag("cgroup-delegate");
+    let device_cgroup_rules: Vec<DeviceRule> = matches
+        .get_many::<DeviceRule>("device-cgroup-rule")
+        .map(|rules| rules.cloned().collect())
+        .unwrap_or_default();
+    let no_device_cgroup = matches.get_flag("no-device-cgroup");
+    let devices: Vec<DeviceSpec> = matches
+        .get_many::<DeviceSpec>("device")
+        .map(|devices| devices.cloned().collect())
+        .unwrap_or_default();
+    let cgroup_driver = *matches
+        .get_one::<CgroupDriver>("cgroup-driver")
+        .expect("cgroup-driver has a default");
+    let no_pivot = matches.get_flag("no-pivot");
+    let rootfs_propagation = *matches
+        .get_one::<Propagation>("rootfs-propagation")
+        .expect("rootfs-propagation has a default");
+    let keep_old_root = matches.get_flag("keep-old-root");
+    let memory_limit = matches.get_one::<u64>("memory").map(|mb| mb * 1024 * 1024);
+    let memory_high_mb = matches.get_one::<u64>("memory-high").copied();
+    let oom_score_adj = matches.get_one::<i32>("oom-score-adj").copied();
+    let memory_oom_group = matches.get_flag("memory-oom-group");
+    let memory_swappiness = matches.get_one::<u64>("memory-swappiness").copied();
+    let strict = matches.get_flag("strict");
+    let force_kill = matches.get_flag("force-kill");
+    let stats_json = matches.get_one::<PathBuf>("stats-json").cloned();
+    let stats_interval = matches.get_one::<u64>("stats-interval").copied();
+    let pids_watch = matches.get_flag("pids-watch");
+    let stats_format_json = matches
+        .get_one::<String>("stats-format")
+        .is_some_and(|format| format == "json");
+    let events_fd = matches.get_one::<i32>("events-fd").copied();
+    let sysctls: Vec<(String, String)> = matches
+        .get_many::<(String, String)>("sysctl")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let capabilities = CapabilityConfig {
+        drop: matches
+            .get_many::<CapabilitySpec>("cap-drop")
+            .map(|vals| vals.copied().collect())
+            .unwrap_or_default(),
+        add: matches
+            .get_many::<CapabilitySpec>("cap-add")
+            .map(|vals| vals.copied().collect())
+            .unwrap_or_default(),
+        ambient: matches
+            .get_many::<CapabilitySpec>("cap-ambient")
+            .map(|vals| vals.copied().collect())
+            .unwrap_or_default(),
+    };
+    let rlimit_nofile = matches.get_one::<NofileLimit>("rlimit-nofile").copied();
+    let nice = matches.get_one::<i32>("nice").copied();
+    let ionice = matches.get_one::<IoPriority>("ionice").copied();
+    let sched = matches
+        .get_one::<SchedPolicy>("sched-policy")
+        .map(|policy| {
+            let priority = matches
+                .get_one::<i32>("sched-priority")
+                .copied()
+                .unwrap_or(*policy.priorities().start());
+            (*policy, priority)
+        });
+    let cpu_rt_runtime = matches.get_one::<u64>("cpu-rt-runtime").copied();
+    let cpu_rt_period = matches.get_one::<u64>("cpu-rt-period").copied();
+    let ulimits: Vec<Rlimit> = matches
+        .get_many::<Rlimit>("ulimit")
+        .map(|vals| vals.copied().collect())
+        .unwrap_or_default();
+    let seccomp = match matches.get_one::<PathBuf>("seccomp-profile") {
+        Some(path) => SeccompMode::Profile(path.clone()),
+        None if matches
+            .get_one::<String>("seccomp")
+            .is_some_and(|mode| mode == "unconfined") =>
+        {
+            SeccompMode::Unconfined
+        }
+        None => SeccompMode::Default,
+    };
+    let no_new_privileges = matches.get_flag("no-new-privileges");
+    let group_add: Vec<String> = matches
+        .get_many::<String>("group-add")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let network = NetworkConfig {
+        mode: matches
+            .get_one::<String>("network")
+            .and_then(|mode| mode.parse::<NetworkMode>().ok())
+            .unwrap_or(NetworkMode::None),
+        bridge: matches
+            .get_one::<String>("bridge")
+            .expect("bridge has a default")
+            .clone(),
+        subnet: *matches
+            .get_one::<Subnet>("subnet")
+            .expect("subnet has a default"),
+        dns: DnsConfig {
+            servers: matches
+                .get_many::<IpAddr>("dns")
+                .map(|vals| vals.copied().collect())
+                .unwrap_or_default(),
+            search: matches
+                .get_many::<String>("dns-search")
+                .map(|vals| vals.cloned().collect())
+                .unwrap_or_default(),
+            ephemeral: matches
+                .get_one::<String>("resolv-conf")
+                .is_some_and(|mode| mode == "ephemeral"),
+        },
+    };
+    let no_pid_ns = matches.get_flag("no-pid-ns");
+    let no_net_ns = matches.get_flag("no-net-ns");
+    let no_ipc_ns = matches.get_flag("no-ipc-ns")
+        || matches.get_one::<String>("ipc").map(String::as_str) == Some("host");
+    let no_uts_ns = matches.get_flag("no-uts-ns")
+        || matches.get_one::<String>("uts").map(String::as_str) == Some("host");
+    let no_cgroup_ns = matches.get_flag("no-cgroup-ns");
+    let time_offsets = matches.get_one::<TimeOffsets>("time-offset").copied();
+    let mut join_ns: Vec<JoinNamespace> = matches
+        .get_many::<JoinNamespace>("join-ns")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    if let Some(mut values) = matches.get_many::<String>("join-container") {
+        let (id, types) = (values.next(), values.next());
+        if let (Some(id), Some(types)) = (id, types) {
+            join_ns.extend(container_namespaces(id, types)?);
+        }
+    }
+    let no_mount_ns = matches.get_flag("no-mount-ns");
+    let userns = matches.get_flag("userns");
+    let publish: Vec<PortMapping> = matches
+        .get_many::<PortMapping>("publish")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let labels: Vec<(String, String)> = matches
+        .get_many::<(String, String)>("label")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let label_files: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("label-file")
+        .map(|vals| vals.cloned().collect())
+        .unwrap_or_default();
+    let bundle = matches.get_one::<PathBuf>("bundle").cloned();
+    let log_file = matches.get_one::<PathBuf>("log-file").cloned();
+    let log_max_size = matches.get_one::<u64>("log-max-size").copied();
+    let pid_file = matches.get_one::<PathBuf>("pid-file").cloned();
+    let log_driver = *matches
+        .get_one::<LogDriver>("log-driver")
+        .expect("log-driver has a default");
+    let health = matches
+        .get_one::<String>("health-cmd")
+        .map(|command| HealthConfig {
+            command: command.clone(),
+            interval: *matches
+                .get_one::<Duration>("health-interval")
+                .expect("health-interval has a default"),
+            timeout: *matches
+                .get_one::<Duration>("health-timeout")
+                .expect("health-timeout has a default"),
+            retries: *matches
+                .get_one::<u32>("health-retries")
+                .expect("health-retries has a default"),
+            start_period: *matches
+                .get_one::<Duration>("health-start-period")
+                .expect("health-start-period has a default"),
+            on_failure: *matches
+                .get_one::<HealthAction>("health-on-failure")
+                .expect("health-on-failure has a default"),
+        });
+    let mut config = ContainerConfig {
+        id: None,
+        name: matches.get_one::<String>("name").cloned(),
         rootfs,
+        rootfs_tar,
+        rootfs_keep,
+        image: None,
         command,
         args,
         hostname,
-        memory_limit_mb,
+        hostname_strict,
+        extra_hosts,
+        workdir,
+        umask,
+        skip_preflight,
+        env: Vec::new(),
+        env_host,
+        env_host_all: matches.get_flag("env-host-all"),
+        env_files,
+        setsid,
+        init,
+        tty: matches.get_flag("tty"),
+        interactive: matches.get_flag("interactive"),
+        output_buffering,
+        host_proc_sys_ro,
+        overlay,
+        state_dir,
+        rm,
+        read_only,
+        tmpfs,
+        copies,
+        copy_chown,
+        shm_size,
+        mask_paths,
+        no_default_masks,
+        readonly_paths,
+        cgroup_rw,
+        cgroup_reuse,
+        cgroup_best_effort,
+        cgroup_delegate,
+        cgroup_driver,
+        device_cgroup_rules,
+        no_device_cgroup,
+        devices,
+        no_pivot,
+        rootfs_propagation,
+        keep_old_root,
+        memory_limit,
+        memory_high_mb,
+        cpu_weight: None,
+        cpu_quota: None,
+        cpu_period: None,
+        pids_limit: None,
+        oom_score_adj,
+        memory_oom_group,
+        memory_swappiness,
+        cpu_rt_runtime,
+        cpu_rt_period,
+        strict,
+        force_kill,
+        stats_json,
+        stats_interval,
+        pids_watch,
+        timeout: matches.get_one::<u64>("timeout").copied(),
+        stop_grace: *matches
+            .get_one::<u64>("stop-grace")
+            .expect("stop-grace has a default"),
+        restart: *matches
+            .get_one::<RestartPolicy>("restart")
+            .expect("restart has a default"),
+        health,
+        stats_format_json,
+        events_fd,
+        sysctls,
+        capabilities,
+        rlimit_nofile,
+        ulimits,
+        nice,
+        ionice,
+        sched,
+        seccomp,
+        no_new_privileges,
+        group_add,
+        network,
+        no_pid_ns,
+        no_net_ns,
+        no_ipc_ns,
+        no_uts_ns,
+        no_cgroup_ns,
+        time_offsets,
+        join_ns,
+        no_mount_ns,
+        userns,
+        publish,
+        labels,
+        label_files,
+        bundle,
+        log_file,
+        log_max_size,
+        log_driver,
+        pid_file,
+    };
+    if let Some(path) = matches.get_one::<PathBuf>("config") {
+        let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        ConfigFile::load(path)?.apply(path, &mut config, explicit)?;
+        if config.rootfs.as_os_str().is_empty() && !matches.contains_id("extracted-rootfs") {
+            return Err(ContainerError::invalid_configuration(format!(
+                "{} sets no rootfs and none was given on the command line",
+                path.display()
+            )));
+        }
+        if config.command.is_empty() && !matches.contains_id("image") {
+            return Err(ContainerError::invalid_configuration(format!(
+                "{} sets no command and none was given on the command line",
+                path.display()
+            )));
+        }
+    }
+    if let Some(bundle) = config.bundle.clone() {
+        let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        Spec::load(&bundle)?.apply(&bundle, &mut config, explicit)?;
+    }
+    if let Some(reference) = matches.get_one::<ImageRef>("image") {
+        let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let image = Image::load(reference)?;
+        image.apply(&mut config, explicit)?;
+        config.image = Some(image);
+    }
+    // After the bundle's process.env so that --env wins over it.
+    if let Some(env) = matches.get_many::<(String, String)>("env") {
+        config.env.extend(env.cloned());
+    }
+    Ok(config)
+}
+
+fn parse_owner(s: &str) -> Result<(u32, u32), String> {
+    s.split_once(':')
+        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+        .ok_or_else(|| format!("invalid owner '{s}', expected numeric UID:GID"))
+}
+
+// A number with an optional ms, s, m or h suffix; bare numbers are seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let value: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{s}'"))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 3600)),
+        _ => Err(format!(
+            "invalid duration '{s}', expected a number with an optional ms, s, m or h suffix"
+        )),
+    }
+}
+
+// The namespaces of a running container's init, for --join-container.
+fn container_namespaces(id: &str, types: &str) -> ContainerResult<Vec<JoinNamespace>> {
+    let id = validate_id(id).map_err(ContainerError::invalid_configuration)?;
+    let state = ContainerState::load(&id)?;
+    if !matches!(state.status, Status::Running | Status::Paused) {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Cannot join the namespaces of container {id}: it is {}",
+            state.status
+        )));
+    }
+    types
+        .split(',')
+        .map(|kind| {
+            let kind = kind
+                .parse::<NamespaceType>()
+                .map_err(ContainerError::invalid_configuration)?;
+            Ok(JoinNamespace {
+                kind,
+                path: PathBuf::from(format!("/proc/{}/ns/{kind}", state.pid)),
+            })
+        })
+        .collect()
+}
+
+fn parse_container_path(path: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(path);
+    if !path.is_absolute() {
+        return Err(format!(
+            "container path must be absolute, got '{}'",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+// NAME:IP; the name cannot contain ':' so IPv6 addresses need no brackets.
+fn parse_add_host(spec: &str) -> Result<(String, IpAddr), String> {
+    let (name, addr) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME:IP, got '{spec}'"))?;
+    if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c == '#') {
+        return Err(format!("invalid host name '{name}'"));
+    }
+    let addr = addr
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map_err(|e| format!("invalid address '{addr}': {e}"))?;
+    Ok((name.to_string(), addr))
+}
+
+fn parse_search_domain(domain: &str) -> Result<String, String> {
+    let domain = domain.trim_end_matches('.');
+    if domain.is_empty() || domain.len() > 253 {
+        return Err(format!("invalid search domain '{domain}'"));
+    }
+    if let Some(c) = domain
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '.'))
+    {
+        return Err(format!(
+            "invalid character '{c}' in search domain '{domain}'"
+        ));
+    }
+    Ok(domain.to_string())
+}
+
+fn parse_sysctl(spec: &str) -> Result<(String, String), String> {
+    let (key, value) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{spec}'"))?;
+    let key = key.trim();
+    if key.is_empty()
+        || key
+            .split('.')
+            .any(|part| part.is_empty() || part.contains('/'))
+    {
+        return Err(format!("invalid sysctl key '{key}'"));
+    }
+    if SysctlManager::namespace(key).is_none() {
+        return Err(format!(
+            "sysctl '{key}' is not namespaced and would change the host; only net.*, \
+             kernel.msg*, kernel.sem, kernel.shm*, fs.mqueue.*, kernel.hostname and \
+             kernel.domainname can be set"
+        ));
+    }
+    Ok((key.to_string(), value.trim().to_string()))
+}
+
+fn parse_umask(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|mask| *mask <= 0o777)
+        .ok_or_else(|| format!("invalid umask '{value}', expected an octal value up to 0777"))
+}
+
+fn parse_label(spec: &str) -> Result<(String, String), String> {
+    let (key, value) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{spec}'"))?;
+    validate_key(key.trim())?;
+    Ok((key.trim().to_string(), value.to_string()))
+}
+
+fn parse_label_filter(spec: &str) -> Result<(String, Option<String>), String> {
+    let label = spec
+        .strip_prefix("label=")
+        .ok_or_else(|| format!("expected label=KEY[=VALUE], got '{spec}'"))?;
+    let (key, value) = match label.split_once('=') {
+        Some((key, value)) => (key, Some(value.to_string())),
+        None => (label, None),
+    };
+    validate_key(key)?;
+    Ok((key.to_string(), value))
+}
+
+fn validate_key(key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("key must not be empty".to_string());
+    }
+    if key.chars().any(|c| c.is_whitespace() || c == '=') {
+        return Err(format!("key '{key}' must not contain whitespace or '='"));
+    }
+    Ok(())
+}
+
+// Parses dotenv-style files: KEY=VALUE per line, '#' comments and blank lines
+// ignored, and a single pair of surrounding quotes stripped from the value.
+pub fn parse_key_value_file(path: &Path) -> ContainerResult<Vec<(String, String)>> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        ContainerError::invalid_configuration(format!("Failed to read {}: {e}", path.display()))
+    })?;
+    let mut entries = Vec::new();
+    for (index, raw_line) in content.lines().enumerate() {
+        let line_no = index + 1;
+        let line = raw_line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: String| {
+            ContainerError::invalid_configuration(format!("{}:{line_no}: {reason}", path.display()))
+        };
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("expected KEY=VALUE, got '{line}'")))?;
+        let key = key.trim();
+        validate_key(key).map_err(invalid)?;
+        entries.push((key.to_string(), unquote(value.trim()).map_err(invalid)?));
+    }
+    Ok(entries)
+}
+
+fn unquote(value: &str) -> Result<String, String> {
+    for quote in ['"', '\''] {
+        if let Some(rest) = value.strip_prefix(quote) {
+            return rest.strip_suffix(quote).map(str::to_string).ok_or_else(|| {
+                format!("unterminated {quote} quote (multi-line values are not supported)")
+            });
+        }
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        let command = cli_command();
+        let args = hoist_verbosity(&command, args.iter().map(OsString::from).collect());
+        Cli {
+            matches: command.try_get_matches_from(args).unwrap(),
+        }
+    }
+
+    #[test]
+    fn verbosity_before_a_subcommand() {
+        let cli = cli(&["container_rs", "-v", "list"]);
+        assert_eq!(cli.log_level(), Some(LevelFilter::Debug));
+        assert!(matches!(cli.command().unwrap(), CliCommand::List { .. }));
+        let cli = self::cli(&["container_rs", "-q", "state", "web"]);
+        assert_eq!(cli.log_level(), Some(LevelFilter::Warn));
+        assert!(matches!(cli.command().unwrap(), CliCommand::State { .. }));
+    }
+
+    #[test]
+    fn verbosity_after_a_subcommand() {
+        assert_eq!(
+            cli(&["container_rs", "list", "-vv"]).log_level(),
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(
+            cli(&["container_rs", "--quiet", "--quiet", "list"]).log_level(),
+            Some(LevelFilter::Error)
+        );
+    }
+
+    #[test]
+    fn verbosity_with_a_bare_run() {
+        let cli = cli(&["container_rs", "-vv", "--rootfs", "/rootfs", "--", "list"]);
+        assert_eq!(cli.log_level(), Some(LevelFilter::Trace));
+        match cli.command().unwrap() {
+            CliCommand::Run { config, .. } => assert_eq!(config.command, "list"),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn no_flags_leave_the_level_to_rust_log() {
+        assert_eq!(cli(&["container_rs", "list"]).log_level(), None);
+    }
+
+    fn key_value_file(content: &str) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), content).unwrap();
+        file
+    }
+
+    #[test]
+    fn key_value_files_skip_comments_and_strip_quotes() {
+        let file = key_value_file(
+            "# comment\n\nPLAIN=a b\n  SPACED = padded  \nDOUBLE=\"x=1\"\nSINGLE='#not a comment'\nEMPTY=\n",
+        );
+        assert_eq!(
+            parse_key_value_file(file.path()).unwrap(),
+            [
+                ("PLAIN".to_string(), "a b".to_string()),
+                ("SPACED".to_string(), "padded".to_string()),
+                ("DOUBLE".to_string(), "x=1".to_string()),
+                ("SINGLE".to_string(), "#not a comment".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_value_file_errors_name_the_line() {
+        let error = |content: &str| {
+            let file = key_value_file(content);
+            let error = parse_key_value_file(file.path()).unwrap_err().to_string();
+            let line = error.split_once(&*file.path().to_string_lossy()).unwrap().1;
+            line.to_string()
+        };
+        assert!(error("A=1\nnot a pair\n").starts_with(":2: expected KEY=VALUE"));
+        assert!(error("=value\n").starts_with(":1: key must not be empty"));
+        assert!(error("MY KEY=1\n").starts_with(":1: key 'MY KEY' must not contain"));
+        assert!(error("A=\"open\n").starts_with(":1: unterminated \" quote"));
+        let missing = parse_key_value_file(Path::new("/nonexistent/env")).unwrap_err();
+        assert_eq!(missing.exit_code(), 2);
+    }
+
+    #[test]
+    fn inline_labels_override_label_files() {
+        let first = key_value_file("tier=web\nteam=a\n");
+        let second = key_value_file("team=b\n");
+        let mut config = ContainerConfig::new(PathBuf::from("/rootfs"), OsString::from("sh"));
+        config.label_files = vec![first.path().to_path_buf(), second.path().to_path_buf()];
+        config.labels = vec![parse_label("tier=db").unwrap()];
+        let labels = config.resolve_labels().unwrap();
+        assert_eq!(labels["tier"], "db");
+        assert_eq!(labels["team"], "b");
+        assert!(parse_label("novalue").is_err());
+        assert_eq!(
+            parse_label_filter("label=tier").unwrap(),
+            ("tier".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn unresolvable_device_fails_the_cgroup_config() {
+        let mut config = ContainerConfig::new(PathBuf::from("/rootfs"), OsString::from("sh"));
+        config.devices = vec!["/dev/null".parse().unwrap()];
+        assert!(config.cgroup_config("test".to_string()).is_ok());
+        config.devices.push("/nonexistent/device".parse().unwrap());
+        let error = config.cgroup_config("test".to_string()).unwrap_err();
+        assert!(error.to_string().contains("/nonexistent/device"), "{error}");
+        assert_eq!(error.exit_code(), 2);
+        // Without a device cgroup there is no rule to build.
+        config.no_device_cgroup = true;
+        assert!(config.cgroup_config("test".to_string()).is_ok());
     }
 }
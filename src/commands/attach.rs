@@ -0,0 +1,172 @@
+use crate::console::{ATTACH_SOCKET, FRAME_INPUT, FRAME_RESIZE, RawTerminal, encode_frame};
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::{ContainerState, Status};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::signal::{SigHandler, Signal, signal};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const DEFAULT_DETACH_KEYS: &str = "ctrl-p,ctrl-q";
+
+static WINDOW_CHANGED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_winch(_: i32) {
+    WINDOW_CHANGED.store(true, Ordering::SeqCst);
+}
+
+// Docker's format: a comma-separated list of `ctrl-<key>` or single
+// characters.
+pub fn parse_detach_keys(s: &str) -> Result<Vec<u8>, String> {
+    s.split(',')
+        .map(|key| match key.strip_prefix("ctrl-") {
+            Some(name) if name.len() == 1 => {
+                let c = name.as_bytes()[0].to_ascii_uppercase();
+                match c {
+                    b'@'..=b'_' => Ok(c & 0x1f),
+                    _ => Err(format!("invalid detach key '{key}'")),
+                }
+            }
+            None if key.len() == 1 => Ok(key.as_bytes()[0]),
+            _ => Err(format!("invalid detach key '{key}'")),
+        })
+        .collect()
+}
+
+fn window_size_frame() -> Option<Vec<u8>> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(io::stdin().as_raw_fd(), libc::TIOCGWINSZ, &mut size) } != 0 {
+        return None;
+    }
+    let mut payload = size.ws_row.to_be_bytes().to_vec();
+    payload.extend_from_slice(&size.ws_col.to_be_bytes());
+    Some(encode_frame(FRAME_RESIZE, &payload))
+}
+
+// Holds back a partial detach sequence until it either completes or turns
+// out to be ordinary input.
+struct DetachMatcher<'a> {
+    keys: &'a [u8],
+    matched: usize,
+}
+
+impl DetachMatcher<'_> {
+    // Returns the bytes to forward, and whether the sequence was completed.
+    fn feed(&mut self, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = Vec::with_capacity(input.len());
+        for &byte in input {
+            if byte == self.keys[self.matched] {
+                self.matched += 1;
+                if self.matched == self.keys.len() {
+                    return (out, true);
+                }
+                continue;
+            }
+            out.extend_from_slice(&self.keys[..self.matched]);
+            self.matched = usize::from(byte == self.keys[0]);
+            if self.matched == 0 {
+                out.push(byte);
+            }
+        }
+        (out, false)
+    }
+}
+
+pub fn run(id: &str, detach_keys: &[u8]) -> ContainerResult<()> {
+    let state = ContainerState::load(id)?;
+    if state.status != Status::Running {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Container {id} is {}, only running containers can be attached",
+            state.status
+        )));
+    }
+    let socket = state.dir().join(ATTACH_SOCKET);
+    if !socket.exists() {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Container {id} has no console to attach to, it was started without -t"
+        )));
+    }
+    let mut stream = UnixStream::connect(socket).map_err(|e| {
+        ContainerError::initialization(format!("Cannot attach to container {id}: {e}"))
+    })?;
+
+    let terminal = RawTerminal::enable();
+    if terminal.is_some() {
+        unsafe {
+            signal(Signal::SIGWINCH, SigHandler::Handler(handle_winch)).ok();
+        }
+        if let Some(frame) = window_size_frame() {
+            stream.write_all(&frame)?;
+        }
+    }
+    let detached = relay(&mut stream, detach_keys)?;
+    drop(terminal);
+    if detached {
+        eprintln!("Detached from container {id}");
+    }
+    Ok(())
+}
+
+// Copies stdin to the container and its console to stdout. Returns true when
+// the user detached, false when the container's console closed.
+fn relay(stream: &mut UnixStream, detach_keys: &[u8]) -> ContainerResult<bool> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut matcher = DetachMatcher {
+        keys: detach_keys,
+        matched: 0,
+    };
+    let mut stdin_open = true;
+    let mut buffer = [0u8; 4096];
+    let timeout = PollTimeout::from(200u16);
+    loop {
+        if WINDOW_CHANGED.swap(false, Ordering::SeqCst)
+            && let Some(frame) = window_size_frame()
+        {
+            stream.write_all(&frame)?;
+        }
+        let mut fds = vec![PollFd::new(stream.as_fd(), PollFlags::POLLIN)];
+        if stdin_open {
+            fds.push(PollFd::new(stdin.as_fd(), PollFlags::POLLIN));
+        }
+        match poll(&mut fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => continue,
+            Ok(_) => {}
+            Err(e) => {
+                return Err(ContainerError::process_execution(format!(
+                    "Attach relay failed: {e}"
+                )));
+            }
+        }
+        let ready: Vec<bool> = fds.iter().map(|fd| fd.any().unwrap_or(false)).collect();
+        drop(fds);
+
+        if ready[0] {
+            match stream.read(&mut buffer)? {
+                0 => return Ok(false),
+                n => {
+                    stdout.write_all(&buffer[..n])?;
+                    stdout.flush()?;
+                }
+            }
+        }
+        if ready.get(1).copied().unwrap_or(false) {
+            // Straight from the fd: Stdin's own buffer would hide data from
+            // poll.
+            match nix::unistd::read(stdin.as_fd(), &mut buffer).map_err(io::Error::from)? {
+                0 => stdin_open = false,
+                n => {
+                    let (input, detach) = matcher.feed(&buffer[..n]);
+                    if !input.is_empty() {
+                        stream.write_all(&encode_frame(FRAME_INPUT, &input))?;
+                    }
+                    if detach {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use crate::cli::ContainerConfig;
+use crate::console::{ATTACH_SOCKET, ConsoleServer, console_socket_pair};
+use crate::container::Container;
+use crate::error::{ContainerError, ContainerResult};
+use crate::health::HealthListener;
+use crate::log_file::LogFile;
+use crate::restart::RestartPolicy;
+use crate::state::{
+    ContainerState, RUN_DIR, Status, container_dir, generate_id, process_start_time,
+};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::signal::Signal;
+use nix::sys::stat::Mode;
+use nix::sys::wait::{Id, WaitPidFlag, WaitStatus, waitid};
+use nix::unistd::{ForkResult, Pid, dup2, fork, mkfifo, pipe2, setsid};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// The supervisor of a created container reads one request byte from this
+// FIFO: start the command, or tear the container down without running it.
+const START_FIFO: &str = "start.fifo";
+// Container output, unless --log-file says otherwise, and the supervisor's own
+// diagnostics.
+const CONSOLE_LOG: &str = "console.log";
+const RUNTIME_LOG: &str = "runtime.log";
+pub const REQUEST_START: u8 = b's';
+pub const REQUEST_ABORT: u8 = b'a';
+
+// Sets the container up in a detached supervisor process and returns once its
+// init is parked before exec. The supervisor stays behind to run the
+// container and clean up after it.
+pub fn run(id: &str, config: ContainerConfig) -> ContainerResult<()> {
+    launch(id, config, false)
+}
+
+// `run -d`: create and start in one go under a generated id, which is printed.
+// The supervisor runs in its own session, so the container outlives the
+// terminal and never sees its SIGHUP or job control signals.
+pub fn detach(config: ContainerConfig) -> ContainerResult<()> {
+    let id = generate_id()?;
+    launch(&id, config, true)?;
+    println!("{id}");
+    Ok(())
+}
+
+fn launch(id: &str, mut config: ContainerConfig, start: bool) -> ContainerResult<()> {
+    if config.restart != RestartPolicy::No {
+        return Err(ContainerError::invalid_configuration(
+            "--restart is only supported for containers run in the foreground",
+        ));
+    }
+    if let Some(name) = &config.name {
+        check_name_available(name)?;
+    }
+    if let Some(other) = ContainerState::find_by_name(id)? {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Id {id} is already in use as the name of container {}",
+            other.id
+        )));
+    }
+    config.id = Some(id.to_string());
+    let dir = container_dir(id);
+    if config.log_file.is_none() {
+        config.log_file = Some(dir.join(CONSOLE_LOG));
+    }
+    let labels = config.resolve_labels()?;
+    fs::create_dir_all(RUN_DIR)?;
+    fs::create_dir(&dir).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => {
+            ContainerError::invalid_configuration(format!("Container {id} already exists"))
+        }
+        _ => e.into(),
+    })?;
+    let mut state = ContainerState::new(id, &config);
+    state.labels = labels;
+    let result = state
+        .save()
+        .and_then(|()| {
+            mkfifo(&dir.join(START_FIFO), Mode::S_IRUSR | Mode::S_IWUSR).map_err(Into::into)
+        })
+        .and_then(|()| daemonize_supervisor(state.clone(), config, start));
+    if result.is_err() {
+        let _ = state.remove();
+    }
+    result
+}
+
+// Names share the namespace of ids: either one finds the container.
+fn check_name_available(name: &str) -> ContainerResult<()> {
+    let owner = match ContainerState::find_by_name(name)? {
+        Some(state) => Some(state.id),
+        None if container_dir(name).exists() => Some(name.to_string()),
+        None => None,
+    };
+    match owner {
+        Some(id) => Err(ContainerError::invalid_configuration(format!(
+            "Name {name} is already in use by container {id}"
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Hands a request to the supervisor. The FIFO is opened non-blocking so a
+// supervisor that has gone away shows up as ENXIO rather than a hang.
+pub fn send_request(id: &str, request: u8) -> ContainerResult<()> {
+    let path = container_dir(id).join(START_FIFO);
+    let mut fifo = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&path)
+        .map_err(|e| {
+            ContainerError::initialization(format!(
+                "Cannot reach the supervisor of container {id}: {e}"
+            ))
+        })?;
+    fifo.write_all(&[request])?;
+    Ok(())
+}
+
+fn daemonize_supervisor(
+    state: ContainerState,
+    config: ContainerConfig,
+    start: bool,
+) -> ContainerResult<()> {
+    // Close-on-exec so the workload never holds the report pipe open.
+    let (reader, writer) = pipe2(OFlag::O_CLOEXEC).map_err(|e| {
+        ContainerError::initialization(format!("Failed to create report pipe: {e}"))
+    })?;
+    match unsafe { fork()? } {
+        ForkResult::Parent { child } => {
+            drop(writer);
+            let _ = nix::sys::wait::waitpid(child, None);
+            // The container init inherits the write end too, so read a single
+            // line instead of waiting for EOF.
+            let mut report = String::new();
+            BufReader::new(File::from(reader)).read_line(&mut report)?;
+            match report.trim_end() {
+                "ok" => Ok(()),
+                "" => Err(ContainerError::initialization(
+                    "Supervisor exited before the container was created",
+                )),
+                error => Err(ContainerError::initialization(error.to_string())),
+            }
+        }
+        ForkResult::Child => {
+            drop(reader);
+            let mut report = File::from(writer);
+            // A new session without a controlling terminal; the intermediate
+            // child exits so the supervisor is reparented away from the caller.
+            let _ = setsid();
+            match unsafe { fork() } {
+                Ok(ForkResult::Parent { .. }) => unsafe { libc::_exit(0) },
+                Ok(ForkResult::Child) => {}
+                Err(e) => {
+                    let _ = writeln!(report, "Failed to fork supervisor: {e}");
+                    unsafe { libc::_exit(1) }
+                }
+            }
+            let code = match supervise(state, config, start, &mut report) {
+                Ok(()) => 0,
+                Err(e) => {
+                    log::error!("Container supervisor error: {e}");
+                    let _ = writeln!(report, "{e}");
+                    1
+                }
+            };
+            std::process::exit(code);
+        }
+    }
+}
+
+fn supervise(
+    state: ContainerState,
+    config: ContainerConfig,
+    start: bool,
+    report: &mut File,
+) -> ContainerResult<()> {
+    redirect_stdio(&state.dir().join(RUNTIME_LOG))?;
+    // Read-write so opening never blocks and the FIFO never reports EOF.
+    let fifo = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(state.dir().join(START_FIFO))?;
+    let attach_path = state.dir().join(ATTACH_SOCKET);
+    // Only a PTY gives us a console to relay and attach to; without one the
+    // init writes the log file itself.
+    let console = if config.tty {
+        let listener = UnixListener::bind(&attach_path)?;
+        listener.set_nonblocking(true)?;
+        let log = config
+            .log_file
+            .as_deref()
+            .map(|path| LogFile::open(path, config.log_max_size, config.log_driver))
+            .transpose()?;
+        Some((listener, log, console_socket_pair()?))
+    } else {
+        None
+    };
+    // The health monitor records its status from its own thread.
+    let state = Arc::new(Mutex::new(state));
+    let health_state = Arc::clone(&state);
+    let mut container = Container::from_config(config)
+        .defer_start(!start)
+        .health_listener(HealthListener::new(move |health| {
+            if let Err(e) = update_state(&health_state, |state| state.health = Some(health)) {
+                log::warn!("Failed to record health status: {e}");
+            }
+        }));
+    if let Some((_, _, (_, init_console))) = &console {
+        container = container.console_socket(init_console.as_raw_fd());
+    }
+    let mut handle = container.spawn()?;
+    let console = console.map(|(listener, log, (socket, init_console))| {
+        drop(init_console);
+        ConsoleServer::spawn(socket, listener, log)
+    });
+    update_state(&state, |state| {
+        state.pid = handle.pid().as_raw();
+        state.start_time = process_start_time(state.pid);
+        state.cgroup_path = handle.cgroup_path().map(Path::to_path_buf);
+        state.status = if start {
+            Status::Running
+        } else {
+            Status::Created
+        };
+    })?;
+    report.write_all(b"ok\n")?;
+
+    let id = update_state(&state, |state| state.id.clone())?;
+    if !start {
+        match wait_for_request(&fifo, handle.pid())? {
+            Some(REQUEST_START) => {
+                handle.start()?;
+                update_state(&state, |state| state.status = Status::Running)?;
+                log::info!("Started container {id}");
+            }
+            Some(_) => {
+                log::info!("Container {id} deleted before it was started");
+                let _ = handle.kill(Signal::SIGKILL);
+            }
+            None => log::warn!("Container {id} init exited before start"),
+        }
+    }
+    let result = handle.wait();
+    if let Some(console) = console {
+        console.stop();
+        let _ = fs::remove_file(&attach_path);
+    }
+    update_state(&state, |state| {
+        state.status = Status::Stopped;
+        state.exit_code = result.as_ref().ok().map(|outcome| outcome.exit_code());
+    })?;
+    result.map(|_| ())
+}
+
+// Applies `change` to the shared state and saves it. The state file is read
+// back first, as `pause` and `unpause` update its status from outside.
+fn update_state<T>(
+    state: &Mutex<ContainerState>,
+    change: impl FnOnce(&mut ContainerState) -> T,
+) -> ContainerResult<T> {
+    let mut state = state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Ok(current) = ContainerState::load(&state.id) {
+        state.status = current.status;
+    }
+    let value = change(&mut state);
+    state.save()?;
+    Ok(value)
+}
+
+fn redirect_stdio(log: &Path) -> ContainerResult<()> {
+    let console = OpenOptions::new().create(true).append(true).open(log)?;
+    let null = File::open("/dev/null")?;
+    for (source, fd) in [
+        (null.as_fd(), 0),
+        (console.as_fd(), 1),
+        (console.as_fd(), 2),
+    ] {
+        let mut target = unsafe { OwnedFd::from_raw_fd(fd) };
+        dup2(source, &mut target)?;
+        std::mem::forget(target);
+    }
+    Ok(())
+}
+
+// Waits for a request byte, polling so that an init that dies while parked
+// (e.g. from `kill`) is noticed. The init is not reaped here.
+fn wait_for_request(fifo: &File, init: Pid) -> ContainerResult<Option<u8>> {
+    let timeout = PollTimeout::from(200u16);
+    loop {
+        let mut fds = [PollFd::new(fifo.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) => {
+                let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+                match waitid(Id::Pid(init), flags) {
+                    Ok(WaitStatus::StillAlive) => continue,
+                    _ => return Ok(None),
+                }
+            }
+            Ok(_) => {
+                let mut request = [0u8; 1];
+                let mut reader = fifo;
+                return match reader.read(&mut request)? {
+                    1 => Ok(Some(request[0])),
+                    _ => Ok(None),
+                };
+            }
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                return Err(ContainerError::initialization(format!(
+                    "Failed to wait for a start request: {e}"
+                )));
+            }
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use crate::commands::create::{REQUEST_ABORT, send_request};
+use crate::commands::unpause::thaw;
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::{ContainerState, Status};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use std::time::Duration;
+
+// How long to wait for the supervisor to tear a container down.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn run(id: &str, force: bool) -> ContainerResult<()> {
+    let state = ContainerState::load(id)?;
+    match state.status {
+        Status::Stopped => {}
+        Status::Created => {
+            send_request(&state.id, REQUEST_ABORT)?;
+            ContainerState::wait_for(id, Some(STOP_TIMEOUT), |state| {
+                state.status == Status::Stopped
+            })?;
+        }
+        Status::Running | Status::Paused if force => {
+            if state.status == Status::Paused {
+                thaw(state.clone())?;
+            }
+            let _ = kill(Pid::from_raw(state.pid), Signal::SIGKILL);
+            ContainerState::wait_for(id, Some(STOP_TIMEOUT), |state| {
+                state.status == Status::Stopped
+            })?;
+        }
+        status => {
+            return Err(ContainerError::invalid_configuration(format!(
+                "Container {id} is {status}; stop it first or use --force"
+            )));
+        }
+    }
+    state.remove()?;
+    log::info!("Deleted container {id}");
+    Ok(())
+}
@@ -0,0 +1,23 @@
+use crate::commands::unpause::thaw;
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::{ContainerState, Status};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+
+pub fn run(id: &str, signal: Signal) -> ContainerResult<()> {
+    let mut state = ContainerState::load(id)?;
+    if state.status == Status::Paused {
+        state = thaw(state)?;
+    }
+    if !matches!(state.status, Status::Created | Status::Running) {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Container {id} is {}, not running",
+            state.status
+        )));
+    }
+    kill(Pid::from_raw(state.pid), signal).map_err(|e| {
+        ContainerError::process_execution(format!("Failed to send {signal} to container {id}: {e}"))
+    })?;
+    log::debug!("Sent {signal} to container {id} (PID {})", state.pid);
+    Ok(())
+}
@@ -0,0 +1,232 @@
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::{ContainerState, RUN_DIR, Status, now_secs, validate_id};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+// CFS period the kernel uses when none was given.
+const DEFAULT_CPU_PERIOD: u64 = 100_000;
+
+pub fn run(
+    json: bool,
+    prune: bool,
+    label_filters: &[(String, Option<String>)],
+) -> ContainerResult<()> {
+    let mut states = read_states(Path::new(RUN_DIR))?;
+    states.retain(|state| matches_labels(state, label_filters));
+    if prune {
+        states.retain(|state| {
+            if state.status != Status::Stopped {
+                return true;
+            }
+            match state.remove() {
+                Ok(()) => log::info!("Pruned stopped container {}", state.id),
+                Err(e) => log::warn!("Failed to prune container {}: {e}", state.id),
+            }
+            false
+        });
+    }
+    if json {
+        let json = serde_json::to_string_pretty(&states).map_err(|e| {
+            ContainerError::initialization(format!("Failed to serialize state: {e}"))
+        })?;
+        println!("{json}");
+    } else {
+        print_table(&states);
+    }
+    Ok(())
+}
+
+// Every directory under `dir` holding a state file, sorted by creation time.
+// Other entries (e.g. the IPAM store) are skipped, and so are corrupt state
+// files, with a warning.
+fn read_states(dir: &Path) -> ContainerResult<Vec<ContainerState>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut states = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(id) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if validate_id(&id).is_err() || !entry.path().join("state.json").is_file() {
+            continue;
+        }
+        match ContainerState::load_from(&entry.path()) {
+            Ok(state) => states.push(state),
+            Err(e) => log::warn!("Skipping container {id}: {e}"),
+        }
+    }
+    states.sort_by(|a, b| (a.created, &a.id).cmp(&(b.created, &b.id)));
+    Ok(states)
+}
+
+fn matches_labels(state: &ContainerState, filters: &[(String, Option<String>)]) -> bool {
+    filters
+        .iter()
+        .all(|(key, value)| match (state.labels.get(key), value) {
+            (Some(label), Some(value)) => label == value,
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+}
+
+fn print_table(states: &[ContainerState]) {
+    let now = now_secs();
+    let rows: Vec<[String; 8]> = states
+        .iter()
+        .map(|state| {
+            let uptime = match state.status {
+                Status::Created | Status::Running | Status::Paused => {
+                    format_duration(now.saturating_sub(state.created))
+                }
+                _ => "-".to_string(),
+            };
+            [
+                state.id.clone(),
+                state.name.clone().unwrap_or_else(|| "-".to_string()),
+                state.pid.to_string(),
+                match state.health {
+                    Some(health) if state.status == Status::Running => {
+                        format!("{} ({health})", state.status)
+                    }
+                    _ => state.status.to_string(),
+                },
+                uptime,
+                state
+                    .memory_limit
+                    .map(format_bytes)
+                    .unwrap_or_else(|| "-".to_string()),
+                format_cpu(state),
+                state.rootfs.display().to_string(),
+            ]
+        })
+        .collect();
+    let header = [
+        "ID", "NAME", "PID", "STATUS", "UPTIME", "MEMORY", "CPU", "ROOTFS",
+    ];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(&header);
+    for row in &rows {
+        print_row(&row.each_ref().map(String::as_str));
+    }
+}
+
+fn format_cpu(state: &ContainerState) -> String {
+    match (state.cpu_quota, state.cpu_weight) {
+        (Some(quota), _) => {
+            let period = state.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD);
+            format!("{}%", quota * 100 / period.max(1))
+        }
+        (None, Some(weight)) => format!("weight {weight}"),
+        (None, None) => "-".to_string(),
+    }
+}
+
+// In the largest unit that divides it evenly.
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        _ if bytes.is_multiple_of(1024 * 1024) => format!("{}M", bytes / 1024 / 1024),
+        _ if bytes.is_multiple_of(1024) => format!("{}K", bytes / 1024),
+        _ => format!("{bytes}B"),
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        3600..86400 => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::ContainerConfig;
+    use crate::state::process_start_time;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    fn write_state(dir: &Path, id: &str, pid: i32, created: u64) {
+        let mut state =
+            ContainerState::new(id, &ContainerConfig::new(PathBuf::from("/"), "sh".into()));
+        state.pid = pid;
+        state.start_time = process_start_time(pid);
+        state.status = Status::Running;
+        state.created = created;
+        let json = serde_json::to_string(&state).unwrap();
+        fs::create_dir(dir.join(id)).unwrap();
+        fs::write(dir.join(id).join("state.json"), json).unwrap();
+    }
+
+    #[test]
+    fn live_stale_and_corrupt_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        write_state(dir.path(), "live", std::process::id() as i32, 2);
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id() as i32;
+        child.wait().unwrap();
+        write_state(dir.path(), "stale", pid, 1);
+        fs::create_dir(dir.path().join("corrupt")).unwrap();
+        fs::write(dir.path().join("corrupt/state.json"), "{").unwrap();
+        // Not a container: no state file, or not a valid id.
+        fs::create_dir(dir.path().join("ipam")).unwrap();
+        fs::create_dir(dir.path().join(".hidden")).unwrap();
+
+        let states = read_states(dir.path()).unwrap();
+        let listed: Vec<(&str, Status)> = states
+            .iter()
+            .map(|state| (state.id.as_str(), state.status))
+            .collect();
+        assert_eq!(
+            listed,
+            [("stale", Status::Stopped), ("live", Status::Running)]
+        );
+    }
+
+    #[test]
+    fn label_filters_must_all_match() {
+        let mut state = ContainerState::new(
+            "web",
+            &ContainerConfig::new(PathBuf::from("/"), "sh".into()),
+        );
+        state.labels.insert("app".to_string(), "shop".to_string());
+        state.labels.insert("tier".to_string(), "front".to_string());
+        let filter = |filters: &[(&str, Option<&str>)]| {
+            let filters: Vec<(String, Option<String>)> = filters
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.map(str::to_string)))
+                .collect();
+            matches_labels(&state, &filters)
+        };
+        assert!(filter(&[]));
+        assert!(filter(&[("app", None)]));
+        assert!(filter(&[("app", Some("shop")), ("tier", Some("front"))]));
+        assert!(!filter(&[("app", Some("blog"))]));
+        assert!(!filter(&[("app", None), ("env", None)]));
+    }
+
+    #[test]
+    fn missing_directory_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_states(&dir.path().join("none")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,10 @@
+pub mod attach;
+pub mod create;
+pub mod delete;
+pub mod kill;
+pub mod list;
+pub mod pause;
+pub mod start;
+pub mod state;
+pub mod unpause;
+pub mod wait;
@@ -0,0 +1,47 @@
+use crate::cgroup::Freezer;
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::{ContainerState, Status};
+
+pub fn run(id: &str) -> ContainerResult<()> {
+    let mut state = ContainerState::load(id)?;
+    if state.status != Status::Running {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Container {id} is {}, only running containers can be paused",
+            state.status
+        )));
+    }
+    freezer(&state)?.freeze()?;
+    state.status = Status::Paused;
+    state.save()?;
+    log::info!("Paused container {id}");
+    Ok(())
+}
+
+// Every container gets a cgroup for its device rules, so only one started
+// with --no-device-cgroup and no resource limits lacks one.
+pub fn freezer(state: &ContainerState) -> ContainerResult<Freezer> {
+    let path = state.cgroup_path.as_deref().ok_or_else(|| {
+        ContainerError::invalid_configuration(format!(
+            "Container {} cannot be paused: it has no cgroup to freeze, as it was started \
+             with --no-device-cgroup and no resource limits",
+            state.id
+        ))
+    })?;
+    Freezer::for_cgroup(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::ContainerConfig;
+    use std::path::PathBuf;
+
+    #[test]
+    fn container_without_cgroup_says_why() {
+        let config = ContainerConfig::new(PathBuf::from("/rootfs"), "sh".into());
+        let state = ContainerState::new("web", &config);
+        let error = freezer(&state).unwrap_err().to_string();
+        assert!(error.contains("web cannot be paused"), "{error}");
+        assert!(error.contains("--no-device-cgroup"), "{error}");
+    }
+}
@@ -0,0 +1,19 @@
+use crate::commands::create::{REQUEST_START, send_request};
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::{ContainerState, Status};
+use std::time::Duration;
+
+pub fn run(id: &str) -> ContainerResult<()> {
+    let state = ContainerState::load(id)?;
+    if state.status != Status::Created {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Container {id} is {}, only created containers can be started",
+            state.status
+        )));
+    }
+    send_request(&state.id, REQUEST_START)?;
+    ContainerState::wait_for(id, Some(Duration::from_secs(5)), |state| {
+        state.status != Status::Created
+    })?;
+    Ok(())
+}
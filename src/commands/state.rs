@@ -0,0 +1,10 @@
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::ContainerState;
+
+pub fn run(id: &str) -> ContainerResult<()> {
+    let state = ContainerState::load(id)?;
+    let json = serde_json::to_string_pretty(&state)
+        .map_err(|e| ContainerError::initialization(format!("Failed to serialize state: {e}")))?;
+    println!("{json}");
+    Ok(())
+}
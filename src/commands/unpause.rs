@@ -0,0 +1,24 @@
+use crate::commands::pause::freezer;
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::{ContainerState, Status};
+
+pub fn run(id: &str) -> ContainerResult<()> {
+    let state = ContainerState::load(id)?;
+    if state.status != Status::Paused {
+        return Err(ContainerError::invalid_configuration(format!(
+            "Container {id} is {}, not paused",
+            state.status
+        )));
+    }
+    thaw(state)?;
+    log::info!("Resumed container {id}");
+    Ok(())
+}
+
+// Also used by kill and delete: a frozen container never acts on signals.
+pub fn thaw(mut state: ContainerState) -> ContainerResult<ContainerState> {
+    freezer(&state)?.thaw()?;
+    state.status = Status::Running;
+    state.save()?;
+    Ok(state)
+}
@@ -0,0 +1,12 @@
+use crate::error::{ContainerError, ContainerResult};
+use crate::state::{ContainerState, Status};
+
+// Returns the container's exit code once its supervisor has recorded it.
+pub fn run(id: &str) -> ContainerResult<i32> {
+    let state = ContainerState::wait_for(id, None, |state| state.status == Status::Stopped)?;
+    state.exit_code.ok_or_else(|| {
+        ContainerError::process_execution(format!(
+            "Container {id} stopped without recording an exit code"
+        ))
+    })
+}
@@ -0,0 +1,170 @@
+use crate::cli::ContainerConfig;
+use crate::error::{ContainerError, ContainerResult};
+use crate::filesystem::{CopySpec, TmpfsMount};
+use crate::network::NetworkMode;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// A container definition for `--config FILE`, in TOML. Keys mirror the
+// command line; an option given on the command line wins over the file.
+// Unknown keys are errors so a typo never silently does nothing.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    // Relative paths are taken from the directory holding the file.
+    rootfs: Option<PathBuf>,
+    command: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    hostname: Option<String>,
+    workdir: Option<PathBuf>,
+    network: Option<String>,
+    #[serde(default)]
+    tmpfs: Vec<String>,
+    #[serde(default)]
+    copy: Vec<String>,
+    #[serde(default)]
+    namespaces: NamespacesSection,
+    #[serde(default)]
+    resources: ResourcesSection,
+}
+
+// Whether the container gets its own namespace of each kind; all default to
+// true, as on the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NamespacesSection {
+    pid: Option<bool>,
+    net: Option<bool>,
+    ipc: Option<bool>,
+    uts: Option<bool>,
+    cgroup: Option<bool>,
+    mount: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResourcesSection {
+    memory_mb: Option<u64>,
+    memory_high_mb: Option<u64>,
+    cpu_weight: Option<u64>,
+    cpu_quota: Option<u64>,
+    cpu_period: Option<u64>,
+    pids_limit: Option<u64>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> ContainerResult<Self> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            ContainerError::invalid_configuration(format!("Failed to read {}: {e}", path.display()))
+        })?;
+        toml::from_str(&content).map_err(|e| {
+            ContainerError::invalid_configuration(format!(
+                "Invalid {}: {}",
+                path.display(),
+                e.to_string().trim_end()
+            ))
+        })
+    }
+
+    // Fills `config` from the file wherever `explicit` says the command line
+    // left an option at its default.
+    pub fn apply(
+        self,
+        path: &Path,
+        config: &mut ContainerConfig,
+        explicit: impl Fn(&str) -> bool,
+    ) -> ContainerResult<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let invalid = |key: &str, e: String| {
+            ContainerError::invalid_configuration(format!("{}: {key}: {e}", path.display()))
+        };
+        if let Some(rootfs) = self.rootfs
+            && !explicit("rootfs")
+        {
+            config.rootfs = dir.join(rootfs);
+        }
+        if let Some(command) = self.command
+            && !explicit("command")
+        {
+            config.command = OsString::from(command);
+            config.args = self.args.into_iter().map(OsString::from).collect();
+        }
+        // Before --env, which is appended later and so wins.
+        config.env.extend(self.env);
+        if let Some(hostname) = self.hostname
+            && !explicit("hostname")
+        {
+            config.hostname = Some(hostname);
+        }
+        if let Some(workdir) = self.workdir
+            && !explicit("workdir")
+        {
+            config.workdir = Some(workdir);
+        }
+        if let Some(network) = self.network
+            && !explicit("network")
+        {
+            config.network.mode =
+                NetworkMode::from_str(&network).map_err(|e| invalid("network", e))?;
+        }
+        if !explicit("tmpfs") {
+            for tmpfs in &self.tmpfs {
+                let mount = TmpfsMount::from_str(tmpfs).map_err(|e| invalid("tmpfs", e))?;
+                config.tmpfs.push(mount);
+            }
+        }
+        if !explicit("copy") {
+            for copy in &self.copy {
+                let mut spec = CopySpec::from_str(copy).map_err(|e| invalid("copy", e))?;
+                if let Some((source, _)) = copy.rsplit_once(':')
+                    && Path::new(source).is_relative()
+                {
+                    spec.source = std::path::absolute(dir.join(source))?;
+                }
+                config.copies.push(spec);
+            }
+        }
+        let namespaces = [
+            (self.namespaces.pid, "no-pid-ns", &mut config.no_pid_ns),
+            (self.namespaces.net, "no-net-ns", &mut config.no_net_ns),
+            (self.namespaces.ipc, "no-ipc-ns", &mut config.no_ipc_ns),
+            (self.namespaces.uts, "no-uts-ns", &mut config.no_uts_ns),
+            (
+                self.namespaces.cgroup,
+                "no-cgroup-ns",
+                &mut config.no_cgroup_ns,
+            ),
+            (
+                self.namespaces.mount,
+                "no-mount-ns",
+                &mut config.no_mount_ns,
+            ),
+        ];
+        for (isolate, flag, shared) in namespaces {
+            if let Some(isolate) = isolate
+                && !explicit(flag)
+            {
+                *shared = !isolate;
+            }
+        }
+        let resources = self.resources;
+        if resources.memory_mb.is_some() && !explicit("memory") {
+            config.memory_limit = resources.memory_mb.map(|mb| mb * 1024 * 1024);
+        }
+        if resources.memory_high_mb.is_some() && !explicit("memory-high") {
+            config.memory_high_mb = resources.memory_high_mb;
+        }
+        config.cpu_weight = resources.cpu_weight.or(config.cpu_weight);
+        config.cpu_quota = resources.cpu_quota.or(config.cpu_quota);
+        config.cpu_period = resources.cpu_period.or(config.cpu_period);
+        config.pids_limit = resources.pids_limit.or(config.pids_limit);
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use crate::error::{ContainerError, ContainerResult};
+use crate::log_file::{LogFile, LogStream};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use nix::sys::socket::{
+    AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType, recvmsg,
+    sendmsg, socketpair,
+};
+use nix::sys::termios::{SetArg, Termios, cfmakeraw, tcgetattr, tcsetattr};
+use nix::unistd::isatty;
+use std::fs::File;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread::JoinHandle;
+
+// Detached containers do not relay their PTY themselves: the init passes the
+// master to its supervisor over a console socket, and the supervisor copies
+// the output to the log file and serves `attach` sessions.
+pub const ATTACH_SOCKET: &str = "attach.sock";
+
+// Frames sent by an attach client: a tag byte, a big-endian u16 length and
+// the payload. A resize carries rows and columns as big-endian u16s.
+pub const FRAME_INPUT: u8 = b'i';
+pub const FRAME_RESIZE: u8 = b'r';
+
+const POLL_INTERVAL: u16 = 200;
+
+pub fn console_socket_pair() -> ContainerResult<(OwnedFd, OwnedFd)> {
+    socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )
+    .map_err(|e| ContainerError::initialization(format!("Failed to create console socket: {e}")))
+}
+
+pub fn send_master(socket: RawFd, master: &OwnedFd) -> ContainerResult<()> {
+    let fds = [master.as_raw_fd()];
+    sendmsg::<()>(
+        socket,
+        &[IoSlice::new(b"m")],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )
+    .map_err(|e| ContainerError::process_execution(format!("Failed to send PTY master: {e}")))?;
+    Ok(())
+}
+
+// Blocks until the init sends the master; None if it exits without doing so.
+fn receive_master(socket: &OwnedFd) -> Option<OwnedFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut byte)];
+    let mut space = nix::cmsg_space!(RawFd);
+    let msg = recvmsg::<()>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut space),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )
+    .ok()?;
+    msg.cmsgs().ok()?.find_map(|cmsg| match cmsg {
+        ControlMessageOwned::ScmRights(fds) => {
+            fds.first().map(|fd| unsafe { OwnedFd::from_raw_fd(*fd) })
+        }
+        _ => None,
+    })
+}
+
+pub fn encode_frame(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 3);
+    frame.push(tag);
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn set_window_size(master: &File, rows: u16, cols: u16) {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) } != 0 {
+        log::debug!(
+            "Failed to resize console to {cols}x{rows}: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+// Puts the terminal in raw mode so keys reach the container unprocessed, and
+// restores it when dropped.
+pub struct RawTerminal {
+    original: Termios,
+}
+
+impl RawTerminal {
+    pub fn enable() -> Option<Self> {
+        let stdin = io::stdin();
+        if !isatty(stdin.as_fd()).unwrap_or(false) {
+            return None;
+        }
+        let original = tcgetattr(stdin.as_fd()).ok()?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &raw).ok()?;
+        Some(Self { original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = tcsetattr(io::stdin().as_fd(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+static WINDOW_MASTER: AtomicI32 = AtomicI32::new(-1);
+
+// Only ioctls on fds, which is async-signal-safe.
+extern "C" fn copy_window_size(_: libc::c_int) {
+    let master = WINDOW_MASTER.load(Ordering::SeqCst);
+    if master < 0 {
+        return;
+    }
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    unsafe {
+        if libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut size) == 0 {
+            libc::ioctl(master, libc::TIOCSWINSZ, &size);
+        }
+    }
+}
+
+// Keeps a PTY the size of the terminal we were started from, re-copying on
+// every SIGWINCH. Resizing the PTY makes the kernel send SIGWINCH to its
+// foreground process group, so the workload needs no signal from us.
+pub struct WindowSizeFollower {
+    // Our own copy, so the fd the handler uses stays open until we are dropped.
+    _master: OwnedFd,
+}
+
+impl WindowSizeFollower {
+    // None when stdin is not a terminal.
+    pub fn start(master: &OwnedFd) -> Option<Self> {
+        if !isatty(io::stdin().as_fd()).unwrap_or(false) {
+            return None;
+        }
+        let master = master.try_clone().ok()?;
+        WINDOW_MASTER.store(master.as_raw_fd(), Ordering::SeqCst);
+        copy_window_size(libc::SIGWINCH);
+        let action = SigAction::new(
+            SigHandler::Handler(copy_window_size),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        if let Err(e) = unsafe { sigaction(Signal::SIGWINCH, &action) } {
+            log::debug!("Cannot follow terminal resizes: {e}");
+        }
+        Some(Self { _master: master })
+    }
+}
+
+impl Drop for WindowSizeFollower {
+    fn drop(&mut self) {
+        // Before our copy of the master is closed and its number reused.
+        WINDOW_MASTER.store(-1, Ordering::SeqCst);
+    }
+}
+
+pub struct ConsoleServer {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl ConsoleServer {
+    // `listener` must be non-blocking.
+    pub fn spawn(socket: OwnedFd, listener: UnixListener, log: Option<LogFile>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                if let Some(master) = receive_master(&socket) {
+                    serve(File::from(master), &listener, log, &stop);
+                }
+            })
+        };
+        Self { stop, thread }
+    }
+
+    // Waits for the console to drain; the relay ends on its own once the
+    // container's side of the PTY is closed.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.thread.join();
+    }
+}
+
+struct Session {
+    stream: UnixStream,
+    pending: Vec<u8>,
+}
+
+fn serve(mut master: File, listener: &UnixListener, mut log: Option<LogFile>, stop: &AtomicBool) {
+    let mut session: Option<Session> = None;
+    let mut buffer = [0u8; 8192];
+    let timeout = PollTimeout::from(POLL_INTERVAL);
+    loop {
+        let mut fds = vec![
+            PollFd::new(master.as_fd(), PollFlags::POLLIN),
+            PollFd::new(listener.as_fd(), PollFlags::POLLIN),
+        ];
+        if let Some(session) = &session {
+            fds.push(PollFd::new(session.stream.as_fd(), PollFlags::POLLIN));
+        }
+        match poll(&mut fds, timeout) {
+            Ok(0) if stop.load(Ordering::SeqCst) => break,
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+        let ready: Vec<bool> = fds.iter().map(|fd| fd.any().unwrap_or(false)).collect();
+        drop(fds);
+
+        if ready[0] {
+            // EIO once every slave fd is closed, i.e. the container is gone.
+            let n = match master.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if let Some(log) = &mut log {
+                log.write(LogStream::Stdout, &buffer[..n]);
+            }
+            if let Some(active) = &mut session
+                && active.stream.write_all(&buffer[..n]).is_err()
+            {
+                log::info!("Attach session closed");
+                session = None;
+            }
+        }
+        if ready[1]
+            && let Ok((mut stream, _)) = listener.accept()
+        {
+            if session.is_some() {
+                let _ = stream.write_all(b"another session is already attached\r\n");
+            } else if stream.set_nonblocking(false).is_ok() {
+                log::info!("Attach session opened");
+                session = Some(Session {
+                    stream,
+                    pending: Vec::new(),
+                });
+            }
+        }
+        if ready.get(2).copied().unwrap_or(false)
+            && let Some(active) = &mut session
+            && !handle_client(active, &mut master)
+        {
+            log::info!("Attach session closed");
+            session = None;
+        }
+    }
+    if let Some(log) = &mut log {
+        log.finish();
+    }
+}
+
+// Applies every complete frame the client has sent. False once the client
+// has gone away.
+fn handle_client(session: &mut Session, master: &mut File) -> bool {
+    let mut buffer = [0u8; 4096];
+    match session.stream.read(&mut buffer) {
+        Ok(0) | Err(_) => return false,
+        Ok(n) => session.pending.extend_from_slice(&buffer[..n]),
+    }
+    while session.pending.len() >= 3 {
+        let len = u16::from_be_bytes([session.pending[1], session.pending[2]]) as usize;
+        if session.pending.len() < 3 + len {
+            break;
+        }
+        let frame: Vec<u8> = session.pending.drain(..3 + len).collect();
+        let payload = &frame[3..];
+        match frame[0] {
+            FRAME_INPUT => {
+                if master.write_all(payload).is_err() {
+                    return false;
+                }
+            }
+            FRAME_RESIZE if payload.len() == 4 => {
+                let rows = u16::from_be_bytes([payload[0], payload[1]]);
+                let cols = u16::from_be_bytes([payload[2], payload[3]]);
+                set_window_size(master, rows, cols);
+            }
+            tag => log::debug!("Ignoring unknown attach frame {tag:#x}"),
+        }
+    }
+    true
+}
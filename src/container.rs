@@ -0,0 +1,899 @@
+use crate::archive::ExtractedRootfs;
+use crate::cgroup::{CgroupManager, UsageSummary};
+use crate::cli::ContainerConfig;
+use crate::error::{ContainerError, ContainerResult};
+use crate::events::{Event, EventSink, FdEventSink, NullEventSink};
+use crate::filesystem::{
+    FilesystemConfig, FilesystemManager, HostsConfig, OverlayDirs, Propagation, ResolvConf,
+};
+use crate::health::{HealthListener, HealthMonitor};
+use crate::log_file::LogFile;
+use crate::monitor::{PIDS_WATCH_INTERVAL, PidsWatch, StatsMonitor};
+use crate::namespace::{NamespaceConfig, NamespaceManager, NamespaceType};
+use crate::network::{NetworkManager, NetworkMode};
+use crate::preflight;
+use crate::process::{ProcessManager, ProcessOptions, WaitOutcome};
+use crate::publish::{self, PortForwarder};
+use crate::seccomp::SeccompProgram;
+use crate::signals;
+use crate::state::generate_id;
+use crate::sync::SyncPipe;
+use crate::sysctl::SysctlManager;
+use crate::watchdog::Watchdog;
+use caps::{CapSet, Capability};
+use log::{debug, error, info, warn};
+use nix::sys::signal::{Signal, kill};
+use nix::sys::stat::{Mode, umask};
+use nix::sys::termios::{SetArg, Termios, tcgetattr, tcsetattr};
+use nix::unistd::{Gid, Pid, Uid, gethostname, getpid};
+use std::ffi::OsString;
+use std::io;
+use std::os::fd::{AsFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// High-level entry point for embedding the runtime:
+// `Container::new(rootfs).hostname("web").memory_mb(256).command("/bin/sh").spawn()`.
+#[derive(Debug)]
+pub struct Container {
+    config: ContainerConfig,
+    defer_start: bool,
+    console_socket: Option<RawFd>,
+    health_listener: Option<HealthListener>,
+    event_sink: Option<Box<dyn EventSink>>,
+}
+
+impl Container {
+    pub fn new(rootfs: impl Into<PathBuf>) -> Self {
+        Self::from_config(ContainerConfig::new(
+            rootfs.into(),
+            OsString::from("/bin/sh"),
+        ))
+    }
+
+    pub fn from_config(config: ContainerConfig) -> Self {
+        Self {
+            config,
+            defer_start: false,
+            console_socket: None,
+            health_listener: None,
+            event_sink: None,
+        }
+    }
+
+    // Park the init once its environment is set up; it only execs the command
+    // after ContainerHandle::start.
+    pub fn defer_start(mut self, defer: bool) -> Self {
+        self.defer_start = defer;
+        self
+    }
+
+    // Have the init send its PTY master over this socket (see console.rs)
+    // rather than copying the output to its own stdout.
+    pub fn console_socket(mut self, socket: RawFd) -> Self {
+        self.console_socket = Some(socket);
+        self
+    }
+
+    // Called with every change of the --health-cmd status.
+    pub fn health_listener(mut self, listener: HealthListener) -> Self {
+        self.health_listener = Some(listener);
+        self
+    }
+
+    // Receives the container's lifecycle events, in place of --events-fd.
+    pub fn with_event_sink(mut self, sink: impl EventSink + 'static) -> Self {
+        self.event_sink = Some(Box::new(sink));
+        self
+    }
+
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.config.hostname = Some(hostname.into());
+        self
+    }
+
+    pub fn memory_mb(mut self, mb: u64) -> Self {
+        self.config.memory_limit = Some(mb * 1024 * 1024);
+        self
+    }
+
+    pub fn command(mut self, command: impl Into<OsString>) -> Self {
+        self.config.command = command.into();
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.config.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn config(&self) -> &ContainerConfig {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut ContainerConfig {
+        &mut self.config
+    }
+
+    // Sets up the host side of the container and starts its init. Returns once
+    // the init has been released towards exec; the forked init itself never
+    // returns from here.
+    pub fn spawn(self) -> ContainerResult<ContainerHandle> {
+        let mut config = self.config;
+        if !Uid::current().is_root() {
+            error!("Root privileges required for container operations");
+            return Err(ContainerError::RootRequired);
+        }
+
+        let ns_config = plan(&mut config)?;
+        // Taken before the init can switch it to raw mode.
+        let terminal = tcgetattr(io::stdin().as_fd()).ok();
+        let id = match &config.id {
+            Some(id) => id.clone(),
+            None => generate_id()?,
+        };
+        // What the container is called on the host (cgroup, overlay layers)
+        // and, unless --hostname says otherwise, inside it.
+        let name = config.name.clone().unwrap_or(id);
+        if config.hostname.is_none()
+            && ns_config.isolate_uts
+            && NamespaceManager::validate_hostname(&name, true).is_ok()
+        {
+            config.hostname = Some(name.clone());
+        }
+        // Compiled up front so a bad profile fails before anything is set up.
+        let seccomp = SeccompProgram::from_mode(&config.seccomp, &config.capabilities)?;
+        // Opened here so a bad path fails the start. With a console socket
+        // the supervisor logs PTY output itself; the init only uses this
+        // when it has to fall back to pipes.
+        let log_file = config
+            .log_file
+            .as_deref()
+            .map(|path| LogFile::open(path, config.log_max_size, config.log_driver))
+            .transpose()?;
+        if config.log_max_size.is_some() && config.log_file.is_none() {
+            warn!("--log-max-size has no effect without --log-file");
+        }
+        let mut events: Box<dyn EventSink> = match (self.event_sink, config.events_fd) {
+            (Some(sink), _) => sink,
+            (None, Some(fd)) => Box::new(FdEventSink::from_raw_fd(fd)?),
+            (None, None) => Box::new(NullEventSink),
+        };
+        if let (Some(adj), Some(mem)) = (config.oom_score_adj, config.memory_limit)
+            && adj < 0
+        {
+            warn!(
+                "oom_score_adj {adj} only protects against the host OOM killer; \
+                 the {mem}-byte cgroup memory limit will still OOM-kill container processes"
+            );
+        }
+        let name = format!("container-{name}");
+        if !config.skip_preflight {
+            preflight::check_kernel(&config, &ns_config, &name)?;
+        }
+        let extracted_rootfs = match (&config.rootfs_tar, &config.image) {
+            (Some(archive), _) => Some(ExtractedRootfs::extract(
+                archive,
+                &config.state_dir,
+                &name,
+                config.rootfs_keep,
+            )?),
+            (None, Some(image)) => {
+                Some(image.extract(&config.state_dir, &name, config.rootfs_keep)?)
+            }
+            (None, None) => None,
+        };
+        if let Some(extracted) = &extracted_rootfs {
+            config.rootfs = extracted.path().to_path_buf();
+        }
+        if !config.skip_preflight && config.rootfs.is_dir() {
+            preflight::check_rootfs(&config)?;
+        }
+        let overlay = if config.overlay {
+            Some(OverlayDirs::prepare(
+                &config.rootfs,
+                &config.state_dir,
+                &name,
+                config.rm,
+            )?)
+        } else {
+            None
+        };
+        let cgroup_manager = if config.needs_cgroup() {
+            let manager = CgroupManager::new(config.cgroup_config(name.clone())?)?;
+            manager.setup()?;
+            Some(manager)
+        } else {
+            info!(
+                "No resource limits or device rules, skipping cgroup setup; the container cannot be paused"
+            );
+            None
+        };
+        // Only the init is placed in new namespaces: the runtime itself stays in the
+        // host mount and network namespaces so it can still reach the cgroupfs
+        // after the container exits.
+        let mut sync = InitSync {
+            cgroup_joined: SyncPipe::new()?,
+            namespaces_ready: SyncPipe::new()?,
+            host_setup_done: SyncPipe::new()?,
+            command_started: SyncPipe::new()?,
+            start_gate: if self.defer_start {
+                Some(SyncPipe::new()?)
+            } else {
+                None
+            },
+        };
+        let mut joins = NamespaceManager::open_namespaces(&config.join_ns)?;
+        let host_pid_ns = match joins
+            .iter()
+            .position(|(kind, _)| *kind == NamespaceType::Pid)
+        {
+            Some(index) => Some(NamespaceManager::join_pid_namespace(
+                &joins.remove(index).1,
+            )?),
+            None => None,
+        };
+        let host_time = config
+            .time_offsets
+            .map(NamespaceManager::enter_time_namespace)
+            .transpose()?;
+        let Some(child) = NamespaceManager::enter_pid_namespace(ns_config.for_clone())? else {
+            // The container init must not tear down the cgroup it is running in;
+            // cleanup is the outer runtime's job once the init has exited. The same
+            // goes for the overlay layers it is about to mount.
+            std::mem::forget(cgroup_manager);
+            let overlay = overlay.map(std::mem::ManuallyDrop::new);
+            std::mem::forget(extracted_rootfs);
+            // Joined before run_init unshares the namespaces it creates.
+            let result = sync
+                .cgroup_joined
+                .wait()
+                .and_then(|()| NamespaceManager::join_namespaces(&joins))
+                .and_then(|()| {
+                    run_init(
+                        &config,
+                        &ns_config,
+                        overlay.as_deref(),
+                        seccomp,
+                        InitOutput {
+                            console_socket: self.console_socket,
+                            log_file,
+                        },
+                        &mut sync,
+                    )
+                });
+            // This is a forked copy of the caller: it must never return into
+            // their code. Its exit code is the workload's, for the outer
+            // runtime to pass on.
+            let code = match result {
+                Ok(outcome) => outcome.exit_code(),
+                Err(e) => {
+                    error!("Container runtime error: {e}");
+                    e.exit_code()
+                }
+            };
+            std::process::exit(code);
+        };
+        if let Some(host_time) = host_time {
+            NamespaceManager::restore_time_namespace(host_time);
+        }
+        if let Some(host_pid_ns) = host_pid_ns {
+            NamespaceManager::restore_pid_namespace(host_pid_ns);
+        }
+        drop(joins);
+        // The init is moved in before it unshares its cgroup namespace, which
+        // is rooted at the cgroup it is in at that point.
+        if let Some(manager) = &cgroup_manager
+            && let Err(e) = manager.add_process(child.as_raw())
+        {
+            let _ = kill(child, Signal::SIGKILL);
+            let _ = NamespaceManager::wait_for_container(child);
+            return Err(e);
+        }
+        sync.cgroup_joined.notify()?;
+        // systemd starts the scope with the runtime in it, and only the
+        // container belongs there, so freezing it never stops the runtime.
+        if let Some(manager) = &cgroup_manager
+            && let Err(e) = manager.evacuate_self()
+        {
+            warn!("Failed to leave the container cgroup: {e}");
+        }
+        events.emit(Event::Created {
+            pid: child.as_raw(),
+        });
+        let host_side = match &config.pid_file {
+            Some(path) => write_pid_file(path, child),
+            None => Ok(()),
+        }
+        .and_then(|()| {
+            prepare_host_side(
+                child,
+                &config,
+                &ns_config,
+                cgroup_manager.as_ref(),
+                &mut sync.namespaces_ready,
+            )
+        });
+        let (network, forwarder) = match host_side {
+            Ok(host_side) => host_side,
+            Err(e) => {
+                let _ = kill(child, Signal::SIGKILL);
+                let _ = NamespaceManager::wait_for_container(child);
+                if let Some(path) = &config.pid_file {
+                    let _ = std::fs::remove_file(path);
+                }
+                return Err(e);
+            }
+        };
+        sync.host_setup_done.notify()?;
+        let monitor = match (&cgroup_manager, config.stats_interval) {
+            (Some(manager), Some(seconds)) => Some(StatsMonitor::spawn(
+                manager.stats_reader(),
+                Duration::from_secs(seconds),
+                config.stats_format_json,
+            )),
+            _ => None,
+        };
+        let pids_watch = match &cgroup_manager {
+            Some(manager)
+                if config.pids_watch
+                    || (config.stats_interval.is_some() && config.pids_limit.is_some()) =>
+            {
+                Some(PidsWatch::spawn(
+                    manager.stats_reader(),
+                    config
+                        .stats_interval
+                        .map_or(PIDS_WATCH_INTERVAL, Duration::from_secs),
+                ))
+            }
+            _ => None,
+        };
+        let mut handle = ContainerHandle {
+            pid: child,
+            start_gate: sync.start_gate,
+            command_started: Some(sync.command_started),
+            watchdog: None,
+            health: None,
+            health_listener: self.health_listener,
+            terminal,
+            config,
+            events,
+            monitor,
+            pids_watch,
+            forwarder,
+            network,
+            overlay,
+            extracted_rootfs,
+            cgroup_manager,
+        };
+        if handle.start_gate.is_none() {
+            handle.wait_command_started();
+            handle.arm_watchdog();
+            handle.start_health_monitor()?;
+        }
+        Ok(handle)
+    }
+}
+
+// A running container. Fields are dropped in declaration order, which tears
+// the host side down from the outside in.
+pub struct ContainerHandle {
+    pid: Pid,
+    start_gate: Option<SyncPipe>,
+    command_started: Option<SyncPipe>,
+    watchdog: Option<Watchdog>,
+    health: Option<HealthMonitor>,
+    health_listener: Option<HealthListener>,
+    // The init puts our terminal in raw mode; if it is killed before it can
+    // undo that, we do.
+    terminal: Option<Termios>,
+    config: ContainerConfig,
+    events: Box<dyn EventSink>,
+    monitor: Option<StatsMonitor>,
+    pids_watch: Option<PidsWatch>,
+    forwarder: Option<PortForwarder>,
+    network: Option<NetworkManager>,
+    overlay: Option<OverlayDirs>,
+    extracted_rootfs: Option<ExtractedRootfs>,
+    cgroup_manager: Option<CgroupManager>,
+}
+
+impl ContainerHandle {
+    // Host PID of the container init.
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn cgroup_path(&self) -> Option<&Path> {
+        self.cgroup_manager.as_ref().map(CgroupManager::path)
+    }
+
+    // Lets a container spawned with defer_start exec its command; a no-op
+    // otherwise.
+    pub fn start(&mut self) -> ContainerResult<()> {
+        match self.start_gate.take() {
+            Some(mut gate) => {
+                gate.notify()?;
+                self.wait_command_started();
+                self.arm_watchdog();
+                self.start_health_monitor()
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Running is only reported once the init is past its setup. An init that
+    // fails before shows up in wait() instead.
+    fn wait_command_started(&mut self) {
+        let Some(mut started) = self.command_started.take() else {
+            return;
+        };
+        match started.wait() {
+            Ok(()) => self.events.emit(Event::Running {
+                pid: self.pid.as_raw(),
+            }),
+            Err(e) => debug!("Container init did not start the command: {e}"),
+        }
+    }
+
+    // --timeout counts from the command's start, not from when the container
+    // was created.
+    fn arm_watchdog(&mut self) {
+        if let Some(seconds) = self.config.timeout {
+            self.watchdog = Some(Watchdog::spawn(
+                self.pid,
+                Duration::from_secs(seconds),
+                Duration::from_secs(self.config.stop_grace),
+            ));
+        }
+    }
+
+    // Like --timeout, the health checks and their start period only begin
+    // once the command runs.
+    fn start_health_monitor(&mut self) -> ContainerResult<()> {
+        if let Some(health) = &self.config.health {
+            let env = ProcessManager::build_environment(&self.config.env)?;
+            self.health = Some(HealthMonitor::spawn(
+                self.pid,
+                health.clone(),
+                env,
+                self.health_listener.take(),
+            )?);
+        }
+        Ok(())
+    }
+
+    pub fn kill(&self, signal: Signal) -> ContainerResult<()> {
+        kill(self.pid, signal).map_err(|e| {
+            ContainerError::process_execution(format!(
+                "Failed to send {signal} to container {}: {e}",
+                self.pid
+            ))
+        })
+    }
+
+    // Relays signals sent to the runtime (e.g. `kill -HUP`) to the init,
+    // which passes them on to the workload, until wait() returns.
+    pub fn forward_signals(&self) {
+        signals::set_target(Some(self.pid));
+        signals::install_forwarding(true);
+    }
+
+    // Waits for the init to exit and releases the host-side resources.
+    pub fn wait(mut self) -> ContainerResult<WaitOutcome> {
+        let mut outcome = NamespaceManager::wait_for_container(self.pid);
+        signals::set_target(None);
+        if let Some(health) = self.health.take() {
+            health.stop();
+        }
+        if let Some(watchdog) = self.watchdog.take()
+            && watchdog.stop()
+            && outcome.is_ok()
+        {
+            outcome = Ok(WaitOutcome::TimedOut);
+        }
+        if let Some(terminal) = &self.terminal {
+            let _ = tcsetattr(io::stdin().as_fd(), SetArg::TCSANOW, terminal);
+        }
+        if let Some(path) = &self.config.pid_file {
+            let _ = std::fs::remove_file(path);
+        }
+        if let Some(monitor) = self.monitor.take() {
+            monitor.stop();
+        }
+        if let Some(pids_watch) = self.pids_watch.take() {
+            pids_watch.stop();
+        }
+        let outcome = outcome?;
+        let exit_code = outcome.exit_code();
+        // An OOM kill is reported before the exit it caused.
+        let mut oom = None;
+        if let Some(manager) = &self.cgroup_manager {
+            let usage = manager.usage_summary();
+            info!("Resource usage: {usage}");
+            // The workload's outcome matters more than the stats file.
+            if let Some(target) = &self.config.stats_json
+                && let Err(e) = write_stats_json(&usage, target)
+            {
+                warn!("Failed to write usage summary to {}: {e}", target.display());
+            }
+            if exit_code != 0
+                && let Err(e) = manager.check_oom_events()
+            {
+                if let ContainerError::OutOfMemory { kills, limit } = e {
+                    self.events.emit(Event::Oom { kills, limit });
+                }
+                oom = Some(e);
+            }
+        }
+        self.events.emit(Event::Exited { code: exit_code });
+        if let Some(e) = oom {
+            return Err(e);
+        }
+        drop(self.forwarder.take());
+        drop(self.network.take());
+        drop(self.overlay.take());
+        drop(self.extracted_rootfs.take());
+        Ok(outcome)
+    }
+}
+
+// Handshakes between the runtime and the container init, in the order they
+// happen.
+struct InitSync {
+    // The runtime has moved the init into the container cgroup.
+    cgroup_joined: SyncPipe,
+    namespaces_ready: SyncPipe,
+    host_setup_done: SyncPipe,
+    start_gate: Option<SyncPipe>,
+    // The init is past its setup and about to run the command.
+    command_started: SyncPipe,
+}
+
+// Where the init sends the workload's console output.
+struct InitOutput {
+    console_socket: Option<RawFd>,
+    log_file: Option<LogFile>,
+}
+
+// Everything the container init does between clone and exec.
+fn run_init(
+    config: &ContainerConfig,
+    ns_config: &NamespaceConfig,
+    overlay: Option<&OverlayDirs>,
+    seccomp: Option<SeccompProgram>,
+    output: InitOutput,
+    sync: &mut InitSync,
+) -> ContainerResult<WaitOutcome> {
+    NamespaceManager::unshare_namespaces(ns_config.for_unshare())?;
+    sync.namespaces_ready.notify()?;
+    sync.host_setup_done.wait()?;
+    info!("Running as PID 1 in container (host PID: {})", getpid());
+    // --hostname without a UTS namespace of our own is refused up front.
+    let hostname = if ns_config.isolate_uts {
+        let hostname = config.hostname.as_deref().unwrap_or("rust-container");
+        NamespaceManager::set_hostname(hostname)?;
+        Some(hostname.to_string())
+    } else {
+        None
+    };
+    let rootfs_path = config.rootfs.as_path();
+    let fs_config = filesystem_config(config, ns_config, hostname);
+    // /proc/sys is read-only inside the container once its filesystem is set
+    // up. Namespaced keys resolve against this process's namespaces, so the
+    // host's /proc reaches the container's values just as well.
+    SysctlManager::apply(&config.sysctls)?;
+    FilesystemManager::setup_container_filesystem(rootfs_path, &fs_config, overlay)?;
+    // Checked in the container's final root, with the overlay and copies in it.
+    let workdir = config
+        .workdir
+        .as_deref()
+        .map(|dir| FilesystemManager::validate_workdir(Path::new("/"), dir))
+        .transpose()?;
+    if let Some(gate) = &mut sync.start_gate {
+        info!("Container created, waiting for start");
+        gate.wait()?;
+    }
+    sync.command_started.notify()?;
+    info!("Container environment setup complete, executing command...");
+    // Here rather than in the workload alone so the init, which shows in
+    // /proc/1/status, has it too; the mounts above are done by now.
+    if let Some(mask) = config.umask {
+        umask(Mode::from_bits_truncate(mask));
+        debug!("Set umask to {mask:04o}");
+    }
+
+    // Whatever the UTS namespace now answers, the host's own with --uts host;
+    // an explicit -e HOSTNAME still wins.
+    let mut env = Vec::new();
+    if let Ok(hostname) = gethostname()
+        && let Ok(hostname) = hostname.into_string()
+    {
+        env.push(("HOSTNAME".to_string(), hostname));
+    }
+    env.extend(config.env.iter().cloned());
+    let process_options = ProcessOptions {
+        oom_score_adj: config.oom_score_adj,
+        workdir,
+        env,
+        setsid: config.setsid.unwrap_or(config.tty),
+        init: config.init,
+        tty: config.tty,
+        interactive: config.interactive,
+        output_buffering: config.output_buffering,
+        capabilities: config.capabilities.clone(),
+        rlimit_nofile: config.rlimit_nofile,
+        ulimits: config.ulimits.clone(),
+        nice: config.nice,
+        ionice: config.ionice,
+        sched: config.sched,
+        seccomp,
+        no_new_privileges: config.no_new_privileges,
+        groups: config.group_add.clone(),
+        console_socket: output.console_socket,
+        log_file: output.log_file,
+    };
+    ProcessManager::execute_container_command(&config.command, &config.args, &process_options)
+}
+
+// Rejects namespace combinations that would make container setup act on the
+// host instead: pivoting the host's root, renaming the host, and so on.
+fn validate_namespace_usage(
+    config: &ContainerConfig,
+    ns_config: &NamespaceConfig,
+) -> ContainerResult<()> {
+    if !ns_config.isolate_mount {
+        if !config.no_pivot {
+            return Err(ContainerError::invalid_configuration(
+                "--no-mount-ns needs --no-pivot: pivot_root would replace the host's root",
+            ));
+        }
+        // Each of these mounts something, which would land on the host.
+        let mounting = [
+            (config.overlay, "--overlay"),
+            (config.read_only, "--read-only"),
+            (!config.tmpfs.is_empty(), "--tmpfs"),
+            (!config.mask_paths.is_empty(), "--mask-path"),
+            (!config.readonly_paths.is_empty(), "--readonly-path"),
+            (!config.devices.is_empty(), "--device"),
+            (config.host_proc_sys_ro, "--host-proc-sys-ro"),
+            (config.cgroup_rw, "--cgroup-rw"),
+            (config.keep_old_root, "--keep-old-root"),
+            (!config.extra_hosts.is_empty(), "--add-host"),
+            (config.network.dns.resolv_conf().is_some(), "--dns"),
+            (
+                config.rootfs_propagation != Propagation::Private,
+                "--rootfs-propagation",
+            ),
+        ];
+        if let Some((_, option)) = mounting.iter().find(|(set, _)| *set) {
+            return Err(ContainerError::invalid_configuration(format!(
+                "{option} cannot be used with --no-mount-ns: it would mount on the host"
+            )));
+        }
+    }
+    if config.no_uts_ns && config.hostname.is_some() {
+        return Err(ContainerError::invalid_configuration(
+            "--hostname cannot be used with --uts host: it would rename the host",
+        ));
+    }
+    for (index, join) in config.join_ns.iter().enumerate() {
+        let kind = join.kind;
+        if config.join_ns[..index]
+            .iter()
+            .any(|other| other.kind == kind)
+        {
+            return Err(ContainerError::invalid_configuration(format!(
+                "The {kind} namespace is joined more than once"
+            )));
+        }
+        // Each of these asks for a namespace of the container's own, or for
+        // the host's.
+        let conflict = match kind {
+            NamespaceType::Pid => config.no_pid_ns.then_some("--no-pid-ns"),
+            NamespaceType::Net if config.no_net_ns => Some("--no-net-ns"),
+            NamespaceType::Net => (config.network.mode != NetworkMode::None).then_some("--network"),
+            NamespaceType::Ipc => config.no_ipc_ns.then_some("--no-ipc-ns"),
+            NamespaceType::Uts if config.no_uts_ns => Some("--no-uts-ns"),
+            // The joined namespace's name belongs to whoever created it.
+            NamespaceType::Uts => config.hostname.is_some().then_some("--hostname"),
+            NamespaceType::Cgroup => config.no_cgroup_ns.then_some("--no-cgroup-ns"),
+            NamespaceType::Time => config.time_offsets.is_some().then_some("--time-offset"),
+        };
+        if let Some(option) = conflict {
+            return Err(ContainerError::invalid_configuration(format!(
+                "Cannot both join the {kind} namespace at {} and use {option}",
+                join.path.display()
+            )));
+        }
+    }
+    if !ns_config.isolate_net && config.network.mode == NetworkMode::Bridge {
+        return Err(ContainerError::invalid_configuration(
+            "--network bridge needs a network namespace, drop --no-net-ns",
+        ));
+    }
+    if !config.publish.is_empty() && config.network.mode != NetworkMode::Bridge {
+        return Err(ContainerError::invalid_configuration(
+            "--publish requires --network bridge",
+        ));
+    }
+    publish::validate_mappings(&config.publish)?;
+    for (key, _) in &config.sysctls {
+        let refusal = match SysctlManager::namespace(key) {
+            Some("net") if !ns_config.isolate_net => {
+                "a network namespace: it would change the host's network stack"
+            }
+            Some("ipc") if !ns_config.isolate_ipc => {
+                "an IPC namespace: it would change the host's limits"
+            }
+            Some("uts") if !ns_config.isolate_uts => {
+                "a UTS namespace: it would change the host's names"
+            }
+            _ => continue,
+        };
+        return Err(ContainerError::invalid_configuration(format!(
+            "Refusing to set {key} without {refusal}"
+        )));
+    }
+    Ok(())
+}
+
+// The checks that need nothing set up yet, shared by spawn and --check:
+// resolves the environment and labels, and returns the namespaces the
+// container gets.
+pub fn plan(config: &mut ContainerConfig) -> ContainerResult<NamespaceConfig> {
+    let labels = config.resolve_labels()?;
+    if !labels.is_empty() {
+        debug!("Container labels: {labels:?}");
+    }
+    // Env files are host paths, so they are read before the init leaves
+    // the host's filesystem.
+    config.env = config.resolve_env()?;
+    // A NUL byte cannot reach execve; catch it before anything is set up
+    // rather than in the init.
+    if config.command.is_empty() {
+        return Err(ContainerError::invalid_configuration(
+            "The command is empty",
+        ));
+    }
+    ProcessManager::build_argv(Path::new(&config.command), &config.args)?;
+    ProcessManager::build_environment(&config.env)?;
+    config.capabilities.validate()?;
+    if let Some(nice) = config.nice
+        && nice < 0
+        && !caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_NICE).unwrap_or(false)
+    {
+        return Err(ContainerError::capability(format!(
+            "--nice {nice} needs CAP_SYS_NICE, which the runtime lacks"
+        )));
+    }
+    if let Some((policy, priority)) = config.sched {
+        let priorities = policy.priorities();
+        if !priorities.contains(&priority) {
+            return Err(ContainerError::invalid_configuration(format!(
+                "--sched-priority {priority} is out of range for {policy}, which takes {} to {}",
+                priorities.start(),
+                priorities.end()
+            )));
+        }
+        if !caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_NICE).unwrap_or(false) {
+            return Err(ContainerError::capability(format!(
+                "--sched-policy {policy} needs CAP_SYS_NICE, which the runtime lacks"
+            )));
+        }
+    }
+    let ns_config = config.namespace_config();
+    validate_namespace_usage(config, &ns_config)?;
+    if let Some(hostname) = &config.hostname {
+        NamespaceManager::validate_hostname(hostname, config.hostname_strict)?;
+    }
+    Ok(ns_config)
+}
+
+pub fn filesystem_config(
+    config: &ContainerConfig,
+    ns_config: &NamespaceConfig,
+    hostname: Option<String>,
+) -> FilesystemConfig {
+    let resolv_conf = match config.network.dns.resolv_conf() {
+        Some(content) => ResolvConf::Generated {
+            content,
+            ephemeral: config.network.dns.ephemeral,
+        },
+        None if !ns_config.isolate_net => ResolvConf::Host,
+        None => ResolvConf::Keep,
+    };
+    FilesystemConfig {
+        resolv_conf,
+        hosts: HostsConfig {
+            hostname,
+            extra_hosts: config.extra_hosts.clone(),
+        },
+        host_proc_sys_ro: config.host_proc_sys_ro,
+        read_only: config.read_only,
+        tmpfs: config.tmpfs.clone(),
+        shm_size: config.shm_size,
+        isolate_ipc: ns_config.isolate_ipc,
+        host_ipc: config.no_ipc_ns,
+        masked_paths: config.masked_paths(),
+        readonly_paths: config.readonly_paths(),
+        // A delegated cgroup is of no use behind a read-only mount.
+        cgroup_rw: config.cgroup_rw || config.cgroup_delegate,
+        no_pivot: config.no_pivot,
+        share_mount_ns: !ns_config.isolate_mount,
+        keep_old_root: config.keep_old_root,
+        copies: config.copies.clone(),
+        copy_chown: config
+            .copy_chown
+            .map(|(uid, gid)| (Uid::from_raw(uid), Gid::from_raw(gid))),
+        devices: config.devices.clone(),
+        propagation: config.rootfs_propagation,
+    }
+}
+
+// Runs in the runtime once the container init has unshared its namespaces and
+// before it is allowed to continue towards exec.
+fn prepare_host_side(
+    child: Pid,
+    config: &ContainerConfig,
+    ns_config: &NamespaceConfig,
+    cgroup_manager: Option<&CgroupManager>,
+    namespaces_ready: &mut SyncPipe,
+) -> ContainerResult<(Option<NetworkManager>, Option<PortForwarder>)> {
+    namespaces_ready.wait()?;
+    if ns_config.isolate_user {
+        NamespaceManager::write_id_mappings(child)?;
+    }
+    if config.cgroup_delegate
+        && let Some(manager) = cgroup_manager
+    {
+        let (uid, gid) = NamespaceManager::mapped_root(child)?;
+        manager.delegate(uid, gid)?;
+    }
+    match config.network.mode {
+        NetworkMode::Bridge => {
+            let network = NetworkManager::setup_bridge(child, &config.network)?;
+            let forwarder = if config.publish.is_empty() {
+                None
+            } else {
+                Some(PortForwarder::start(&config.publish, network.address())?)
+            };
+            Ok((Some(network), forwarder))
+        }
+        NetworkMode::None | NetworkMode::Host => Ok((None, None)),
+    }
+}
+
+// Written to a temporary file and renamed, so readers never see it half
+// written.
+fn write_pid_file(path: &Path, pid: Pid) -> ContainerResult<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, format!("{pid}\n"))
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| {
+            ContainerError::initialization(format!(
+                "Failed to write pid file {}: {e}",
+                path.display()
+            ))
+        })?;
+    debug!("Wrote init PID {pid} to {}", path.display());
+    Ok(())
+}
+
+fn write_stats_json(usage: &UsageSummary, target: &Path) -> ContainerResult<()> {
+    let json = serde_json::to_string(usage).map_err(|e| {
+        ContainerError::invalid_configuration(format!("Failed to serialize usage summary: {e}"))
+    })?;
+    if target == Path::new("-") {
+        println!("{json}");
+    } else {
+        std::fs::write(target, format!("{json}\n"))?;
+        debug!("Wrote usage summary to {}", target.display());
+    }
+    Ok(())
+}
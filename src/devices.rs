@@ -0,0 +1,508 @@
+use crate::error::{ContainerError, ContainerResult, ErrorDetail};
+use nix::sys::stat::{major, minor};
+use std::fmt;
+use std::fs::{self, File};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// The devices a container may create and open unless told otherwise. The
+// PTY slaves under /dev/pts go with ptmx, or --tty would be of no use.
+pub const DEFAULT_RULES: [&str; 9] = [
+    "c 1:3 rwm",   // null
+    "c 1:5 rwm",   // zero
+    "c 1:7 rwm",   // full
+    "c 1:8 rwm",   // random
+    "c 1:9 rwm",   // urandom
+    "c 5:0 rwm",   // tty
+    "c 5:1 rwm",   // console
+    "c 5:2 rwm",   // ptmx
+    "c 136:* rwm", // pts
+];
+
+// One line of the devices controller's allow list, as in devices.allow:
+// "TYPE MAJOR:MINOR ACCESS", with TYPE a (all), b or c, `*` for any number
+// and ACCESS some of r(ead), w(rite) and m(knod).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRule {
+    pub kind: char,
+    pub major: Option<u32>,
+    pub minor: Option<u32>,
+    pub access: String,
+}
+
+impl FromStr for DeviceRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let (kind, numbers, access) = match fields[..] {
+            [kind, numbers, access] => (kind, numbers, access),
+            // `a` alone is every device, as devices.allow takes it.
+            ["a"] => ("a", "*:*", "rwm"),
+            _ => {
+                return Err(format!(
+                    "invalid device rule '{s}', expected e.g. 'c 10:200 rwm'"
+                ));
+            }
+        };
+        let kind = match kind {
+            "a" | "b" | "c" => kind.chars().next().unwrap_or('a'),
+            other => return Err(format!("invalid device type '{other}', expected a, b or c")),
+        };
+        let number = |n: &str| match n {
+            "*" => Ok(None),
+            n => n
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid device number '{n}' in '{s}'")),
+        };
+        let (major, minor) = numbers
+            .split_once(':')
+            .ok_or_else(|| format!("invalid device numbers '{numbers}', expected MAJOR:MINOR"))?;
+        if access.is_empty() || !access.chars().all(|c| "rwm".contains(c)) {
+            return Err(format!(
+                "invalid device access '{access}', expected some of r, w and m"
+            ));
+        }
+        Ok(DeviceRule {
+            kind,
+            major: number(major)?,
+            minor: number(minor)?,
+            access: access.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for DeviceRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = |n: Option<u32>| n.map_or("*".to_string(), |n| n.to_string());
+        write!(
+            f,
+            "{} {}:{} {}",
+            self.kind,
+            number(self.major),
+            number(self.minor),
+            self.access
+        )
+    }
+}
+
+impl DeviceRule {
+    pub fn defaults() -> Vec<DeviceRule> {
+        DEFAULT_RULES
+            .iter()
+            .map(|rule| rule.parse().expect("default device rules are valid"))
+            .collect()
+    }
+}
+
+// --device HOST[:CONTAINER][:ACCESS]: a host device node recreated in the
+// container, by default at the same path and with rwm access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSpec {
+    pub host: PathBuf,
+    pub container: PathBuf,
+    pub access: String,
+}
+
+impl FromStr for DeviceSpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (host, container, access) = match parts[..] {
+            [host] => (host, host, "rwm"),
+            [host, access] if !access.starts_with('/') => (host, host, access),
+            [host, container] => (host, container, "rwm"),
+            [host, container, access] => (host, container, access),
+            _ => {
+                return Err(format!(
+                    "invalid device '{s}', expected HOST[:CONTAINER][:rwm]"
+                ));
+            }
+        };
+        for path in [host, container] {
+            if !path.starts_with('/') {
+                return Err(format!("device path '{path}' must be absolute"));
+            }
+        }
+        if access.is_empty() || !access.chars().all(|c| "rwm".contains(c)) {
+            return Err(format!(
+                "invalid device access '{access}', expected some of r, w and m"
+            ));
+        }
+        Ok(DeviceSpec {
+            host: PathBuf::from(host),
+            container: PathBuf::from(container),
+            access: access.to_string(),
+        })
+    }
+}
+
+impl DeviceSpec {
+    // The host node as it is now; fails for anything but a device node.
+    pub fn resolve(&self) -> ContainerResult<HostDevice> {
+        let meta = fs::metadata(&self.host).map_err(|e| {
+            ContainerError::invalid_configuration(format!("--device {}: {e}", self.host.display()))
+        })?;
+        let kind = if meta.file_type().is_char_device() {
+            'c'
+        } else if meta.file_type().is_block_device() {
+            'b'
+        } else {
+            return Err(ContainerError::invalid_configuration(format!(
+                "--device {}: not a device node",
+                self.host.display()
+            )));
+        };
+        Ok(HostDevice {
+            spec: self.clone(),
+            kind,
+            rdev: meta.rdev(),
+            mode: meta.mode() & 0o7777,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HostDevice {
+    pub spec: DeviceSpec,
+    pub kind: char,
+    pub rdev: u64,
+    pub mode: u32,
+}
+
+impl HostDevice {
+    // What the device cgroup has to let through for it.
+    pub fn rule(&self) -> DeviceRule {
+        DeviceRule {
+            kind: self.kind,
+            major: Some(major(self.rdev) as u32),
+            minor: Some(minor(self.rdev) as u32),
+            access: self.spec.access.clone(),
+        }
+    }
+}
+
+// cgroup v2 has no devices controller: access is decided by a
+// BPF_PROG_TYPE_CGROUP_DEVICE program attached to the cgroup, which gets
+// each mknod and open of a device node and returns 1 to allow it. Anything
+// it returns 0 for fails with EPERM.
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_ATTACH: libc::c_long = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+// Alongside programs of other managers (systemd's), all of which must allow.
+const BPF_F_ALLOW_MULTI: u32 = 2;
+
+const BPF_DEVCG_DEV_BLOCK: i32 = 1;
+const BPF_DEVCG_DEV_CHAR: i32 = 2;
+const BPF_DEVCG_ACC_MKNOD: i32 = 1;
+const BPF_DEVCG_ACC_READ: i32 = 2;
+const BPF_DEVCG_ACC_WRITE: i32 = 4;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BpfInsn {
+    code: u8,
+    // dst_reg in the low nibble, src_reg in the high one.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Self {
+            code,
+            regs: (src << 4) | dst,
+            off,
+            imm,
+        }
+    }
+    // dst = *(u32 *)(src + off)
+    const fn load_word(dst: u8, src: u8, off: i16) -> Self {
+        Self::new(0x61, dst, src, off, 0)
+    }
+    // dst &= imm
+    const fn and(dst: u8, imm: i32) -> Self {
+        Self::new(0x57, dst, 0, 0, imm)
+    }
+    // dst >>= imm
+    const fn shift_right(dst: u8, imm: i32) -> Self {
+        Self::new(0x77, dst, 0, 0, imm)
+    }
+    // dst = src
+    const fn mov(dst: u8, src: u8) -> Self {
+        Self::new(0xbf, dst, src, 0, 0)
+    }
+    // dst = imm
+    const fn mov_imm(dst: u8, imm: i32) -> Self {
+        Self::new(0xb7, dst, 0, 0, imm)
+    }
+    // if dst != imm goto +off
+    const fn jump_ne(dst: u8, imm: i32, off: i16) -> Self {
+        Self::new(0x55, dst, 0, off, imm)
+    }
+    // if dst != src goto +off
+    const fn jump_ne_reg(dst: u8, src: u8, off: i16) -> Self {
+        Self::new(0x5d, dst, src, off, 0)
+    }
+    const fn exit() -> Self {
+        Self::new(0x95, 0, 0, 0, 0)
+    }
+}
+
+// The allow list as a program. The context is struct bpf_cgroup_dev_ctx:
+// access_type (device type in the low 16 bits, access in the high ones),
+// major and minor, loaded into r2 to r5. Each rule is a block of checks
+// that jumps to the next block on a mismatch and returns 1 when all pass;
+// falling off the last one returns 0.
+fn device_program(rules: &[DeviceRule]) -> Vec<BpfInsn> {
+    let mut program = vec![
+        BpfInsn::load_word(2, 1, 0),
+        BpfInsn::and(2, 0xffff),
+        BpfInsn::load_word(3, 1, 0),
+        BpfInsn::shift_right(3, 16),
+        BpfInsn::load_word(4, 1, 4),
+        BpfInsn::load_word(5, 1, 8),
+    ];
+    for rule in rules {
+        let mut checks = Vec::new();
+        match rule.kind {
+            'b' => checks.push(BpfInsn::jump_ne(2, BPF_DEVCG_DEV_BLOCK, 0)),
+            'c' => checks.push(BpfInsn::jump_ne(2, BPF_DEVCG_DEV_CHAR, 0)),
+            _ => {}
+        }
+        let access = rule.access.chars().fold(0, |mask, c| {
+            mask | match c {
+                'm' => BPF_DEVCG_ACC_MKNOD,
+                'r' => BPF_DEVCG_ACC_READ,
+                _ => BPF_DEVCG_ACC_WRITE,
+            }
+        });
+        let all = BPF_DEVCG_ACC_MKNOD | BPF_DEVCG_ACC_READ | BPF_DEVCG_ACC_WRITE;
+        if access != all {
+            // The access asked for must be within the rule's.
+            checks.push(BpfInsn::mov(1, 3));
+            checks.push(BpfInsn::and(1, access));
+            checks.push(BpfInsn::jump_ne_reg(1, 3, 0));
+        }
+        if let Some(major) = rule.major {
+            checks.push(BpfInsn::jump_ne(4, major as i32, 0));
+        }
+        if let Some(minor) = rule.minor {
+            checks.push(BpfInsn::jump_ne(5, minor as i32, 0));
+        }
+        // Every jump lands just past this block's two-instruction return.
+        let len = checks.len();
+        for (i, insn) in checks.iter_mut().enumerate() {
+            if insn.code == 0x55 || insn.code == 0x5d {
+                insn.off = (len - i - 1 + 2) as i16;
+            }
+        }
+        program.extend(checks);
+        program.push(BpfInsn::mov_imm(0, 1));
+        program.push(BpfInsn::exit());
+    }
+    program.push(BpfInsn::mov_imm(0, 0));
+    program.push(BpfInsn::exit());
+    program
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+fn bpf<T>(command: libc::c_long, attr: &mut T) -> std::io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            command,
+            attr as *mut T,
+            std::mem::size_of::<T>(),
+        )
+    };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+// Loads the allow list and attaches it to `cgroup`, where it stays for as
+// long as the cgroup exists.
+pub fn attach_device_program(cgroup: &Path, rules: &[DeviceRule]) -> ContainerResult<()> {
+    let program = device_program(rules);
+    let license = c"Apache-2.0";
+    let mut name = [0u8; 16];
+    name[..12].copy_from_slice(b"container_rs");
+    let mut load = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        prog_name: name,
+        ..Default::default()
+    };
+    let prog = match bpf(BPF_PROG_LOAD, &mut load) {
+        Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd as i32) },
+        Err(e) => {
+            // Once more with the verifier's log, for its reasons.
+            let mut log_buf = vec![0u8; 64 * 1024];
+            load.log_level = 1;
+            load.log_size = log_buf.len() as u32;
+            load.log_buf = log_buf.as_mut_ptr() as u64;
+            if let Ok(fd) = bpf(BPF_PROG_LOAD, &mut load) {
+                drop(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+            }
+            let log = String::from_utf8_lossy(&log_buf);
+            log::debug!("BPF verifier log: {}", log.trim_end_matches('\0'));
+            return Err(ContainerError::Cgroup {
+                detail: ErrorDetail::new("Failed to load the device access program for")
+                    .path(cgroup)
+                    .source(e),
+            });
+        }
+    };
+    let target = File::open(cgroup).map_err(|e| ContainerError::Cgroup {
+        detail: ErrorDetail::new("Failed to open").path(cgroup).source(e),
+    })?;
+    let mut attach = ProgAttachAttr {
+        target_fd: target.as_raw_fd() as u32,
+        attach_bpf_fd: prog.as_raw_fd() as u32,
+        attach_type: BPF_CGROUP_DEVICE,
+        attach_flags: BPF_F_ALLOW_MULTI,
+    };
+    bpf(BPF_PROG_ATTACH, &mut attach).map_err(|e| ContainerError::Cgroup {
+        detail: ErrorDetail::new("Failed to attach the device access program to")
+            .path(cgroup)
+            .source(e),
+    })?;
+    log::info!(
+        "Attached a device access program ({} rule(s)) to {:?}",
+        rules.len(),
+        cgroup
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<DeviceRule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    // Runs `program` the way the kernel would for an access to a device,
+    // for the handful of instructions device_program emits.
+    fn run(program: &[BpfInsn], kind: i32, access: i32, major: u32, minor: u32) -> u64 {
+        let context = [(access << 16 | kind) as u32, major, minor];
+        let mut regs = [0u64; 11];
+        let mut pc = 0;
+        loop {
+            let insn = program[pc];
+            let (dst, src) = ((insn.regs & 0xf) as usize, (insn.regs >> 4) as usize);
+            let imm = insn.imm as i64 as u64;
+            pc += 1;
+            match insn.code {
+                0x61 => {
+                    assert_eq!(src, 1, "loads only from the context");
+                    regs[dst] = context[insn.off as usize / 4] as u64;
+                }
+                0x57 => regs[dst] &= imm,
+                0x77 => regs[dst] >>= imm,
+                0xbf => regs[dst] = regs[src],
+                0xb7 => regs[dst] = imm,
+                0x55 if regs[dst] != imm => pc += insn.off as usize,
+                0x5d if regs[dst] != regs[src] => pc += insn.off as usize,
+                0x55 | 0x5d => {}
+                0x95 => return regs[0],
+                code => panic!("unexpected opcode {code:#x}"),
+            }
+        }
+    }
+
+    const CHAR: i32 = BPF_DEVCG_DEV_CHAR;
+    const BLOCK: i32 = BPF_DEVCG_DEV_BLOCK;
+    const READ: i32 = BPF_DEVCG_ACC_READ;
+    const WRITE: i32 = BPF_DEVCG_ACC_WRITE;
+    const MKNOD: i32 = BPF_DEVCG_ACC_MKNOD;
+
+    #[test]
+    fn rule_compiles_to_one_block() {
+        let program = device_program(&rules(&["c 1:3 r"]));
+        assert_eq!(
+            program[6..],
+            [
+                BpfInsn::jump_ne(2, CHAR, 7),
+                BpfInsn::mov(1, 3),
+                BpfInsn::and(1, READ),
+                BpfInsn::jump_ne_reg(1, 3, 4),
+                BpfInsn::jump_ne(4, 1, 3),
+                BpfInsn::jump_ne(5, 3, 2),
+                BpfInsn::mov_imm(0, 1),
+                BpfInsn::exit(),
+                BpfInsn::mov_imm(0, 0),
+                BpfInsn::exit(),
+            ]
+        );
+    }
+
+    #[test]
+    fn allowed_device_and_access() {
+        let program = device_program(&rules(&["c 1:3 rw", "b 8:0 rwm"]));
+        assert_eq!(run(&program, CHAR, READ, 1, 3), 1);
+        assert_eq!(run(&program, CHAR, READ | WRITE, 1, 3), 1);
+        assert_eq!(run(&program, BLOCK, MKNOD, 8, 0), 1);
+    }
+
+    #[test]
+    fn everything_else_is_denied() {
+        let program = device_program(&rules(&["c 1:3 rw"]));
+        // /dev/mem
+        assert_eq!(run(&program, CHAR, READ, 1, 1), 0);
+        // Access beyond the rule's.
+        assert_eq!(run(&program, CHAR, MKNOD, 1, 3), 0);
+        assert_eq!(run(&program, CHAR, READ | MKNOD, 1, 3), 0);
+        // The same numbers as a block device.
+        assert_eq!(run(&program, BLOCK, READ, 1, 3), 0);
+        assert_eq!(run(&device_program(&[]), CHAR, READ, 1, 3), 0);
+    }
+
+    #[test]
+    fn wildcards_match_any_number_and_type() {
+        let program = device_program(&rules(&["c 136:* rwm", "a *:5 r"]));
+        assert_eq!(run(&program, CHAR, WRITE, 136, 0), 1);
+        assert_eq!(run(&program, CHAR, WRITE, 136, 42), 1);
+        assert_eq!(run(&program, CHAR, WRITE, 137, 0), 0);
+        assert_eq!(run(&program, BLOCK, READ, 9, 5), 1);
+        assert_eq!(run(&program, CHAR, READ, 1, 5), 1);
+        assert_eq!(run(&program, BLOCK, WRITE, 9, 5), 0);
+        let all = device_program(&rules(&["a"]));
+        assert_eq!(run(&all, BLOCK, READ | WRITE | MKNOD, 259, 7), 1);
+    }
+}
@@ -1,40 +1,211 @@
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use std::borrow::Cow;
 use std::ffi::NulError;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum ContainerError {
-    #[error("IO error: {source}")]
+    // `context` holds the frames added by Context::context, innermost first.
+    #[error("{}IO error: {source}", frames(.context))]
     Io {
-        #[from]
+        #[source]
         source: std::io::Error,
+        context: Vec<String>,
     },
-    #[error("Nix error: {source}")]
+    #[error("{}Nix error: {source}", frames(.context))]
     Nix {
-        #[from]
+        #[source]
         source: nix::Error,
+        context: Vec<String>,
+    },
+    #[error("Namespace setup failed: {detail}")]
+    NamespaceSetup {
+        #[source]
+        detail: ErrorDetail,
+    },
+    #[error("Filesystem setup failed: {detail}")]
+    Filesystem {
+        #[source]
+        detail: ErrorDetail,
+    },
+    #[error("Process execution failed: {detail}")]
+    ProcessExecution {
+        #[source]
+        detail: ErrorDetail,
     },
-    #[error("Namespace setup failed: {message}")]
-    NamespaceSetup { message: String },
-    #[error("Filesystem setup failed : {message}")]
-    Filesystem { message: String },
-    #[error("Process execution failed: {message}")]
-    ProcessExecution { message: String },
     #[error("Root privileges required")]
     RootRequired,
-    #[error("Invalid configuration: {message}")]
-    InvalidConfiguration { message: String },
+    #[error("Invalid configuration: {detail}")]
+    InvalidConfiguration {
+        #[source]
+        detail: ErrorDetail,
+    },
     #[error("Invalid string format: {source}")]
     InvalidString {
         #[from]
         source: NulError,
     },
-    #[error("Container initialization failed: {message}")]
-    Initialization { message: String },
-    #[error("Cgroup(V2) setup failed: {message}")]
-    Cgroup { message: String },
+    #[error("Container initialization failed: {detail}")]
+    Initialization {
+        #[source]
+        detail: ErrorDetail,
+    },
+    #[error("Cgroup(V2) setup failed: {detail}")]
+    Cgroup {
+        #[source]
+        detail: ErrorDetail,
+    },
+    #[error("Network setup failed: {detail}")]
+    Network {
+        #[source]
+        detail: ErrorDetail,
+    },
+    #[error("Capability setup failed: {detail}")]
+    Capability {
+        #[source]
+        detail: ErrorDetail,
+    },
+    #[error("Seccomp setup failed: {detail}")]
+    Seccomp {
+        #[source]
+        detail: ErrorDetail,
+    },
+    #[error(
+        "Container was killed by the OOM killer ({kills} kill(s), memory limit: {limit} bytes)"
+    )]
+    OutOfMemory { kills: u64, limit: u64 },
 }
 pub type ContainerResult<T> = Result<T, ContainerError>;
 
+impl From<std::io::Error> for ContainerError {
+    fn from(source: std::io::Error) -> Self {
+        ContainerError::Io {
+            source,
+            context: Vec::new(),
+        }
+    }
+}
+
+impl From<nix::Error> for ContainerError {
+    fn from(source: nix::Error) -> Self {
+        ContainerError::Nix {
+            source,
+            context: Vec::new(),
+        }
+    }
+}
+
+// Context frames as a prefix, outermost first.
+fn frames(context: &[String]) -> String {
+    context
+        .iter()
+        .rev()
+        .map(|frame| format!("{frame}: "))
+        .collect()
+}
+
+pub const EXIT_INVALID_CONFIGURATION: i32 = 2;
+pub const EXIT_RUNTIME_ERROR: i32 = 125;
+pub const EXIT_COMMAND_NOT_EXECUTABLE: i32 = 126;
+pub const EXIT_COMMAND_NOT_FOUND: i32 = 127;
+
+// The OS error underneath a failure, kept as is so its errno survives.
+#[derive(Debug)]
+pub enum ErrorSource {
+    Io(std::io::Error),
+    Nix(nix::Error),
+}
+
+impl ErrorSource {
+    pub fn errno(&self) -> Option<Errno> {
+        match self {
+            ErrorSource::Io(e) => e.raw_os_error().map(Errno::from_raw),
+            ErrorSource::Nix(e) => Some(*e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ErrorSource {
+    fn from(e: std::io::Error) -> Self {
+        ErrorSource::Io(e)
+    }
+}
+
+impl From<nix::Error> for ErrorSource {
+    fn from(e: nix::Error) -> Self {
+        ErrorSource::Nix(e)
+    }
+}
+
+impl fmt::Display for ErrorSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorSource::Io(e) => e.fmt(f),
+            ErrorSource::Nix(e) => e.fmt(f),
+        }
+    }
+}
+
+// What failed, on which path and why. Displayed as
+// "<context>: <operation> <path>: <source>", with context frames added by
+// Context::context outermost first.
+#[derive(Debug)]
+pub struct ErrorDetail {
+    pub operation: Cow<'static, str>,
+    pub path: Option<PathBuf>,
+    pub source: Option<ErrorSource>,
+    // Innermost first, in the order they were added.
+    pub context: Vec<String>,
+}
+
+impl ErrorDetail {
+    pub fn new(operation: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            operation: operation.into(),
+            path: None,
+            source: None,
+            context: Vec::new(),
+        }
+    }
+    pub fn path(mut self, path: impl AsRef<Path>) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+    pub fn source(mut self, source: impl Into<ErrorSource>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+    pub fn errno(&self) -> Option<Errno> {
+        self.source.as_ref().and_then(ErrorSource::errno)
+    }
+}
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", frames(&self.context), self.operation)?;
+        if let Some(path) = &self.path {
+            write!(f, " {}", path.display())?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, ": {source}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ErrorDetail {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.source {
+            Some(ErrorSource::Io(e)) => Some(e),
+            Some(ErrorSource::Nix(e)) => Some(e),
+            None => None,
+        }
+    }
+}
+
 pub trait Context<T> {
     fn context<C>(self, context: C) -> ContainerResult<T>
     where
@@ -45,31 +216,21 @@ impl<T> Context<T> for ContainerResult<T> {
     where
         C: Into<String>,
     {
-        self.map_err(|err| {
-            let context_msg = context.into();
-            match err {
-                ContainerError::NamespaceSetup { message } => ContainerError::NamespaceSetup {
-                    message: format!("{context_msg}:{message}"),
-                },
-                ContainerError::Filesystem { message } => ContainerError::Filesystem {
-                    message: format!("{context_msg}:{message}"),
-                },
-                ContainerError::Initialization { message } => ContainerError::Initialization {
-                    message: format!("{context_msg}:{message}"),
-                },
-                ContainerError::ProcessExecution { message } => ContainerError::ProcessExecution {
-                    message: format!("{context_msg}:{message}"),
-                },
-                ContainerError::InvalidConfiguration { message } => {
-                    ContainerError::InvalidConfiguration {
-                        message: format!("{context_msg}:{message}"),
+        self.map_err(|mut err| {
+            match &mut err {
+                ContainerError::Io {
+                    context: frames, ..
+                }
+                | ContainerError::Nix {
+                    context: frames, ..
+                } => frames.push(context.into()),
+                _ => {
+                    if let Some(detail) = err.detail_mut() {
+                        detail.context.push(context.into());
                     }
                 }
-                ContainerError::Cgroup { message } => ContainerError::Cgroup {
-                    message: format!("{context_msg}:{message}"),
-                },
-                _ => err,
             }
+            err
         })
     }
 }
@@ -77,32 +238,181 @@ impl<T> Context<T> for ContainerResult<T> {
 impl ContainerError {
     pub fn name_space(message: impl Into<String>) -> Self {
         ContainerError::NamespaceSetup {
-            message: message.into(),
+            detail: ErrorDetail::new(message.into()),
         }
     }
     pub fn filesystem_setup(message: impl Into<String>) -> Self {
         ContainerError::Filesystem {
-            message: message.into(),
+            detail: ErrorDetail::new(message.into()),
         }
     }
     pub fn initialization(message: impl Into<String>) -> Self {
         ContainerError::Initialization {
-            message: message.into(),
+            detail: ErrorDetail::new(message.into()),
         }
     }
     pub fn process_execution(message: impl Into<String>) -> Self {
         ContainerError::ProcessExecution {
-            message: message.into(),
+            detail: ErrorDetail::new(message.into()),
         }
     }
     pub fn invalid_configuration(message: impl Into<String>) -> Self {
         ContainerError::InvalidConfiguration {
-            message: message.into(),
+            detail: ErrorDetail::new(message.into()),
         }
     }
     pub fn cgroup_setup(message: impl Into<String>) -> Self {
         ContainerError::Cgroup {
-            message: message.into(),
+            detail: ErrorDetail::new(message.into()),
+        }
+    }
+    pub fn network(message: impl Into<String>) -> Self {
+        ContainerError::Network {
+            detail: ErrorDetail::new(message.into()),
+        }
+    }
+    pub fn capability(message: impl Into<String>) -> Self {
+        ContainerError::Capability {
+            detail: ErrorDetail::new(message.into()),
+        }
+    }
+    pub fn seccomp(message: impl Into<String>) -> Self {
+        ContainerError::Seccomp {
+            detail: ErrorDetail::new(message.into()),
+        }
+    }
+
+    pub fn detail(&self) -> Option<&ErrorDetail> {
+        match self {
+            ContainerError::NamespaceSetup { detail }
+            | ContainerError::Filesystem { detail }
+            | ContainerError::ProcessExecution { detail }
+            | ContainerError::InvalidConfiguration { detail }
+            | ContainerError::Initialization { detail }
+            | ContainerError::Cgroup { detail }
+            | ContainerError::Network { detail }
+            | ContainerError::Capability { detail }
+            | ContainerError::Seccomp { detail } => Some(detail),
+            _ => None,
+        }
+    }
+
+    fn detail_mut(&mut self) -> Option<&mut ErrorDetail> {
+        match self {
+            ContainerError::NamespaceSetup { detail }
+            | ContainerError::Filesystem { detail }
+            | ContainerError::ProcessExecution { detail }
+            | ContainerError::InvalidConfiguration { detail }
+            | ContainerError::Initialization { detail }
+            | ContainerError::Cgroup { detail }
+            | ContainerError::Network { detail }
+            | ContainerError::Capability { detail }
+            | ContainerError::Seccomp { detail } => Some(detail),
+            _ => None,
+        }
+    }
+
+    // The errno behind the error, wherever it was wrapped.
+    pub fn errno(&self) -> Option<Errno> {
+        match self {
+            ContainerError::Io { source, .. } => source.raw_os_error().map(Errno::from_raw),
+            ContainerError::Nix { source, .. } => Some(*source),
+            _ => self.detail().and_then(ErrorDetail::errno),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.detail().and_then(|detail| detail.path.as_deref())
+    }
+
+    // The runtime's exit status when it fails before the workload has run,
+    // following the shell and other OCI runtimes: 127 when the command does
+    // not exist, 126 when it cannot be executed, 2 for a configuration the
+    // runtime refuses and 125 for any other failure of the runtime itself.
+    // Once the workload runs, its own status is passed on instead.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ContainerError::InvalidConfiguration { .. } | ContainerError::InvalidString { .. } => {
+                EXIT_INVALID_CONFIGURATION
+            }
+            ContainerError::ProcessExecution { detail } => match detail.errno() {
+                Some(Errno::ENOENT) => EXIT_COMMAND_NOT_FOUND,
+                Some(Errno::EACCES | Errno::EPERM | Errno::ENOEXEC | Errno::EISDIR) => {
+                    EXIT_COMMAND_NOT_EXECUTABLE
+                }
+                _ => EXIT_RUNTIME_ERROR,
+            },
+            // The OOM killer's SIGKILL, reported like any other signal.
+            ContainerError::OutOfMemory { .. } => 128 + Signal::SIGKILL as i32,
+            _ => EXIT_RUNTIME_ERROR,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failing<E>(error: E) -> ContainerResult<()>
+    where
+        ContainerError: From<E>,
+    {
+        Err(error.into())
+    }
+
+    #[test]
+    fn oom_kill_exits_like_sigkill() {
+        let error = ContainerError::OutOfMemory {
+            kills: 1,
+            limit: 16 << 20,
+        };
+        assert_eq!(error.exit_code(), 137);
+        assert_eq!(
+            ContainerError::invalid_configuration("bad").exit_code(),
+            EXIT_INVALID_CONFIGURATION
+        );
+        assert_eq!(
+            ContainerError::initialization("failed").exit_code(),
+            EXIT_RUNTIME_ERROR
+        );
+    }
+
+    #[test]
+    fn context_keeps_the_errno() {
+        let errors = [
+            failing(std::io::Error::from_raw_os_error(libc::ENOENT)),
+            failing(Errno::ENOENT),
+            Err(ContainerError::Filesystem {
+                detail: ErrorDetail::new("Failed to open")
+                    .path("/missing")
+                    .source(Errno::ENOENT),
+            }),
+        ];
+        for error in errors {
+            let error = error
+                .context("reading the config")
+                .context("starting")
+                .unwrap_err();
+            assert_eq!(error.errno(), Some(Errno::ENOENT), "{error}");
+            assert!(
+                error.to_string().contains("starting: reading the config: "),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn detail_frames_come_before_the_operation() {
+        let error = Err::<(), _>(ContainerError::Filesystem {
+            detail: ErrorDetail::new("Failed to open")
+                .path("/missing")
+                .source(Errno::ENOENT),
+        })
+        .context("mounting proc")
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Filesystem setup failed: mounting proc: Failed to open /missing: ENOENT: No such file or directory"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::error::{ContainerError, ContainerResult};
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{BorrowedFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Event {
+    // The container init exists (host PID) but has not exec'd yet.
+    Created { pid: i32 },
+    // The init has been released to start the command (host PID of the
+    // init, as for Created).
+    Running { pid: i32 },
+    Exited { code: i32 },
+    Oom { kills: u64, limit: u64 },
+}
+
+pub trait EventSink: Send + std::fmt::Debug {
+    fn emit(&mut self, event: Event);
+}
+
+// Used when nobody asked for events.
+#[derive(Debug, Default)]
+pub struct NullEventSink;
+
+impl EventSink for NullEventSink {
+    fn emit(&mut self, _event: Event) {}
+}
+
+// Writes one JSON object per line to an fd handed to us by the caller
+// (--events-fd). The fd is close-on-exec so the workload never inherits it.
+#[derive(Debug)]
+pub struct FdEventSink {
+    file: File,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp_ms: u128,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl FdEventSink {
+    pub fn from_raw_fd(fd: RawFd) -> ContainerResult<Self> {
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        fcntl(borrowed, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(|e| {
+            ContainerError::invalid_configuration(format!("Events fd {fd} is not usable: {e}"))
+        })?;
+        Ok(Self {
+            file: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+}
+
+impl EventSink for FdEventSink {
+    fn emit(&mut self, event: Event) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let record = Record {
+            timestamp_ms,
+            event: &event,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(json) => json + "\n",
+            Err(e) => {
+                log::warn!("Failed to serialize event {event:?}: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            log::warn!("Failed to write event {event:?}: {e}");
+        }
+    }
+}
+
+// Collects events in memory so embedders and tests can assert on the exact
+// sequence a run produced (see Container::with_event_sink). Clones share the
+// same buffer.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryEventSink {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl MemoryEventSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events
+            .lock()
+            .map(|events| events.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl EventSink for MemoryEventSink {
+    fn emit(&mut self, event: Event) {
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event);
+        }
+    }
+}
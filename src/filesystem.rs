@@ -1,12 +1,554 @@
 use core::str;
+use nix::errno::Errno;
+use nix::fcntl::{AtFlags, OFlag, open, openat};
 use nix::mount::{MntFlags, MsFlags, mount, umount2};
-use nix::unistd::{chdir, chroot, pivot_root};
-use std::fs;
-use std::os::unix::fs::symlink;
-use std::path::{Path, PathBuf};
+use nix::sys::stat::{Mode, SFlag, fchmod, makedev, mkdirat, mknod};
+use nix::unistd::{
+    Gid, Uid, UnlinkatFlags, chdir, chroot, fchown, fchownat, pivot_root, symlinkat, unlinkat,
+};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, DirBuilder, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::net::IpAddr;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt, chown, symlink};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-use crate::error::{ContainerError, ContainerResult, Context};
-use crate::filesystem;
+use crate::devices::{DeviceSpec, HostDevice};
+use crate::error::{ContainerError, ContainerResult, Context, ErrorDetail};
+use crate::process::DEFAULT_PATH;
+
+const HOST_PROC_SYS: &str = "/host/proc-sys";
+// Hidden, so a directory left behind by a failed cleanup is less visible.
+const PUT_OLD: &str = "/.oldroot";
+const HOSTNAME_ADDR: &str = "127.0.1.1";
+// The OCI runtime-spec defaults (as used by runc and Docker).
+pub const DEFAULT_MASKED_PATHS: [&str; 11] = [
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/sys/firmware",
+    "/sys/devices/virtual/powercap",
+];
+pub const DEFAULT_READONLY_PATHS: [&str; 5] = [
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+];
+const DEV_NODES: [(&str, u64, u64); 6] = [
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+#[derive(Debug, Clone, Default)]
+pub enum ResolvConf {
+    // Leave whatever the rootfs ships with.
+    #[default]
+    Keep,
+    // Share the host's file, for containers using the host's network.
+    Host,
+    Generated {
+        content: String,
+        ephemeral: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HostsConfig {
+    pub hostname: Option<String>,
+    pub extra_hosts: Vec<(String, IpAddr)>,
+}
+
+impl HostsConfig {
+    // Keeps the rootfs entries except an old 127.0.1.1 hostname line, which is
+    // replaced, and appends --add-host entries last.
+    fn render(&self, existing: &str) -> String {
+        let mut content: String = existing
+            .lines()
+            .filter(|line| {
+                self.hostname.is_none() || line.split_whitespace().next() != Some(HOSTNAME_ADDR)
+            })
+            .map(|line| format!("{line}\n"))
+            .collect();
+        if let Some(hostname) = &self.hostname {
+            content.push_str(&format!("{HOSTNAME_ADDR}\t{hostname}\n"));
+        }
+        for (name, addr) in &self.extra_hosts {
+            content.push_str(&format!("{addr}\t{name}\n"));
+        }
+        content
+    }
+}
+
+// `--tmpfs PATH[:OPTIONS]`. Mount flags are split out of the options and the
+// rest is passed to tmpfs as mount data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TmpfsMount {
+    pub path: PathBuf,
+    pub flags: MsFlags,
+    pub data: String,
+}
+
+impl FromStr for TmpfsMount {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, options) = s.split_once(':').unwrap_or((s, ""));
+        let path = PathBuf::from(path);
+        if !path.is_absolute() || path == Path::new("/") {
+            return Err(format!(
+                "tmpfs path must be absolute and not /, got '{}'",
+                path.display()
+            ));
+        }
+        let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+        let mut data = Vec::new();
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            match key {
+                "ro" => flags |= MsFlags::MS_RDONLY,
+                "noexec" => flags |= MsFlags::MS_NOEXEC,
+                "exec" => flags &= !MsFlags::MS_NOEXEC,
+                "size" => {
+                    parse_size(value)?;
+                    data.push(option);
+                }
+                "mode" => {
+                    match u32::from_str_radix(value, 8) {
+                        Ok(mode) if mode <= 0o7777 => {}
+                        _ => return Err(format!("invalid tmpfs mode '{value}', expected octal")),
+                    }
+                    data.push(option);
+                }
+                "uid" | "gid" | "nr_inodes" => {
+                    value
+                        .parse::<u64>()
+                        .map_err(|_| format!("invalid tmpfs {key} '{value}'"))?;
+                    data.push(option);
+                }
+                _ => return Err(format!("unsupported tmpfs option '{option}'")),
+            }
+        }
+        Ok(TmpfsMount {
+            path,
+            flags,
+            data: data.join(","),
+        })
+    }
+}
+
+// `--copy SRC:DEST`: a host file or directory copied to DEST in the rootfs
+// before the container starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopySpec {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+}
+
+impl FromStr for CopySpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (source, destination) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("invalid copy '{s}', expected SRC:DEST"))?;
+        let destination = PathBuf::from(destination);
+        if !destination.is_absolute()
+            || destination.file_name().is_none()
+            || destination
+                .components()
+                .any(|part| part == Component::ParentDir)
+        {
+            return Err(format!(
+                "copy destination must be an absolute path without '..' and not /, got '{}'",
+                destination.display()
+            ));
+        }
+        // Made absolute now, as the init may run from another directory.
+        let source = std::path::absolute(source)
+            .map_err(|e| format!("invalid copy source '{source}': {e}"))?;
+        Ok(CopySpec {
+            source,
+            destination,
+        })
+    }
+}
+
+// Sizes take an optional k/m/g suffix, as tmpfs itself does.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 1u64 << 10),
+        Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{value}', expected e.g. 64m"))
+}
+
+// How mount and unmount events travel between the host and the container's
+// root once it is set up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Propagation {
+    #[default]
+    Private,
+    // From the host into the container.
+    Rslave,
+    // Both ways.
+    Rshared,
+}
+
+impl Propagation {
+    fn flags(self) -> MsFlags {
+        let flag = match self {
+            Propagation::Private => MsFlags::MS_PRIVATE,
+            Propagation::Rslave => MsFlags::MS_SLAVE,
+            Propagation::Rshared => MsFlags::MS_SHARED,
+        };
+        flag | MsFlags::MS_REC
+    }
+}
+
+impl FromStr for Propagation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "private" => Ok(Propagation::Private),
+            "rslave" => Ok(Propagation::Rslave),
+            "rshared" => Ok(Propagation::Rshared),
+            other => Err(format!(
+                "unknown propagation '{other}', expected private, rslave or rshared"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Propagation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Propagation::Private => "private",
+            Propagation::Rslave => "rslave",
+            Propagation::Rshared => "rshared",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FilesystemConfig {
+    pub resolv_conf: ResolvConf,
+    pub hosts: HostsConfig,
+    pub host_proc_sys_ro: bool,
+    pub read_only: bool,
+    pub tmpfs: Vec<TmpfsMount>,
+    pub shm_size: u64,
+    pub isolate_ipc: bool,
+    // The host's IPC namespace, as opposed to a joined one.
+    pub host_ipc: bool,
+    pub masked_paths: Vec<PathBuf>,
+    pub readonly_paths: Vec<PathBuf>,
+    pub cgroup_rw: bool,
+    pub no_pivot: bool,
+    // --no-mount-ns: nothing is mounted, the rootfs is only chrooted into.
+    pub share_mount_ns: bool,
+    pub keep_old_root: bool,
+    pub copies: Vec<CopySpec>,
+    pub copy_chown: Option<(Uid, Gid)>,
+    pub devices: Vec<DeviceSpec>,
+    pub propagation: Propagation,
+}
+
+impl FilesystemConfig {
+    // What setup_container_filesystem will do, one line per step and in the
+    // same order, for --check. Paths are the container's.
+    pub fn plan(&self, overlay: bool) -> Vec<String> {
+        let mut steps = Vec::new();
+        if self.share_mount_ns {
+            steps.extend(self.copy_steps());
+            steps.push(
+                "chroot into the rootfs (host's mount namespace, nothing mounted)".to_string(),
+            );
+            return steps;
+        }
+        if overlay {
+            steps.push("overlay on / (rootfs as the read-only lower layer)".to_string());
+        }
+        steps.extend(self.copy_steps());
+        if let ResolvConf::Host = self.resolv_conf {
+            steps.push("bind host /etc/resolv.conf on /etc/resolv.conf".to_string());
+        }
+        if self.host_proc_sys_ro {
+            steps.push(format!(
+                "bind host /proc/sys on {HOST_PROC_SYS} (read-only)"
+            ));
+        }
+        steps.push("proc on /proc".to_string());
+        steps.push("sysfs on /sys".to_string());
+        steps.push(format!(
+            "{} into the rootfs",
+            if self.no_pivot {
+                "chroot"
+            } else {
+                "pivot_root"
+            }
+        ));
+        steps.push("devtmpfs on /dev (a minimal tmpfs /dev where not allowed)".to_string());
+        steps.push("devpts on /dev/pts".to_string());
+        for device in &self.devices {
+            steps.push(format!(
+                "device {} at {} ({})",
+                device.host.display(),
+                device.container.display(),
+                device.access
+            ));
+        }
+        steps.push(format!(
+            "cgroup2 on /sys/fs/cgroup ({})",
+            if self.cgroup_rw {
+                "read-write"
+            } else {
+                "read-only"
+            }
+        ));
+        if self.host_ipc {
+            steps.push("bind the host's /dev/shm".to_string());
+        } else {
+            steps.push(format!("tmpfs on /dev/shm (size={})", self.shm_size));
+        }
+        if self.isolate_ipc {
+            steps.push("mqueue on /dev/mqueue".to_string());
+        }
+        if let ResolvConf::Generated { ephemeral, .. } = &self.resolv_conf {
+            steps.push(if *ephemeral {
+                "bind generated /etc/resolv.conf".to_string()
+            } else {
+                "write generated /etc/resolv.conf".to_string()
+            });
+        }
+        if let Some(hostname) = &self.hosts.hostname {
+            steps.push(format!("bind generated /etc/hostname ({hostname})"));
+        }
+        if self.hosts.hostname.is_some() || !self.hosts.extra_hosts.is_empty() {
+            steps.push("bind generated /etc/hosts".to_string());
+        }
+        for tmpfs in &self.tmpfs {
+            steps.push(match tmpfs.data.as_str() {
+                "" => format!("tmpfs on {}", tmpfs.path.display()),
+                data => format!("tmpfs on {} ({data})", tmpfs.path.display()),
+            });
+        }
+        for path in &self.masked_paths {
+            steps.push(format!("mask {}", path.display()));
+        }
+        for path in &self.readonly_paths {
+            steps.push(format!("read-only {}", path.display()));
+        }
+        if !self.keep_old_root {
+            steps.push("detach the host's root".to_string());
+        }
+        if self.propagation != Propagation::Private {
+            steps.push(format!("make / {}", self.propagation));
+        }
+        if self.read_only {
+            steps.push("remount / read-only, tmpfs on /tmp and /run".to_string());
+        }
+        steps
+    }
+    fn copy_steps(&self) -> impl Iterator<Item = String> + '_ {
+        self.copies.iter().map(|copy| {
+            let owner = match self.copy_chown {
+                Some((uid, gid)) => format!(", owned by {uid}:{gid}"),
+                None => String::new(),
+            };
+            format!(
+                "copy {} to {}{owner}",
+                copy.source.display(),
+                copy.destination.display()
+            )
+        })
+    }
+}
+
+// Either way the host's /dev stays reachable under PUT_OLD until the old root
+// is cleaned up: pivot_root moves the whole host root there, while the chroot
+// path only binds the host's /dev there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RootSwitch {
+    Pivot,
+    Chroot,
+}
+
+impl RootSwitch {
+    fn host_dev(self) -> PathBuf {
+        match self {
+            RootSwitch::Pivot => Path::new(PUT_OLD).join("dev"),
+            RootSwitch::Chroot => PathBuf::from(PUT_OLD),
+        }
+    }
+    // Where a host path is reachable from now on, if it is.
+    fn host_path(self, path: &Path) -> Option<PathBuf> {
+        // With chroot only the host's /dev is kept, at PUT_OLD.
+        let relative = match self {
+            RootSwitch::Pivot => path.strip_prefix("/").ok()?,
+            RootSwitch::Chroot => path.strip_prefix("/dev").ok()?,
+        };
+        Some(Path::new(PUT_OLD).join(relative))
+    }
+}
+
+// Per-container overlay layers under <state-dir>/<name>/. The runtime creates
+// them before the init is cloned and removes them after it exits when `remove`
+// is set; otherwise they are kept so the container's changes can be inspected.
+#[derive(Debug)]
+pub struct OverlayDirs {
+    root: PathBuf,
+    lower: PathBuf,
+    upper: PathBuf,
+    work: PathBuf,
+    merged: PathBuf,
+    remove: bool,
+}
+
+impl OverlayDirs {
+    pub fn prepare(
+        rootfs_path: &Path,
+        state_dir: &Path,
+        name: &str,
+        remove: bool,
+    ) -> ContainerResult<Self> {
+        let lower = fs::canonicalize(rootfs_path).map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to canonicalize rootfs: {e}"))
+        })?;
+        fs::create_dir_all(state_dir).map_err(|e| {
+            ContainerError::filesystem_setup(format!(
+                "Failed to create state directory {state_dir:?}: {e}"
+            ))
+        })?;
+        let root = state_dir.join(name);
+        // Both are separators in the overlay mount options.
+        for path in [&lower, &root] {
+            let path = path.to_string_lossy();
+            if path.contains([',', ':']) {
+                return Err(ContainerError::invalid_configuration(format!(
+                    "Overlay layer path {path} must not contain ',' or ':'"
+                )));
+            }
+        }
+        DirBuilder::new().mode(0o700).create(&root).map_err(|e| {
+            ContainerError::filesystem_setup(format!(
+                "Failed to create overlay directory {root:?}: {e}"
+            ))
+        })?;
+        // From here on, Drop removes what has been created if asked to.
+        let dirs = Self {
+            upper: root.join("upper"),
+            work: root.join("work"),
+            merged: root.join("merged"),
+            root,
+            lower,
+            remove,
+        };
+        for path in [&dirs.upper, &dirs.work, &dirs.merged] {
+            let mode = if *path == dirs.work { 0o700 } else { 0o755 };
+            DirBuilder::new().mode(mode).create(path).map_err(|e| {
+                ContainerError::filesystem_setup(format!("Failed to create {path:?}: {e}"))
+            })?;
+        }
+        // The merged root takes its owner and mode from the upper directory,
+        // so it has to match the rootfs or / would change under the container.
+        let lower_meta = fs::metadata(&dirs.lower)?;
+        fs::set_permissions(&dirs.upper, lower_meta.permissions())?;
+        chown(&dirs.upper, Some(lower_meta.uid()), Some(lower_meta.gid()))?;
+        log::info!("Prepared overlay layers in {:?}", dirs.root);
+        Ok(dirs)
+    }
+    pub fn cleanup(&self) {
+        match fs::remove_dir_all(&self.root) {
+            Ok(()) => log::info!("Removed overlay layers in {:?}", self.root),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to remove overlay layers in {:?}: {e}", self.root),
+        }
+    }
+}
+
+impl Drop for OverlayDirs {
+    fn drop(&mut self) {
+        if self.remove {
+            self.cleanup();
+        } else {
+            log::info!("Container changes preserved in {:?}", self.upper);
+        }
+    }
+}
+
+// Takes down the mounts a setup that fails partway through has made. Most go
+// with the init's mount namespace anyway, but a shared mount under the rootfs
+// (--rootfs-propagation rshared) has propagated them to the host as well.
+#[derive(Debug)]
+struct MountRollback {
+    known: Option<Vec<u64>>,
+}
+
+impl MountRollback {
+    fn start() -> Self {
+        Self {
+            known: Some(mount_ids().into_iter().map(|(id, _)| id).collect()),
+        }
+    }
+
+    fn commit(mut self) {
+        self.known = None;
+    }
+}
+
+impl Drop for MountRollback {
+    fn drop(&mut self) {
+        let Some(known) = self.known.take() else {
+            return;
+        };
+        // Newest first; a detached parent takes its children along, which
+        // then fail harmlessly.
+        for (_, mount_point) in mount_ids()
+            .into_iter()
+            .rev()
+            .filter(|(id, _)| !known.contains(id))
+        {
+            match umount2(&mount_point, MntFlags::MNT_DETACH) {
+                Ok(()) => log::debug!("Unmounted {mount_point:?} after a failed setup"),
+                Err(e) => log::debug!("Could not unmount {mount_point:?}: {e}"),
+            }
+        }
+    }
+}
+
+// Each mount's ID and mount point, in /proc/self/mountinfo order.
+fn mount_ids() -> Vec<(u64, PathBuf)> {
+    let mountinfo = fs::read("/proc/self/mountinfo").unwrap_or_default();
+    mountinfo
+        .split(|b| *b == b'\n')
+        .filter_map(|line| {
+            let mut fields = line.split(|b| *b == b' ');
+            let id = str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+            Some((id, unescape_mount_path(fields.nth(3)?)))
+        })
+        .collect()
+}
 
 #[derive(Debug)]
 pub struct FilesystemManager;
@@ -14,13 +556,13 @@ impl FilesystemManager {
     pub fn validate_rootfs(rootfs_path: &Path) -> ContainerResult<()> {
         log::info!("Validating rootfs at: {rootfs_path:?}");
         if !rootfs_path.exists() {
-            return Err(ContainerError::Filesystem {
-                message: format!("Rootfs path does not exist: {rootfs_path:?}"),
+            return Err(ContainerError::InvalidConfiguration {
+                detail: ErrorDetail::new("Rootfs path does not exist:").path(rootfs_path),
             });
         }
         if !rootfs_path.is_dir() {
-            return Err(ContainerError::Filesystem {
-                message: format!("Rootfs path is not a directory: {rootfs_path:?}"),
+            return Err(ContainerError::InvalidConfiguration {
+                detail: ErrorDetail::new("Rootfs path is not a directory:").path(rootfs_path),
             });
         }
         let essential_dir = ["bin", "lib", "etc"];
@@ -33,25 +575,568 @@ impl FilesystemManager {
         log::debug!("Rootfs validation passed");
         Ok(())
     }
-    pub fn setup_container_filesystem(rootfs_path: &Path) -> ContainerResult<()> {
+    // Catches commands that exist but cannot run before anything is set up:
+    // execve reports a dynamic binary whose loader is missing as ENOENT, and
+    // one built for another architecture as ENOEXEC, neither of which points
+    // at the real problem. A command that cannot be found in the rootfs is
+    // left alone, as it may come from a mount; the init reports it if not.
+    pub fn validate_command(
+        rootfs_path: &Path,
+        command: &OsStr,
+        workdir: Option<&Path>,
+        env: &[(String, String)],
+    ) -> ContainerResult<()> {
+        let in_rootfs = |path: &Path| -> ContainerResult<PathBuf> {
+            let resolved = Self::resolve_in_rootfs(rootfs_path, path)?;
+            Ok(rootfs_path.join(resolved.strip_prefix("/").unwrap_or(&resolved)))
+        };
+        let Some((path, host_path)) = Self::find_command(rootfs_path, command, workdir, env)?
+        else {
+            log::debug!("Command {command:?} not found in the rootfs, skipping preflight");
+            return Ok(());
+        };
+        let Some(elf) = ElfInfo::read(&host_path)? else {
+            return Ok(());
+        };
+        if let Some(host_machine) = elf_machine_for_arch(std::env::consts::ARCH)
+            && elf.machine != host_machine
+            && !elf_machine_compatible(host_machine, elf.machine)
+        {
+            return Err(ContainerError::invalid_configuration(format!(
+                "{} is built for {}, the host is {}; use --skip-preflight to try anyway",
+                path.display(),
+                elf_machine_name(elf.machine),
+                std::env::consts::ARCH
+            )));
+        }
+        if let Some(interpreter) = elf.interpreter
+            && !in_rootfs(&interpreter)?.is_file()
+        {
+            return Err(ContainerError::invalid_configuration(format!(
+                "{} needs the dynamic linker {}, which is missing from the rootfs; use --skip-preflight to try anyway",
+                path.display(),
+                interpreter.display()
+            )));
+        }
+        log::debug!("Preflight check of {} passed", path.display());
+        Ok(())
+    }
+    // Where the command would be found, as its container path and the host
+    // path of the file in the rootfs; None if it is not there (yet).
+    pub fn find_command(
+        rootfs_path: &Path,
+        command: &OsStr,
+        workdir: Option<&Path>,
+        env: &[(String, String)],
+    ) -> ContainerResult<Option<(PathBuf, PathBuf)>> {
+        let candidates: Vec<PathBuf> = if command.as_bytes().contains(&b'/') {
+            vec![workdir.unwrap_or(Path::new("/")).join(command)]
+        } else {
+            let search_path = env
+                .iter()
+                .rev()
+                .find(|(key, _)| key == "PATH")
+                .map_or(DEFAULT_PATH, |(_, value)| value.as_str());
+            search_path
+                .split(':')
+                .filter(|dir| Path::new(dir).is_absolute())
+                .map(|dir| Path::new(dir).join(command))
+                .collect()
+        };
+        for candidate in candidates {
+            let resolved = Self::resolve_in_rootfs(rootfs_path, &candidate)?;
+            let host_path = rootfs_path.join(resolved.strip_prefix("/").unwrap_or(&resolved));
+            if host_path.is_file() {
+                return Ok(Some((candidate, host_path)));
+            }
+        }
+        Ok(None)
+    }
+    // Resolves `path` the way it would be seen from inside the container:
+    // absolute symlinks restart at the rootfs, and nothing may climb above it.
+    pub fn resolve_in_rootfs(rootfs_path: &Path, path: &Path) -> ContainerResult<PathBuf> {
+        const MAX_SYMLINKS: usize = 40;
+        let mut resolved = PathBuf::from("/");
+        let mut pending: Vec<PathBuf> = vec![path.to_path_buf()];
+        let mut symlinks = 0;
+        while let Some(next) = pending.pop() {
+            let mut components = next.components().peekable();
+            while let Some(component) = components.next() {
+                match component {
+                    Component::RootDir => resolved = PathBuf::from("/"),
+                    Component::CurDir | Component::Prefix(_) => {}
+                    Component::ParentDir => {
+                        if !resolved.pop() {
+                            return Err(ContainerError::filesystem_setup(format!(
+                                "Path {path:?} escapes the container root"
+                            )));
+                        }
+                    }
+                    Component::Normal(name) => {
+                        let candidate = resolved.join(name);
+                        let host_path = rootfs_path.join(candidate.strip_prefix("/").unwrap());
+                        match fs::symlink_metadata(&host_path) {
+                            Ok(meta) if meta.file_type().is_symlink() => {
+                                symlinks += 1;
+                                if symlinks > MAX_SYMLINKS {
+                                    return Err(ContainerError::filesystem_setup(format!(
+                                        "Too many levels of symbolic links resolving {path:?}"
+                                    )));
+                                }
+                                let target = fs::read_link(&host_path).map_err(|e| {
+                                    ContainerError::filesystem_setup(format!(
+                                        "Failed to read symlink {host_path:?}: {e}"
+                                    ))
+                                })?;
+                                let rest: PathBuf = components.collect();
+                                if !rest.as_os_str().is_empty() {
+                                    pending.push(rest);
+                                }
+                                pending.push(target);
+                                break;
+                            }
+                            _ => resolved = candidate,
+                        }
+                    }
+                }
+            }
+        }
+        Ok(resolved)
+    }
+    pub fn validate_workdir(rootfs_path: &Path, workdir: &Path) -> ContainerResult<PathBuf> {
+        if !workdir.is_absolute() {
+            return Err(ContainerError::filesystem_setup(format!(
+                "Working directory must be an absolute path: {workdir:?}"
+            )));
+        }
+        let resolved = Self::resolve_in_rootfs(rootfs_path, workdir)
+            .context(format!("resolving working directory {workdir:?}"))?;
+        let host_path = rootfs_path.join(resolved.strip_prefix("/").unwrap());
+        if !host_path.exists() {
+            return Err(ContainerError::filesystem_setup(format!(
+                "Working directory {workdir:?} does not exist in the container"
+            )));
+        }
+        if !host_path.is_dir() {
+            return Err(ContainerError::filesystem_setup(format!(
+                "Working directory {workdir:?} exists in the container but is not a directory"
+            )));
+        }
+        log::debug!("Working directory {workdir:?} resolves to {resolved:?} in the container");
+        Ok(resolved)
+    }
+    pub fn setup_container_filesystem(
+        rootfs_path: &Path,
+        config: &FilesystemConfig,
+        overlay: Option<&OverlayDirs>,
+    ) -> ContainerResult<()> {
         log::info!("Setting up container filesystem");
-        Self::validate_rootfs(&rootfs_path)?;
+        Self::validate_rootfs(rootfs_path)?;
         let abs_path = fs::canonicalize(rootfs_path).map_err(|e| {
             ContainerError::filesystem_setup(format!("Failed to canonicalize path: {e}"))
         })?;
         log::debug!("Using absolute path: {abs_path:?}");
-        Self::pivot_root(&abs_path)?;
-        Self::mount_proc(Path::new("/"))?;
-        Self::mount_sysfs(Path::new("/"))?;
-        Self::mount_devtmpfs(Path::new("/"))?;
+        if config.share_mount_ns {
+            return Self::enter_shared_rootfs(&abs_path, config);
+        }
+        let rollback = MountRollback::start();
+        let target = overlay.map_or(abs_path.as_path(), |overlay| &overlay.merged);
+        if config.propagation == Propagation::Rshared {
+            Self::check_shared_below(target)?;
+        }
+        // Keep the mounts below from propagating back to the host; best effort.
+        // With rshared only "/" itself, which pivot_root refuses to move
+        // away from while it is shared.
+        let root_flags = match config.propagation {
+            Propagation::Rshared => MsFlags::MS_SLAVE,
+            _ => MsFlags::MS_SLAVE | MsFlags::MS_REC,
+        };
+        mount(None::<&str>, "/", None::<&str>, root_flags, None::<&str>).ok();
+        if config.propagation == Propagation::Rshared {
+            Self::make_parent_mount_private(target)?;
+        }
+        let abs_path = match overlay {
+            Some(overlay) => {
+                Self::mount_overlay(overlay)?;
+                overlay.merged.clone()
+            }
+            None => abs_path,
+        };
+        // Into the merged tree with --overlay, so the rootfs stays pristine.
+        for copy in &config.copies {
+            Self::copy_into_rootfs(&abs_path, copy, config.copy_chown)?;
+        }
+        if let ResolvConf::Host = config.resolv_conf {
+            Self::bind_host_resolv_conf(&abs_path)?;
+        }
+        // The host's /proc is unreachable after the pivot, so it is bound into
+        // the rootfs first and carried along by the recursive rootfs bind.
+        if config.host_proc_sys_ro {
+            Self::bind_host_proc_sys(&abs_path)?;
+        }
+        // proc and sysfs go in while the host's instances are still visible:
+        // inside a user namespace the kernel only permits them in that case.
+        Self::mount_proc(&abs_path)?;
+        Self::mount_sysfs(&abs_path)?;
+        // Looked at while the host's paths are still where they say.
+        let devices = config
+            .devices
+            .iter()
+            .map(DeviceSpec::resolve)
+            .collect::<ContainerResult<Vec<_>>>()?;
+        let switch = Self::pivot_root(&abs_path, config.no_pivot, config.propagation)?;
+        Self::mount_devtmpfs(Path::new("/"), &switch.host_dev())?;
+        Self::mount_devpts()?;
+        for device in &devices {
+            Self::create_device(device, switch)?;
+        }
+        Self::mount_cgroup2(config.cgroup_rw)?;
+        if config.host_ipc {
+            Self::bind_host_shm(&switch.host_dev())?;
+        } else {
+            Self::mount_shm(config.shm_size)?;
+        }
+        if config.isolate_ipc {
+            Self::mount_mqueue()?;
+        } else {
+            log::debug!("Sharing the host's IPC namespace, not mounting a new mqueue");
+        }
+        if let ResolvConf::Generated { content, ephemeral } = &config.resolv_conf {
+            Self::install_resolv_conf(content, *ephemeral)?;
+        }
+        Self::install_hosts_files(&config.hosts)?;
+        for tmpfs in &config.tmpfs {
+            Self::mount_tmpfs(tmpfs)?;
+        }
+        Self::mask_paths(&config.masked_paths)?;
+        Self::readonly_paths(&config.readonly_paths)?;
+        Self::cleanup_old_root(Path::new(PUT_OLD), config.keep_old_root)?;
+        // Again now that the rootfs is "/", for the mounts made since.
+        if config.propagation != Propagation::Private {
+            mount(
+                None::<&str>,
+                "/",
+                None::<&str>,
+                config.propagation.flags(),
+                None::<&str>,
+            )
+            .map_err(|e| {
+                ContainerError::filesystem_setup(format!(
+                    "Failed to make the root {}: {e}",
+                    config.propagation
+                ))
+            })?;
+        }
+        // Last, so mount points for the files above and the old root's
+        // directory can still be created and removed. Only the root mount
+        // itself becomes read-only; proc, sysfs and /dev keep their own flags.
+        if config.read_only {
+            Self::remount_root_readonly()?;
+            Self::mount_scratch_tmpfs(&config.tmpfs)?;
+        }
+        rollback.commit();
         log::info!("Container filesystem setup completed");
         Ok(())
     }
+    // Every directory on the way to the destination is opened relative to the
+    // previous one with O_NOFOLLOW, so a symlink planted in the rootfs cannot
+    // redirect the copy onto the host.
+    fn copy_into_rootfs(
+        rootfs_path: &Path,
+        copy: &CopySpec,
+        chown: Option<(Uid, Gid)>,
+    ) -> ContainerResult<()> {
+        let failed = |e: &dyn std::fmt::Display| {
+            ContainerError::filesystem_setup(format!(
+                "Failed to copy {} to {}: {e}",
+                copy.source.display(),
+                copy.destination.display()
+            ))
+        };
+        let source_meta = fs::metadata(&copy.source).map_err(|e| failed(&e))?;
+        let mut dir = open_dir_nofollow(None, rootfs_path).map_err(|e| failed(&e))?;
+        let parent = copy.destination.parent().unwrap_or(Path::new("/"));
+        for part in parent.components() {
+            let Component::Normal(part) = part else {
+                continue;
+            };
+            match mkdirat(&dir, part, Mode::from_bits_truncate(0o755)) {
+                Ok(()) | Err(Errno::EEXIST) => {}
+                Err(e) => return Err(failed(&e)),
+            }
+            dir = open_dir_nofollow(Some(&dir), Path::new(part)).map_err(|e| match e {
+                Errno::ELOOP | Errno::ENOTDIR => failed(&format!(
+                    "{} in the rootfs is a symlink or not a directory",
+                    Path::new(part).display()
+                )),
+                e => failed(&e),
+            })?;
+        }
+        let name = copy.destination.file_name().unwrap_or_default();
+        let result = if source_meta.is_dir() {
+            copy_dir(&dir, Path::new(name), &copy.source, chown)
+        } else {
+            copy_file(&dir, Path::new(name), &copy.source, &source_meta, chown)
+        };
+        result.map_err(|e| failed(&e))?;
+        log::info!(
+            "Copied {} to {}",
+            copy.source.display(),
+            copy.destination.display()
+        );
+        Ok(())
+    }
+    // With --network host the container uses the host's resolvers, so it sees
+    // the host's resolv.conf (including later updates) rather than a copy.
+    fn bind_host_resolv_conf(rootfs_path: &Path) -> ContainerResult<()> {
+        let host_resolv = Path::new("/etc/resolv.conf");
+        if !host_resolv.exists() {
+            log::warn!("Host has no /etc/resolv.conf, leaving the container's untouched");
+            return Ok(());
+        }
+        let resolved = Self::resolve_in_rootfs(rootfs_path, host_resolv)
+            .context("resolving /etc/resolv.conf in rootfs")?;
+        let target = rootfs_path.join(resolved.strip_prefix("/").unwrap());
+        if !target.exists() {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::File::create(&target).map_err(|e| {
+                ContainerError::filesystem_setup(format!(
+                    "Failed to create resolv.conf mount point {target:?}: {e}"
+                ))
+            })?;
+        }
+        mount(
+            Some(host_resolv),
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to bind mount host resolv.conf: {e}"))
+        })?;
+        log::info!("Bind mounted host /etc/resolv.conf into the container");
+        Ok(())
+    }
+    // Runs after the pivot, so paths are the container's own.
+    fn install_resolv_conf(content: &str, ephemeral: bool) -> ContainerResult<()> {
+        let target = Self::resolve_in_rootfs(Path::new("/"), Path::new("/etc/resolv.conf"))
+            .context("resolving /etc/resolv.conf")?;
+        if !ephemeral {
+            match fs::write(&target, content) {
+                Ok(()) => {
+                    log::info!("Wrote {target:?}");
+                    return Ok(());
+                }
+                Err(e) if e.raw_os_error() == Some(Errno::EROFS as i32) => {
+                    log::warn!(
+                        "Rootfs is read-only, bind mounting a generated resolv.conf instead"
+                    );
+                }
+                Err(e) => {
+                    return Err(ContainerError::filesystem_setup(format!(
+                        "Failed to write {target:?}: {e}"
+                    )));
+                }
+            }
+        }
+        Self::bind_generated_file(&target, content)?;
+        log::info!("Bind mounted an in-memory resolv.conf over {target:?}");
+        Ok(())
+    }
+    // /etc/hostname and /etc/hosts are always bind mounts of generated files,
+    // so a shared or read-only rootfs is never modified.
+    fn install_hosts_files(hosts: &HostsConfig) -> ContainerResult<()> {
+        if let Some(hostname) = &hosts.hostname {
+            let target = Self::resolve_in_rootfs(Path::new("/"), Path::new("/etc/hostname"))?;
+            Self::bind_generated_file(&target, &format!("{hostname}\n"))?;
+        }
+        if hosts.hostname.is_none() && hosts.extra_hosts.is_empty() {
+            return Ok(());
+        }
+        let target = Self::resolve_in_rootfs(Path::new("/"), Path::new("/etc/hosts"))?;
+        let existing = fs::read_to_string(&target).unwrap_or_else(|_| {
+            "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n".into()
+        });
+        Self::bind_generated_file(&target, &hosts.render(&existing))?;
+        log::info!("Installed generated /etc/hostname and /etc/hosts");
+        Ok(())
+    }
+    // Generated files are staged on a throwaway tmpfs stacked on the old root,
+    // the one directory guaranteed to exist between the pivot and its cleanup,
+    // then bind mounted into place. Detaching the staging tmpfs leaves the bind
+    // mount (and its backing memory) alive, and the rootfs is never touched.
+    fn bind_generated_file(target: &Path, content: &str) -> ContainerResult<()> {
+        let staging = Path::new(PUT_OLD);
+        mount(
+            Some("tmpfs"),
+            staging,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            Some("size=64k,mode=0755"),
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to mount staging tmpfs: {e}"))
+        })?;
+        let result = Self::bind_staged_file(staging, target, content);
+        if let Err(e) = umount2(staging, MntFlags::MNT_DETACH) {
+            log::warn!("Failed to detach staging tmpfs: {e}");
+        }
+        result
+    }
+    fn bind_staged_file(staging: &Path, target: &Path, content: &str) -> ContainerResult<()> {
+        let name = target.file_name().unwrap_or(target.as_os_str());
+        let source = staging.join(name);
+        fs::write(&source, content)?;
+        if !target.exists() {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::File::create(target).map_err(|e| {
+                ContainerError::filesystem_setup(format!(
+                    "Failed to create mount point {target:?}: {e}"
+                ))
+            })?;
+        }
+        mount(
+            Some(&source),
+            target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to bind mount over {target:?}: {e}"))
+        })?;
+        Ok(())
+    }
+    // Only global keys show host values here: namespaced ones (kernel.hostname,
+    // net.*) are resolved against the reader's namespaces by the kernel.
+    fn bind_host_proc_sys(rootfs_path: &Path) -> ContainerResult<()> {
+        let target = rootfs_path.join(HOST_PROC_SYS.trim_start_matches('/'));
+        fs::create_dir_all(&target).map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to create {HOST_PROC_SYS}: {e}"))
+        })?;
+        mount(
+            Some("/proc/sys"),
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to bind mount host /proc/sys: {e}"))
+        })?;
+        mount(
+            None::<&str>,
+            &target,
+            None::<&str>,
+            MsFlags::MS_BIND
+                | MsFlags::MS_REMOUNT
+                | MsFlags::MS_RDONLY
+                | MsFlags::MS_NOSUID
+                | MsFlags::MS_NODEV
+                | MsFlags::MS_NOEXEC,
+            None::<&str>,
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!(
+                "Failed to remount {HOST_PROC_SYS} read-only: {e}"
+            ))
+        })?;
+        log::info!("Exposed host /proc/sys read-only at {HOST_PROC_SYS}");
+        Ok(())
+    }
+    // Files are hidden behind /dev/null and directories behind an empty
+    // read-only tmpfs. Paths the kernel or rootfs doesn't have are skipped.
+    fn mask_paths(paths: &[PathBuf]) -> ContainerResult<()> {
+        for path in paths {
+            let Ok(meta) = fs::metadata(path) else {
+                continue;
+            };
+            let result = if meta.is_dir() {
+                mount(
+                    Some("tmpfs"),
+                    path,
+                    Some("tmpfs"),
+                    MsFlags::MS_RDONLY,
+                    Some("size=0"),
+                )
+            } else {
+                mount(
+                    Some("/dev/null"),
+                    path,
+                    None::<&str>,
+                    MsFlags::MS_BIND,
+                    None::<&str>,
+                )
+            };
+            result.map_err(|e| {
+                ContainerError::filesystem_setup(format!("Failed to mask {path:?}: {e}"))
+            })?;
+            log::debug!("Masked {path:?}");
+        }
+        Ok(())
+    }
+    fn mount_tmpfs(tmpfs: &TmpfsMount) -> ContainerResult<()> {
+        let target = Self::resolve_in_rootfs(Path::new("/"), &tmpfs.path)?;
+        fs::create_dir_all(&target).map_err(|e| {
+            ContainerError::filesystem_setup(format!(
+                "Failed to create tmpfs mount point {target:?}: {e}"
+            ))
+        })?;
+        mount(
+            Some("tmpfs"),
+            &target,
+            Some("tmpfs"),
+            tmpfs.flags,
+            Some(tmpfs.data.as_str()),
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to mount tmpfs at {target:?}: {e}"))
+        })?;
+        log::info!("Mounted tmpfs at {target:?} ({})", tmpfs.data);
+        Ok(())
+    }
+    fn mount_overlay(overlay: &OverlayDirs) -> ContainerResult<()> {
+        // Built from the raw paths so non-UTF-8 names survive.
+        let mut options = OsString::from("lowerdir=");
+        options.push(&overlay.lower);
+        options.push(",upperdir=");
+        options.push(&overlay.upper);
+        options.push(",workdir=");
+        options.push(&overlay.work);
+        mount(
+            Some("overlay"),
+            &overlay.merged,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_os_str()),
+        )
+        .map_err(|e| {
+            let hint = if e == Errno::EINVAL {
+                " (the state directory's filesystem may not support overlay upper layers)"
+            } else {
+                ""
+            };
+            ContainerError::filesystem_setup(format!("Failed to mount overlay: {e}{hint}"))
+        })?;
+        log::info!(
+            "Mounted overlay of {:?} at {:?}",
+            overlay.lower,
+            overlay.merged
+        );
+        Ok(())
+    }
     fn mount_proc(rootfs_path: &Path) -> ContainerResult<()> {
         let proc_path = rootfs_path.join("proc");
         if !proc_path.exists() {
             fs::create_dir_all(&proc_path).map_err(|e| ContainerError::Filesystem {
-                message: format!("Failed to create /proc directory: {e}"),
+                detail: ErrorDetail::new("Failed to create")
+                    .path(&proc_path)
+                    .source(e),
             })?;
         }
         mount(
@@ -62,7 +1147,9 @@ impl FilesystemManager {
             None::<&str>,
         )
         .map_err(|e| ContainerError::Filesystem {
-            message: format!("Failed to mount proc: {e}"),
+            detail: ErrorDetail::new("Failed to mount proc on")
+                .path(&proc_path)
+                .source(e),
         })
         .context("mounting proc filesystem")?;
         log::info!("Mounted proc filesystem");
@@ -70,35 +1157,282 @@ impl FilesystemManager {
     }
     fn mount_sysfs(rootfs_path: &Path) -> ContainerResult<()> {
         let sys_path = rootfs_path.join("sys");
-        if sys_path.exists() {
-            if let Err(e) = mount(
+        if sys_path.exists()
+            && let Err(e) = mount(
                 Some("sysfs"),
                 &sys_path,
                 Some("sysfs"),
                 MsFlags::empty(),
                 None::<&str>,
-            ) {
-                log::warn!("Failed to mount sysfs: {e}, continuing anyway")
-            }
+            )
+        {
+            log::warn!("Failed to mount sysfs: {e}, continuing anyway")
         }
         log::debug!("Mounted sysfs filesystem");
         Ok(())
     }
-    fn mount_devtmpfs(rootfs_path: &Path) -> ContainerResult<()> {
+    fn mount_devtmpfs(rootfs_path: &Path, host_dev: &Path) -> ContainerResult<()> {
         let dev_path = rootfs_path.join("dev");
         if !dev_path.exists() {
             return Ok(());
         }
-        if let Err(e) = mount(
+        match mount(
             Some("devtmpfs"),
             &dev_path,
             Some("devtmpfs"),
             MsFlags::empty(),
             None::<&str>,
         ) {
-            log::warn!("Failed to mount devtmpfs: {e}, continuing anyway");
+            Ok(()) => log::debug!("Mounted devtmpfs filesystem"),
+            Err(e) => {
+                log::warn!("Failed to mount devtmpfs: {e}, creating a minimal /dev instead");
+                Self::populate_minimal_dev(&dev_path, host_dev)?;
+            }
+        }
+        Ok(())
+    }
+    // Common in user namespaces and nested containers. Nodes are created with
+    // mknod where allowed and otherwise bind mounted from the host's /dev,
+    // which is still reachable under the old root at this point.
+    fn populate_minimal_dev(dev_path: &Path, host_dev: &Path) -> ContainerResult<()> {
+        mount(
+            Some("tmpfs"),
+            dev_path,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("mode=0755"),
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to mount /dev tmpfs: {e}"))
+        })?;
+        for (name, major, minor) in DEV_NODES {
+            let path = dev_path.join(name);
+            match mknod(
+                &path,
+                SFlag::S_IFCHR,
+                Mode::from_bits_truncate(0o666),
+                makedev(major, minor),
+            ) {
+                // mknod is subject to the umask.
+                Ok(()) => fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?,
+                Err(e) => {
+                    log::debug!("mknod {path:?} failed: {e}, bind mounting the host's node");
+                    Self::bind_host_device(&host_dev.join(name), &path)?;
+                }
+            }
+        }
+        for (name, target) in [
+            ("fd", "/proc/self/fd"),
+            ("stdin", "/proc/self/fd/0"),
+            ("stdout", "/proc/self/fd/1"),
+            ("stderr", "/proc/self/fd/2"),
+        ] {
+            symlink(target, dev_path.join(name))?;
+        }
+        log::info!("Populated a minimal /dev");
+        Ok(())
+    }
+    // A --device node: left alone if devtmpfs already has it in place,
+    // otherwise made with mknod, or bound from the host where mknod is not
+    // permitted (in a user namespace).
+    fn create_device(device: &HostDevice, switch: RootSwitch) -> ContainerResult<()> {
+        let target = &device.spec.container;
+        let failed = |e: &dyn std::fmt::Display| {
+            ContainerError::filesystem_setup(format!(
+                "Failed to create device {}: {e}",
+                target.display()
+            ))
+        };
+        match fs::symlink_metadata(target) {
+            Ok(meta) if meta.rdev() == device.rdev && meta.mode() & 0o7777 == device.mode => {
+                log::debug!("Device {target:?} is already in place");
+                return Ok(());
+            }
+            Ok(_) => fs::remove_file(target).map_err(|e| failed(&e))?,
+            Err(_) => {}
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|e| failed(&e))?;
+        }
+        let kind = if device.kind == 'b' {
+            SFlag::S_IFBLK
+        } else {
+            SFlag::S_IFCHR
+        };
+        match mknod(
+            target,
+            kind,
+            Mode::from_bits_truncate(device.mode),
+            device.rdev,
+        ) {
+            // mknod is subject to the umask.
+            Ok(()) => {
+                fs::set_permissions(target, fs::Permissions::from_mode(device.mode))
+                    .map_err(|e| failed(&e))?;
+                log::info!("Created device {target:?}");
+            }
+            Err(e) => {
+                let source = switch.host_path(&device.spec.host).ok_or_else(|| {
+                    failed(&format!(
+                        "mknod failed ({e}) and only host devices under /dev can be bound \
+                         in with --no-pivot"
+                    ))
+                })?;
+                log::debug!("mknod {target:?} failed: {e}, bind mounting {source:?}");
+                File::create(target).map_err(|e| failed(&e))?;
+                mount(
+                    Some(&source),
+                    target,
+                    None::<&str>,
+                    MsFlags::MS_BIND,
+                    None::<&str>,
+                )
+                .map_err(|e| failed(&e))?;
+                log::info!("Bound host device {:?} to {target:?}", device.spec.host);
+            }
+        }
+        Ok(())
+    }
+    fn bind_host_device(source: &Path, target: &Path) -> ContainerResult<()> {
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        if !source.exists() {
+            log::warn!("Host has no /dev/{name}, leaving it out of the container");
+            return Ok(());
+        }
+        fs::File::create(target)?;
+        mount(
+            Some(source),
+            target,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to bind mount /dev/{name}: {e}"))
+        })?;
+        Ok(())
+    }
+    // The init already sits in its cgroup, so with a cgroup namespace the
+    // container sees its own subtree as the root of the hierarchy.
+    fn mount_cgroup2(read_write: bool) -> ContainerResult<()> {
+        let cgroup_path = Path::new("/sys/fs/cgroup");
+        if !cgroup_path.is_dir() {
+            log::debug!("No /sys/fs/cgroup in the container, not mounting cgroup2");
+            return Ok(());
+        }
+        let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+        if !read_write {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        if let Err(e) = mount(
+            Some("cgroup2"),
+            cgroup_path,
+            Some("cgroup2"),
+            flags,
+            None::<&str>,
+        ) {
+            log::warn!("Failed to mount cgroup2 at /sys/fs/cgroup: {e}, continuing anyway");
+            return Ok(());
+        }
+        log::debug!(
+            "Mounted cgroup2 {}",
+            if read_write {
+                "read-write"
+            } else {
+                "read-only"
+            }
+        );
+        Ok(())
+    }
+    // A private devpts instance, so PTYs allocated through /dev/ptmx after the
+    // pivot belong to the container. /dev may be the host's devtmpfs, so an
+    // existing /dev/ptmx is covered with a bind mount rather than replaced.
+    fn mount_devpts() -> ContainerResult<()> {
+        let pts_path = Path::new("/dev/pts");
+        fs::create_dir_all(pts_path).map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to create /dev/pts: {e}"))
+        })?;
+        mount(
+            Some("devpts"),
+            pts_path,
+            Some("devpts"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("newinstance,ptmxmode=0666,mode=0620,gid=5"),
+        )
+        .map_err(|e| ContainerError::filesystem_setup(format!("Failed to mount devpts: {e}")))?;
+        let ptmx = Path::new("/dev/ptmx");
+        if ptmx.symlink_metadata().is_ok() {
+            mount(
+                Some("/dev/pts/ptmx"),
+                ptmx,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .map_err(|e| {
+                ContainerError::filesystem_setup(format!("Failed to bind mount /dev/ptmx: {e}"))
+            })?;
+        } else {
+            symlink("pts/ptmx", ptmx)?;
         }
-        log::debug!("Mounted devtmpfs filesystem");
+        log::debug!("Mounted devpts instance");
+        Ok(())
+    }
+    // A private instance, so shm_open neither fails on a missing /dev/shm nor
+    // shares segments with the host through devtmpfs.
+    fn mount_shm(size: u64) -> ContainerResult<()> {
+        let shm_path = Path::new("/dev/shm");
+        fs::create_dir_all(shm_path).map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to create /dev/shm: {e}"))
+        })?;
+        mount(
+            Some("shm"),
+            shm_path,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            Some(format!("mode=1777,size={size}").as_str()),
+        )
+        .map_err(|e| ContainerError::filesystem_setup(format!("Failed to mount /dev/shm: {e}")))?;
+        log::debug!("Mounted /dev/shm ({size} bytes)");
+        Ok(())
+    }
+    // POSIX shared memory lives in /dev/shm, so sharing the host's IPC
+    // namespace means sharing its segments there too.
+    fn bind_host_shm(host_dev: &Path) -> ContainerResult<()> {
+        let shm_path = Path::new("/dev/shm");
+        fs::create_dir_all(shm_path).map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to create /dev/shm: {e}"))
+        })?;
+        mount(
+            Some(&host_dev.join("shm")),
+            shm_path,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to bind mount host /dev/shm: {e}"))
+        })?;
+        log::debug!("Bound the host's /dev/shm");
+        Ok(())
+    }
+    // An mqueue mount shows the queues of the IPC namespace that mounted it.
+    fn mount_mqueue() -> ContainerResult<()> {
+        let mqueue_path = Path::new("/dev/mqueue");
+        fs::create_dir_all(mqueue_path).map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to create /dev/mqueue: {e}"))
+        })?;
+        mount(
+            Some("mqueue"),
+            mqueue_path,
+            Some("mqueue"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None::<&str>,
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to mount /dev/mqueue: {e}"))
+        })?;
+        log::debug!("Mounted mqueue filesystem");
         Ok(())
     }
     // fn pivot_root(rootfs_path: &Path) -> ContainerResult<()> {
@@ -134,19 +1468,13 @@ impl FilesystemManager {
     //     log::debug!("Root pivot completed successfully");
     //     Ok(())
     // }
-    fn pivot_root(rootfs_path: &Path) -> ContainerResult<()> {
+    fn pivot_root(
+        rootfs_path: &Path,
+        no_pivot: bool,
+        propagation: Propagation,
+    ) -> ContainerResult<RootSwitch> {
         log::info!("Pivoting root to: {rootfs_path:?}");
 
-        // Alternative: Remount with MS_SLAVE first, then MS_PRIVATE
-        mount(
-            None::<&str>,
-            "/",
-            None::<&str>,
-            MsFlags::MS_SLAVE | MsFlags::MS_REC,
-            None::<&str>,
-        )
-        .ok(); // Ignore errors, best effort
-
         mount(
             Some(rootfs_path),
             rootfs_path,
@@ -158,26 +1486,32 @@ impl FilesystemManager {
             ContainerError::filesystem_setup(format!("Failed to bind mount rootfs: {e}"))
         })?;
 
-        mount(
-            None::<&str>,
-            rootfs_path,
-            None::<&str>,
-            MsFlags::MS_PRIVATE | MsFlags::MS_REC,
-            None::<&str>,
-        )
-        .map_err(|e| {
-            ContainerError::filesystem_setup(format!("Failed to make mount private: {e}"))
-        })?;
+        // pivot_root refuses a shared new root; rshared is applied once the
+        // rootfs is "/", and the bind of its private mount is private already.
+        if propagation != Propagation::Rshared {
+            mount(
+                None::<&str>,
+                rootfs_path,
+                None::<&str>,
+                propagation.flags(),
+                None::<&str>,
+            )
+            .map_err(|e| {
+                ContainerError::filesystem_setup(format!("Failed to make mount {propagation}: {e}"))
+            })?;
+        }
 
         // Change to the new root
         chdir(rootfs_path)
             .map_err(|e| ContainerError::Filesystem {
-                message: format!("chdir to rootfs failed: {e}"),
+                detail: ErrorDetail::new("Failed to chdir to")
+                    .path(rootfs_path)
+                    .source(e),
             })
             .context("changing to rootfs directory")?;
 
         // Create the directory for the old root inside the new root
-        let put_old_name = "oldroot";
+        let put_old_name = PUT_OLD.trim_start_matches('/');
         if !Path::new(put_old_name).exists() {
             fs::create_dir_all(put_old_name)
                 .map_err(|e| {
@@ -186,32 +1520,563 @@ impl FilesystemManager {
                 .context("creating oldroot directory")?;
         }
 
+        if no_pivot {
+            return Self::chroot_into_cwd();
+        }
+
         // Pivot root using "." for new_root since we're already in it
-        pivot_root(".", put_old_name)
-            .map_err(|e| ContainerError::Filesystem {
-                message: format!("pivot_root failed: {e}"),
-            })
-            .context("pivoting root filesystem")?;
+        match pivot_root(".", put_old_name) {
+            Ok(()) => {}
+            // The rootfs lives on the initramfs or another mount pivot_root
+            // cannot move away from.
+            Err(Errno::EINVAL) => {
+                log::warn!("pivot_root failed with EINVAL, falling back to chroot");
+                return Self::chroot_into_cwd();
+            }
+            Err(e) => {
+                return Err(ContainerError::Filesystem {
+                    detail: ErrorDetail::new("pivot_root failed").source(e),
+                })
+                .context("pivoting root filesystem");
+            }
+        }
 
         // Change to the new root directory
         chdir("/")
             .map_err(|e| ContainerError::filesystem_setup(format!("chdir to new root failed: {e}")))
             .context("changing to new root directory")?;
 
-        // Cleanup
-        Self::cleanup_old_root(Path::new("/oldroot"))?;
-
         log::debug!("Root pivot completed successfully");
+        Ok(RootSwitch::Pivot)
+    }
+    // With the host's mount namespace every mount would land on the host, so
+    // the rootfs is used exactly as it is on disk: /proc, /dev and the rest
+    // are whatever it brings along.
+    fn enter_shared_rootfs(rootfs_path: &Path, config: &FilesystemConfig) -> ContainerResult<()> {
+        log::warn!("Sharing the host's mount namespace: nothing is mounted in the rootfs");
+        for copy in &config.copies {
+            Self::copy_into_rootfs(rootfs_path, copy, config.copy_chown)?;
+        }
+        chdir(rootfs_path)
+            .map_err(|e| ContainerError::Filesystem {
+                detail: ErrorDetail::new("Failed to chdir to")
+                    .path(rootfs_path)
+                    .source(e),
+            })
+            .context("changing to rootfs directory")?;
+        chroot(".").map_err(|e| ContainerError::filesystem_setup(format!("chroot failed: {e}")))?;
+        chdir("/")
+            .map_err(|e| ContainerError::filesystem_setup(format!("chdir to new root failed: {e}")))
+            .context("changing to new root directory")?;
+        Ok(())
+    }
+    // The host's mounts stay in this mount namespace underneath the chroot,
+    // and a process with CAP_SYS_CHROOT can climb back out to them.
+    fn chroot_into_cwd() -> ContainerResult<RootSwitch> {
+        log::warn!(
+            "Using chroot instead of pivot_root: the host's mounts remain reachable, \
+             mount isolation is weaker"
+        );
+        mount(
+            Some("/dev"),
+            PUT_OLD.trim_start_matches('/'),
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to bind mount host /dev: {e}"))
+        })?;
+        chroot(".").map_err(|e| ContainerError::filesystem_setup(format!("chroot failed: {e}")))?;
+        chdir("/")
+            .map_err(|e| ContainerError::filesystem_setup(format!("chdir to new root failed: {e}")))
+            .context("changing to new root directory")?;
+        Ok(RootSwitch::Chroot)
+    }
+    // Inside a user namespace the kernel refuses a remount that would clear
+    // flags locked by the original mount, so the current ones are carried over.
+    fn remount_readonly(path: &Path) -> ContainerResult<()> {
+        let flags =
+            Self::mount_flags(path)? | MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+        mount(None::<&str>, path, None::<&str>, flags, None::<&str>).map_err(|e| {
+            ContainerError::filesystem_setup(format!("Failed to remount {path:?} read-only: {e}"))
+        })
+    }
+    // Per-mount flags of the topmost mount at `path`, from /proc/self/mountinfo.
+    fn mount_flags(path: &Path) -> ContainerResult<MsFlags> {
+        let mountinfo = fs::read("/proc/self/mountinfo")?;
+        // Later lines are stacked on top of earlier ones.
+        let options = mountinfo
+            .rsplit(|b| *b == b'\n')
+            .find_map(|line| {
+                let mut fields = line.split(|b| *b == b' ');
+                let mount_point = fields.nth(4)?;
+                let options = fields.next()?;
+                (unescape_mount_path(mount_point) == path).then_some(options)
+            })
+            .ok_or_else(|| {
+                ContainerError::filesystem_setup(format!("{path:?} is not a mount point"))
+            })?;
+        let mut flags = MsFlags::empty();
+        for option in options.split(|b| *b == b',') {
+            flags |= match option {
+                b"ro" => MsFlags::MS_RDONLY,
+                b"nosuid" => MsFlags::MS_NOSUID,
+                b"nodev" => MsFlags::MS_NODEV,
+                b"noexec" => MsFlags::MS_NOEXEC,
+                b"noatime" => MsFlags::MS_NOATIME,
+                b"nodiratime" => MsFlags::MS_NODIRATIME,
+                b"relatime" => MsFlags::MS_RELATIME,
+                _ => MsFlags::empty(),
+            };
+        }
+        Ok(flags)
+    }
+    // The mounts in /proc/self/mountinfo with whether each is shared, in
+    // mount order.
+    fn mount_sharing() -> ContainerResult<Vec<(PathBuf, bool)>> {
+        let mountinfo = fs::read("/proc/self/mountinfo")?;
+        Ok(mountinfo
+            .split(|b| *b == b'\n')
+            .filter_map(|line| {
+                let mut fields = line.split(|b| *b == b' ');
+                let mount_point = unescape_mount_path(fields.nth(4)?);
+                // Optional fields run from after the options to the "-".
+                let shared = fields
+                    .skip(1)
+                    .take_while(|field| *field != b"-")
+                    .any(|field| field.starts_with(b"shared:"));
+                Some((mount_point, shared))
+            })
+            .collect())
+    }
+    // With rshared the rootfs's own mount is made private (below), so what
+    // propagates to the host are the mounts under it that the host shares.
+    fn check_shared_below(rootfs: &Path) -> ContainerResult<()> {
+        let shared = Self::mount_sharing()?
+            .into_iter()
+            .any(|(mount_point, shared)| {
+                shared && mount_point != rootfs && mount_point.starts_with(rootfs)
+            });
+        if !shared {
+            return Err(ContainerError::invalid_configuration(format!(
+                "--rootfs-propagation rshared: no mount under {} is shared on the host, so \
+                 nothing would propagate back to it; make them shared first \
+                 (mount --make-rshared)",
+                rootfs.display()
+            )));
+        }
+        Ok(())
+    }
+    // The mount holding the rootfs stops being shared: pivot_root refuses a
+    // new root whose parent mount is, and the container's mounts would show
+    // up on the host through it.
+    fn make_parent_mount_private(rootfs: &Path) -> ContainerResult<()> {
+        let Some((mount_point, shared)) = Self::mount_sharing()?
+            .into_iter()
+            .filter(|(mount_point, _)| rootfs.starts_with(mount_point))
+            .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+        else {
+            return Ok(());
+        };
+        if shared {
+            mount(
+                None::<&str>,
+                &mount_point,
+                None::<&str>,
+                MsFlags::MS_PRIVATE,
+                None::<&str>,
+            )
+            .map_err(|e| {
+                ContainerError::filesystem_setup(format!(
+                    "Failed to make {} private: {e}",
+                    mount_point.display()
+                ))
+            })?;
+            log::debug!("Made the rootfs's mount {mount_point:?} private");
+        }
         Ok(())
     }
-    fn cleanup_old_root(put_old: &Path) -> ContainerResult<()> {
-        if let Err(e) = umount2("/oldroot", MntFlags::MNT_DETACH) {
-            log::warn!("Failed to unmount old root: {e}, but continuing")
+    fn remount_root_readonly() -> ContainerResult<()> {
+        Self::remount_readonly(Path::new("/"))?;
+        log::info!("Remounted container root read-only");
+        Ok(())
+    }
+    // OCI readonlyPaths: each path is bind mounted onto itself so it can be
+    // remounted read-only on its own. Missing paths are skipped.
+    fn readonly_paths(paths: &[PathBuf]) -> ContainerResult<()> {
+        for path in paths {
+            if fs::symlink_metadata(path).is_err() {
+                continue;
+            }
+            mount(
+                Some(path),
+                path,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None::<&str>,
+            )
+            .map_err(|e| {
+                ContainerError::filesystem_setup(format!("Failed to bind mount {path:?}: {e}"))
+            })?;
+            Self::remount_readonly(path)?;
+            // The recursive bind carried along the mounts below, which a
+            // remount of the top one leaves writable.
+            let mut below: Vec<PathBuf> = Self::mount_sharing()?
+                .into_iter()
+                .map(|(mount_point, _)| mount_point)
+                .filter(|mount_point| mount_point != path && mount_point.starts_with(path))
+                .collect();
+            below.dedup();
+            for mount_point in &below {
+                Self::remount_readonly(mount_point)?;
+            }
+            log::debug!("Made {path:?} read-only");
         }
-        if let Err(e) = fs::remove_dir_all("/oldroot") {
-            log::warn!("Failed to remove old root directory: {e}")
+        Ok(())
+    }
+    // Most software expects some writable scratch space, even on an immutable
+    // root (/dev/shm always has its own tmpfs). Mount points cannot be created
+    // any more, so missing ones are skipped.
+    fn mount_scratch_tmpfs(user_mounts: &[TmpfsMount]) -> ContainerResult<()> {
+        for (path, mode) in [("/tmp", "1777"), ("/run", "0755")] {
+            if user_mounts.iter().any(|m| m.path == Path::new(path)) {
+                continue;
+            }
+            if !Path::new(path).is_dir() {
+                log::warn!(
+                    "{path} does not exist in the read-only root, not mounting a tmpfs there"
+                );
+                continue;
+            }
+            mount(
+                Some("tmpfs"),
+                path,
+                Some("tmpfs"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some(format!("mode={mode}").as_str()),
+            )
+            .map_err(|e| {
+                ContainerError::filesystem_setup(format!("Failed to mount tmpfs at {path}: {e}"))
+            })?;
+            log::debug!("Mounted writable tmpfs at {path}");
         }
-        log::debug!("Old root cleanup completed");
         Ok(())
     }
+    // The directory is only removed once the unmount succeeded and only with
+    // rmdir, so nothing under a still-mounted old root can ever be deleted.
+    fn cleanup_old_root(put_old: &Path, keep: bool) -> ContainerResult<()> {
+        const RMDIR_ATTEMPTS: u32 = 5;
+        if keep {
+            log::info!("Keeping the old root mounted at {put_old:?}");
+            return Ok(());
+        }
+        // Otherwise unmounting the host's mounts that are shared with those
+        // under the rootfs (rshared) takes the latter along.
+        mount(
+            None::<&str>,
+            put_old,
+            None::<&str>,
+            MsFlags::MS_SLAVE | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .ok();
+        if let Err(e) = umount2(put_old, MntFlags::MNT_DETACH) {
+            log::warn!("Failed to unmount old root: {e}, leaving {put_old:?} in place");
+            return Ok(());
+        }
+        for attempt in 0..RMDIR_ATTEMPTS {
+            match fs::remove_dir(put_old) {
+                Ok(()) => {
+                    log::debug!("Old root cleanup completed");
+                    return Ok(());
+                }
+                Err(e) if e.raw_os_error() == Some(Errno::EBUSY as i32) => {
+                    std::thread::sleep(Duration::from_millis(10 << attempt));
+                }
+                Err(e) => {
+                    log::warn!("Failed to remove old root directory {put_old:?}: {e}");
+                    return Ok(());
+                }
+            }
+        }
+        log::warn!("Old root directory {put_old:?} still busy, leaving it in place");
+        Ok(())
+    }
+}
+
+// What the preflight check needs from an ELF header: the target machine and
+// the PT_INTERP path of a dynamically linked binary.
+struct ElfInfo {
+    machine: u16,
+    interpreter: Option<PathBuf>,
+}
+
+const PT_INTERP: u32 = 3;
+
+impl ElfInfo {
+    // None for anything that is not ELF (e.g. a script).
+    fn read(path: &Path) -> ContainerResult<Option<Self>> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; 64];
+        let len = file.read(&mut header)?;
+        if len < 52 || header[..4] != *b"\x7fELF" {
+            return Ok(None);
+        }
+        let is_64 = header[4] == 2;
+        let little_endian = header[5] == 1;
+        let u16_at = |bytes: &[u8], at: usize| {
+            let raw = [bytes[at], bytes[at + 1]];
+            if little_endian {
+                u16::from_le_bytes(raw)
+            } else {
+                u16::from_be_bytes(raw)
+            }
+        };
+        let u32_at = |bytes: &[u8], at: usize| {
+            let raw = bytes[at..at + 4].try_into().unwrap_or([0; 4]);
+            if little_endian {
+                u32::from_le_bytes(raw)
+            } else {
+                u32::from_be_bytes(raw)
+            }
+        };
+        let u64_at = |bytes: &[u8], at: usize| {
+            let raw = bytes[at..at + 8].try_into().unwrap_or([0; 8]);
+            if little_endian {
+                u64::from_le_bytes(raw)
+            } else {
+                u64::from_be_bytes(raw)
+            }
+        };
+        let machine = u16_at(&header, 18);
+        let (phoff, phentsize, phnum) = if is_64 {
+            (
+                u64_at(&header, 32),
+                u16_at(&header, 54),
+                u16_at(&header, 56),
+            )
+        } else {
+            (
+                u64::from(u32_at(&header, 28)),
+                u16_at(&header, 42),
+                u16_at(&header, 44),
+            )
+        };
+        let mut interpreter = None;
+        let mut entry = vec![0u8; usize::from(phentsize)];
+        if entry.len() >= if is_64 { 40 } else { 20 } {
+            for index in 0..u64::from(phnum) {
+                file.seek(SeekFrom::Start(phoff + index * u64::from(phentsize)))?;
+                file.read_exact(&mut entry)?;
+                if u32_at(&entry, 0) != PT_INTERP {
+                    continue;
+                }
+                let (offset, size) = if is_64 {
+                    (u64_at(&entry, 8), u64_at(&entry, 32))
+                } else {
+                    (u64::from(u32_at(&entry, 4)), u64::from(u32_at(&entry, 16)))
+                };
+                let mut path = vec![0u8; size.min(4096) as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut path)?;
+                let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+                path.truncate(end);
+                interpreter = Some(PathBuf::from(OsString::from_vec(path)));
+                break;
+            }
+        }
+        Ok(Some(Self {
+            machine,
+            interpreter,
+        }))
+    }
+}
+
+// e_machine values of the architectures Rust reports in std::env::consts::ARCH.
+const ELF_MACHINES: [(&str, u16); 10] = [
+    ("x86", 3),
+    ("x86_64", 62),
+    ("arm", 40),
+    ("aarch64", 183),
+    ("riscv64", 243),
+    ("powerpc", 20),
+    ("powerpc64", 21),
+    ("s390x", 22),
+    ("mips", 8),
+    ("loongarch64", 258),
+];
+
+fn elf_machine_for_arch(arch: &str) -> Option<u16> {
+    ELF_MACHINES
+        .iter()
+        .find(|(name, _)| *name == arch)
+        .map(|(_, machine)| *machine)
+}
+
+fn elf_machine_name(machine: u16) -> String {
+    ELF_MACHINES
+        .iter()
+        .find(|(_, value)| *value == machine)
+        .map_or_else(
+            || format!("ELF machine {machine}"),
+            |(name, _)| name.to_string(),
+        )
+}
+
+// 32-bit binaries the 64-bit kernel can usually run natively.
+fn elf_machine_compatible(host: u16, binary: u16) -> bool {
+    matches!((host, binary), (62, 3) | (183, 40))
+}
+
+fn open_dir_nofollow(dir: Option<&OwnedFd>, path: &Path) -> Result<OwnedFd, Errno> {
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    match dir {
+        Some(dir) => openat(dir, path, flags, Mode::empty()),
+        None => open(path, flags, Mode::empty()),
+    }
+}
+
+fn copy_file(
+    dir: &OwnedFd,
+    name: &Path,
+    source: &Path,
+    meta: &fs::Metadata,
+    chown: Option<(Uid, Gid)>,
+) -> ContainerResult<()> {
+    let mode = Mode::from_bits_truncate(meta.mode() & 0o7777);
+    let target = openat(
+        dir,
+        name,
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        mode,
+    )?;
+    let mut target = File::from(target);
+    std::io::copy(&mut File::open(source)?, &mut target)?;
+    // Ownership first: chown clears the set-id bits.
+    if let Some((uid, gid)) = chown {
+        fchown(&target, Some(uid), Some(gid))?;
+    }
+    fchmod(&target, mode)?;
+    Ok(())
+}
+
+// Symlinks inside a copied directory are recreated as they are, not followed.
+fn copy_dir(
+    parent: &OwnedFd,
+    name: &Path,
+    source: &Path,
+    chown: Option<(Uid, Gid)>,
+) -> ContainerResult<()> {
+    let mode = Mode::from_bits_truncate(fs::metadata(source)?.mode() & 0o7777);
+    match mkdirat(parent, name, Mode::from_bits_truncate(0o700)) {
+        Ok(()) | Err(Errno::EEXIST) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let dir = open_dir_nofollow(Some(parent), name)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = PathBuf::from(entry.file_name());
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.is_dir() {
+            copy_dir(&dir, &name, &path, chown)?;
+        } else if meta.is_file() {
+            copy_file(&dir, &name, &path, &meta, chown)?;
+        } else if meta.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            match unlinkat(&dir, &name, UnlinkatFlags::NoRemoveDir) {
+                Ok(()) | Err(Errno::ENOENT) => {}
+                Err(e) => return Err(e.into()),
+            }
+            symlinkat(&target, &dir, &name)?;
+            if let Some((uid, gid)) = chown {
+                fchownat(
+                    &dir,
+                    &name,
+                    Some(uid),
+                    Some(gid),
+                    AtFlags::AT_SYMLINK_NOFOLLOW,
+                )?;
+            }
+        } else {
+            log::warn!("Not copying special file {}", path.display());
+        }
+    }
+    if let Some((uid, gid)) = chown {
+        fchown(&dir, Some(uid), Some(gid))?;
+    }
+    fchmod(&dir, mode)?;
+    Ok(())
+}
+
+// mountinfo escapes space, tab, newline and backslash as \ooo octal.
+pub fn unescape_mount_path(field: &[u8]) -> PathBuf {
+    let mut path = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let escaped = field
+            .get(i + 1..i + 4)
+            .filter(|_| field[i] == b'\\')
+            .and_then(|octal| std::str::from_utf8(octal).ok())
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match escaped {
+            Some(byte) => {
+                path.push(byte);
+                i += 4;
+            }
+            None => {
+                path.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tmpfs_options_split_into_flags_and_data() {
+        let tmpfs: TmpfsMount = "/run".parse().unwrap();
+        assert_eq!(tmpfs.path, Path::new("/run"));
+        assert_eq!(tmpfs.flags, MsFlags::MS_NOSUID | MsFlags::MS_NODEV);
+        assert_eq!(tmpfs.data, "");
+
+        let tmpfs: TmpfsMount = "/tmp:ro,noexec,size=64m,mode=1777,uid=1000"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            tmpfs.flags,
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_RDONLY | MsFlags::MS_NOEXEC
+        );
+        assert_eq!(tmpfs.data, "size=64m,mode=1777,uid=1000");
+
+        let tmpfs: TmpfsMount = "/scratch:noexec,exec".parse().unwrap();
+        assert!(!tmpfs.flags.contains(MsFlags::MS_NOEXEC));
+    }
+
+    #[test]
+    fn bad_tmpfs_specs_are_rejected() {
+        let error = |spec: &str| spec.parse::<TmpfsMount>().unwrap_err();
+        assert!(error("tmp").contains("must be absolute"));
+        assert!(error("/").contains("must be absolute and not /"));
+        assert!(error("/tmp:size=lots").contains("invalid size 'lots'"));
+        assert!(error("/tmp:mode=999").contains("invalid tmpfs mode '999'"));
+        assert!(error("/tmp:uid=root").contains("invalid tmpfs uid 'root'"));
+        assert!(error("/tmp:suid").contains("unsupported tmpfs option 'suid'"));
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("64M"), Ok(64 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("m").is_err());
+        assert!(parse_size("99999999999g").is_err());
+    }
 }
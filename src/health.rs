@@ -0,0 +1,346 @@
+use crate::error::{ContainerError, ContainerResult};
+use crate::namespace::CLONE_NEWTIME;
+use nix::errno::Errno;
+use nix::fcntl::{OFlag, open};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sched::{CloneFlags, setns};
+use nix::sys::signal::{Signal, kill};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::{ForkResult, Pid, fork, pipe2};
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsFd, AsRawFd};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Probe output kept for the log; the rest is read and dropped.
+const MAX_OUTPUT: usize = 4096;
+
+// Joined in this order; the mount namespace last, as it changes our root.
+const NAMESPACES: [(&str, CloneFlags); 7] = [
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("time", CLONE_NEWTIME),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HealthStatus::Starting => "starting",
+            HealthStatus::Healthy => "healthy",
+            HealthStatus::Unhealthy => "unhealthy",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HealthAction {
+    #[default]
+    None,
+    Kill,
+}
+
+impl FromStr for HealthAction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(HealthAction::None),
+            "kill" => Ok(HealthAction::Kill),
+            other => Err(format!("unknown health failure action '{other}'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    pub command: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub start_period: Duration,
+    pub on_failure: HealthAction,
+}
+
+// Told about every change of the health status.
+#[derive(Clone)]
+pub struct HealthListener(Arc<dyn Fn(HealthStatus) + Send + Sync>);
+
+impl HealthListener {
+    pub fn new(listener: impl Fn(HealthStatus) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(listener))
+    }
+}
+
+impl fmt::Debug for HealthListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HealthListener")
+    }
+}
+
+// Runs --health-cmd inside the container every interval. The status starts
+// out as `starting`, becomes `healthy` on the first passing probe and
+// `unhealthy` after `retries` failures in a row.
+#[derive(Debug)]
+pub struct HealthMonitor {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl HealthMonitor {
+    pub fn spawn(
+        pid: Pid,
+        config: HealthConfig,
+        env: Vec<CString>,
+        listener: Option<HealthListener>,
+    ) -> ContainerResult<Self> {
+        let argv = [
+            CString::new("/bin/sh")?,
+            CString::new("-c")?,
+            CString::new(config.command.as_str())?,
+        ];
+        let (stop, stop_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let notify = |status: HealthStatus| {
+                if let Some(listener) = &listener {
+                    (listener.0)(status);
+                }
+            };
+            notify(HealthStatus::Starting);
+            let mut status = HealthStatus::Starting;
+            let mut failures = 0;
+            // Nothing runs before the start period is over.
+            let mut delay = config.interval.max(config.start_period);
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(delay) {
+                delay = config.interval;
+                let passed = match probe(pid, &argv, &env, config.timeout) {
+                    Ok(result) if result.passed() => true,
+                    Ok(result) => {
+                        log::warn!("Health check failed: {result}");
+                        false
+                    }
+                    Err(e) => {
+                        log::warn!("Health check could not run: {e}");
+                        false
+                    }
+                };
+                failures = if passed { 0 } else { failures + 1 };
+                let next = if passed {
+                    HealthStatus::Healthy
+                } else if failures >= config.retries {
+                    HealthStatus::Unhealthy
+                } else {
+                    status
+                };
+                if next != status {
+                    log::info!("Container is {next}");
+                    status = next;
+                    notify(status);
+                }
+                if status == HealthStatus::Unhealthy && config.on_failure == HealthAction::Kill {
+                    log::warn!("Killing unhealthy container");
+                    let _ = kill(pid, Signal::SIGKILL);
+                    break;
+                }
+            }
+            log::debug!("Health monitor stopped");
+        });
+        Ok(Self { stop, handle })
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        if self.handle.join().is_err() {
+            log::warn!("Health monitor thread panicked");
+        }
+    }
+}
+
+struct ProbeResult {
+    status: Option<WaitStatus>,
+    output: Vec<u8>,
+}
+
+impl ProbeResult {
+    fn passed(&self) -> bool {
+        matches!(self.status, Some(WaitStatus::Exited(_, 0)))
+    }
+}
+
+impl fmt::Display for ProbeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(WaitStatus::Exited(_, code)) => write!(f, "exit status {code}")?,
+            Some(WaitStatus::Signaled(_, signal, _)) => write!(f, "killed by {signal}")?,
+            _ => f.write_str("timed out")?,
+        }
+        let output = String::from_utf8_lossy(&self.output);
+        let output = output.trim();
+        if !output.is_empty() {
+            write!(f, ": {output}")?;
+        }
+        Ok(())
+    }
+}
+
+// Runs the probe in the namespaces of the container init. setns() into a
+// mount namespace is refused to a multi-threaded process and a new PID
+// namespace only applies to children, so a forked helper joins the
+// namespaces and forks the probe itself.
+fn probe(
+    pid: Pid,
+    argv: &[CString],
+    env: &[CString],
+    timeout: Duration,
+) -> ContainerResult<ProbeResult> {
+    let mut namespaces = Vec::new();
+    // With --no-mount-ns the container's root is only a chroot, which joining
+    // namespaces does not reach.
+    let mut chroot_into = None;
+    for (name, flag) in NAMESPACES {
+        let path = format!("/proc/{pid}/ns/{name}");
+        // Namespaces the container shares with us, or the kernel lacks, need
+        // no joining.
+        let Ok(own) = std::fs::read_link(format!("/proc/self/ns/{name}")) else {
+            continue;
+        };
+        if std::fs::read_link(&path)? == own {
+            if flag == CloneFlags::CLONE_NEWNS {
+                chroot_into = Some(open(
+                    format!("/proc/{pid}/root").as_str(),
+                    OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                    Mode::empty(),
+                )?);
+            }
+            continue;
+        }
+        namespaces.push((
+            open(
+                path.as_str(),
+                OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )?,
+            flag,
+        ));
+    }
+    // Built before forking, which must not allocate in a threaded process.
+    let argv_ptrs: Vec<*const libc::c_char> = argv
+        .iter()
+        .map(|arg| arg.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect();
+    let env_ptrs: Vec<*const libc::c_char> = env
+        .iter()
+        .map(|var| var.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect();
+    let (output, output_tx) = pipe2(OFlag::O_CLOEXEC).map_err(|e| {
+        ContainerError::process_execution(format!("Failed to create probe pipe: {e}"))
+    })?;
+    let null = open(
+        "/dev/null",
+        OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+
+    match unsafe { fork()? } {
+        ForkResult::Child => unsafe {
+            for (fd, flag) in &namespaces {
+                if setns(fd, *flag).is_err() {
+                    libc::_exit(126);
+                }
+            }
+            if let Some(root) = &chroot_into
+                && (libc::fchdir(root.as_raw_fd()) != 0
+                    || libc::chroot(c".".as_ptr()) != 0
+                    || libc::chdir(c"/".as_ptr()) != 0)
+            {
+                libc::_exit(126);
+            }
+            let probe = libc::fork();
+            if probe == 0 {
+                // Dies with the helper, which is what a timeout kills.
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                libc::dup2(null.as_raw_fd(), 0);
+                libc::dup2(output_tx.as_raw_fd(), 1);
+                libc::dup2(output_tx.as_raw_fd(), 2);
+                libc::execve(argv_ptrs[0], argv_ptrs.as_ptr(), env_ptrs.as_ptr());
+                libc::_exit(127);
+            }
+            if probe < 0 {
+                libc::_exit(126);
+            }
+            let mut status = 0;
+            while libc::waitpid(probe, &mut status, 0) < 0 {}
+            if libc::WIFEXITED(status) {
+                libc::_exit(libc::WEXITSTATUS(status));
+            }
+            libc::_exit(128 + libc::WTERMSIG(status));
+        },
+        ForkResult::Parent { child } => {
+            drop(output_tx);
+            let output = read_output(File::from(output), timeout);
+            let status = match output {
+                Some(_) => loop {
+                    match waitpid(child, None) {
+                        Err(Errno::EINTR) => continue,
+                        result => break result.ok(),
+                    }
+                },
+                None => {
+                    let _ = kill(child, Signal::SIGKILL);
+                    let _ = waitpid(child, None);
+                    None
+                }
+            };
+            Ok(ProbeResult {
+                status,
+                output: output.unwrap_or_default(),
+            })
+        }
+    }
+}
+
+// Reads until the probe closes its output, or None once the timeout passes.
+fn read_output(mut pipe: File, timeout: Duration) -> Option<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(pipe.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(_) => return None,
+        }
+        match pipe.read(&mut buffer) {
+            Ok(0) | Err(_) => return Some(output),
+            Ok(n) => {
+                let room = MAX_OUTPUT.saturating_sub(output.len());
+                output.extend_from_slice(&buffer[..n.min(room)]);
+            }
+        }
+    }
+}
@@ -0,0 +1,546 @@
+use crate::archive::ExtractedRootfs;
+use crate::cli::ContainerConfig;
+use crate::error::{ContainerError, ContainerResult};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tar::{Archive, EntryType};
+
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+// Layers the runtime can apply; zstd and foreign layers are not among them.
+const LAYER_TYPES: [&str; 5] = [
+    "application/vnd.oci.image.layer.v1.tar",
+    "application/vnd.oci.image.layer.v1.tar+gzip",
+    "application/vnd.docker.image.rootfs.diff.tar",
+    "application/vnd.docker.image.rootfs.diff.tar.gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+];
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    // An OCI image layout directory (oci-layout, index.json, blobs/).
+    Oci,
+    // The tarball written by `docker save`.
+    DockerArchive,
+}
+
+// --image TRANSPORT:PATH[:TAG], e.g. oci:/images/alpine:3.20 or
+// docker-archive:alpine.tar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub format: ImageFormat,
+    pub path: PathBuf,
+    pub tag: Option<String>,
+}
+
+impl FromStr for ImageRef {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, rest) = match s.split_once(':') {
+            Some(("oci", rest)) => (ImageFormat::Oci, rest),
+            Some(("docker-archive", rest)) => (ImageFormat::DockerArchive, rest),
+            _ => {
+                return Err(format!(
+                    "invalid image '{s}', expected oci:PATH[:TAG] or docker-archive:PATH[:TAG]"
+                ));
+            }
+        };
+        // A tag never contains '/', so a colon in a directory name is no tag.
+        let (path, tag) = match rest.rsplit_once(':') {
+            Some((path, tag)) if !tag.is_empty() && !tag.contains('/') => (path, Some(tag)),
+            _ => (rest, None),
+        };
+        if path.is_empty() {
+            return Err(format!("invalid image '{s}': no path given"));
+        }
+        Ok(Self {
+            format,
+            path: PathBuf::from(path),
+            tag: tag.map(str::to_string),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+// One entry of a docker-archive's manifest.json.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Vec<String>,
+    layers: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ImageSpec {
+    #[serde(default)]
+    config: ImageConfig,
+}
+
+// The runtime defaults an image carries for its containers.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+// Where the image's files are read from.
+#[derive(Debug, Clone)]
+enum Source {
+    Layout(PathBuf),
+    Archive(TarIndex),
+}
+
+// A resolved image: its layers, bottom first, and its config.
+#[derive(Debug, Clone)]
+pub struct Image {
+    reference: String,
+    source: Source,
+    layers: Vec<String>,
+    pub config: ImageConfig,
+}
+
+impl fmt::Display for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} layer(s))", self.reference, self.layers.len())
+    }
+}
+
+impl Image {
+    pub fn load(image: &ImageRef) -> ContainerResult<Self> {
+        let reference = match &image.tag {
+            Some(tag) => format!("{}:{tag}", image.path.display()),
+            None => image.path.display().to_string(),
+        };
+        let mut loaded = match image.format {
+            ImageFormat::Oci => Self::load_layout(image)?,
+            ImageFormat::DockerArchive => Self::load_archive(image)?,
+        };
+        loaded.reference = reference;
+        log::debug!(
+            "Image {} has {} layer(s), config {:?}",
+            loaded.reference,
+            loaded.layers.len(),
+            loaded.config
+        );
+        Ok(loaded)
+    }
+
+    fn load_layout(image: &ImageRef) -> ContainerResult<Self> {
+        let source = Source::Layout(image.path.clone());
+        let index: Index = source.read_json("index.json")?;
+        let found: Vec<&str> = index
+            .manifests
+            .iter()
+            .filter_map(|manifest| manifest.annotations.get(REF_NAME))
+            .map(String::as_str)
+            .collect();
+        let candidates: Vec<Descriptor> = index
+            .manifests
+            .iter()
+            .filter(|manifest| match &image.tag {
+                Some(tag) => manifest.annotations.get(REF_NAME) == Some(tag),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        if candidates.is_empty() {
+            return Err(ContainerError::invalid_configuration(format!(
+                "No manifest tagged {} in {}; found {}",
+                image.tag.as_deref().unwrap_or_default(),
+                image.path.display(),
+                if found.is_empty() {
+                    "no tags".to_string()
+                } else {
+                    found.join(", ")
+                }
+            )));
+        }
+        let mut descriptor = select_platform(candidates, &image.path)?;
+        // A tag may point at an index of per-platform manifests.
+        loop {
+            match descriptor.media_type.as_str() {
+                OCI_INDEX | DOCKER_MANIFEST_LIST => {
+                    let nested: Index = source.read_json(&blob_path(&descriptor.digest)?)?;
+                    descriptor = select_platform(nested.manifests, &image.path)?;
+                }
+                OCI_MANIFEST | DOCKER_MANIFEST => break,
+                other => {
+                    return Err(ContainerError::invalid_configuration(format!(
+                        "Unsupported manifest media type '{other}' in {}; expected {OCI_MANIFEST} or {DOCKER_MANIFEST}",
+                        image.path.display()
+                    )));
+                }
+            }
+        }
+        let manifest: Manifest = source.read_json(&blob_path(&descriptor.digest)?)?;
+        let unsupported: Vec<&str> = manifest
+            .layers
+            .iter()
+            .map(|layer| layer.media_type.as_str())
+            .filter(|media_type| !LAYER_TYPES.contains(media_type))
+            .collect();
+        if !unsupported.is_empty() {
+            return Err(ContainerError::invalid_configuration(format!(
+                "Image {} has layers of unsupported media type(s) {}; supported are {}",
+                image.path.display(),
+                unsupported.join(", "),
+                LAYER_TYPES.join(", ")
+            )));
+        }
+        let spec: ImageSpec = source.read_json(&blob_path(&manifest.config.digest)?)?;
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| blob_path(&layer.digest))
+            .collect::<ContainerResult<_>>()?;
+        Ok(Self {
+            reference: String::new(),
+            source,
+            layers,
+            config: spec.config,
+        })
+    }
+
+    fn load_archive(image: &ImageRef) -> ContainerResult<Self> {
+        let source = Source::Archive(TarIndex::read(&image.path)?);
+        let manifests: Vec<ArchiveManifest> = source.read_json("manifest.json")?;
+        let found: Vec<String> = manifests
+            .iter()
+            .flat_map(|manifest| manifest.repo_tags.iter().cloned())
+            .collect();
+        let manifest = match &image.tag {
+            // Either the full repo:tag as docker saved it, or just the tag.
+            Some(tag) => manifests.into_iter().find(|manifest| {
+                manifest.repo_tags.iter().any(|repo_tag| {
+                    repo_tag == tag || repo_tag.rsplit_once(':').map(|(_, t)| t) == Some(tag)
+                })
+            }),
+            None if manifests.len() == 1 => manifests.into_iter().next(),
+            None => {
+                return Err(ContainerError::invalid_configuration(format!(
+                    "{} holds {} images ({}); pick one with docker-archive:PATH:TAG",
+                    image.path.display(),
+                    manifests.len(),
+                    found.join(", ")
+                )));
+            }
+        };
+        let manifest = manifest.ok_or_else(|| {
+            ContainerError::invalid_configuration(format!(
+                "No image tagged {} in {}; found {}",
+                image.tag.as_deref().unwrap_or_default(),
+                image.path.display(),
+                if found.is_empty() {
+                    "no tags".to_string()
+                } else {
+                    found.join(", ")
+                }
+            ))
+        })?;
+        let spec: ImageSpec = source.read_json(&manifest.config)?;
+        Ok(Self {
+            reference: String::new(),
+            source,
+            layers: manifest.layers,
+            config: spec.config,
+        })
+    }
+
+    // Makes the image config the defaults for `config`: its Env goes before
+    // --env, a command on the command line replaces Cmd but, as with docker,
+    // is still run through the Entrypoint, and --workdir wins over
+    // WorkingDir.
+    pub fn apply(
+        &self,
+        config: &mut ContainerConfig,
+        explicit: impl Fn(&str) -> bool,
+    ) -> ContainerResult<()> {
+        for entry in self.config.env.iter().flatten() {
+            let (key, value) = entry.split_once('=').ok_or_else(|| {
+                ContainerError::invalid_configuration(format!(
+                    "Image {} has an Env entry '{entry}' that is not KEY=VALUE",
+                    self.reference
+                ))
+            })?;
+            config.env.push((key.to_string(), value.to_string()));
+        }
+        let mut argv: Vec<OsString> = self
+            .config
+            .entrypoint
+            .iter()
+            .flatten()
+            .map(OsString::from)
+            .collect();
+        if explicit("command") {
+            argv.push(std::mem::take(&mut config.command));
+            argv.append(&mut config.args);
+        } else {
+            argv.extend(self.config.cmd.iter().flatten().map(OsString::from));
+        }
+        let Some((command, args)) = argv.split_first() else {
+            return Err(ContainerError::invalid_configuration(format!(
+                "No command given and image {} has no Entrypoint or Cmd",
+                self.reference
+            )));
+        };
+        config.command = command.clone();
+        config.args = args.to_vec();
+        if !explicit("workdir")
+            && let Some(dir) = self.config.working_dir.as_deref().filter(|d| !d.is_empty())
+        {
+            config.workdir = Some(PathBuf::from(dir));
+        }
+        if let Some(user) = self.config.user.as_deref().filter(|u| !u.is_empty()) {
+            log::warn!("Ignoring image User {user}: the container runs as root");
+        }
+        Ok(())
+    }
+
+    // Applies the layers in order into a fresh rootfs under `state_dir`.
+    pub fn extract(
+        &self,
+        state_dir: &Path,
+        name: &str,
+        keep: bool,
+    ) -> ContainerResult<ExtractedRootfs> {
+        let rootfs = ExtractedRootfs::create(state_dir, name, keep)?;
+        log::info!(
+            "Extracting image {} to {}",
+            self.reference,
+            rootfs.path().display()
+        );
+        for (number, layer) in self.layers.iter().enumerate() {
+            log::debug!(
+                "Applying layer {}/{}: {layer}",
+                number + 1,
+                self.layers.len()
+            );
+            rootfs.unpack(
+                self.source.open(layer)?,
+                true,
+                &format!("layer {layer} of {}", self.reference),
+            )?;
+        }
+        Ok(rootfs)
+    }
+}
+
+impl Source {
+    fn open(&self, name: &str) -> ContainerResult<Box<dyn Read>> {
+        match self {
+            Source::Layout(dir) => {
+                let path = dir.join(name);
+                let file = File::open(&path).map_err(|e| {
+                    ContainerError::invalid_configuration(format!(
+                        "Failed to open {}: {e}",
+                        path.display()
+                    ))
+                })?;
+                Ok(Box::new(file))
+            }
+            Source::Archive(index) => index.open(name),
+        }
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, name: &str) -> ContainerResult<T> {
+        let mut content = String::new();
+        self.open(name)?.read_to_string(&mut content)?;
+        serde_json::from_str(&content)
+            .map_err(|e| ContainerError::invalid_configuration(format!("Invalid {name}: {e}")))
+    }
+}
+
+// blobs/<algorithm>/<hex> for a digest, refusing anything that could leave
+// the blobs directory.
+fn blob_path(digest: &str) -> ContainerResult<String> {
+    match digest.split_once(':') {
+        Some((algorithm, hex))
+            if !algorithm.is_empty()
+                && !hex.is_empty()
+                && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+                && hex.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            Ok(format!("blobs/{algorithm}/{hex}"))
+        }
+        _ => Err(ContainerError::invalid_configuration(format!(
+            "Invalid digest '{digest}'"
+        ))),
+    }
+}
+
+// The image's name for the host architecture, as in the OCI image spec.
+fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64le",
+        other => other,
+    }
+}
+
+// Picks the manifest for this host when there is more than one.
+fn select_platform(mut manifests: Vec<Descriptor>, path: &Path) -> ContainerResult<Descriptor> {
+    if manifests.len() == 1 {
+        return Ok(manifests.remove(0));
+    }
+    let found: Vec<String> = manifests
+        .iter()
+        .map(|manifest| match &manifest.platform {
+            Some(platform) => format!("{}/{}", platform.os, platform.architecture),
+            None => manifest.digest.clone(),
+        })
+        .collect();
+    manifests
+        .into_iter()
+        .find(|manifest| {
+            manifest.platform.as_ref().is_some_and(|platform| {
+                platform.os == "linux" && platform.architecture == host_architecture()
+            })
+        })
+        .ok_or_else(|| {
+            ContainerError::invalid_configuration(format!(
+                "No manifest for linux/{} in {}; found {}",
+                host_architecture(),
+                path.display(),
+                found.join(", ")
+            ))
+        })
+}
+
+// Where each member of a docker-archive lies in the tarball, so members can
+// be read in any order without unpacking the whole archive.
+#[derive(Debug, Clone)]
+struct TarIndex {
+    path: PathBuf,
+    entries: HashMap<PathBuf, TarMember>,
+}
+
+#[derive(Debug, Clone)]
+enum TarMember {
+    Data { offset: u64, size: u64 },
+    // Newer docker versions link the legacy layer paths to blobs/.
+    Link(PathBuf),
+}
+
+impl TarIndex {
+    fn read(path: &Path) -> ContainerResult<Self> {
+        let file = File::open(path).map_err(|e| {
+            ContainerError::invalid_configuration(format!(
+                "Cannot open image archive {}: {e}",
+                path.display()
+            ))
+        })?;
+        let mut entries = HashMap::new();
+        let mut archive = Archive::new(file);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = normalize(&entry.path()?);
+            let member = match entry.header().entry_type() {
+                EntryType::Symlink => match entry.link_name()? {
+                    Some(target) => TarMember::Link(normalize(
+                        &name.parent().unwrap_or(Path::new("")).join(target),
+                    )),
+                    None => continue,
+                },
+                EntryType::Regular | EntryType::Continuous => TarMember::Data {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                },
+                _ => continue,
+            };
+            entries.insert(name, member);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    fn open(&self, name: &str) -> ContainerResult<Box<dyn Read>> {
+        let mut name = normalize(Path::new(name));
+        // Bounded, in case links form a loop.
+        for _ in 0..8 {
+            match self.entries.get(&name) {
+                Some(TarMember::Data { offset, size }) => {
+                    let mut file = File::open(&self.path)?;
+                    file.seek(SeekFrom::Start(*offset))?;
+                    return Ok(Box::new(file.take(*size)));
+                }
+                Some(TarMember::Link(target)) => name = target.clone(),
+                None => break,
+            }
+        }
+        Err(ContainerError::invalid_configuration(format!(
+            "{} has no member {}",
+            self.path.display(),
+            name.display()
+        )))
+    }
+}
+
+// Resolves `.` and `..` lexically, as member names are not host paths.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
@@ -0,0 +1,42 @@
+mod archive;
+mod capabilities;
+mod cgroup;
+pub mod check;
+pub mod cli;
+pub mod commands;
+mod config_file;
+mod console;
+mod container;
+mod devices;
+pub mod error;
+pub mod events;
+mod filesystem;
+mod health;
+mod image;
+mod log_file;
+pub mod logging;
+mod monitor;
+mod namespace;
+mod netlink;
+mod network;
+mod preflight;
+mod process;
+mod publish;
+pub mod restart;
+mod seccomp;
+mod signals;
+mod spec;
+mod state;
+mod sync;
+mod sysctl;
+mod systemd;
+mod watchdog;
+
+pub use cgroup::CgroupConfig;
+pub use cli::ContainerConfig;
+pub use container::{Container, ContainerHandle};
+pub use error::{ContainerError, ContainerResult};
+pub use filesystem::FilesystemManager;
+pub use health::{HealthListener, HealthStatus};
+pub use namespace::NamespaceConfig;
+pub use process::{ProcessManager, WaitOutcome};
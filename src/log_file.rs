@@ -0,0 +1,325 @@
+use crate::error::{ContainerError, ContainerResult};
+use nix::fcntl::{OFlag, open, openat, renameat};
+use nix::sys::stat::Mode;
+use serde::Serialize;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A line longer than this is written out as partial records rather than
+// buffered without bound (the same limit as Docker's json-file driver).
+const MAX_LINE: usize = 16 * 1024;
+
+// `raw` copies output byte for byte; `json-file` writes one
+// {"time","stream","log"} object per line, like Docker's json-file driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogDriver {
+    #[default]
+    Raw,
+    JsonFile,
+}
+
+impl FromStr for LogDriver {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(LogDriver::Raw),
+            "json-file" => Ok(LogDriver::JsonFile),
+            other => Err(format!("unknown log driver '{other}'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    stream: LogStream,
+    log: &'a str,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+}
+
+// Container output persisted to a file (--log-file). The file is opened by
+// the runtime before the container exists, so a bad path fails the start, and
+// renamed relative to a directory fd so rotation still works from inside
+// the container's mount namespace.
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    dir: OwnedFd,
+    name: OsString,
+    file: File,
+    written: u64,
+    max_size: Option<u64>,
+    driver: LogDriver,
+    // Incomplete lines per stream, json-file only.
+    pending: [Vec<u8>; 2],
+    failed: bool,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, max_size: Option<u64>, driver: LogDriver) -> ContainerResult<Self> {
+        let error = |e: nix::Error| {
+            ContainerError::invalid_configuration(format!(
+                "Cannot open log file {}: {e}",
+                path.display()
+            ))
+        };
+        let name = path
+            .file_name()
+            .ok_or_else(|| {
+                ContainerError::invalid_configuration(format!(
+                    "Log file {} is not a file path",
+                    path.display()
+                ))
+            })?
+            .to_os_string();
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let dir = open(
+            parent,
+            OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(error)?;
+        let file = open_in(&dir, &name, OFlag::empty()).map_err(error)?;
+        let written = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(Self {
+            path: path.to_path_buf(),
+            dir,
+            name,
+            file,
+            written,
+            max_size,
+            driver,
+            pending: Default::default(),
+            failed: false,
+        })
+    }
+
+    pub fn try_clone(&self) -> ContainerResult<Self> {
+        Ok(Self {
+            path: self.path.clone(),
+            dir: self.dir.try_clone()?,
+            name: self.name.clone(),
+            file: self.file.try_clone()?,
+            written: self.written,
+            max_size: self.max_size,
+            driver: self.driver,
+            pending: self.pending.clone(),
+            failed: self.failed,
+        })
+    }
+
+    pub fn write(&mut self, stream: LogStream, data: &[u8]) {
+        match self.driver {
+            LogDriver::Raw => self.append(data),
+            LogDriver::JsonFile => {
+                let index = stream as usize;
+                self.pending[index].extend_from_slice(data);
+                while let Some(end) = self.pending[index].iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = self.pending[index].drain(..=end).collect();
+                    self.append_record(stream, &line, false);
+                }
+                if self.pending[index].len() >= MAX_LINE {
+                    let line = std::mem::take(&mut self.pending[index]);
+                    self.append_record(stream, &line, true);
+                }
+            }
+        }
+    }
+
+    // Writes out whatever incomplete lines are left once output has ended.
+    pub fn finish(&mut self) {
+        for stream in [LogStream::Stdout, LogStream::Stderr] {
+            let line = std::mem::take(&mut self.pending[stream as usize]);
+            if !line.is_empty() {
+                self.append_record(stream, &line, true);
+            }
+        }
+    }
+
+    fn append_record(&mut self, stream: LogStream, line: &[u8], partial: bool) {
+        let record = Record {
+            time: rfc3339_now(),
+            stream,
+            log: &String::from_utf8_lossy(line),
+            partial,
+        };
+        if let Ok(mut json) = serde_json::to_vec(&record) {
+            json.push(b'\n');
+            self.append(&json);
+        }
+    }
+
+    // Failures after the start are reported once and then ignored: losing
+    // the log must not take the container down with it.
+    fn append(&mut self, data: &[u8]) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.rotate_if_needed(data.len() as u64) {
+            log::warn!("Failed to rotate {}: {e}", self.path.display());
+        }
+        match self.file.write_all(data) {
+            Ok(()) => self.written += data.len() as u64,
+            Err(e) => {
+                log::warn!(
+                    "Failed to write {}, no longer logging: {e}",
+                    self.path.display()
+                );
+                self.failed = true;
+            }
+        }
+    }
+
+    // Moves the current file to `<name>.1` and starts an empty one once the
+    // next write would take it past --log-max-size.
+    fn rotate_if_needed(&mut self, incoming: u64) -> nix::Result<()> {
+        let Some(max_size) = self.max_size else {
+            return Ok(());
+        };
+        if self.written == 0 || self.written + incoming <= max_size {
+            return Ok(());
+        }
+        let mut rotated = self.name.clone();
+        rotated.push(".1");
+        renameat(
+            &self.dir,
+            self.name.as_os_str(),
+            &self.dir,
+            rotated.as_os_str(),
+        )?;
+        self.file = open_in(&self.dir, &self.name, OFlag::O_TRUNC)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn open_in(dir: &OwnedFd, name: &OsStr, extra: OFlag) -> nix::Result<File> {
+    let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND | OFlag::O_CLOEXEC | extra;
+    openat(dir, name, flags, Mode::from_bits_truncate(0o640)).map(File::from)
+}
+
+// UTC with nanoseconds, e.g. 2024-05-01T12:00:00.123456789Z.
+fn rfc3339_now() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:09}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        now.subsec_nanos()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn records(path: &Path) -> Vec<Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn json_file_writes_one_record_per_line_and_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("container.log");
+        let mut log = LogFile::open(&path, None, LogDriver::JsonFile).unwrap();
+        log.write(LogStream::Stdout, b"first\nsec");
+        log.write(LogStream::Stderr, b"oops\n");
+        log.write(LogStream::Stdout, b"ond\ntail");
+        log.finish();
+        let records = records(&path);
+        let fields: Vec<(&str, &str, bool)> = records
+            .iter()
+            .map(|record| {
+                (
+                    record["stream"].as_str().unwrap(),
+                    record["log"].as_str().unwrap(),
+                    record.get("partial").is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("stdout", "first\n", false),
+                ("stderr", "oops\n", false),
+                ("stdout", "second\n", false),
+                ("stdout", "tail", true),
+            ]
+        );
+        let time = records[0]["time"].as_str().unwrap();
+        assert_eq!(time.len(), "2024-05-01T12:00:00.123456789Z".len(), "{time}");
+        assert!(time.ends_with('Z'), "{time}");
+    }
+
+    #[test]
+    fn long_lines_are_split_into_partial_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("container.log");
+        let mut log = LogFile::open(&path, None, LogDriver::JsonFile).unwrap();
+        log.write(LogStream::Stdout, &vec![b'x'; MAX_LINE + 10]);
+        log.write(LogStream::Stdout, b"\n");
+        let records = records(&path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["log"].as_str().unwrap().len(), MAX_LINE + 10);
+        assert_eq!(records[0]["partial"], true);
+        assert_eq!(records[1]["log"], "\n");
+    }
+
+    #[test]
+    fn raw_output_is_kept_as_is_and_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("container.log");
+        let mut log = LogFile::open(&path, Some(8), LogDriver::Raw).unwrap();
+        log.write(LogStream::Stdout, b"12345");
+        log.write(LogStream::Stderr, b"678");
+        log.write(LogStream::Stdout, b"9");
+        assert_eq!(
+            std::fs::read(dir.path().join("container.log.1")).unwrap(),
+            b"12345678"
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"9");
+    }
+
+    #[test]
+    fn drivers_parse_by_name() {
+        assert_eq!("raw".parse(), Ok(LogDriver::Raw));
+        assert_eq!("json-file".parse(), Ok(LogDriver::JsonFile));
+        assert!("journald".parse::<LogDriver>().is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use env_logger::{Builder, Target, WriteStyle};
+use log::LevelFilter;
+use nix::fcntl::{FcntlArg, fcntl};
+use nix::unistd::isatty;
+use std::io::{self, Write};
+use std::os::fd::BorrowedFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// Where log lines go: the runtime's stderr, or a private copy of it in a
+// forked workload child whose stdio has been handed to the workload.
+static LOG_FD: AtomicI32 = AtomicI32::new(libc::STDERR_FILENO);
+
+// `level` comes from -v/-q; without it RUST_LOG applies, and Info if that is
+// not set either.
+pub fn init(level: Option<LevelFilter>) {
+    let mut builder = Builder::from_default_env();
+    builder
+        .format_timestamp_micros()
+        .format_module_path(false)
+        .target(Target::Pipe(Box::new(LogWriter)));
+    if let Some(level) = filter_level(level, std::env::var_os("RUST_LOG").is_some()) {
+        builder.filter_level(level);
+    }
+    // A pipe target is never styled on its own.
+    if std::env::var_os("RUST_LOG_STYLE").is_none() && isatty(io::stderr()).unwrap_or(false) {
+        builder.write_style(WriteStyle::Always);
+    }
+    builder.init();
+}
+
+// The level forced on top of RUST_LOG, if any.
+fn filter_level(level: Option<LevelFilter>, rust_log: bool) -> Option<LevelFilter> {
+    match level {
+        Some(level) => Some(level),
+        None if !rust_log => Some(LevelFilter::Info),
+        None => None,
+    }
+}
+
+// For a child between fork and execve: once its stdio is the workload's PTY
+// or log pipes, the runtime's own lines would land in the workload's output.
+// They keep going to the runtime's stderr instead, through a close-on-exec
+// copy that disappears with the execve.
+pub fn keep_stderr() {
+    match fcntl(io::stderr(), FcntlArg::F_DUPFD_CLOEXEC(3)) {
+        Ok(fd) => LOG_FD.store(fd, Ordering::Relaxed),
+        Err(e) => log::debug!("Failed to duplicate stderr for logging: {e}"),
+    }
+}
+
+struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only ever stderr or the copy made by keep_stderr, neither of which
+        // is closed while the process can still log.
+        let fd = unsafe { BorrowedFd::borrow_raw(LOG_FD.load(Ordering::Relaxed)) };
+        Ok(nix::unistd::write(fd, buf)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_rust_log() {
+        assert_eq!(
+            filter_level(Some(LevelFilter::Debug), true),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            filter_level(Some(LevelFilter::Warn), false),
+            Some(LevelFilter::Warn)
+        );
+    }
+
+    #[test]
+    fn rust_log_applies_without_flags() {
+        assert_eq!(filter_level(None, true), None);
+        assert_eq!(filter_level(None, false), Some(LevelFilter::Info));
+    }
+}
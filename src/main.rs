@@ -1,94 +1,62 @@
-mod cgroup;
-mod cli;
-mod error;
-mod filesystem;
-mod namespace;
-mod process;
-
-use std::{
-    sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
-    },
-    thread,
-};
-
-use cli::{ContainerConfig, parse_args};
-use error::{ContainerError, ContainerResult};
-use filesystem::FilesystemManager;
+use container_rs::check;
+use container_rs::cli::{Cli, CliCommand, parse_args};
+use container_rs::commands;
+use container_rs::error::ContainerResult;
+use container_rs::logging;
+use container_rs::restart;
 use log::{debug, error, info};
-use namespace::{NamespaceConfig, NamespaceManager};
-use nix::sys::signal;
-use nix::{
-    libc::{self, nice, signal},
-    unistd::{Pid, Uid, getpid},
-};
-use process::ProcessManager;
-// use signal_hook::iterator::Signals;
-
-use crate::cgroup::{CgroupConfig, CgroupManager};
+use nix::unistd::getpid;
 
 fn main() {
-    env_logger::Builder::from_default_env()
-        .format_timestamp_micros()
-        .format_module_path(false)
-        .filter_level(log::LevelFilter::Info)
-        .init();
-
-    if let Err(e) = run() {
-        error!("Container runtime error: {e}");
-        std::process::exit(1)
+    // Parsed first so -v/-q can set the level; building the command may
+    // already log.
+    let cli = parse_args();
+    logging::init(cli.log_level());
+
+    match run(cli) {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            error!("Container runtime error: {e}");
+            std::process::exit(e.exit_code())
+        }
     }
 }
 
-fn run() -> ContainerResult<()> {
-    let config = parse_args();
-    info!("Starting container runtime (PID: {})", getpid());
-    debug!("Configuration: {config:?}");
-    if !Uid::current().is_root() {
-        error!("Root privileges required for container operations");
-        return Err(ContainerError::RootRequired);
-    }
-
-    let ns_config = NamespaceConfig {
-        isolate_pid: true,
-        isolate_net: true,
-        isolate_mount: true,
-        isolate_uts: true,
-        isolate_ipc: true,
-        isolate_user: false,
-    };
-    let cgroup_manager = if config.memory_limit_mb.is_some() {
-        let mut cgroup_config = CgroupConfig::new(format!("container-{}", getpid()));
-        if let Some(mem) = config.memory_limit_mb {
-            cgroup_config = cgroup_config.with_memory_mb(mem);
-            info!("Setting memory limit: {} MB", mem);
+fn run(cli: Cli) -> ContainerResult<i32> {
+    match cli.command()? {
+        CliCommand::Run {
+            config,
+            detach: true,
+        } => commands::create::detach(*config).map(|()| 0),
+        CliCommand::Run { config, .. } => {
+            info!("Starting container runtime (PID: {})", getpid());
+            debug!("Configuration: {config:?}");
+            let outcome = restart::run(*config)?;
+            Ok(outcome.exit_code())
         }
-        let manager = CgroupManager::new(cgroup_config)?;
-        manager.setup()?;
-        manager.add_process(getpid().as_raw());
-        Some(manager)
-    } else {
-        info!("No resource limits specified, skipping cgroup setup");
-        None
-    };
-    NamespaceManager::unshare_namespaces(ns_config)?;
-    NamespaceManager::enter_pid_namespace()?;
-    info!("Running as PID 1 in container (host PID: {})", getpid());
-    let hostname = config.hostname.as_deref().unwrap_or("rust-container");
-    NamespaceManager::set_hostname(&hostname)?;
-    let rootfs_path = std::path::Path::new(&config.rootfs);
-    FilesystemManager::setup_container_filesystem(&rootfs_path)?;
-    info!("Container environment setup complete, executing command...");
-
-    ProcessManager::execute_container_command(&config.command, &config.args)?;
-    // if let Some(ref manager) = cgroup_manager {
-    //     info!("Cleaning up cgroups before exit...");
-    //     // manager.cleanup().ok();
-    //     if let Err(e) = manager.cleanup() {
-    //         log::warn!("Failed to clean up cgroup: {:?}", e);
-    //     }
-    // }
-
-    Ok(())
+        CliCommand::Check { config } => {
+            let report = check::run(*config);
+            report.print();
+            Ok(report.exit_code())
+        }
+        CliCommand::Create { id, config } => {
+            debug!("Configuration: {config:?}");
+            commands::create::run(&id, *config).map(|()| 0)
+        }
+        CliCommand::Start { id } => commands::start::run(&id).map(|()| 0),
+        CliCommand::Kill { id, signal } => commands::kill::run(&id, signal).map(|()| 0),
+        CliCommand::Pause { id } => commands::pause::run(&id).map(|()| 0),
+        CliCommand::Unpause { id } => commands::unpause::run(&id).map(|()| 0),
+        CliCommand::Delete { id, force } => commands::delete::run(&id, force).map(|()| 0),
+        CliCommand::State { id } => commands::state::run(&id).map(|()| 0),
+        CliCommand::List {
+            json,
+            prune,
+            label_filters,
+        } => commands::list::run(json, prune, &label_filters).map(|()| 0),
+        CliCommand::Wait { id } => commands::wait::run(&id),
+        CliCommand::Attach { id, detach_keys } => {
+            commands::attach::run(&id, &detach_keys).map(|()| 0)
+        }
+    }
 }
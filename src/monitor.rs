@@ -0,0 +1,128 @@
+use crate::cgroup::StatsReader;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct StatsMonitor {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl StatsMonitor {
+    pub fn spawn(reader: StatsReader, interval: Duration, json: bool) -> Self {
+        let (stop, stop_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let sample = reader.sample();
+                if json {
+                    match serde_json::to_string(&sample) {
+                        Ok(line) => eprintln!("{line}"),
+                        Err(e) => log::warn!("Failed to serialize stats sample: {e}"),
+                    }
+                } else {
+                    eprintln!("[stats] {sample}");
+                }
+            }
+            log::debug!("Stats monitor stopped");
+        });
+        log::info!("Streaming container stats every {interval:?}");
+        Self { stop, handle }
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        if self.handle.join().is_err() {
+            log::warn!("Stats monitor thread panicked");
+        }
+    }
+}
+
+// How often --pids-watch samples without --stats-interval.
+pub const PIDS_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+// Share of pids.max at which the container is reported to be running out.
+const PIDS_WARN_PERCENT: u64 = 90;
+// How many of the most common commands a warning names.
+const TOP_COMMANDS: usize = 5;
+
+// Logs a warning when the container nears its pids limit and when forks fail
+// on it, which the workload itself only sees as EAGAIN.
+#[derive(Debug)]
+pub struct PidsWatch {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl PidsWatch {
+    pub fn spawn(reader: StatsReader, interval: Duration) -> Self {
+        if reader.pids().is_none() {
+            log::warn!("The container cgroup has no pids controller; its PIDs cannot be watched");
+        }
+        let (stop, stop_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut near_limit = false;
+            let mut limit_hits = reader.pids().map_or(0, |pids| pids.limit_hits);
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let Some(pids) = reader.pids() else {
+                    continue;
+                };
+                // Warned once per approach, not on every sample above it.
+                let was_near = near_limit;
+                near_limit = pids.max != u64::MAX
+                    && pids.current.saturating_mul(100)
+                        >= pids.max.saturating_mul(PIDS_WARN_PERCENT);
+                if near_limit && !was_near {
+                    log::warn!(
+                        "Container is using {} of its {} PIDs; top commands: {}",
+                        pids.current,
+                        pids.max,
+                        top_commands(&reader.processes())
+                    );
+                }
+                if pids.limit_hits > limit_hits {
+                    log::warn!(
+                        "{} fork(s) in the container failed on its limit of {} PIDs; top commands: {}",
+                        pids.limit_hits - limit_hits,
+                        pids.max,
+                        top_commands(&reader.processes())
+                    );
+                }
+                limit_hits = pids.limit_hits;
+            }
+            log::debug!("PIDs watch stopped");
+        });
+        log::info!("Watching the container's PIDs every {interval:?}");
+        Self { stop, handle }
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        if self.handle.join().is_err() {
+            log::warn!("PIDs watch thread panicked");
+        }
+    }
+}
+
+// "sh x12, python3 x3", most common first.
+fn top_commands(pids: &[i32]) -> String {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for pid in pids {
+        // Skipping processes that are gone already.
+        if let Ok(comm) = fs::read_to_string(format!("/proc/{pid}/comm")) {
+            *counts.entry(comm.trim_end().to_string()).or_default() += 1;
+        }
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    if counts.is_empty() {
+        return "none".to_string();
+    }
+    counts
+        .iter()
+        .take(TOP_COMMANDS)
+        .map(|(comm, count)| format!("{comm} x{count}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
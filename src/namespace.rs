@@ -1,9 +1,136 @@
-use nix::sched::{CloneFlags, unshare};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sched::{CloneFlags, setns, unshare};
 use nix::sys::wait::{WaitStatus, waitpid};
-use nix::unistd::{ForkResult, fork};
-use nix::unistd::{getpid, sethostname};
+use nix::unistd::{Pid, getpid, sethostname};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::{ContainerError, ContainerResult, Context, ErrorDetail};
+use crate::process::WaitOutcome;
+
+// __NEW_UTS_LEN: the kernel rejects anything longer with EINVAL.
+const HOST_NAME_MAX: usize = 64;
+// Not in nix yet. Its value overlaps the clone() exit-signal bits, so it only
+// ever goes to unshare.
+pub const CLONE_NEWTIME: CloneFlags = CloneFlags::from_bits_retain(libc::CLONE_NEWTIME);
+
+// Clock offsets of a time namespace, in seconds: `boottime=86400,monotonic=0`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeOffsets {
+    pub monotonic: i64,
+    pub boottime: i64,
+}
+
+impl FromStr for TimeOffsets {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut offsets = TimeOffsets::default();
+        for entry in s.split(',') {
+            let (clock, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected CLOCK=SECONDS, got '{entry}'"))?;
+            let value = value
+                .parse()
+                .map_err(|_| format!("invalid offset '{value}' for {clock}"))?;
+            match clock {
+                "monotonic" => offsets.monotonic = value,
+                "boottime" => offsets.boottime = value,
+                _ => {
+                    return Err(format!(
+                        "unknown clock '{clock}', expected monotonic or boottime"
+                    ));
+                }
+            }
+        }
+        Ok(offsets)
+    }
+}
+// The namespaces a container can join instead of creating its own. Joining a
+// mount or user namespace is not supported: the container's root filesystem
+// and ID mappings are set up on the assumption that they are its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamespaceType {
+    Pid,
+    Net,
+    Ipc,
+    Uts,
+    Cgroup,
+    Time,
+}
+
+impl NamespaceType {
+    pub fn flag(self) -> CloneFlags {
+        match self {
+            NamespaceType::Pid => CloneFlags::CLONE_NEWPID,
+            NamespaceType::Net => CloneFlags::CLONE_NEWNET,
+            NamespaceType::Ipc => CloneFlags::CLONE_NEWIPC,
+            NamespaceType::Uts => CloneFlags::CLONE_NEWUTS,
+            NamespaceType::Cgroup => CloneFlags::CLONE_NEWCGROUP,
+            NamespaceType::Time => CLONE_NEWTIME,
+        }
+    }
+}
+
+impl fmt::Display for NamespaceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            NamespaceType::Pid => "pid",
+            NamespaceType::Net => "net",
+            NamespaceType::Ipc => "ipc",
+            NamespaceType::Uts => "uts",
+            NamespaceType::Cgroup => "cgroup",
+            NamespaceType::Time => "time",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for NamespaceType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pid" => Ok(NamespaceType::Pid),
+            "net" => Ok(NamespaceType::Net),
+            "ipc" => Ok(NamespaceType::Ipc),
+            "uts" => Ok(NamespaceType::Uts),
+            "cgroup" => Ok(NamespaceType::Cgroup),
+            "time" => Ok(NamespaceType::Time),
+            "mnt" | "user" => Err(format!("joining a {s} namespace is not supported")),
+            _ => Err(format!(
+                "unknown namespace '{s}', expected pid, net, ipc, uts, cgroup or time"
+            )),
+        }
+    }
+}
+
+// `--join-ns net=/proc/1234/ns/net`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinNamespace {
+    pub kind: NamespaceType,
+    pub path: PathBuf,
+}
+
+impl FromStr for JoinNamespace {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected TYPE=PATH, got '{s}'"))?;
+        if path.is_empty() {
+            return Err(format!("missing path for the {kind} namespace"));
+        }
+        Ok(Self {
+            kind: kind.parse()?,
+            path: PathBuf::from(path),
+        })
+    }
+}
 
-use crate::error::{ContainerError, ContainerResult, Context};
 #[derive(Debug, Clone, Copy)]
 pub struct NamespaceConfig {
     pub isolate_pid: bool,
@@ -12,6 +139,8 @@ pub struct NamespaceConfig {
     pub isolate_uts: bool,
     pub isolate_ipc: bool,
     pub isolate_user: bool,
+    pub isolate_cgroup: bool,
+    pub isolate_time: bool,
 }
 impl Default for NamespaceConfig {
     fn default() -> Self {
@@ -22,11 +151,41 @@ impl Default for NamespaceConfig {
             isolate_uts: true,
             isolate_ipc: true,
             isolate_user: false,
+            isolate_cgroup: true,
+            isolate_time: false,
         }
     }
 }
 impl NamespaceConfig {
-    pub fn to_clone_flags(&self) -> CloneFlags {
+    // Namespaces created together with the container init. The user namespace
+    // has to come first so that it owns the PID namespace (and everything the
+    // init unshares afterwards); otherwise proc cannot be mounted inside it.
+    // The cgroup namespace is left to unshare so that its root is the cgroup
+    // the init was born into, not wherever the runtime is at clone time.
+    pub fn for_clone(self) -> Self {
+        Self {
+            isolate_pid: self.isolate_pid,
+            isolate_net: false,
+            isolate_mount: false,
+            isolate_uts: false,
+            isolate_ipc: false,
+            isolate_user: self.isolate_user,
+            isolate_cgroup: false,
+            isolate_time: false,
+        }
+    }
+    // The time namespace is not here either: unsharing it only affects
+    // children, so the runtime sets it up before the clone (see
+    // NamespaceManager::enter_time_namespace).
+    pub fn for_unshare(self) -> Self {
+        Self {
+            isolate_pid: false,
+            isolate_user: false,
+            isolate_time: false,
+            ..self
+        }
+    }
+    pub fn to_clone_flags(self) -> CloneFlags {
         let mut flags = CloneFlags::empty();
         if self.isolate_pid {
             flags |= CloneFlags::CLONE_NEWPID;
@@ -46,6 +205,12 @@ impl NamespaceConfig {
         if self.isolate_user {
             flags |= CloneFlags::CLONE_NEWUSER;
         }
+        if self.isolate_cgroup {
+            flags |= CloneFlags::CLONE_NEWCGROUP;
+        }
+        if self.isolate_time {
+            flags |= CLONE_NEWTIME;
+        }
         flags
     }
 }
@@ -61,71 +226,271 @@ impl NamespaceManager {
         }
         unshare(flags)
             .map_err(|e| ContainerError::NamespaceSetup {
-                message: format!("Failed to unshare namespaces: {e} (flags: {flags:?})"),
+                detail: ErrorDetail::new(format!("Failed to unshare namespaces {flags:?}"))
+                    .source(e),
             })
             .context("unshare system call failed")?;
         log::info!("Successfully unshared namespaces: {flags:?}");
         Ok(())
     }
-    pub fn enter_pid_namespace() -> ContainerResult<()> {
-        log::info!("Forking to enter PID namespace");
-        match unsafe { fork() } {
-            Ok(ForkResult::Parent { child }) => {
+    // Forks the container init directly into new PID/user namespaces. clone()
+    // without a new stack behaves like fork(), but unlike unshare(CLONE_NEWPID)
+    // it leaves the runtime free to create threads afterwards.
+    pub fn enter_pid_namespace(config: NamespaceConfig) -> ContainerResult<Option<Pid>> {
+        let flags = config.to_clone_flags();
+        log::info!("Forking container init with namespaces: {flags:?}");
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_clone,
+                flags.bits() as libc::c_long | libc::SIGCHLD as libc::c_long,
+                0,
+                0,
+                0,
+                0,
+            )
+        };
+        match ret {
+            -1 => Err(ContainerError::NamespaceSetup {
+                detail: ErrorDetail::new(format!("clone failed (flags: {flags:?})"))
+                    .source(Errno::last()),
+            }),
+            0 => {
+                log::info!("Child process started (host PID: {})", getpid());
+                Ok(None)
+            }
+            child => {
+                let child = Pid::from_raw(child as i32);
                 log::info!(
                     "Parent process waiting for container child (PID: {})",
                     child
                 );
-                loop {
-                    match waitpid(child, None) {
-                        Ok(WaitStatus::Exited(_, code)) => {
-                            log::info!("Container exited with code: {}", code);
-                            std::process::exit(code);
-                        }
-                        Ok(WaitStatus::Signaled(_, signal, _)) => {
-                            log::warn!("Container killed by signal: {:?}", signal);
-                            std::process::exit(128 + signal as i32);
-                        }
-                        Ok(WaitStatus::Stopped(_, _)) => {
-                            log::debug!("Child process stopped, continuing to wait");
-                            continue;
-                        }
-                        Ok(WaitStatus::Continued(_)) => {
-                            log::debug!("Child process continued, continuing to wait");
-                            continue;
-                        }
-                        Ok(status) => {
-                            log::warn!("Container exited with unexpected status: {:?}", status);
-                            std::process::exit(1);
-                        }
-                        Err(nix::errno::Errno::ECHILD) => {
-                            // Child already exited (race condition)
-                            log::debug!("Child already exited");
-                            std::process::exit(0);
-                        }
-                        Err(e) => {
-                            log::error!("Failed to wait for child: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
+                Ok(Some(child))
+            }
+        }
+    }
+    // Opened up front, while the paths still resolve against the host's /proc.
+    pub fn open_namespaces(
+        joins: &[JoinNamespace],
+    ) -> ContainerResult<Vec<(NamespaceType, OwnedFd)>> {
+        joins
+            .iter()
+            .map(|join| {
+                let file = File::open(&join.path).map_err(|e| ContainerError::NamespaceSetup {
+                    detail: ErrorDetail::new(format!("Failed to open {} namespace", join.kind))
+                        .path(&join.path)
+                        .source(e),
+                })?;
+                Ok((join.kind, file.into()))
+            })
+            .collect()
+    }
+    pub fn join_namespaces(namespaces: &[(NamespaceType, OwnedFd)]) -> ContainerResult<()> {
+        for (kind, fd) in namespaces {
+            setns(fd, kind.flag()).map_err(|e| ContainerError::NamespaceSetup {
+                detail: ErrorDetail::new(format!("Failed to join {kind} namespace")).source(e),
+            })?;
+            log::info!("Joined existing {kind} namespace");
+        }
+        Ok(())
+    }
+    // Joining a PID namespace only moves the children forked afterwards, so
+    // the runtime joins it right before cloning the init and goes back with
+    // restore_pid_namespace once it has.
+    pub fn join_pid_namespace(namespace: &OwnedFd) -> ContainerResult<OwnedFd> {
+        let previous = File::open("/proc/self/ns/pid")?;
+        setns(namespace, CloneFlags::CLONE_NEWPID).map_err(|e| ContainerError::NamespaceSetup {
+            detail: ErrorDetail::new("Failed to join pid namespace").source(e),
+        })?;
+        log::info!("Joined existing pid namespace");
+        Ok(previous.into())
+    }
+    pub fn restore_pid_namespace(previous: OwnedFd) {
+        if let Err(e) = setns(previous, CloneFlags::CLONE_NEWPID) {
+            log::debug!("Could not go back to the previous pid namespace: {e}");
+        }
+    }
+    // Creates a time namespace for the children we fork from now on, with
+    // `offsets` applied. They can only be written before any process has
+    // entered it, so this runs right before the init is cloned. Returns our
+    // previous namespace for restore_time_namespace.
+    pub fn enter_time_namespace(offsets: TimeOffsets) -> ContainerResult<OwnedFd> {
+        if !Path::new("/proc/self/ns/time").exists() {
+            return Err(ContainerError::name_space(
+                "--time-offset is not supported: this kernel has no time namespaces (Linux 5.6 or later with CONFIG_TIME_NS)",
+            ));
+        }
+        let previous = File::open("/proc/self/ns/time_for_children")?;
+        unshare(CLONE_NEWTIME).map_err(|e| ContainerError::NamespaceSetup {
+            detail: ErrorDetail::new("Failed to create time namespace").source(e),
+        })?;
+        let offsets_path = "/proc/self/timens_offsets";
+        for (clock, offset) in [
+            ("monotonic", offsets.monotonic),
+            ("boottime", offsets.boottime),
+        ] {
+            if let Err(e) = fs::write(offsets_path, format!("{clock} {offset} 0\n")) {
+                let _ = setns(&previous, CLONE_NEWTIME);
+                return Err(ContainerError::NamespaceSetup {
+                    detail: match e.kind() {
+                        ErrorKind::NotFound => ErrorDetail::new(format!(
+                            "--time-offset is not supported: {offsets_path} does not exist"
+                        )),
+                        _ => ErrorDetail::new(format!(
+                            "Failed to set {clock} offset to {offset}s in"
+                        ))
+                        .path(offsets_path)
+                        .source(e),
+                    },
+                });
+            }
+        }
+        log::info!(
+            "Created time namespace (monotonic {:+}s, boottime {:+}s)",
+            offsets.monotonic,
+            offsets.boottime
+        );
+        Ok(previous.into())
+    }
+    // Only the container gets the shifted clocks, not whatever else the
+    // runtime forks later.
+    pub fn restore_time_namespace(previous: OwnedFd) {
+        if let Err(e) = setns(previous, CLONE_NEWTIME) {
+            log::debug!("Could not go back to the previous time namespace: {e}");
+        }
+    }
+    // Maps the full ID range onto itself: root in the container stays root on
+    // disk, while namespaced capabilities no longer apply to host resources.
+    pub fn write_id_mappings(child: Pid) -> ContainerResult<()> {
+        for map in ["uid_map", "gid_map"] {
+            let path = format!("/proc/{}/{map}", child.as_raw());
+            fs::write(&path, "0 0 4294967295\n").map_err(|e| ContainerError::NamespaceSetup {
+                detail: ErrorDetail::new("Failed to write").path(&path).source(e),
+            })?;
+        }
+        log::debug!("Wrote identity ID mappings for {child}");
+        Ok(())
+    }
+    // The host IDs the child's root maps to, through its user namespace if it
+    // has one.
+    pub fn mapped_root(child: Pid) -> ContainerResult<(u32, u32)> {
+        let mut ids = [0; 2];
+        for (id, map) in ids.iter_mut().zip(["uid_map", "gid_map"]) {
+            let path = format!("/proc/{}/{map}", child.as_raw());
+            let content =
+                fs::read_to_string(&path).map_err(|e| ContainerError::NamespaceSetup {
+                    detail: ErrorDetail::new("Failed to read").path(&path).source(e),
+                })?;
+            *id = content
+                .lines()
+                .find_map(
+                    |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                        ["0", outside, _] => outside.parse().ok(),
+                        _ => None,
+                    },
+                )
+                .ok_or_else(|| ContainerError::name_space(format!("{path} does not map ID 0")))?;
+        }
+        Ok((ids[0], ids[1]))
+    }
+    // The init exits with its workload's exit code (128 + signal for a signal
+    // death), so a normal exit is passed on as is.
+    pub fn wait_for_container(child: Pid) -> ContainerResult<WaitOutcome> {
+        loop {
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => {
+                    log::info!("Container exited with code: {}", code);
+                    return Ok(WaitOutcome::Exited(code));
+                }
+                Ok(WaitStatus::Signaled(_, signal, _)) => {
+                    log::warn!("Container killed by signal: {:?}", signal);
+                    return Ok(WaitOutcome::Signaled(signal));
+                }
+                Ok(WaitStatus::Stopped(_, _)) => {
+                    log::debug!("Child process stopped, continuing to wait");
+                    continue;
+                }
+                Ok(WaitStatus::Continued(_)) => {
+                    log::debug!("Child process continued, continuing to wait");
+                    continue;
+                }
+                Ok(status) => {
+                    log::warn!("Container exited with unexpected status: {:?}", status);
+                    return Ok(WaitOutcome::Exited(1));
+                }
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(nix::errno::Errno::ECHILD) => {
+                    // Child already exited (race condition)
+                    log::debug!("Child already exited");
+                    return Ok(WaitOutcome::Exited(0));
+                }
+                Err(e) => {
+                    return Err(ContainerError::NamespaceSetup {
+                        detail: ErrorDetail::new("Failed to wait for child").source(e),
+                    });
                 }
             }
-            Ok(ForkResult::Child) => {
-                log::info!(
-                    "Child process started (PID 1 in container, host PID: {})",
-                    getpid()
-                );
-                Ok(())
+        }
+    }
+    // Strict mode enforces RFC 1123: dot-separated labels of ASCII letters,
+    // digits and hyphens, no label starting or ending with a hyphen. Relaxed
+    // mode only enforces what the kernel itself would reject.
+    pub fn validate_hostname(hostname: &str, strict: bool) -> ContainerResult<()> {
+        let invalid = |reason: String| {
+            ContainerError::invalid_configuration(format!(
+                "Invalid hostname {hostname:?}: {reason}"
+            ))
+        };
+        if hostname.len() > HOST_NAME_MAX {
+            return Err(invalid(format!(
+                "{} bytes long, the limit is {HOST_NAME_MAX}",
+                hostname.len()
+            )));
+        }
+        if let Some(pos) = hostname.find('\0') {
+            return Err(invalid(format!("NUL byte at position {pos}")));
+        }
+        if !strict {
+            return Ok(());
+        }
+        if hostname.is_empty() {
+            return Err(invalid("must not be empty".to_string()));
+        }
+        let mut offset = 0;
+        for label in hostname.split('.') {
+            if label.is_empty() {
+                return Err(invalid(format!("empty label at position {offset}")));
             }
-            Err(e) => Err(ContainerError::NamespaceSetup {
-                message: format!("Fork failed: {}", e),
-            }),
+            if label.len() > 63 {
+                return Err(invalid(format!(
+                    "label {label:?} is {} bytes long, the limit is 63",
+                    label.len()
+                )));
+            }
+            for (index, c) in label.char_indices() {
+                let allowed = c.is_ascii_alphanumeric()
+                    || (c == '-' && index != 0 && index + c.len_utf8() != label.len());
+                if !allowed {
+                    let pos = offset + index;
+                    let highlighted = format!(
+                        "{}[{c}]{}",
+                        &hostname[..pos],
+                        &hostname[pos + c.len_utf8()..]
+                    );
+                    return Err(invalid(format!(
+                        "character {c:?} at position {pos} is not allowed ({highlighted}); \
+                         use --hostname-strict=false to skip RFC 1123 checks"
+                    )));
+                }
+            }
+            offset += label.len() + 1;
         }
+        Ok(())
     }
     pub fn set_hostname(hostname: &str) -> ContainerResult<()> {
         log::info!("Setting hostname to: {hostname}");
         sethostname(hostname)
             .map_err(|e| ContainerError::NamespaceSetup {
-                message: format!("Failed to set hostname: {e}"),
+                detail: ErrorDetail::new("Failed to set hostname").source(e),
             })
             .context("sethostname system call failed")?;
         log::debug!("Hostname set successfully");
@@ -136,3 +501,55 @@ impl NamespaceManager {
     //     getpid().as_raw()
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(hostname: &str, strict: bool) -> String {
+        NamespaceManager::validate_hostname(hostname, strict)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn length_limit_applies_in_both_modes() {
+        // Labels within their own limit of 63, the name at the kernel's.
+        let longest = format!("{}.{}", "a".repeat(31), "b".repeat(32));
+        let too_long = format!("{longest}c");
+        for strict in [true, false] {
+            assert!(NamespaceManager::validate_hostname(&longest, strict).is_ok());
+            assert!(error(&too_long, strict).contains("65 bytes long, the limit is 64"));
+        }
+    }
+
+    #[test]
+    fn strict_labels_follow_rfc_1123() {
+        for valid in ["web", "web-1", "a.b.c", "1x", &"a".repeat(63)] {
+            assert!(
+                NamespaceManager::validate_hostname(valid, true).is_ok(),
+                "{valid}"
+            );
+        }
+        assert!(error("", true).contains("must not be empty"));
+        assert!(error("a..b", true).contains("empty label at position 2"));
+        assert!(error("a.", true).contains("empty label"));
+        assert!(error(&"a".repeat(64), true).contains("the limit is 63"));
+        assert!(error("-web", true).contains("'-' at position 0"));
+        assert!(error("web-", true).contains("'-' at position 3"));
+        assert!(error("web.-x", true).contains("web.[-]x"));
+        assert!(error("my_host", true).contains("'_' at position 2"));
+        assert!(error("hôst", true).contains("'ô' at position 1"));
+    }
+
+    #[test]
+    fn lenient_mode_only_rejects_what_the_kernel_would() {
+        for hostname in ["", "-web", "my_host", "a..b", "hôst", &"a".repeat(64)] {
+            assert!(
+                NamespaceManager::validate_hostname(hostname, false).is_ok(),
+                "{hostname}"
+            );
+        }
+        assert!(error("a\0b", false).contains("NUL byte at position 1"));
+    }
+}
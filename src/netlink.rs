@@ -0,0 +1,351 @@
+use crate::error::{ContainerError, ContainerResult, ErrorDetail};
+use nix::errno::Errno;
+use nix::sched::{CloneFlags, setns};
+use std::fs::File;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::thread;
+
+// Just enough rtnetlink for bridge networking: links, addresses and a default
+// route, without depending on the host's iproute2.
+
+const NLMSG_HDRLEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const VETH_INFO_PEER: u16 = 1;
+const RECV_BUFFER: usize = 32 * 1024;
+
+// One request message; attributes are appended in order and nests are
+// closed by the callback returning.
+struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    fn new(kind: u16, flags: u16) -> Self {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        Self { buf }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self.buf.resize(self.buf.len().next_multiple_of(4), 0);
+        self
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) -> &mut Self {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.push(data)
+    }
+
+    fn name(&mut self, kind: u16, name: &str) -> &mut Self {
+        let mut data = name.as_bytes().to_vec();
+        data.push(0);
+        self.attr(kind, &data)
+    }
+
+    fn nest(&mut self, kind: u16, fill: impl FnOnce(&mut Self)) -> &mut Self {
+        let start = self.buf.len();
+        self.attr(kind, &[]);
+        fill(self);
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+
+    fn into_bytes(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+fn ifinfomsg(index: i32, flags: u32, change: u32) -> [u8; IFINFOMSG_LEN] {
+    let mut msg = [0u8; IFINFOMSG_LEN];
+    msg[0] = libc::AF_UNSPEC as u8;
+    msg[4..8].copy_from_slice(&index.to_ne_bytes());
+    msg[8..12].copy_from_slice(&flags.to_ne_bytes());
+    msg[12..16].copy_from_slice(&change.to_ne_bytes());
+    msg
+}
+
+// A NETLINK_ROUTE socket, bound to the network namespace it was opened in.
+pub struct Netlink {
+    socket: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn open() -> ContainerResult<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(error("open a netlink socket", Errno::last()));
+        }
+        Ok(Self {
+            socket: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    // A socket stays in the namespace it was created in, so a short-lived
+    // thread joins the container's to open it there.
+    pub fn open_in(netns: &File) -> ContainerResult<Self> {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    setns(netns, CloneFlags::CLONE_NEWNET)
+                        .map_err(|e| error("join the container's network namespace", e))?;
+                    Self::open()
+                })
+                .join()
+                .expect("netlink thread panicked")
+        })
+    }
+
+    pub fn link_index(&mut self, name: &str) -> ContainerResult<i32> {
+        let mut request = Request::new(libc::RTM_GETLINK, 0);
+        request
+            .push(&ifinfomsg(0, 0, 0))
+            .name(libc::IFLA_IFNAME, name);
+        let reply = self
+            .exchange(request)
+            .map_err(|e| error(format!("look up link {name}"), e))?;
+        let index = reply
+            .get(NLMSG_HDRLEN + 4..NLMSG_HDRLEN + 8)
+            .ok_or_else(|| error(format!("look up link {name}"), Errno::EBADMSG))?;
+        Ok(i32::from_ne_bytes(index.try_into().unwrap()))
+    }
+
+    pub fn add_bridge(&mut self, name: &str) -> ContainerResult<()> {
+        let mut request = Request::new(libc::RTM_NEWLINK, create_flags());
+        request
+            .push(&ifinfomsg(0, 0, 0))
+            .name(libc::IFLA_IFNAME, name)
+            .nest(libc::IFLA_LINKINFO, |info| {
+                info.name(libc::IFLA_INFO_KIND, "bridge");
+            });
+        self.acked(request)
+            .map_err(|e| error(format!("create bridge {name}"), e))
+    }
+
+    pub fn add_veth(&mut self, name: &str, peer: &str) -> ContainerResult<()> {
+        let mut request = Request::new(libc::RTM_NEWLINK, create_flags());
+        request
+            .push(&ifinfomsg(0, 0, 0))
+            .name(libc::IFLA_IFNAME, name)
+            .nest(libc::IFLA_LINKINFO, |info| {
+                info.name(libc::IFLA_INFO_KIND, "veth")
+                    .nest(libc::IFLA_INFO_DATA, |data| {
+                        data.nest(VETH_INFO_PEER, |peer_info| {
+                            peer_info
+                                .push(&ifinfomsg(0, 0, 0))
+                                .name(libc::IFLA_IFNAME, peer);
+                        });
+                    });
+            });
+        self.acked(request)
+            .map_err(|e| error(format!("create veth pair {name}/{peer}"), e))
+    }
+
+    pub fn delete_link(&mut self, name: &str) -> ContainerResult<()> {
+        let mut request = Request::new(libc::RTM_DELLINK, libc::NLM_F_ACK as u16);
+        request
+            .push(&ifinfomsg(0, 0, 0))
+            .name(libc::IFLA_IFNAME, name);
+        self.acked(request)
+            .map_err(|e| error(format!("delete link {name}"), e))
+    }
+
+    pub fn set_up(&mut self, name: &str) -> ContainerResult<()> {
+        let up = libc::IFF_UP as u32;
+        self.set_link(name, &ifinfomsg(0, up, up), |_| {})
+            .map_err(|e| error(format!("bring up {name}"), e))
+    }
+
+    pub fn set_master(&mut self, name: &str, master: i32) -> ContainerResult<()> {
+        self.set_link(name, &ifinfomsg(0, 0, 0), |request| {
+            request.attr(libc::IFLA_MASTER, &master.to_ne_bytes());
+        })
+        .map_err(|e| error(format!("attach {name} to its bridge"), e))
+    }
+
+    // Moves the link into the namespace and renames it there in one step, so
+    // the new name never has to be free on the host.
+    pub fn move_to(&mut self, name: &str, netns: &File, new_name: &str) -> ContainerResult<()> {
+        let index = self.link_index(name)?;
+        let fd = netns.as_raw_fd() as u32;
+        let mut request = Request::new(libc::RTM_NEWLINK, libc::NLM_F_ACK as u16);
+        request
+            .push(&ifinfomsg(index, 0, 0))
+            .attr(libc::IFLA_NET_NS_FD, &fd.to_ne_bytes())
+            .name(libc::IFLA_IFNAME, new_name);
+        self.acked(request)
+            .map_err(|e| error(format!("move {name} into the container"), e))
+    }
+
+    pub fn add_address(
+        &mut self,
+        name: &str,
+        address: Ipv4Addr,
+        prefix: u8,
+    ) -> ContainerResult<()> {
+        let index = self.link_index(name)?;
+        let mut msg = [0u8; 8];
+        msg[0] = libc::AF_INET as u8;
+        msg[1] = prefix;
+        msg[4..8].copy_from_slice(&index.to_ne_bytes());
+        let mut request = Request::new(libc::RTM_NEWADDR, create_flags());
+        request
+            .push(&msg)
+            .attr(libc::IFA_LOCAL, &address.octets())
+            .attr(libc::IFA_ADDRESS, &address.octets());
+        self.acked(request)
+            .map_err(|e| error(format!("add {address}/{prefix} to {name}"), e))
+    }
+
+    pub fn add_default_route(&mut self, gateway: Ipv4Addr) -> ContainerResult<()> {
+        let msg = [
+            libc::AF_INET as u8,
+            0,
+            0,
+            0,
+            libc::RT_TABLE_MAIN,
+            libc::RTPROT_BOOT,
+            libc::RT_SCOPE_UNIVERSE,
+            libc::RTN_UNICAST,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let mut request = Request::new(libc::RTM_NEWROUTE, create_flags());
+        request
+            .push(&msg)
+            .attr(libc::RTA_GATEWAY, &gateway.octets());
+        self.acked(request)
+            .map_err(|e| error(format!("add a default route via {gateway}"), e))
+    }
+
+    // The link is named rather than indexed; the kernel looks it up.
+    fn set_link(
+        &mut self,
+        name: &str,
+        msg: &[u8; IFINFOMSG_LEN],
+        fill: impl FnOnce(&mut Request),
+    ) -> Result<(), Errno> {
+        let mut request = Request::new(libc::RTM_NEWLINK, libc::NLM_F_ACK as u16);
+        request.push(msg).name(libc::IFLA_IFNAME, name);
+        fill(&mut request);
+        self.acked(request)
+    }
+
+    fn acked(&mut self, request: Request) -> Result<(), Errno> {
+        self.exchange(request).map(drop)
+    }
+
+    // Sends the request and returns the first reply that is not an
+    // acknowledgement; an error reply becomes its errno.
+    fn exchange(&mut self, request: Request) -> Result<Vec<u8>, Errno> {
+        self.seq += 1;
+        let message = request.into_bytes(self.seq);
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as u16;
+        let sent = unsafe {
+            libc::sendto(
+                self.socket.as_raw_fd(),
+                message.as_ptr().cast(),
+                message.len(),
+                0,
+                (&raw const address).cast(),
+                size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        Errno::result(sent)?;
+        let mut buf = vec![0u8; RECV_BUFFER];
+        loop {
+            let received = Errno::result(unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                    0,
+                )
+            })? as usize;
+            let mut offset = 0;
+            while offset + NLMSG_HDRLEN <= received {
+                let header = &buf[offset..];
+                let len = u32::from_ne_bytes(header[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(header[4..6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(header[8..12].try_into().unwrap());
+                if len < NLMSG_HDRLEN || offset + len > received {
+                    return Err(Errno::EBADMSG);
+                }
+                if seq == self.seq {
+                    if kind != libc::NLMSG_ERROR as u16 {
+                        return Ok(header[..len].to_vec());
+                    }
+                    let code = header
+                        .get(NLMSG_HDRLEN..NLMSG_HDRLEN + 4)
+                        .map(|code| i32::from_ne_bytes(code.try_into().unwrap()))
+                        .ok_or(Errno::EBADMSG)?;
+                    return match code {
+                        0 => Ok(Vec::new()),
+                        code => Err(Errno::from_raw(-code)),
+                    };
+                }
+                offset += len.next_multiple_of(4);
+            }
+        }
+    }
+}
+
+fn create_flags() -> u16 {
+    (libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16
+}
+
+fn error(operation: impl Into<String>, errno: Errno) -> ContainerError {
+    ContainerError::Network {
+        detail: ErrorDetail::new(format!("Failed to {}", operation.into())).source(errno),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_are_padded_and_nests_cover_their_contents() {
+        let mut request = Request::new(libc::RTM_NEWLINK, 0);
+        request
+            .name(libc::IFLA_IFNAME, "eth0")
+            .nest(libc::IFLA_LINKINFO, |info| {
+                info.name(libc::IFLA_INFO_KIND, "veth");
+            });
+        let bytes = request.into_bytes(7);
+        // "eth0\0" is 9 bytes with its header, padded to 12; "veth\0" nests
+        // the same way inside a 4-byte nest header.
+        assert_eq!(bytes.len(), NLMSG_HDRLEN + 12 + 4 + 12);
+        assert_eq!(
+            u32::from_ne_bytes(bytes[0..4].try_into().unwrap()),
+            bytes.len() as u32
+        );
+        assert_eq!(u32::from_ne_bytes(bytes[8..12].try_into().unwrap()), 7);
+        assert_eq!(u16::from_ne_bytes(bytes[16..18].try_into().unwrap()), 9);
+        assert_eq!(&bytes[20..25], b"eth0\0");
+        assert_eq!(u16::from_ne_bytes(bytes[28..30].try_into().unwrap()), 16);
+        assert_eq!(
+            u16::from_ne_bytes(bytes[30..32].try_into().unwrap()),
+            libc::IFLA_LINKINFO
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use crate::error::{ContainerError, ContainerResult};
+use crate::netlink::Netlink;
+use nix::unistd::{Pid, getpid};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const IPAM_DIR: &str = "/run/container_rs/ipam";
+const CONTAINER_IFACE: &str = "eth0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkMode {
+    None,
+    Host,
+    Bridge,
+}
+
+impl FromStr for NetworkMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(NetworkMode::None),
+            "host" => Ok(NetworkMode::Host),
+            "bridge" => Ok(NetworkMode::Bridge),
+            other => Err(format!("unknown network mode '{other}'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    pub network: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected CIDR notation like 10.88.0.0/24, got '{s}'"))?;
+        let addr: Ipv4Addr = addr
+            .parse()
+            .map_err(|e| format!("invalid subnet address '{addr}': {e}"))?;
+        let prefix: u8 = prefix
+            .parse()
+            .map_err(|e| format!("invalid prefix length '{prefix}': {e}"))?;
+        if !(8..=30).contains(&prefix) {
+            return Err(format!(
+                "prefix length must be between 8 and 30, got {prefix}"
+            ));
+        }
+        let mask = u32::MAX << (32 - prefix);
+        Ok(Subnet {
+            network: Ipv4Addr::from(u32::from(addr) & mask),
+            prefix,
+        })
+    }
+}
+
+impl Subnet {
+    fn host(&self, index: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + index)
+    }
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.host(1)
+    }
+    fn host_count(&self) -> u32 {
+        (1u32 << (32 - self.prefix)) - 2
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DnsConfig {
+    pub servers: Vec<IpAddr>,
+    pub search: Vec<String>,
+    // Keep the generated resolv.conf in memory instead of writing the rootfs.
+    pub ephemeral: bool,
+}
+
+impl DnsConfig {
+    // glibc ignores nameserver lines past the third one.
+    const MAX_SERVERS: usize = 3;
+
+    pub fn resolv_conf(&self) -> Option<String> {
+        if self.servers.is_empty() && self.search.is_empty() {
+            return None;
+        }
+        if self.servers.len() > Self::MAX_SERVERS {
+            log::warn!(
+                "{} DNS servers given, resolvers only use the first {}",
+                self.servers.len(),
+                Self::MAX_SERVERS
+            );
+        }
+        let mut content = String::from("# Generated by container_rs\n");
+        if !self.search.is_empty() {
+            content.push_str(&format!("search {}\n", self.search.join(" ")));
+        }
+        for server in &self.servers {
+            content.push_str(&format!("nameserver {server}\n"));
+        }
+        Some(content)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub mode: NetworkMode,
+    pub bridge: String,
+    pub subnet: Subnet,
+    pub dns: DnsConfig,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            mode: NetworkMode::None,
+            bridge: "crs0".to_string(),
+            subnet: Subnet {
+                network: Ipv4Addr::new(10, 88, 0, 0),
+                prefix: 24,
+            },
+            dns: DnsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NetworkManager {
+    host_veth: String,
+    address: Ipv4Addr,
+    lease_path: PathBuf,
+}
+
+impl NetworkManager {
+    pub fn setup_bridge(child: Pid, config: &NetworkConfig) -> ContainerResult<Self> {
+        log::info!(
+            "Setting up bridge networking on {} ({}/{})",
+            config.bridge,
+            config.subnet.network,
+            config.subnet.prefix
+        );
+        let (address, lease_path) = Self::allocate_address(config, child)?;
+        // From here on, Drop tears down whatever has been created so far.
+        let manager = Self {
+            host_veth: format!("veth{}", child.as_raw()),
+            address,
+            lease_path,
+        };
+        let peer = format!("vethc{}", child.as_raw());
+        let mut host = Netlink::open()?;
+        Self::ensure_bridge(&mut host, config)?;
+        host.add_veth(&manager.host_veth, &peer)?;
+        let bridge = host.link_index(&config.bridge)?;
+        host.set_master(&manager.host_veth, bridge)?;
+        host.set_up(&manager.host_veth)?;
+
+        let netns = File::open(format!("/proc/{}/ns/net", child.as_raw())).map_err(|e| {
+            ContainerError::network(format!("Failed to open container network namespace: {e}"))
+        })?;
+        host.move_to(&peer, &netns, CONTAINER_IFACE)?;
+        let cidr = format!("{}/{}", address, config.subnet.prefix);
+        let gateway = config.subnet.gateway();
+        let mut container = Netlink::open_in(&netns)?;
+        container.set_up("lo")?;
+        container.add_address(CONTAINER_IFACE, address, config.subnet.prefix)?;
+        container.set_up(CONTAINER_IFACE)?;
+        container.add_default_route(gateway)?;
+        log::info!(
+            "Container network ready: {CONTAINER_IFACE} {cidr} via {gateway} (host side {})",
+            manager.host_veth
+        );
+        Ok(manager)
+    }
+
+    fn ensure_bridge(host: &mut Netlink, config: &NetworkConfig) -> ContainerResult<()> {
+        let bridge = config.bridge.as_str();
+        if !Path::new("/sys/class/net").join(bridge).exists() {
+            host.add_bridge(bridge)?;
+            log::info!("Created bridge {bridge}");
+        }
+        let (gateway, prefix) = (config.subnet.gateway(), config.subnet.prefix);
+        if let Err(e) = host.add_address(bridge, gateway, prefix) {
+            // Already assigned by a previous container on this bridge.
+            log::debug!("Bridge address {gateway}/{prefix} not added: {e}");
+        }
+        host.set_up(bridge)
+    }
+
+    // Leases are files named after the address containing the owning runtime's
+    // PID, created with O_EXCL so concurrent runtimes never hand out the same
+    // address. Leases left behind by dead runtimes are reclaimed.
+    fn allocate_address(
+        config: &NetworkConfig,
+        child: Pid,
+    ) -> ContainerResult<(Ipv4Addr, PathBuf)> {
+        let dir = Path::new(IPAM_DIR).join(&config.bridge);
+        fs::create_dir_all(&dir).map_err(|e| {
+            ContainerError::network(format!("Failed to create IPAM directory {dir:?}: {e}"))
+        })?;
+        let count = config.subnet.host_count();
+        let start = child.as_raw() as u32 % count;
+        for offset in 0..count {
+            let index = (start + offset) % count + 1;
+            let address = config.subnet.host(index);
+            if address == config.subnet.gateway() {
+                continue;
+            }
+            let lease_path = dir.join(address.to_string());
+            for _ in 0..2 {
+                match OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&lease_path)
+                {
+                    Ok(mut file) => {
+                        file.write_all(getpid().to_string().as_bytes())?;
+                        log::debug!("Allocated address {address}");
+                        return Ok((address, lease_path));
+                    }
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                        if !Self::lease_is_stale(&lease_path) {
+                            break;
+                        }
+                        log::debug!("Reclaiming stale lease for {address}");
+                        let _ = fs::remove_file(&lease_path);
+                    }
+                    Err(e) => {
+                        return Err(ContainerError::network(format!(
+                            "Failed to create lease {lease_path:?}: {e}"
+                        )));
+                    }
+                }
+            }
+        }
+        Err(ContainerError::network(format!(
+            "No free addresses left in {}/{}",
+            config.subnet.network, config.subnet.prefix
+        )))
+    }
+
+    fn lease_is_stale(lease_path: &Path) -> bool {
+        fs::read_to_string(lease_path)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<i32>().ok())
+            .is_some_and(|pid| !Path::new(&format!("/proc/{pid}")).exists())
+    }
+
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    pub fn teardown(&self) {
+        if Path::new("/sys/class/net").join(&self.host_veth).exists() {
+            match Netlink::open().and_then(|mut host| host.delete_link(&self.host_veth)) {
+                Ok(()) => log::info!("Removed veth {}", self.host_veth),
+                Err(e) => log::warn!("Failed to remove veth {}: {e}", self.host_veth),
+            }
+        }
+        if let Err(e) = fs::remove_file(&self.lease_path)
+            && e.kind() != ErrorKind::NotFound
+        {
+            log::warn!("Failed to release address {}: {e}", self.address);
+        }
+    }
+}
+
+impl Drop for NetworkManager {
+    fn drop(&mut self) {
+        self.teardown();
+    }
+}
@@ -0,0 +1,275 @@
+use crate::cgroup::{CGROUP_ROOT, CgroupManager, process_cgroups_v1};
+use crate::cli::ContainerConfig;
+use crate::error::{ContainerError, ContainerResult};
+use crate::filesystem::{FilesystemManager, unescape_mount_path};
+use crate::namespace::{CLONE_NEWTIME, NamespaceConfig};
+use nix::errno::Errno;
+use nix::sched::{CloneFlags, unshare};
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::{ForkResult, fork};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Kernel features the runtime relies on, probed before anything is set up so
+// a locked-down kernel fails with the feature's name and the flag to drop
+// instead of an errno from deep inside setup. --skip-preflight skips all of it.
+
+// A namespace as /proc/self/ns names it, and how to do without it.
+struct Namespace {
+    name: &'static str,
+    flag: CloneFlags,
+    hint: &'static str,
+}
+
+fn wanted_namespaces(ns_config: &NamespaceConfig) -> Vec<Namespace> {
+    [
+        (
+            ns_config.isolate_mount,
+            "mnt",
+            CloneFlags::CLONE_NEWNS,
+            "run with --no-mount-ns --no-pivot to share the host's",
+        ),
+        (
+            ns_config.isolate_pid,
+            "pid",
+            CloneFlags::CLONE_NEWPID,
+            "run with --no-pid-ns to share the host's",
+        ),
+        (
+            ns_config.isolate_net,
+            "net",
+            CloneFlags::CLONE_NEWNET,
+            "run with --network host to share the host's",
+        ),
+        (
+            ns_config.isolate_ipc,
+            "ipc",
+            CloneFlags::CLONE_NEWIPC,
+            "run with --no-ipc-ns to share the host's",
+        ),
+        (
+            ns_config.isolate_uts,
+            "uts",
+            CloneFlags::CLONE_NEWUTS,
+            "run with --no-uts-ns to share the host's",
+        ),
+        (
+            ns_config.isolate_cgroup,
+            "cgroup",
+            CloneFlags::CLONE_NEWCGROUP,
+            "run with --no-cgroup-ns to share the host's",
+        ),
+        (
+            ns_config.isolate_user,
+            "user",
+            CloneFlags::CLONE_NEWUSER,
+            "drop --userns",
+        ),
+        (
+            ns_config.isolate_time,
+            "time",
+            CLONE_NEWTIME,
+            "drop --time-offset",
+        ),
+    ]
+    .into_iter()
+    .filter(|(wanted, ..)| *wanted)
+    .map(|(_, name, flag, hint)| Namespace { name, flag, hint })
+    .collect()
+}
+
+// Namespaces, cgroup controllers and overlayfs, as far as the container
+// uses them.
+pub fn check_kernel(
+    config: &ContainerConfig,
+    ns_config: &NamespaceConfig,
+    name: &str,
+) -> ContainerResult<()> {
+    if let Some(e) = check_namespaces(ns_config).into_iter().next() {
+        return Err(e);
+    }
+    check_devices(config)?;
+    check_realtime(config)?;
+    if config.needs_cgroup() {
+        CgroupManager::check(&config.cgroup_config(name.to_string())?)?;
+    }
+    if config.overlay {
+        check_overlay()?;
+    }
+    Ok(())
+}
+
+// Every --device names an existing device node on the host.
+pub fn check_devices(config: &ContainerConfig) -> ContainerResult<()> {
+    for device in &config.devices {
+        device.resolve()?;
+    }
+    Ok(())
+}
+
+// --sched-policy is refused by the kernel when realtime tasks would get no CPU
+// time at all: throttled to nothing kernel-wide, or in the cgroup v1 cpu
+// cgroup the init ends up in.
+pub fn check_realtime(config: &ContainerConfig) -> ContainerResult<()> {
+    let Some((policy, priority)) = config.sched else {
+        return Ok(());
+    };
+    let global = fs::read_to_string("/proc/sys/kernel/sched_rt_runtime_us").unwrap_or_default();
+    if global.trim() == "0" {
+        return Err(ContainerError::invalid_configuration(format!(
+            "--sched-policy {policy} cannot run: kernel.sched_rt_runtime_us is 0, which leaves \
+             realtime tasks no CPU time"
+        )));
+    }
+    let (runtime, option) = match config.cpu_rt_runtime {
+        Some(runtime) => (Some(runtime), "--cpu-rt-runtime 0"),
+        // Without --cpu-rt-runtime the init stays in the runtime's own cpu
+        // cgroup; without realtime group scheduling there is no such file.
+        None => (
+            process_cgroups_v1(Path::new(CGROUP_ROOT), std::process::id())
+                .into_iter()
+                .find(|cgroup| cgroup.starts_with(Path::new(CGROUP_ROOT).join("cpu")))
+                .and_then(|cgroup| fs::read_to_string(cgroup.join("cpu.rt_runtime_us")).ok())
+                .and_then(|runtime| runtime.trim().parse().ok()),
+            "the runtime's cpu cgroup (cpu.rt_runtime_us is 0); give it some with --cpu-rt-runtime",
+        ),
+    };
+    if runtime == Some(0) {
+        return Err(ContainerError::invalid_configuration(format!(
+            "--sched-policy {policy} --sched-priority {priority} cannot run with no realtime \
+             CPU time: {option}"
+        )));
+    }
+    Ok(())
+}
+
+// One error per namespace the kernel cannot create.
+pub fn check_namespaces(ns_config: &NamespaceConfig) -> Vec<ContainerError> {
+    let wanted = wanted_namespaces(ns_config);
+    let all = wanted
+        .iter()
+        .fold(CloneFlags::empty(), |flags, ns| flags | ns.flag);
+    // One trial for the lot; only a failure is narrowed down.
+    if wanted.iter().all(|ns| proc_ns(ns.name).exists()) && trial_unshare(all).is_ok() {
+        return Vec::new();
+    }
+    wanted
+        .iter()
+        .filter_map(|ns| {
+            let reason = if !proc_ns(ns.name).exists() {
+                format!("there is no {}", proc_ns(ns.name).display())
+            } else {
+                match trial_unshare(ns.flag) {
+                    Ok(()) => return None,
+                    Err(e) => format!("unshare failed: {e}"),
+                }
+            };
+            Some(ContainerError::invalid_configuration(format!(
+                "The kernel cannot create {} namespaces ({reason}); {}",
+                ns.name, ns.hint
+            )))
+        })
+        .collect()
+}
+
+fn proc_ns(name: &str) -> PathBuf {
+    Path::new("/proc/self/ns").join(name)
+}
+
+// unshare in a throwaway child, so the runtime's own namespaces are left
+// alone. The child reports the errno as its exit status.
+fn trial_unshare(flags: CloneFlags) -> Result<(), Errno> {
+    match unsafe { fork()? } {
+        ForkResult::Child => {
+            let code = match unshare(flags) {
+                Ok(()) => 0,
+                Err(e) => e as i32,
+            };
+            unsafe { libc::_exit(code) }
+        }
+        ForkResult::Parent { child } => match waitpid(child, None)? {
+            WaitStatus::Exited(_, 0) => Ok(()),
+            WaitStatus::Exited(_, code) => Err(Errno::from_raw(code)),
+            _ => Err(Errno::ECHILD),
+        },
+    }
+}
+
+pub fn check_overlay() -> ContainerResult<()> {
+    let filesystems = fs::read_to_string("/proc/filesystems").unwrap_or_default();
+    if filesystems
+        .lines()
+        .any(|line| line.split_whitespace().last() == Some("overlay"))
+    {
+        return Ok(());
+    }
+    Err(ContainerError::invalid_configuration(
+        "overlayfs is not available on this kernel (try `modprobe overlay`); \
+         run without --overlay to use the rootfs directly",
+    ))
+}
+
+// The mount the rootfs lives on, and the command in it.
+pub fn check_rootfs(config: &ContainerConfig) -> ContainerResult<()> {
+    let rootfs = fs::canonicalize(&config.rootfs)?;
+    if let Some(mount) = find_mount(&rootfs)? {
+        if mount.options.iter().any(|option| option == "noexec") {
+            return Err(ContainerError::invalid_configuration(format!(
+                "The rootfs {} is on a noexec mount ({}), so nothing in it can run; \
+                 move it to another filesystem",
+                rootfs.display(),
+                mount.mount_point.display()
+            )));
+        }
+        // The initramfs cannot be pivoted away from; the runtime falls back
+        // to chroot, which leaves the host's root reachable by escaping it.
+        if !config.no_pivot && matches!(mount.fs_type.as_str(), "rootfs" | "ramfs") {
+            log::warn!(
+                "The rootfs is on the initramfs, where pivot_root is not possible: \
+                 chroot will be used instead (pass --no-pivot to skip the attempt)"
+            );
+        }
+    }
+    FilesystemManager::validate_command(
+        &config.rootfs,
+        &config.command,
+        config.workdir.as_deref(),
+        &config.env,
+    )
+}
+
+struct MountEntry {
+    mount_point: PathBuf,
+    fs_type: String,
+    options: Vec<String>,
+}
+
+// The innermost mount holding `path`: the longest matching mount point, the
+// last one listed if several are stacked.
+fn find_mount(path: &Path) -> ContainerResult<Option<MountEntry>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let mut found: Option<MountEntry> = None;
+    for line in mountinfo.lines() {
+        let Some((mount, filesystem)) = line.split_once(" - ") else {
+            continue;
+        };
+        let mut fields = mount.split(' ');
+        let (Some(mount_point), Some(options)) = (fields.nth(4), fields.next()) else {
+            continue;
+        };
+        let mount_point = unescape_mount_path(mount_point.as_bytes());
+        if !path.starts_with(&mount_point)
+            || found
+                .as_ref()
+                .is_some_and(|m| m.mount_point.as_os_str().len() > mount_point.as_os_str().len())
+        {
+            continue;
+        }
+        found = Some(MountEntry {
+            mount_point,
+            fs_type: filesystem.split(' ').next().unwrap_or_default().to_string(),
+            options: options.split(',').map(str::to_string).collect(),
+        });
+    }
+    Ok(found)
+}
@@ -1,114 +1,632 @@
-use crate::error::{ContainerError, ContainerResult, Context};
-use nix::mount::{MsFlags, mount};
+use crate::capabilities::{CapabilityConfig, CapabilityManager};
+use crate::console;
+use crate::error::{ContainerError, ContainerResult, ErrorDetail};
+use crate::log_file::{LogFile, LogStream};
+use crate::logging;
+use crate::seccomp::SeccompProgram;
+use crate::signals;
+use nix::errno::Errno;
+use nix::fcntl::{OFlag, open};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
 use nix::pty::openpty;
-use nix::sys::signal::{SigHandler, Signal, kill, signal};
+use nix::sys::prctl::{self, set_no_new_privs};
+use nix::sys::resource::{Resource, getrlimit, setrlimit};
+use nix::sys::signal::Signal;
+use nix::sys::stat::Mode;
+use nix::sys::termios::{SpecialCharacterIndices, tcgetattr};
 use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
-use nix::unistd::{ForkResult, Pid, dup2, execve, fork, pipe, setsid};
-use std::ffi::CString;
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
-use std::path::Path;
-use std::sync::atomic::{AtomicI32, Ordering};
+use nix::unistd::{
+    AccessFlags, ForkResult, Gid, Pid, access, chdir, dup2, execve, fork, pipe2, setgroups, setsid,
+};
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::os::fd::{AsFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+// Commands that reap their own orphans. They are made a child subreaper
+// instead, so orphaned processes are handed to them rather than to us.
+const KNOWN_INITS: [&str; 8] = [
+    "init",
+    "systemd",
+    "tini",
+    "dumb-init",
+    "catatonit",
+    "s6-svscan",
+    "runit",
+    "openrc-init",
+];
 
-extern "C" fn handle_signal(sig: i32) {
-    let child = CHILD_PID.load(Ordering::SeqCst);
-    if child > 0 {
-        if let Ok(signal) = Signal::try_from(sig) {
-            let _ = kill(Pid::from_raw(child), signal);
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+const BLOCK_BUFFER_SIZE: usize = 64 * 1024;
+
+// How the workload ended. The runtime exits like a shell would: with the
+// command's exit status, or 128 + the signal number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitOutcome {
+    Exited(i32),
+    Signaled(Signal),
+    // Stopped by --timeout; exits 124 like coreutils timeout.
+    TimedOut,
+}
+
+impl WaitOutcome {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            WaitOutcome::Exited(code) => code,
+            WaitOutcome::Signaled(signal) => 128 + signal as i32,
+            WaitOutcome::TimedOut => 124,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct ProcessManager;
+#[derive(Debug, Default)]
+pub struct ProcessOptions {
+    pub oom_score_adj: Option<i32>,
+    pub workdir: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+    // Start the workload in a new session. Without one it stays in the
+    // caller's session and process group: terminal job control (Ctrl-Z,
+    // fg/bg) then works, but terminal-generated signals reach the container
+    // directly as well as through the forwarder. A PTY always gets one.
+    pub setsid: bool,
+    pub output_buffering: OutputBuffering,
+    pub capabilities: CapabilityConfig,
+    pub rlimit_nofile: Option<NofileLimit>,
+    pub ulimits: Vec<Rlimit>,
+    pub nice: Option<i32>,
+    pub ionice: Option<IoPriority>,
+    // --sched-policy with its priority.
+    pub sched: Option<(SchedPolicy, i32)>,
+    pub seccomp: Option<SeccompProgram>,
+    pub no_new_privileges: bool,
+    // --group-add: names or GIDs, looked up in the container's /etc/group.
+    pub groups: Vec<String>,
+    // Hand the PTY master over this socket instead of relaying it.
+    pub console_socket: Option<RawFd>,
+    // Relayed PTY output is copied here as well as to stdout.
+    pub log_file: Option<LogFile>,
+    // Reap orphans even when the command looks like an init of its own.
+    pub init: bool,
+    // -t: run the command on a PTY. -i: pass our stdin on to it.
+    pub tty: bool,
+    pub interactive: bool,
+}
 
-impl ProcessManager {
-    pub fn execute_container_command(command: &str, args: &[String]) -> ContainerResult<()> {
-        log::info!("Executing container command: {command} with args: {args:?}");
-        // Self::ensure_devpts_mounted()?;
-        // Find executable path
-        let command_path = if command.starts_with("/") {
-            command.to_string()
+// `max` lifts the soft limit to the current hard limit; a number sets the soft
+// limit, raising the hard limit too when that is allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NofileLimit {
+    Max,
+    Value(u64),
+}
+
+impl FromStr for NofileLimit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("max") {
+            return Ok(NofileLimit::Max);
+        }
+        match s.parse::<u64>() {
+            Ok(0) => Err("the file descriptor limit must be at least 1".to_string()),
+            Ok(value) => Ok(NofileLimit::Value(value)),
+            Err(_) => Err(format!("expected 'max' or a number, got '{s}'")),
+        }
+    }
+}
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const IOPRIO_CLASS_BE: u32 = 2;
+const IOPRIO_CLASS_IDLE: u32 = 3;
+
+// `best-effort[:LEVEL]`, LEVEL 0 (first served) to 7 and 4 when left out, or
+// `idle`: IO only when no one else wants the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoPriority {
+    fn value(self) -> libc::c_int {
+        let (class, level) = match self {
+            IoPriority::BestEffort(level) => (IOPRIO_CLASS_BE, u32::from(level)),
+            IoPriority::Idle => (IOPRIO_CLASS_IDLE, 0),
+        };
+        ((class << IOPRIO_CLASS_SHIFT) | level) as libc::c_int
+    }
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (s, None),
+        };
+        match (class, level) {
+            ("best-effort", None) => Ok(IoPriority::BestEffort(4)),
+            ("best-effort", Some(level)) => level
+                .parse::<u8>()
+                .ok()
+                .filter(|level| *level <= 7)
+                .map(IoPriority::BestEffort)
+                .ok_or_else(|| format!("invalid best-effort level '{level}', expected 0 to 7")),
+            ("idle", None) => Ok(IoPriority::Idle),
+            ("idle", Some(_)) => Err("the idle class has no levels".to_string()),
+            _ => Err(format!(
+                "unknown IO scheduling class '{class}', expected best-effort or idle"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IoPriority::BestEffort(level) => write!(f, "best-effort:{level}"),
+            IoPriority::Idle => f.write_str("idle"),
+        }
+    }
+}
+
+// The realtime scheduling policies: `fifo` runs until it blocks or yields,
+// `rr` shares the CPU in time slices with threads of the same priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    Fifo,
+    RoundRobin,
+}
+
+impl SchedPolicy {
+    fn policy(self) -> libc::c_int {
+        match self {
+            SchedPolicy::Fifo => libc::SCHED_FIFO,
+            SchedPolicy::RoundRobin => libc::SCHED_RR,
+        }
+    }
+
+    // The priorities the kernel accepts for the policy, 1 to 99 on Linux.
+    pub fn priorities(self) -> std::ops::RangeInclusive<i32> {
+        let policy = self.policy();
+        unsafe { libc::sched_get_priority_min(policy)..=libc::sched_get_priority_max(policy) }
+    }
+}
+
+impl FromStr for SchedPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(SchedPolicy::Fifo),
+            "rr" => Ok(SchedPolicy::RoundRobin),
+            other => Err(format!(
+                "unknown scheduling policy '{other}', expected fifo or rr"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SchedPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchedPolicy::Fifo => f.write_str("fifo"),
+            SchedPolicy::RoundRobin => f.write_str("rr"),
+        }
+    }
+}
+
+const RLIMIT_NAMES: [(&str, Resource); 7] = [
+    ("core", Resource::RLIMIT_CORE),
+    ("cpu", Resource::RLIMIT_CPU),
+    ("fsize", Resource::RLIMIT_FSIZE),
+    ("memlock", Resource::RLIMIT_MEMLOCK),
+    ("nofile", Resource::RLIMIT_NOFILE),
+    ("nproc", Resource::RLIMIT_NPROC),
+    ("stack", Resource::RLIMIT_STACK),
+];
+
+// `NAME=SOFT[:HARD]`, as in `nofile=1024:2048` or `core=0`. Either value may
+// be `unlimited`; a missing hard limit equals the soft one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rlimit {
+    pub name: &'static str,
+    pub resource: Resource,
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl FromStr for Rlimit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, values) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=SOFT[:HARD], got '{s}'"))?;
+        let (name, resource) = RLIMIT_NAMES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = RLIMIT_NAMES.iter().map(|(known, _)| *known).collect();
+                format!(
+                    "unknown limit '{name}', expected one of: {}",
+                    names.join(", ")
+                )
+            })?;
+        let parse = |value: &str| -> Result<u64, String> {
+            if value == "unlimited" {
+                return Ok(libc::RLIM_INFINITY);
+            }
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("invalid {name} limit '{value}'"))
+        };
+        let (soft, hard) = match values.split_once(':') {
+            Some((soft, hard)) => (parse(soft)?, parse(hard)?),
+            None => {
+                let value = parse(values)?;
+                (value, value)
+            }
+        };
+        if hard < soft {
+            return Err(format!(
+                "{name} hard limit {hard} is lower than the soft limit {soft}"
+            ));
+        }
+        Ok(Rlimit {
+            name,
+            resource,
+            soft,
+            hard,
+        })
+    }
+}
+
+// How container output relayed from the PTY is written to our stdout: `none`
+// flushes every read (lowest latency), `line` flushes on newlines and `block`
+// batches into a large buffer flushed every FLUSH_INTERVAL (best throughput).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputBuffering {
+    Line,
+    Block,
+    #[default]
+    None,
+}
+
+impl FromStr for OutputBuffering {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(OutputBuffering::Line),
+            "block" => Ok(OutputBuffering::Block),
+            "none" => Ok(OutputBuffering::None),
+            other => Err(format!("unknown output buffering mode '{other}'")),
+        }
+    }
+}
+
+struct OutputSink<W: Write> {
+    buffering: OutputBuffering,
+    writer: BufWriter<W>,
+    last_flush: Instant,
+}
+
+impl<W: Write> OutputSink<W> {
+    fn new(buffering: OutputBuffering, inner: W) -> Self {
+        let capacity = match buffering {
+            OutputBuffering::Block => BLOCK_BUFFER_SIZE,
+            _ => 8 * 1024,
+        };
+        Self {
+            buffering,
+            writer: BufWriter::with_capacity(capacity, inner),
+            last_flush: Instant::now(),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        let flush = match self.buffering {
+            OutputBuffering::None => true,
+            OutputBuffering::Line => data.contains(&b'\n'),
+            OutputBuffering::Block => self.last_flush.elapsed() >= FLUSH_INTERVAL,
+        };
+        if flush { self.flush() } else { Ok(()) }
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.writer.buffer().is_empty()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.writer.flush()
+    }
+}
+
+// Copies PTY output to stdout (and stdin to the PTY) until the master reports
+// EOF/EIO, or until the workload has exited and nothing more arrives within
+// FLUSH_INTERVAL. `exited` becomes readable (hangs up) once the workload is
+// gone; until then the relay only wakes up for I/O, or to flush buffered
+// output. Whatever is still buffered is flushed before returning.
+fn relay_output(
+    mut master: File,
+    buffering: OutputBuffering,
+    mut log: Option<LogFile>,
+    forward_input: bool,
+    exited: OwnedFd,
+) {
+    let mut sink = OutputSink::new(buffering, io::stdout());
+    let mut buffer = [0u8; 8192];
+    let flush_timeout = PollTimeout::try_from(FLUSH_INTERVAL).unwrap_or(PollTimeout::NONE);
+    let stdin = io::stdin();
+    let mut stdin_open = forward_input;
+    let mut at_line_start = true;
+    let mut running = true;
+    loop {
+        let mut fds = vec![PollFd::new(master.as_fd(), PollFlags::POLLIN)];
+        let exited_index = running.then(|| {
+            fds.push(PollFd::new(exited.as_fd(), PollFlags::POLLIN));
+            fds.len() - 1
+        });
+        let stdin_index = stdin_open.then(|| {
+            fds.push(PollFd::new(stdin.as_fd(), PollFlags::POLLIN));
+            fds.len() - 1
+        });
+        let timeout = if running && !sink.has_pending() {
+            PollTimeout::NONE
         } else {
-            ["/bin", "/usr/bin", "/sbin", "/usr/sbin"]
-                .iter()
-                .map(|prefix| format!("{}/{}", prefix, command))
-                .find(|p| Path::new(p).exists())
-                .unwrap_or_else(|| format!("/bin/{}", command))
+            flush_timeout
         };
+        match poll(&mut fds, timeout) {
+            Ok(0) => {
+                // Idle: do not sit on a partial line or a half-full block.
+                let _ = sink.flush();
+                if !running {
+                    break;
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+        let ready: Vec<bool> = fds.iter().map(|fd| fd.any().unwrap_or(false)).collect();
+        drop(fds);
 
-        if !Path::new(&command_path).exists() {
-            return Err(ContainerError::process_execution(format!(
-                "Command not found in container: {}",
-                command_path
-            )));
+        if ready[0] {
+            match master.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if let Some(log) = &mut log {
+                        log.write(LogStream::Stdout, &buffer[..n]);
+                    }
+                    if sink.write(&buffer[..n]).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        if exited_index.is_some_and(|index| ready[index]) {
+            running = false;
+            stdin_open = false;
+            continue;
+        }
+        if stdin_index.is_some_and(|index| ready[index]) {
+            // The keys go through the PTY's line discipline, so ^C becomes a
+            // SIGINT for the container's foreground process group.
+            match nix::unistd::read(stdin.as_fd(), &mut buffer) {
+                Ok(0) => {
+                    send_eof(&mut master, at_line_start);
+                    stdin_open = false;
+                }
+                Err(_) => stdin_open = false,
+                Ok(n) => {
+                    at_line_start = buffer[n - 1] == b'\n';
+                    if master.write_all(&buffer[..n]).is_err() {
+                        stdin_open = false;
+                    }
+                }
+            }
+        }
+    }
+    let _ = sink.flush();
+    if let Some(log) = &mut log {
+        log.finish();
+    }
+}
+
+// A PTY has no stdin to close: EOF is the line discipline's VEOF character
+// (normally ^D), typed twice when it first has to end an unfinished line.
+fn send_eof(master: &mut File, at_line_start: bool) {
+    let eof = tcgetattr(master.as_fd())
+        .map(|termios| termios.control_chars[SpecialCharacterIndices::VEOF as usize])
+        .unwrap_or(0x04);
+    let count = if at_line_start { 1 } else { 2 };
+    let _ = master.write_all(&vec![eof; count]);
+}
+
+// Without a PTY, output is only captured when it has to be logged: stdout and
+// stderr come through separate pipes so each record names its stream. Ends
+// like relay_output once both pipes are closed, or the workload has exited
+// and they have gone quiet.
+fn relay_pipes(stdout: File, stderr: File, mut log: LogFile, exited: OwnedFd) {
+    let mut sources = vec![
+        (
+            stdout,
+            LogStream::Stdout,
+            Box::new(io::stdout()) as Box<dyn Write>,
+        ),
+        (stderr, LogStream::Stderr, Box::new(io::stderr())),
+    ];
+    let mut buffer = [0u8; 8192];
+    let flush_timeout = PollTimeout::try_from(FLUSH_INTERVAL).unwrap_or(PollTimeout::NONE);
+    let mut running = true;
+    while !sources.is_empty() {
+        let mut fds: Vec<PollFd> = sources
+            .iter()
+            .map(|(pipe, _, _)| PollFd::new(pipe.as_fd(), PollFlags::POLLIN))
+            .collect();
+        if running {
+            fds.push(PollFd::new(exited.as_fd(), PollFlags::POLLIN));
+        }
+        let timeout = if running {
+            PollTimeout::NONE
+        } else {
+            flush_timeout
+        };
+        match poll(&mut fds, timeout) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+        let ready: Vec<bool> = fds.iter().map(|fd| fd.any().unwrap_or(false)).collect();
+        drop(fds);
+        if running && ready[sources.len()] {
+            running = false;
+        }
+        let mut closed = Vec::new();
+        for (index, (pipe, stream, out)) in sources.iter_mut().enumerate() {
+            if !ready[index] {
+                continue;
+            }
+            match pipe.read(&mut buffer) {
+                Ok(0) | Err(_) => closed.push(index),
+                Ok(n) => {
+                    log.write(*stream, &buffer[..n]);
+                    let _ = out.write_all(&buffer[..n]);
+                    let _ = out.flush();
+                }
+            }
+        }
+        for index in closed.into_iter().rev() {
+            sources.remove(index);
+        }
+    }
+    log.finish();
+}
+
+// Copies our stdin to the command's stdin pipe, closing the pipe at EOF so
+// the command sees EOF too. Gives up once the workload has exited instead of
+// sitting in a read that may never return.
+fn forward_stdin(mut pipe: File, exited: OwnedFd) {
+    let stdin = io::stdin();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let mut fds = [
+            PollFd::new(stdin.as_fd(), PollFlags::POLLIN),
+            PollFd::new(exited.as_fd(), PollFlags::POLLIN),
+        ];
+        match poll(&mut fds, PollTimeout::NONE) {
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(_) => return,
+        }
+        if fds[1].any().unwrap_or(false) {
+            return;
         }
+        if !fds[0].any().unwrap_or(false) {
+            continue;
+        }
+        match nix::unistd::read(stdin.as_fd(), &mut buffer) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => {
+                if pipe.write_all(&buffer[..n]).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata().is_ok_and(|meta| meta.is_file()) && access(path, AccessFlags::X_OK).is_ok()
+}
+
+// A pipe whose read end hangs up when the write end is dropped, which the
+// relays poll to learn that the workload has exited.
+fn exit_pipe() -> ContainerResult<(OwnedFd, OwnedFd)> {
+    pipe2(OFlag::O_CLOEXEC)
+        .map_err(|e| ContainerError::process_execution(format!("Failed to create exit pipe: {e}")))
+}
+
+#[derive(Debug)]
+pub struct ProcessManager;
+
+impl ProcessManager {
+    pub fn execute_container_command(
+        command: &OsStr,
+        args: &[OsString],
+        options: &ProcessOptions,
+    ) -> ContainerResult<WaitOutcome> {
+        log::info!("Executing container command: {command:?} with args: {args:?}");
+        let command_path = Self::resolve_command(command, options)?;
 
         let argv = Self::build_argv(&command_path, args)?;
-        let envp = Self::build_environment()?;
+        let envp = Self::build_environment(&options.env)?;
+        Self::apply_ulimits(&options.ulimits)?;
+        Self::apply_priority(options.nice, options.ionice)?;
+        if let Some((policy, priority)) = options.sched {
+            Self::apply_sched(policy, priority)?;
+        }
 
-        // Try to create pseudo-terminal, fall back to direct execution if not available
-        let use_pty = openpty(None, None).is_ok();
+        // We are PID 1 of the container, so every orphan in it becomes our
+        // child.
+        let is_init = command_path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| KNOWN_INITS.contains(&name));
+        let reap_orphans = options.init || !is_init;
+        if !reap_orphans {
+            log::debug!("{} reaps its own orphans", command_path.display());
+        }
 
-        if use_pty {
-            Self::execute_with_pty(command, &argv, &envp)
+        if options.tty {
+            if !options.setsid {
+                log::warn!("--no-setsid has no effect with a PTY, which needs its own session");
+            }
+            Self::execute_with_pty(&command_path, &argv, &envp, options, reap_orphans)
         } else {
-            log::warn!("PTY not available (ENODEV), running without PTY support");
-            Self::execute_without_pty(command, &argv, &envp)
-        }
-    }
-    // fn ensure_devpts_mounted() -> ContainerResult<()> {
-    //     // Check if /dev/pts exists
-    //     let dev_pts = Path::new("/dev/pts");
-    //     if !dev_pts.exists() {
-    //         log::info!("Creating /dev/pts directory");
-    //         std::fs::create_dir_all(dev_pts).ok();
-    //     }
-
-    //     // Try to mount devpts if not already mounted
-    //     // We ignore errors here since it might already be mounted
-    //     let result = mount(
-    //         Some("devpts"),
-    //         "/dev/pts",
-    //         Some("devpts"),
-    //         MsFlags::empty(),
-    //         Some("newinstance,ptmxmode=0666,mode=0620"),
-    //     );
-
-    //     match result {
-    //         Ok(_) => {
-    //             log::info!("devpts filesystem mounted at /dev/pts");
-    //         }
-    //         Err(e) => {
-    //             // Check if it's already mounted (EBUSY is normal)
-    //             if e != nix::errno::Errno::EBUSY {
-    //                 log::warn!("Could not mount devpts: {e} (may already be mounted)");
-    //             }
-    //         }
-    //     }
-
-    //     // Ensure /dev/ptmx exists and links to /dev/pts/ptmx
-    //     let dev_ptmx = Path::new("/dev/ptmx");
-    //     if !dev_ptmx.exists() {
-    //         log::info!("Creating /dev/ptmx symlink");
-    //         std::os::unix::fs::symlink("/dev/pts/ptmx", "/dev/ptmx").ok();
-    //     }
-
-    //     Ok(())
-    // }
-    fn execute_with_pty(command: &str, argv: &[CString], envp: &[CString]) -> ContainerResult<()> {
+            Self::execute_without_pty(&command_path, &argv, &envp, options, reap_orphans)
+        }
+    }
+    fn execute_with_pty(
+        command: &Path,
+        argv: &[CString],
+        envp: &[CString],
+        options: &ProcessOptions,
+        reap_orphans: bool,
+    ) -> ContainerResult<WaitOutcome> {
         let pty = openpty(None, None)
             .map_err(|e| ContainerError::process_execution(format!("openpty failed: {e}")))?;
 
-        unsafe {
-            signal(Signal::SIGINT, SigHandler::Handler(handle_signal)).ok();
-            signal(Signal::SIGTERM, SigHandler::Handler(handle_signal)).ok();
-            signal(Signal::SIGQUIT, SigHandler::Handler(handle_signal)).ok();
-        }
+        signals::install_forwarding(false);
+        // Sized before the fork so the command never sees a 0x0 terminal. A
+        // supervisor's console is sized by its attach clients instead.
+        let window = match options.console_socket {
+            Some(_) => None,
+            None => console::WindowSizeFollower::start(&pty.master),
+        };
+        // Keys are handed to the PTY as typed; its own line discipline does
+        // the echoing and turns ^C into signals. Restored when this returns,
+        // whichever way that is.
+        let terminal = match options.console_socket {
+            None if options.interactive => console::RawTerminal::enable(),
+            _ => None,
+        };
 
         match unsafe { fork()? } {
             ForkResult::Child => {
+                logging::keep_stderr();
+                // The PTY slave can only become our controlling terminal in a
+                // fresh session, so --no-setsid does not apply here.
                 let _ = setsid();
 
                 let mut stdin_fd = unsafe { OwnedFd::from_raw_fd(0) };
@@ -119,6 +637,15 @@ impl ProcessManager {
                 dup2(&pty.slave, &mut stdout_fd).unwrap();
                 dup2(&pty.slave, &mut stderr_fd).unwrap();
 
+                // Without a controlling terminal the kernel has no foreground
+                // process group to send SIGWINCH (or ^C's SIGINT) to.
+                if unsafe { libc::ioctl(0, libc::TIOCSCTTY, 0) } != 0 {
+                    log::debug!(
+                        "Failed to make the PTY the controlling terminal: {}",
+                        io::Error::last_os_error()
+                    );
+                }
+
                 std::mem::forget(stdin_fd);
                 std::mem::forget(stdout_fd);
                 std::mem::forget(stderr_fd);
@@ -126,100 +653,363 @@ impl ProcessManager {
                 drop(pty.master);
                 drop(pty.slave);
 
-                unsafe {
-                    signal(Signal::SIGINT, SigHandler::SigDfl).ok();
-                    signal(Signal::SIGTERM, SigHandler::SigDfl).ok();
-                    signal(Signal::SIGQUIT, SigHandler::SigDfl).ok();
-                }
+                signals::reset_forwarding();
 
-                execve(&argv[0], argv, envp).map_err(|e| {
-                    ContainerError::process_execution(format!("execve failed for {command}: {e}"))
+                Self::prepare_child(options, !reap_orphans)?;
+                execve(&argv[0], argv, envp).map_err(|e| ContainerError::ProcessExecution {
+                    detail: ErrorDetail::new("execve failed for")
+                        .path(command)
+                        .source(e),
                 })?;
                 unreachable!()
             }
             ForkResult::Parent { child } => {
-                CHILD_PID.store(child.as_raw(), Ordering::SeqCst);
+                signals::set_target(Some(child));
                 drop(pty.slave);
 
                 log::info!("(Parent) Container process PID: {child}");
 
-                let master_fd = pty.master.as_raw_fd();
-
-                std::thread::spawn(move || {
-                    let mut master = unsafe { std::fs::File::from_raw_fd(master_fd) };
-                    let mut buffer = [0u8; 1024];
-                    use std::io::{Read, Write};
-                    loop {
-                        if let Ok(n) = master.read(&mut buffer) {
-                            if n > 0 {
-                                let _ = std::io::stdout().write_all(&buffer[..n]);
-                                let _ = std::io::stdout().flush();
-                            }
-                        }
+                let (exited, exit_notifier) = exit_pipe()?;
+                let relay = match options.console_socket {
+                    // The supervisor relays the console from here on.
+                    Some(socket) => {
+                        console::send_master(socket, &pty.master)?;
+                        drop(pty.master);
+                        None
                     }
-                });
+                    None => {
+                        let master = std::fs::File::from(pty.master);
+                        let log = options
+                            .log_file
+                            .as_ref()
+                            .map(LogFile::try_clone)
+                            .transpose()?;
+                        let buffering = options.output_buffering;
+                        let forward_input = options.interactive;
+                        Some(std::thread::spawn(move || {
+                            relay_output(master, buffering, log, forward_input, exited)
+                        }))
+                    }
+                };
 
-                Self::wait_for_child(child)?;
-                CHILD_PID.store(0, Ordering::SeqCst);
-                Ok(())
+                let result = Self::wait_for_child(child, reap_orphans);
+                drop(window);
+                drop(exit_notifier);
+                if let Some(relay) = relay {
+                    let _ = relay.join();
+                }
+                drop(terminal);
+                signals::set_target(None);
+                result
             }
         }
     }
 
     fn execute_without_pty(
-        command: &str,
+        command: &Path,
         argv: &[CString],
         envp: &[CString],
-    ) -> ContainerResult<()> {
-        unsafe {
-            signal(Signal::SIGINT, SigHandler::Handler(handle_signal)).ok();
-            signal(Signal::SIGTERM, SigHandler::Handler(handle_signal)).ok();
-            signal(Signal::SIGQUIT, SigHandler::Handler(handle_signal)).ok();
-        }
+        options: &ProcessOptions,
+        reap_orphans: bool,
+    ) -> ContainerResult<WaitOutcome> {
+        signals::install_forwarding(false);
+
+        // With -i the command reads our stdin through a pipe, otherwise it
+        // gets /dev/null.
+        let stdin_pipe = if options.interactive {
+            Some(pipe2(OFlag::O_CLOEXEC).map_err(|e| {
+                ContainerError::process_execution(format!("Failed to create stdin pipe: {e}"))
+            })?)
+        } else {
+            None
+        };
+        let pipes = match &options.log_file {
+            Some(_) => Some((
+                pipe2(OFlag::O_CLOEXEC).map_err(|e| {
+                    ContainerError::process_execution(format!("Failed to create stdout pipe: {e}"))
+                })?,
+                pipe2(OFlag::O_CLOEXEC).map_err(|e| {
+                    ContainerError::process_execution(format!("Failed to create stderr pipe: {e}"))
+                })?,
+            )),
+            None => None,
+        };
 
         match unsafe { fork()? } {
             ForkResult::Child => {
-                let _ = setsid();
-
-                unsafe {
-                    signal(Signal::SIGINT, SigHandler::SigDfl).ok();
-                    signal(Signal::SIGTERM, SigHandler::SigDfl).ok();
-                    signal(Signal::SIGQUIT, SigHandler::SigDfl).ok();
+                logging::keep_stderr();
+                if options.setsid {
+                    let _ = setsid();
                 }
+                let mut stdin_fd = unsafe { OwnedFd::from_raw_fd(0) };
+                match &stdin_pipe {
+                    Some((stdin, _)) => dup2(stdin, &mut stdin_fd)?,
+                    None => {
+                        if let Ok(null) = open("/dev/null", OFlag::O_RDONLY, Mode::empty()) {
+                            dup2(&null, &mut stdin_fd)?;
+                        }
+                    }
+                }
+                std::mem::forget(stdin_fd);
+                if let Some(((_, stdout), (_, stderr))) = &pipes {
+                    let mut stdout_fd = unsafe { OwnedFd::from_raw_fd(1) };
+                    let mut stderr_fd = unsafe { OwnedFd::from_raw_fd(2) };
+                    dup2(stdout, &mut stdout_fd)?;
+                    dup2(stderr, &mut stderr_fd)?;
+                    std::mem::forget(stdout_fd);
+                    std::mem::forget(stderr_fd);
+                }
+
+                signals::reset_forwarding();
 
-                execve(&argv[0], argv, envp).map_err(|e| {
-                    ContainerError::process_execution(format!("execve failed for {command}: {e}"))
+                Self::prepare_child(options, !reap_orphans)?;
+                execve(&argv[0], argv, envp).map_err(|e| ContainerError::ProcessExecution {
+                    detail: ErrorDetail::new("execve failed for")
+                        .path(command)
+                        .source(e),
                 })?;
                 unreachable!()
             }
             ForkResult::Parent { child } => {
-                CHILD_PID.store(child.as_raw(), Ordering::SeqCst);
+                signals::set_target(Some(child));
                 log::info!("(Parent) Container process PID: {child}");
 
-                Self::wait_for_child(child)?;
-                CHILD_PID.store(0, Ordering::SeqCst);
-                Ok(())
+                let (exited, exit_notifier) = exit_pipe()?;
+                let input = match stdin_pipe {
+                    Some((_, stdin)) => {
+                        let exited = exited.try_clone()?;
+                        Some(std::thread::spawn(move || {
+                            forward_stdin(File::from(stdin), exited)
+                        }))
+                    }
+                    None => None,
+                };
+                let relay = match (pipes, &options.log_file) {
+                    (Some(((stdout, _), (stderr, _))), Some(log)) => {
+                        let log = log.try_clone()?;
+                        Some(std::thread::spawn(move || {
+                            relay_pipes(File::from(stdout), File::from(stderr), log, exited)
+                        }))
+                    }
+                    _ => None,
+                };
+                let result = Self::wait_for_child(child, reap_orphans);
+                drop(exit_notifier);
+                for thread in [input, relay].into_iter().flatten() {
+                    let _ = thread.join();
+                }
+                signals::set_target(None);
+                result
+            }
+        }
+    }
+
+    fn prepare_child(options: &ProcessOptions, subreaper: bool) -> ContainerResult<()> {
+        if subreaper {
+            prctl::set_child_subreaper(true).map_err(|e| {
+                ContainerError::process_execution(format!("Failed to become a subreaper: {e}"))
+            })?;
+        }
+        if let Some(adj) = options.oom_score_adj {
+            std::fs::write("/proc/self/oom_score_adj", adj.to_string()).map_err(|e| {
+                let hint = if e.kind() == io::ErrorKind::PermissionDenied {
+                    " (lowering the score below its current value needs CAP_SYS_RESOURCE)"
+                } else {
+                    ""
+                };
+                ContainerError::process_execution(format!(
+                    "Failed to set oom_score_adj to {adj}: {e}{hint}"
+                ))
+            })?;
+            log::debug!("Set oom_score_adj to {adj}");
+        }
+        if let Some(workdir) = &options.workdir {
+            chdir(workdir.as_path()).map_err(|e| {
+                ContainerError::process_execution(format!(
+                    "Failed to change to working directory {workdir:?}: {e}"
+                ))
+            })?;
+            log::debug!("Changed working directory to {workdir:?}");
+        }
+        // While CAP_SETGID is still held.
+        if !options.groups.is_empty() {
+            Self::set_groups(&options.groups)?;
+        }
+        if let Some(limit) = options.rlimit_nofile {
+            Self::apply_nofile_limit(limit)?;
+        }
+        // Installed while CAP_SYS_ADMIN is still held, so the filter does not
+        // force no_new_privs on the workload; the profile must leave the
+        // capability syscalls alone.
+        if let Some(seccomp) = &options.seccomp {
+            seccomp.install()?;
+        }
+        CapabilityManager::apply(&options.capabilities)?;
+        if options.no_new_privileges {
+            set_no_new_privs().map_err(|e| {
+                ContainerError::process_execution(format!("Failed to set no_new_privs: {e}"))
+            })?;
+            log::debug!("Set no_new_privs");
+        }
+        Ok(())
+    }
+
+    // Runs after the pivot, so names resolve against the rootfs's /etc/group.
+    fn set_groups(groups: &[String]) -> ContainerResult<()> {
+        let etc_group = std::fs::read_to_string("/etc/group").unwrap_or_default();
+        let gids = groups
+            .iter()
+            .map(|group| match group.parse::<u32>() {
+                Ok(gid) => Ok(Gid::from_raw(gid)),
+                Err(_) => etc_group
+                    .lines()
+                    .find_map(|line| {
+                        let mut fields = line.split(':');
+                        (fields.next() == Some(group.as_str()))
+                            .then(|| fields.nth(1)?.parse().ok())
+                            .flatten()
+                    })
+                    .map(Gid::from_raw)
+                    .ok_or_else(|| {
+                        ContainerError::process_execution(format!(
+                            "--group-add {group}: no such group in the container's /etc/group"
+                        ))
+                    }),
+            })
+            .collect::<ContainerResult<Vec<_>>>()?;
+        setgroups(&gids).map_err(|e| {
+            // A user namespace whose setgroups file says "deny" never allows
+            // it, whatever the capabilities.
+            let denied = std::fs::read_to_string("/proc/self/setgroups")
+                .is_ok_and(|setgroups| setgroups.trim() == "deny");
+            let reason = if e == Errno::EPERM && denied {
+                "setgroups is denied in this user namespace (/proc/self/setgroups is \
+                 \"deny\"), so supplementary groups cannot be set"
+                    .to_string()
+            } else if e == Errno::EPERM {
+                format!("{e} (needs CAP_SETGID)")
+            } else {
+                e.to_string()
+            };
+            ContainerError::process_execution(format!(
+                "Failed to set supplementary groups {groups:?}: {reason}"
+            ))
+        })?;
+        log::debug!("Supplementary groups: {gids:?}");
+        Ok(())
+    }
+
+    // Set on the container init before forking so the workload inherits them
+    // and they show up in /proc/1/limits too. --rlimit-nofile is applied later
+    // in the workload and wins over a nofile entry here.
+    fn apply_ulimits(limits: &[Rlimit]) -> ContainerResult<()> {
+        for limit in limits {
+            setrlimit(limit.resource, limit.soft, limit.hard).map_err(|e| {
+                ContainerError::process_execution(format!(
+                    "Failed to set {} limit to {}:{}: {e}",
+                    limit.name, limit.soft, limit.hard
+                ))
+            })?;
+            log::debug!("Set {} limit to {}:{}", limit.name, limit.soft, limit.hard);
+        }
+        Ok(())
+    }
+
+    // Like the limits above, on the init so the whole container starts with
+    // them.
+    fn apply_priority(nice: Option<i32>, ionice: Option<IoPriority>) -> ContainerResult<()> {
+        if let Some(nice) = nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(ContainerError::process_execution(format!(
+                    "Failed to set the nice value to {nice}: {}",
+                    io::Error::last_os_error()
+                )));
             }
+            log::debug!("Set nice value to {nice}");
         }
+        if let Some(ionice) = ionice {
+            let result = unsafe {
+                libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ionice.value())
+            };
+            if result != 0 {
+                return Err(ContainerError::process_execution(format!(
+                    "Failed to set the IO priority to {ionice}: {}",
+                    io::Error::last_os_error()
+                )));
+            }
+            log::debug!("Set IO priority to {ionice}");
+        }
+        Ok(())
+    }
+
+    // Inherited by everything the init starts. EPERM is either a missing
+    // CAP_SYS_NICE or a cgroup without realtime CPU time, which is what a new
+    // cgroup v1 cpu cgroup starts with.
+    fn apply_sched(policy: SchedPolicy, priority: i32) -> ContainerResult<()> {
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        if unsafe { libc::sched_setscheduler(0, policy.policy(), &param) } != 0 {
+            let e = io::Error::last_os_error();
+            let hint = if e.raw_os_error() == Some(libc::EPERM) {
+                "; it needs CAP_SYS_NICE and realtime CPU time in the container's cgroup \
+                 (--cpu-rt-runtime on cgroup v1)"
+            } else {
+                ""
+            };
+            return Err(ContainerError::process_execution(format!(
+                "Failed to set the scheduling policy to {policy} priority {priority}: {e}{hint}"
+            )));
+        }
+        log::debug!("Set scheduling policy {policy} priority {priority}");
+        Ok(())
+    }
+
+    // Runs before capabilities are dropped, as raising the hard limit needs
+    // CAP_SYS_RESOURCE. Without it the request is capped at the hard limit.
+    fn apply_nofile_limit(limit: NofileLimit) -> ContainerResult<()> {
+        let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE).map_err(|e| {
+            ContainerError::process_execution(format!("Failed to read RLIMIT_NOFILE: {e}"))
+        })?;
+        let wanted = match limit {
+            NofileLimit::Max => hard,
+            NofileLimit::Value(value) => value,
+        };
+        let applied = if wanted > hard {
+            match setrlimit(Resource::RLIMIT_NOFILE, wanted, wanted) {
+                Ok(()) => wanted,
+                Err(e) => {
+                    log::warn!(
+                        "Cannot raise the RLIMIT_NOFILE hard limit to {wanted} ({e}), capping at {hard}"
+                    );
+                    setrlimit(Resource::RLIMIT_NOFILE, hard, hard)?;
+                    hard
+                }
+            }
+        } else {
+            setrlimit(Resource::RLIMIT_NOFILE, wanted, hard)?;
+            wanted
+        };
+        log::debug!("RLIMIT_NOFILE soft limit {soft} -> {applied}");
+        Ok(())
     }
 
-    fn wait_for_child(child: Pid) -> ContainerResult<()> {
+    // With `reap_orphans`, waits for any child so that orphans reparented to
+    // us do not linger as zombies, until the workload itself exits.
+    fn wait_for_child(child: Pid, reap_orphans: bool) -> ContainerResult<WaitOutcome> {
+        let target = if reap_orphans { None } else { Some(child) };
         loop {
-            match waitpid(child, Some(WaitPidFlag::empty())) {
-                Ok(WaitStatus::Exited(_, status)) => {
+            match waitpid(target, Some(WaitPidFlag::empty())) {
+                Ok(WaitStatus::Exited(pid, status)) if pid == child => {
                     log::info!("Container exited with status: {status}");
-                    if status != 0 {
-                        return Err(ContainerError::process_execution(format!(
-                            "Container process exited with non-zero status: {status}"
-                        )));
-                    }
-                    break;
+                    return Ok(WaitOutcome::Exited(status));
                 }
-                Ok(WaitStatus::Signaled(_, sig, _)) => {
+                Ok(WaitStatus::Signaled(pid, sig, _)) if pid == child => {
                     log::warn!("Container killed by signal: {sig}");
-                    return Err(ContainerError::process_execution(format!(
-                        "Container process killed by signal: {sig}"
-                    )));
+                    return Ok(WaitOutcome::Signaled(sig));
+                }
+                Ok(WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _)) => {
+                    log::debug!("Reaped orphaned process {pid}");
                 }
                 Ok(_) => continue,
                 Err(nix::errno::Errno::EINTR) => continue,
@@ -230,25 +1020,161 @@ impl ProcessManager {
                 }
             }
         }
-        Ok(())
     }
 
-    pub fn build_argv(command_path: &str, args: &[String]) -> ContainerResult<Vec<CString>> {
-        let mut argv = vec![CString::new(command_path).unwrap()];
-        for arg in args {
-            argv.push(CString::new(arg.as_str()).unwrap());
+    // Like execvp: a command containing a slash is taken as a path (relative
+    // ones against the working directory), anything else is looked up in the
+    // PATH the command will run with.
+    fn resolve_command(command: &OsStr, options: &ProcessOptions) -> ContainerResult<PathBuf> {
+        if command.as_bytes().contains(&b'/') {
+            let path = match &options.workdir {
+                Some(workdir) if Path::new(command).is_relative() => workdir.join(command),
+                _ => PathBuf::from(command),
+            };
+            if !path.exists() {
+                return Err(ContainerError::ProcessExecution {
+                    detail: ErrorDetail::new("Command not found in container:")
+                        .path(&path)
+                        .source(Errno::ENOENT),
+                });
+            }
+            if !is_executable(&path) {
+                return Err(ContainerError::ProcessExecution {
+                    detail: ErrorDetail::new("Command is not an executable file:")
+                        .path(&path)
+                        .source(Errno::EACCES),
+                });
+            }
+            return Ok(path);
         }
-        Ok(argv)
+        let search_path = options
+            .env
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "PATH")
+            .map_or(DEFAULT_PATH, |(_, value)| value.as_str());
+        search_path
+            .split(':')
+            // An empty entry means the current directory, as for execvp.
+            .map(|dir| Path::new(if dir.is_empty() { "." } else { dir }).join(command))
+            .find(|candidate| is_executable(candidate))
+            .ok_or_else(|| ContainerError::ProcessExecution {
+                detail: ErrorDetail::new(format!(
+                    "Command not found in container (PATH={search_path}):"
+                ))
+                .path(command)
+                .source(Errno::ENOENT),
+            })
     }
 
-    pub fn build_environment() -> ContainerResult<Vec<CString>> {
-        let envs = vec![
-            "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
-            "TERM=xterm",
-            "HOME=/root",
-            "HOSTNAME=rust-container",
-            "container=rust-container-runtime",
+    pub fn build_argv(command_path: &Path, args: &[OsString]) -> ContainerResult<Vec<CString>> {
+        std::iter::once(command_path.as_os_str())
+            .chain(args.iter().map(OsString::as_os_str))
+            .map(|arg| CString::new(arg.as_bytes()).map_err(Into::into))
+            .collect()
+    }
+
+    // The defaults below, with `extra` entries replacing a default of the same
+    // name and later entries replacing earlier ones.
+    pub fn build_environment(extra: &[(String, String)]) -> ContainerResult<Vec<CString>> {
+        let mut envs: BTreeMap<String, String> = [
+            ("PATH", DEFAULT_PATH),
+            ("TERM", "xterm"),
+            ("HOME", "/root"),
+            ("HOSTNAME", "rust-container"),
+            ("container", "rust-container-runtime"),
+        ]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        envs.extend(extra.iter().cloned());
+        envs.into_iter()
+            .map(|(key, value)| CString::new(format!("{key}={value}")).map_err(Into::into))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EXIT_INVALID_CONFIGURATION;
+    use std::os::unix::ffi::OsStringExt;
+
+    #[test]
+    fn argv_keeps_arbitrary_bytes() {
+        let args = [
+            OsString::from("-c"),
+            OsString::from_vec(b"echo \xff".to_vec()),
         ];
-        Ok(envs.iter().map(|s| CString::new(*s).unwrap()).collect())
+        let argv = ProcessManager::build_argv(Path::new("/bin/sh"), &args).unwrap();
+        assert_eq!(
+            argv,
+            [
+                c"/bin/sh".to_owned(),
+                c"-c".to_owned(),
+                CString::new(b"echo \xff".to_vec()).unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn embedded_nul_is_rejected() {
+        let args = [OsString::from("-c"), OsString::from("echo a\0b")];
+        let error = ProcessManager::build_argv(Path::new("/bin/sh"), &args).unwrap_err();
+        assert!(
+            matches!(error, ContainerError::InvalidString { .. }),
+            "{error}"
+        );
+        assert_eq!(error.exit_code(), EXIT_INVALID_CONFIGURATION);
+        assert!(ProcessManager::build_argv(Path::new("/bin/s\0h"), &[]).is_err());
+    }
+
+    #[test]
+    fn ulimits_parse_soft_and_hard_values() {
+        let limit: Rlimit = "nofile=1024:2048".parse().unwrap();
+        assert_eq!(limit.resource, Resource::RLIMIT_NOFILE);
+        assert_eq!((limit.soft, limit.hard), (1024, 2048));
+        let limit: Rlimit = "core=0".parse().unwrap();
+        assert_eq!((limit.name, limit.soft, limit.hard), ("core", 0, 0));
+        let limit: Rlimit = "stack=8388608:unlimited".parse().unwrap();
+        assert_eq!(limit.hard, libc::RLIM_INFINITY);
+    }
+
+    #[test]
+    fn bad_ulimits_are_rejected() {
+        let error = |spec: &str| spec.parse::<Rlimit>().unwrap_err();
+        assert!(error("nofile").contains("expected NAME=SOFT[:HARD]"));
+        assert!(error("files=10").contains("unknown limit 'files'"));
+        assert!(error("nofile=ten").contains("invalid nofile limit 'ten'"));
+        assert!(error("nofile=2048:1024").contains("lower than the soft limit"));
+        assert!(error("nofile=unlimited:1024").contains("lower than the soft limit"));
+    }
+
+    #[test]
+    fn output_buffering_modes_flush_when_expected() {
+        assert_eq!("line".parse(), Ok(OutputBuffering::Line));
+        assert_eq!("block".parse(), Ok(OutputBuffering::Block));
+        assert_eq!("none".parse(), Ok(OutputBuffering::None));
+        assert!("full".parse::<OutputBuffering>().is_err());
+
+        let mut none = OutputSink::new(OutputBuffering::None, Vec::new());
+        none.write(b"partial").unwrap();
+        assert!(!none.has_pending());
+        assert_eq!(none.writer.get_ref(), b"partial");
+
+        let mut line = OutputSink::new(OutputBuffering::Line, Vec::new());
+        line.write(b"partial").unwrap();
+        assert!(line.has_pending());
+        line.write(b" line\n").unwrap();
+        assert!(!line.has_pending());
+        assert_eq!(line.writer.get_ref(), b"partial line\n");
+
+        // Block output waits for FLUSH_INTERVAL, newlines or not.
+        let mut block = OutputSink::new(OutputBuffering::Block, Vec::new());
+        block.write(b"one\ntwo\n").unwrap();
+        assert!(block.has_pending());
+        block.last_flush -= FLUSH_INTERVAL;
+        block.write(b"three\n").unwrap();
+        assert_eq!(block.writer.get_ref(), b"one\ntwo\nthree\n");
     }
 }
@@ -0,0 +1,404 @@
+use crate::error::{ContainerError, ContainerResult};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const UDP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// As long as the kernel keeps an unreplied UDP conntrack entry.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub host_ip: IpAddr,
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+// Accepts [HOST_IP:]HOST_PORT:CONTAINER_PORT[/tcp|udp].
+impl FromStr for PortMapping {
+    type Err = String;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (ports, protocol) = match spec.rsplit_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some((_, other)) => return Err(format!("unknown protocol '{other}'")),
+            None => (spec, Protocol::Tcp),
+        };
+        let parts: Vec<&str> = ports.rsplitn(3, ':').collect();
+        let (host_ip, host_port, container_port) = match parts.as_slice() {
+            [container, host] => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), *host, *container),
+            [container, host, ip] => (
+                ip.parse::<IpAddr>()
+                    .map_err(|e| format!("invalid host address '{ip}': {e}"))?,
+                *host,
+                *container,
+            ),
+            _ => {
+                return Err(format!(
+                    "expected [HOST_IP:]HOST_PORT:CONTAINER_PORT[/PROTO], got '{spec}'"
+                ));
+            }
+        };
+        let parse_port = |port: &str| -> Result<u16, String> {
+            match port.parse::<u16>() {
+                Ok(0) => Err("port 0 is not allowed".to_string()),
+                Ok(port) => Ok(port),
+                Err(e) => Err(format!("invalid port '{port}': {e}")),
+            }
+        };
+        Ok(PortMapping {
+            host_ip,
+            host_port: parse_port(host_port)?,
+            container_port: parse_port(container_port)?,
+            protocol,
+        })
+    }
+}
+
+pub fn validate_mappings(mappings: &[PortMapping]) -> ContainerResult<()> {
+    let mut seen = HashSet::new();
+    for mapping in mappings {
+        if !seen.insert((mapping.host_port, mapping.protocol)) {
+            return Err(ContainerError::invalid_configuration(format!(
+                "Host port {}/{} is published more than once",
+                mapping.host_port, mapping.protocol
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct PortForwarder {
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+    tcp_listeners: Vec<SocketAddr>,
+}
+
+impl PortForwarder {
+    pub fn start(mappings: &[PortMapping], container_ip: Ipv4Addr) -> ContainerResult<Self> {
+        let mut forwarder = Self {
+            stop: Arc::new(AtomicBool::new(false)),
+            handles: Vec::new(),
+            tcp_listeners: Vec::new(),
+        };
+        for mapping in mappings {
+            let bind_addr = SocketAddr::new(mapping.host_ip, mapping.host_port);
+            let target = SocketAddr::new(IpAddr::V4(container_ip), mapping.container_port);
+            let bind_error =
+                |e: io::Error| ContainerError::network(format!("Failed to bind {bind_addr}: {e}"));
+            match mapping.protocol {
+                Protocol::Tcp => {
+                    let listener = TcpListener::bind(bind_addr).map_err(bind_error)?;
+                    forwarder.tcp_listeners.push(listener.local_addr()?);
+                    let stop = forwarder.stop.clone();
+                    forwarder
+                        .handles
+                        .push(thread::spawn(move || serve_tcp(listener, target, stop)));
+                }
+                Protocol::Udp => {
+                    let socket = UdpSocket::bind(bind_addr).map_err(bind_error)?;
+                    socket.set_read_timeout(Some(UDP_POLL_INTERVAL))?;
+                    let stop = forwarder.stop.clone();
+                    forwarder.handles.push(thread::spawn(move || {
+                        serve_udp(socket, target, stop, UDP_IDLE_TIMEOUT)
+                    }));
+                }
+            }
+            log::info!("Publishing {bind_addr}/{} -> {target}", mapping.protocol);
+        }
+        Ok(forwarder)
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake blocked accept() calls so the listener threads see the flag.
+        for addr in &self.tcp_listeners {
+            let mut wake = *addr;
+            if wake.ip().is_unspecified() {
+                wake.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            }
+            let _ = TcpStream::connect_timeout(&wake, Duration::from_millis(200));
+        }
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+        log::debug!("Port forwarders stopped");
+    }
+}
+
+impl Drop for PortForwarder {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// A relayed connection. Its sockets are shut down to end it when the
+// forwarder stops, which the relay thread would otherwise never notice.
+struct TcpRelay {
+    sockets: Arc<Mutex<Vec<TcpStream>>>,
+    handle: JoinHandle<()>,
+}
+
+fn serve_tcp(listener: TcpListener, target: SocketAddr, stop: Arc<AtomicBool>) {
+    let mut relays: Vec<TcpRelay> = Vec::new();
+    for client in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        relays.retain(|relay| !relay.handle.is_finished());
+        let (client, shutdown) = match client.and_then(|client| {
+            let shutdown = client.try_clone()?;
+            Ok((client, shutdown))
+        }) {
+            Ok(client) => client,
+            Err(e) => {
+                log::warn!("Failed to accept connection for {target}: {e}");
+                continue;
+            }
+        };
+        let sockets = Arc::new(Mutex::new(vec![shutdown]));
+        let (relay_sockets, relay_stop) = (sockets.clone(), stop.clone());
+        let handle = thread::spawn(move || {
+            if let Err(e) = relay_tcp(client, target, &relay_sockets, &relay_stop) {
+                log::debug!("TCP relay to {target} ended: {e}");
+            }
+        });
+        relays.push(TcpRelay { sockets, handle });
+    }
+    for relay in relays {
+        for socket in relay.sockets.lock().unwrap().iter() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        let _ = relay.handle.join();
+    }
+}
+
+fn relay_tcp(
+    client: TcpStream,
+    target: SocketAddr,
+    sockets: &Mutex<Vec<TcpStream>>,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    let upstream = TcpStream::connect_timeout(&target, CONNECT_TIMEOUT)?;
+    {
+        // Under the lock the forwarder shuts the sockets down with, so the
+        // upstream is either shut down with them or never used.
+        let mut sockets = sockets.lock().unwrap();
+        if stop.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        sockets.push(upstream.try_clone()?);
+    }
+    let (mut client_read, mut upstream_write) = (client.try_clone()?, upstream.try_clone()?);
+    let outbound = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut upstream_write);
+        let _ = upstream_write.shutdown(Shutdown::Write);
+    });
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    let _ = io::copy(&mut upstream_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Write);
+    let _ = outbound.join();
+    Ok(())
+}
+
+// A client's connected upstream socket and the thread relaying its replies.
+struct UdpUpstream {
+    socket: UdpSocket,
+    last_active: Arc<Mutex<Instant>>,
+    retired: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl UdpUpstream {
+    fn retire(&self) {
+        self.retired.store(true, Ordering::SeqCst);
+    }
+}
+
+fn serve_udp(socket: UdpSocket, target: SocketAddr, stop: Arc<AtomicBool>, idle_timeout: Duration) {
+    let mut upstreams: HashMap<SocketAddr, UdpUpstream> = HashMap::new();
+    // Expired upstreams whose reply thread has yet to notice.
+    let mut retiring: Vec<JoinHandle<()>> = Vec::new();
+    let mut buf = [0u8; 65536];
+    while !stop.load(Ordering::SeqCst) {
+        retiring.retain(|handle| !handle.is_finished());
+        upstreams.retain(|client, upstream| {
+            if upstream.last_active.lock().unwrap().elapsed() < idle_timeout {
+                return true;
+            }
+            log::debug!("UDP relay for {client} to {target} expired");
+            upstream.retire();
+            false
+        });
+        let (n, client) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => {
+                log::warn!("UDP receive for {target} failed: {e}");
+                break;
+            }
+        };
+        let upstream = match upstreams.entry(client) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match udp_upstream(&socket, client, target, stop.clone()) {
+                Ok(upstream) => entry.insert(upstream),
+                Err(e) => {
+                    log::warn!("Failed to open UDP upstream to {target}: {e}");
+                    continue;
+                }
+            },
+        };
+        *upstream.last_active.lock().unwrap() = Instant::now();
+        let _ = upstream.socket.send(&buf[..n]);
+    }
+    for upstream in upstreams.into_values() {
+        upstream.retire();
+        retiring.push(upstream.handle);
+    }
+    for handle in retiring {
+        let _ = handle.join();
+    }
+}
+
+// One connected upstream socket per client so replies can be routed back.
+fn udp_upstream(
+    socket: &UdpSocket,
+    client: SocketAddr,
+    target: SocketAddr,
+    stop: Arc<AtomicBool>,
+) -> io::Result<UdpUpstream> {
+    let upstream = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    upstream.connect(target)?;
+    upstream.set_read_timeout(Some(UDP_POLL_INTERVAL))?;
+    let (reply_from, reply_to) = (upstream.try_clone()?, socket.try_clone()?);
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    let retired = Arc::new(AtomicBool::new(false));
+    let (reply_active, reply_retired) = (last_active.clone(), retired.clone());
+    let handle = thread::spawn(move || {
+        let mut buf = [0u8; 65536];
+        while !stop.load(Ordering::SeqCst) && !reply_retired.load(Ordering::SeqCst) {
+            match reply_from.recv(&mut buf) {
+                Ok(n) => {
+                    *reply_active.lock().unwrap() = Instant::now();
+                    let _ = reply_to.send_to(&buf[..n], client);
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(_) => break,
+            }
+        }
+    });
+    Ok(UdpUpstream {
+        socket: upstream,
+        last_active,
+        retired,
+        handle,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn localhost() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+    }
+
+    // An open relay to a server that never hangs up is cut when the
+    // forwarder stops, instead of keeping its thread around.
+    #[test]
+    fn stopping_ends_open_tcp_relays() {
+        let server = TcpListener::bind(localhost()).unwrap();
+        let target = server.local_addr().unwrap();
+        let mapping = PortMapping {
+            host_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            host_port: TcpListener::bind(localhost())
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port(),
+            container_port: target.port(),
+            protocol: Protocol::Tcp,
+        };
+        let forwarder = PortForwarder::start(&[mapping], Ipv4Addr::LOCALHOST).unwrap();
+        let mut client = TcpStream::connect(forwarder.tcp_listeners[0]).unwrap();
+        let (mut accepted, _) = server.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        drop(forwarder);
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+
+    // Once a client has been idle for the timeout its upstream is closed,
+    // and its next datagram goes out through a new one.
+    #[test]
+    fn idle_udp_upstreams_expire() {
+        let server = UdpSocket::bind(localhost()).unwrap();
+        let target = server.local_addr().unwrap();
+        let socket = UdpSocket::bind(localhost()).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let published = socket.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let serve_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            serve_udp(socket, target, serve_stop, Duration::from_millis(100))
+        });
+        let client = UdpSocket::bind(localhost()).unwrap();
+        let mut buf = [0u8; 16];
+        let mut upstream_of = |message: &[u8]| {
+            client.send_to(message, published).unwrap();
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..n], message);
+            from
+        };
+        let first = upstream_of(b"one");
+        assert_eq!(upstream_of(b"two"), first);
+        thread::sleep(Duration::from_millis(300));
+        assert_ne!(upstream_of(b"three"), first);
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use crate::cli::ContainerConfig;
+use crate::container::Container;
+use crate::error::ContainerResult;
+use crate::process::WaitOutcome;
+use crate::signals;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Backoff between restarts doubles from the first value up to the cap, and
+// starts over once a run has lasted longer than STABLE_RUN.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const STABLE_RUN: Duration = Duration::from_secs(10);
+
+// `no`, `on-failure[:MAX]` (restart after a non-zero exit, at most MAX times)
+// or `always`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    OnFailure(Option<u32>),
+    Always,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "no" => Ok(RestartPolicy::No),
+            None if s == "always" => Ok(RestartPolicy::Always),
+            None if s == "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+            Some(("on-failure", max)) => max
+                .parse()
+                .map(|max| RestartPolicy::OnFailure(Some(max)))
+                .map_err(|_| format!("invalid restart count '{max}'")),
+            _ => Err(format!(
+                "unknown restart policy '{s}', expected no, on-failure[:MAX] or always"
+            )),
+        }
+    }
+}
+
+impl RestartPolicy {
+    fn should_restart(self, failed: bool, restarts: u32) -> bool {
+        match self {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure(max) => failed && max.is_none_or(|max| restarts < max),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+// Runs the container in the foreground, re-running it from scratch as the
+// restart policy asks. Every attempt is a full spawn: a new init, namespaces
+// and cgroup, the previous ones having been torn down by wait().
+pub fn run(config: ContainerConfig) -> ContainerResult<WaitOutcome> {
+    let policy = config.restart;
+    let mut restarts = 0;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let started = Instant::now();
+        let handle = Container::from_config(config.clone()).spawn()?;
+        handle.forward_signals();
+        let result = handle.wait();
+        let failed = match &result {
+            Ok(outcome) => outcome.exit_code() != 0,
+            Err(e) => {
+                log::error!("Container failed: {e}");
+                true
+            }
+        };
+        // Being told to stop is not a failure to recover from.
+        if signals::termination_requested() || !policy.should_restart(failed, restarts) {
+            return result;
+        }
+        if started.elapsed() >= STABLE_RUN {
+            backoff = INITIAL_BACKOFF;
+        }
+        restarts += 1;
+        let previous = match &result {
+            Ok(outcome) => format!("exit status {}", outcome.exit_code()),
+            Err(_) => "an error".to_string(),
+        };
+        log::warn!("Restarting container in {backoff:?} (attempt {restarts}) after {previous}");
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
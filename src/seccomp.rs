@@ -0,0 +1,606 @@
+use crate::capabilities::CapabilityConfig;
+use crate::error::{ContainerError, ContainerResult};
+use caps::{Capability, CapsHashSet};
+use seccompiler::{BpfProgram, TargetArch};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Syscalls the default profile refuses with EPERM: kernel module and keyring
+// management, clock changes, tracing other processes, namespace and mount
+// manipulation, and other host-wide knobs no workload should need.
+pub const DEFAULT_BLOCKED_SYSCALLS: [&str; 45] = [
+    "acct",
+    "add_key",
+    "bpf",
+    "clock_adjtime",
+    "clock_settime",
+    "create_module",
+    "delete_module",
+    "finit_module",
+    "get_kernel_syms",
+    "get_mempolicy",
+    "init_module",
+    "ioperm",
+    "iopl",
+    "kcmp",
+    "kexec_file_load",
+    "kexec_load",
+    "keyctl",
+    "lookup_dcookie",
+    "mbind",
+    "mount",
+    "move_pages",
+    "name_to_handle_at",
+    "nfsservctl",
+    "open_by_handle_at",
+    "perf_event_open",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "query_module",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "set_mempolicy",
+    "setns",
+    "settimeofday",
+    "swapon",
+    "swapoff",
+    "sysfs",
+    "_sysctl",
+    "umount2",
+    "unshare",
+    "uselib",
+    "userfaultfd",
+    "ustat",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SeccompMode {
+    #[default]
+    Default,
+    Unconfined,
+    Profile(PathBuf),
+}
+
+// The subset of the Docker/OCI profile format that maps onto a BPF filter.
+// A rule's `includes`/`excludes` are evaluated as Docker does, against the
+// container's capabilities, the native architecture and the running kernel.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeccompProfile {
+    default_action: Action,
+    #[serde(default)]
+    default_errno_ret: Option<u32>,
+    #[serde(default)]
+    syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SyscallRule {
+    names: Vec<String>,
+    action: Action,
+    #[serde(default)]
+    errno_ret: Option<u32>,
+    #[serde(default)]
+    args: Vec<ArgRule>,
+    #[serde(default)]
+    includes: RuleFilter,
+    #[serde(default)]
+    excludes: RuleFilter,
+}
+
+// A rule applies when the container has every capability `includes` names
+// and none `excludes` does, and likewise for the architectures and for a
+// kernel at least `minKernel`. Unknown keys are refused rather than ignored,
+// as ignoring a condition loosens the profile.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct RuleFilter {
+    #[serde(default)]
+    caps: Vec<String>,
+    #[serde(default)]
+    arches: Vec<String>,
+    #[serde(default)]
+    min_kernel: Option<String>,
+}
+
+// What a rule's filters are evaluated against.
+#[derive(Debug, Clone)]
+struct Target {
+    caps: CapsHashSet,
+    arch: &'static str,
+    kernel: (u32, u32),
+}
+
+impl Target {
+    fn native(caps: CapsHashSet) -> Self {
+        let arch = match std::env::consts::ARCH {
+            "x86_64" => "SCMP_ARCH_X86_64",
+            "aarch64" => "SCMP_ARCH_AARCH64",
+            "riscv64" => "SCMP_ARCH_RISCV64",
+            _ => "",
+        };
+        let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+        Self {
+            caps,
+            arch,
+            kernel: parse_kernel_version(&release).unwrap_or((0, 0)),
+        }
+    }
+}
+
+// "6.1.0-13-amd64" and "4.8" alike, as (major, minor).
+fn parse_kernel_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+impl SyscallRule {
+    fn applies(&self, target: &Target) -> ContainerResult<bool> {
+        let has_cap = |name: &String| {
+            Capability::from_str(&caps::to_canonical(name))
+                .map(|cap| target.caps.contains(&cap))
+                .map_err(|_| {
+                    ContainerError::seccomp(format!(
+                        "Unknown capability {name} in the rule for {}",
+                        self.names.join(", ")
+                    ))
+                })
+        };
+        for name in &self.includes.caps {
+            if !has_cap(name)? {
+                return Ok(false);
+            }
+        }
+        for name in &self.excludes.caps {
+            if has_cap(name)? {
+                return Ok(false);
+            }
+        }
+        if !self.includes.arches.is_empty()
+            && !self.includes.arches.iter().any(|arch| arch == target.arch)
+        {
+            return Ok(false);
+        }
+        if self.excludes.arches.iter().any(|arch| arch == target.arch) {
+            return Ok(false);
+        }
+        let at_least = |min_kernel: &String| {
+            parse_kernel_version(min_kernel)
+                .map(|min| target.kernel >= min)
+                .ok_or_else(|| ContainerError::seccomp(format!("Invalid minKernel {min_kernel}")))
+        };
+        if let Some(min_kernel) = &self.includes.min_kernel
+            && !at_least(min_kernel)?
+        {
+            return Ok(false);
+        }
+        if let Some(min_kernel) = &self.excludes.min_kernel
+            && at_least(min_kernel)?
+        {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArgRule {
+    index: u8,
+    value: u64,
+    #[serde(default)]
+    value_two: u64,
+    op: CmpOp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+enum Action {
+    #[serde(rename = "SCMP_ACT_ALLOW")]
+    Allow,
+    #[serde(rename = "SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename = "SCMP_ACT_LOG")]
+    Log,
+    #[serde(rename = "SCMP_ACT_TRAP")]
+    Trap,
+    #[serde(rename = "SCMP_ACT_KILL", alias = "SCMP_ACT_KILL_THREAD")]
+    KillThread,
+    #[serde(rename = "SCMP_ACT_KILL_PROCESS")]
+    KillProcess,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum CmpOp {
+    #[serde(rename = "SCMP_CMP_EQ")]
+    Eq,
+    #[serde(rename = "SCMP_CMP_NE")]
+    Ne,
+    #[serde(rename = "SCMP_CMP_LT")]
+    Lt,
+    #[serde(rename = "SCMP_CMP_LE")]
+    Le,
+    #[serde(rename = "SCMP_CMP_GT")]
+    Gt,
+    #[serde(rename = "SCMP_CMP_GE")]
+    Ge,
+    #[serde(rename = "SCMP_CMP_MASKED_EQ")]
+    MaskedEq,
+}
+
+impl SeccompProfile {
+    pub fn default_profile() -> Self {
+        SeccompProfile {
+            default_action: Action::Allow,
+            default_errno_ret: None,
+            syscalls: vec![SyscallRule {
+                names: DEFAULT_BLOCKED_SYSCALLS
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+                action: Action::Errno,
+                errno_ret: Some(libc::EPERM as u32),
+                args: Vec::new(),
+                includes: RuleFilter::default(),
+                excludes: RuleFilter::default(),
+            }],
+        }
+    }
+
+    pub fn load(path: &Path) -> ContainerResult<Self> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            ContainerError::seccomp(format!("Failed to read profile {}: {e}", path.display()))
+        })?;
+        serde_json::from_str(&content).map_err(|e| {
+            ContainerError::seccomp(format!("Invalid profile {}: {e}", path.display()))
+        })
+    }
+
+    fn action_json(&self, action: Action, errno_ret: Option<u32>) -> Value {
+        match action {
+            Action::Allow => json!("allow"),
+            Action::Errno => {
+                let errno = errno_ret
+                    .or(self.default_errno_ret)
+                    .unwrap_or(libc::EPERM as u32);
+                json!({ "errno": errno })
+            }
+            Action::Log => json!("log"),
+            Action::Trap => json!("trap"),
+            Action::KillThread => json!("kill_thread"),
+            Action::KillProcess => json!("kill_process"),
+        }
+    }
+
+    // The kernel runs every installed filter and keeps the most restrictive
+    // verdict. A base filter applies the default action to everything the
+    // profile does not mention, and each other action gets its own filter
+    // that only fires for its rules.
+    pub fn compile(&self, caps: &CapsHashSet) -> ContainerResult<SeccompProgram> {
+        self.compile_for(&Target::native(caps.clone()))
+    }
+
+    fn compile_for(&self, target: &Target) -> ContainerResult<SeccompProgram> {
+        let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(|e| {
+            ContainerError::seccomp(format!(
+                "Seccomp is not supported on this architecture: {e}"
+            ))
+        })?;
+        let mut rules: Vec<(&SyscallRule, &str)> = Vec::new();
+        for rule in &self.syscalls {
+            if !rule.applies(target)? {
+                log::debug!(
+                    "Skipping seccomp rule for {}: its includes/excludes do not match",
+                    rule.names.join(", ")
+                );
+                continue;
+            }
+            for name in &rule.names {
+                if syscall_known(name, arch) {
+                    rules.push((rule, name));
+                } else {
+                    log::debug!("Skipping seccomp rule for {name}: not a syscall on {arch:?}");
+                }
+            }
+        }
+
+        let mut programs = Vec::new();
+        if self.default_action != Action::Allow {
+            let listed: Vec<_> = rules
+                .iter()
+                .copied()
+                .filter(|(rule, _)| rule.action != self.default_action)
+                .collect();
+            let default = self.action_json(self.default_action, None);
+            programs.push(build_program(default, json!("allow"), &listed, arch)?);
+        }
+        let mut groups: Vec<(Value, Vec<(&SyscallRule, &str)>)> = Vec::new();
+        for (rule, name) in rules {
+            if rule.action == Action::Allow || rule.action == self.default_action {
+                continue;
+            }
+            let action = self.action_json(rule.action, rule.errno_ret);
+            match groups.iter_mut().find(|(a, _)| *a == action) {
+                Some((_, group)) => group.push((rule, name)),
+                None => groups.push((action, vec![(rule, name)])),
+            }
+        }
+        for (action, group) in groups {
+            programs.push(build_program(json!("allow"), action, &group, arch)?);
+        }
+        Ok(SeccompProgram { programs })
+    }
+}
+
+fn syscall_known(name: &str, arch: TargetArch) -> bool {
+    let probe = json!({
+        "probe": {
+            "mismatch_action": "allow",
+            "match_action": "log",
+            "filter": [{ "syscall": name }],
+        }
+    });
+    seccompiler::compile_from_json(probe.to_string().as_bytes(), arch).is_ok()
+}
+
+// A syscall with an unconditional rule matches whatever its arguments are, so
+// conditional rules for it in the same filter are redundant. seccompiler
+// treats a syscall with no conditions at all as an unconditional match.
+fn build_program(
+    mismatch: Value,
+    matched: Value,
+    rules: &[(&SyscallRule, &str)],
+    arch: TargetArch,
+) -> ContainerResult<BpfProgram> {
+    let unconditional: Vec<&str> = rules
+        .iter()
+        .filter(|(rule, _)| rule.args.is_empty())
+        .map(|(_, name)| *name)
+        .collect();
+    let mut filter = Vec::new();
+    for (rule, name) in rules {
+        if rule.args.is_empty() {
+            if !filter.iter().any(|entry: &Value| entry["syscall"] == *name) {
+                filter.push(json!({ "syscall": name }));
+            }
+        } else if !unconditional.contains(name) {
+            let args: Vec<Value> = rule.args.iter().map(arg_json).collect();
+            filter.push(json!({ "syscall": name, "args": args }));
+        }
+    }
+    let source = json!({
+        "main": {
+            "mismatch_action": mismatch,
+            "match_action": matched,
+            "filter": filter,
+        }
+    });
+    let mut map = seccompiler::compile_from_json(source.to_string().as_bytes(), arch)
+        .map_err(|e| ContainerError::seccomp(format!("Failed to compile filter: {e}")))?;
+    map.remove("main")
+        .ok_or_else(|| ContainerError::seccomp("Compiled filter is missing"))
+}
+
+fn arg_json(arg: &ArgRule) -> Value {
+    let (op, value) = match arg.op {
+        CmpOp::Eq => (json!("eq"), arg.value),
+        CmpOp::Ne => (json!("ne"), arg.value),
+        CmpOp::Lt => (json!("lt"), arg.value),
+        CmpOp::Le => (json!("le"), arg.value),
+        CmpOp::Gt => (json!("gt"), arg.value),
+        CmpOp::Ge => (json!("ge"), arg.value),
+        // Docker profiles carry the mask in `value` and the expected bits
+        // in `valueTwo`.
+        CmpOp::MaskedEq => (json!({ "masked_eq": arg.value }), arg.value_two),
+    };
+    json!({ "index": arg.index, "type": "qword", "op": op, "val": value })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SeccompProgram {
+    programs: Vec<BpfProgram>,
+}
+
+impl SeccompProgram {
+    // Capability-gated rules are decided by what the workload keeps.
+    pub fn from_mode(
+        mode: &SeccompMode,
+        capabilities: &CapabilityConfig,
+    ) -> ContainerResult<Option<Self>> {
+        let profile = match mode {
+            SeccompMode::Unconfined => return Ok(None),
+            SeccompMode::Default => SeccompProfile::default_profile(),
+            SeccompMode::Profile(path) => SeccompProfile::load(path)?,
+        };
+        let caps = capabilities.resolve(&caps::runtime::thread_all_supported());
+        profile.compile(&caps).map(Some)
+    }
+
+    // Installing a filter without no_new_privs needs CAP_SYS_ADMIN, which the
+    // workload may already have dropped; no_new_privs is only set then.
+    pub fn install(&self) -> ContainerResult<()> {
+        for program in &self.programs {
+            let prog = libc::sock_fprog {
+                len: program.len() as libc::c_ushort,
+                filter: program.as_ptr() as *mut libc::sock_filter,
+            };
+            match set_filter(&prog) {
+                Ok(()) => continue,
+                Err(e) if e.raw_os_error() != Some(libc::EACCES) => {
+                    return Err(ContainerError::seccomp(format!(
+                        "Failed to install filter: {e}"
+                    )));
+                }
+                Err(_) => log::debug!("Setting no_new_privs to install the seccomp filter"),
+            }
+            if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+                return Err(ContainerError::seccomp(format!(
+                    "Failed to set no_new_privs: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+            set_filter(&prog)
+                .map_err(|e| ContainerError::seccomp(format!("Failed to install filter: {e}")))?;
+        }
+        log::debug!("Installed {} seccomp filter(s)", self.programs.len());
+        Ok(())
+    }
+}
+
+fn set_filter(prog: &libc::sock_fprog) -> std::io::Result<()> {
+    let rc = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            prog as *const libc::sock_fprog,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(json: Value) -> SeccompProfile {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn target(caps: &[Capability]) -> Target {
+        Target {
+            caps: caps.iter().copied().collect(),
+            ..Target::native(CapsHashSet::new())
+        }
+    }
+
+    // Denies everything but read and write, and allows unshare only to a
+    // container with CAP_SYS_ADMIN, like Docker's default profile.
+    fn gated() -> SeccompProfile {
+        profile(json!({
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [
+                { "names": ["read", "write"], "action": "SCMP_ACT_ALLOW" },
+                {
+                    "names": ["unshare"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "includes": { "caps": ["CAP_SYS_ADMIN"] },
+                },
+            ],
+        }))
+    }
+
+    fn programs(profile: &SeccompProfile, target: &Target) -> Vec<BpfProgram> {
+        profile.compile_for(target).unwrap().programs
+    }
+
+    #[test]
+    fn gated_rule_needs_its_capability() {
+        let ungated = profile(json!({
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [{ "names": ["read", "write"], "action": "SCMP_ACT_ALLOW" }],
+        }));
+        let open = profile(json!({
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [
+                { "names": ["read", "write"], "action": "SCMP_ACT_ALLOW" },
+                { "names": ["unshare"], "action": "SCMP_ACT_ALLOW" },
+            ],
+        }));
+        let without = target(&[Capability::CAP_CHOWN]);
+        let with = target(&[Capability::CAP_SYS_ADMIN]);
+        assert_eq!(programs(&gated(), &without), programs(&ungated, &without));
+        assert_eq!(programs(&gated(), &with), programs(&open, &with));
+        assert_ne!(programs(&gated(), &without), programs(&gated(), &with));
+    }
+
+    #[test]
+    fn excluded_capability_drops_the_rule() {
+        let profile = profile(json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{
+                "names": ["ptrace"],
+                "action": "SCMP_ACT_ERRNO",
+                "excludes": { "caps": ["CAP_SYS_PTRACE"] },
+            }],
+        }));
+        assert_eq!(
+            programs(&profile, &target(&[Capability::CAP_SYS_PTRACE])).len(),
+            0
+        );
+        assert_eq!(programs(&profile, &target(&[])).len(), 1);
+    }
+
+    #[test]
+    fn arches_and_min_kernel_are_evaluated() {
+        let rule = |filter: Value| {
+            profile(json!({
+                "defaultAction": "SCMP_ACT_ALLOW",
+                "syscalls": [{
+                    "names": ["ptrace"],
+                    "action": "SCMP_ACT_ERRNO",
+                    "includes": filter,
+                }],
+            }))
+        };
+        let mut native = target(&[]);
+        native.kernel = (5, 10);
+        let native_arch = native.arch;
+        assert_eq!(
+            programs(&rule(json!({ "arches": [native_arch] })), &native).len(),
+            1
+        );
+        assert_eq!(
+            programs(&rule(json!({ "arches": ["SCMP_ARCH_S390X"] })), &native).len(),
+            0
+        );
+        assert_eq!(
+            programs(&rule(json!({ "minKernel": "4.8" })), &native).len(),
+            1
+        );
+        assert_eq!(
+            programs(&rule(json!({ "minKernel": "6.1" })), &native).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn unknown_capability_or_key_is_refused() {
+        let unknown_cap = profile(json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{
+                "names": ["ptrace"],
+                "action": "SCMP_ACT_ERRNO",
+                "includes": { "caps": ["CAP_NOT_A_CAP"] },
+            }],
+        }));
+        assert!(unknown_cap.compile_for(&target(&[])).is_err());
+        let unknown_key = serde_json::from_value::<SeccompProfile>(json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{
+                "names": ["ptrace"],
+                "action": "SCMP_ACT_ERRNO",
+                "includes": { "personality": 8 },
+            }],
+        }));
+        assert!(unknown_key.is_err());
+    }
+
+    #[test]
+    fn kernel_versions() {
+        assert_eq!(parse_kernel_version("6.1.0-13-amd64\n"), Some((6, 1)));
+        assert_eq!(parse_kernel_version("4.8"), Some((4, 8)));
+        assert_eq!(parse_kernel_version("6-rc1"), None);
+    }
+}
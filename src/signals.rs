@@ -0,0 +1,87 @@
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use nix::unistd::Pid;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+static TARGET: AtomicI32 = AtomicI32::new(0);
+static SKIP_TERMINAL: AtomicBool = AtomicBool::new(false);
+static TERMINATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Everything else is relayed. SIGCHLD is how we learn the child exited,
+// SIGKILL and SIGSTOP cannot be caught, the fault signals are about our own
+// process, SIGPIPE is left ignored, and the job-control stops keep stopping
+// the runtime itself.
+const NOT_FORWARDED: [Signal; 14] = [
+    Signal::SIGCHLD,
+    Signal::SIGKILL,
+    Signal::SIGSTOP,
+    Signal::SIGSEGV,
+    Signal::SIGBUS,
+    Signal::SIGFPE,
+    Signal::SIGILL,
+    Signal::SIGSYS,
+    Signal::SIGTRAP,
+    Signal::SIGABRT,
+    Signal::SIGPIPE,
+    Signal::SIGTSTP,
+    Signal::SIGTTIN,
+    Signal::SIGTTOU,
+];
+
+fn forwardable() -> impl Iterator<Item = Signal> {
+    Signal::iterator().filter(|sig| !NOT_FORWARDED.contains(sig))
+}
+
+// Only touches atomics and calls kill(2), both async-signal-safe.
+extern "C" fn forward(sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    if matches!(sig, libc::SIGINT | libc::SIGTERM | libc::SIGQUIT) {
+        TERMINATION_REQUESTED.store(true, Ordering::SeqCst);
+    }
+    let target = TARGET.load(Ordering::SeqCst);
+    if target <= 0 {
+        return;
+    }
+    if SKIP_TERMINAL.load(Ordering::SeqCst)
+        && !info.is_null()
+        && unsafe { (*info).si_code } == libc::SI_KERNEL
+    {
+        return;
+    }
+    unsafe {
+        libc::kill(target, sig);
+    }
+}
+
+// Relays every catchable signal to the current target. With `skip_terminal`,
+// signals generated by the terminal (e.g. Ctrl-C) are not relayed: the
+// container init shares our process group and receives those itself.
+pub fn install_forwarding(skip_terminal: bool) {
+    SKIP_TERMINAL.store(skip_terminal, Ordering::SeqCst);
+    let action = SigAction::new(
+        SigHandler::SigAction(forward),
+        SaFlags::SA_RESTART | SaFlags::SA_SIGINFO,
+        SigSet::empty(),
+    );
+    for sig in forwardable() {
+        if let Err(e) = unsafe { sigaction(sig, &action) } {
+            log::debug!("Cannot forward {sig}: {e}");
+        }
+    }
+}
+
+// Restores the default dispositions, for a forked child about to exec.
+pub fn reset_forwarding() {
+    let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    for sig in forwardable() {
+        let _ = unsafe { sigaction(sig, &action) };
+    }
+}
+
+pub fn set_target(pid: Option<Pid>) {
+    TARGET.store(pid.map_or(0, Pid::as_raw), Ordering::SeqCst);
+}
+
+// Whether we have been sent SIGINT, SIGTERM or SIGQUIT since forwarding was
+// installed, e.g. so a restart policy does not bring the container back.
+pub fn termination_requested() -> bool {
+    TERMINATION_REQUESTED.load(Ordering::SeqCst)
+}
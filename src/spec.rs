@@ -0,0 +1,446 @@
+use crate::cli::ContainerConfig;
+use crate::error::{ContainerError, ContainerResult};
+use crate::filesystem::TmpfsMount;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Mounts every container gets from the runtime itself; bundles list them too.
+const RUNTIME_MOUNTS: [&str; 7] = [
+    "/proc",
+    "/dev",
+    "/dev/pts",
+    "/dev/shm",
+    "/dev/mqueue",
+    "/sys",
+    "/sys/fs/cgroup",
+];
+
+// tmpfs options a bundle may carry that are either our defaults or make no
+// difference to us.
+const IGNORED_TMPFS_OPTIONS: [&str; 6] = [
+    "nosuid",
+    "nodev",
+    "rw",
+    "strictatime",
+    "relatime",
+    "noatime",
+];
+
+// The subset of the OCI runtime spec (config.json) the runtime understands.
+// Everything else is collected in `other` and reported as unsupported.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Spec {
+    #[serde(default)]
+    pub oci_version: Option<String>,
+    #[serde(default)]
+    pub process: Option<Process>,
+    #[serde(default)]
+    pub root: Option<Root>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    #[serde(default)]
+    pub linux: Option<Linux>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Process {
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<String>,
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub terminal: bool,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Root {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mount {
+    pub destination: PathBuf,
+    #[serde(default, rename = "type")]
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub source: Option<PathBuf>,
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Linux {
+    #[serde(default)]
+    pub resources: Option<Resources>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Resources {
+    #[serde(default)]
+    pub memory: Option<MemoryResources>,
+    #[serde(default)]
+    pub cpu: Option<CpuResources>,
+    #[serde(default)]
+    pub pids: Option<PidsResources>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryResources {
+    #[serde(default)]
+    pub limit: Option<i64>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuResources {
+    #[serde(default)]
+    pub shares: Option<u64>,
+    #[serde(default)]
+    pub quota: Option<i64>,
+    #[serde(default)]
+    pub period: Option<u64>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PidsResources {
+    pub limit: i64,
+}
+
+fn warn_unsupported(prefix: &str, other: &BTreeMap<String, Value>) {
+    for key in other.keys() {
+        log::warn!("Ignoring unsupported config.json field {prefix}{key}");
+    }
+}
+
+impl Spec {
+    pub fn load(bundle: &Path) -> ContainerResult<Self> {
+        let path = bundle.join("config.json");
+        let content = fs::read_to_string(&path).map_err(|e| {
+            ContainerError::invalid_configuration(format!("Failed to read {}: {e}", path.display()))
+        })?;
+        serde_json::from_str(&content).map_err(|e| {
+            ContainerError::invalid_configuration(format!("Invalid {}: {e}", path.display()))
+        })
+    }
+
+    // Fills `config` from the spec. `explicit` tells whether a command line
+    // option was given; a spec field that would override one is an error.
+    pub fn apply(
+        &self,
+        bundle: &Path,
+        config: &mut ContainerConfig,
+        explicit: impl Fn(&str) -> bool,
+    ) -> ContainerResult<()> {
+        let conflict = |field: &str, flag: &str| {
+            ContainerError::invalid_configuration(format!(
+                "config.json sets {field}, which conflicts with {flag} on the command line"
+            ))
+        };
+        if let Some(version) = &self.oci_version {
+            log::debug!("Loading OCI bundle {} (spec {version})", bundle.display());
+        }
+        warn_unsupported("", &self.other);
+
+        let root = self
+            .root
+            .as_ref()
+            .ok_or_else(|| ContainerError::invalid_configuration("config.json has no root.path"))?;
+        if explicit("rootfs") {
+            return Err(conflict("root.path", "--rootfs"));
+        }
+        config.rootfs = bundle.join(&root.path);
+        if root.readonly {
+            config.read_only = true;
+        } else if explicit("read-only") {
+            return Err(conflict("root.readonly=false", "--read-only"));
+        }
+
+        if let Some(process) = &self.process {
+            warn_unsupported("process.", &process.other);
+            if let Some((command, args)) = process.args.split_first() {
+                if explicit("command") {
+                    return Err(conflict("process.args", "a command"));
+                }
+                config.command = OsString::from(command);
+                config.args = args.iter().map(OsString::from).collect();
+            }
+            for entry in &process.env {
+                let (key, value) = entry.split_once('=').ok_or_else(|| {
+                    ContainerError::invalid_configuration(format!(
+                        "config.json process.env entry '{entry}' is not KEY=VALUE"
+                    ))
+                })?;
+                config.env.push((key.to_string(), value.to_string()));
+            }
+            if let Some(cwd) = &process.cwd {
+                if explicit("workdir") {
+                    return Err(conflict("process.cwd", "--workdir"));
+                }
+                config.workdir = Some(cwd.clone());
+            }
+            config.tty |= process.terminal;
+        }
+        if config.command.is_empty() {
+            return Err(ContainerError::invalid_configuration(
+                "No command given and config.json has no process.args",
+            ));
+        }
+
+        if let Some(hostname) = &self.hostname {
+            if explicit("hostname") {
+                return Err(conflict("hostname", "--hostname"));
+            }
+            config.hostname = Some(hostname.clone());
+        }
+
+        for mount in &self.mounts {
+            self.apply_mount(mount, config)?;
+        }
+
+        if let Some(linux) = &self.linux {
+            warn_unsupported("linux.", &linux.other);
+            if let Some(resources) = &linux.resources {
+                apply_resources(resources, config)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_mount(&self, mount: &Mount, config: &mut ContainerConfig) -> ContainerResult<()> {
+        let destination = mount.destination.display();
+        if RUNTIME_MOUNTS
+            .iter()
+            .any(|path| mount.destination == Path::new(path))
+        {
+            log::debug!("Mount {destination} from config.json is provided by the runtime");
+            return Ok(());
+        }
+        // Bind mounts included: the container would run without what they
+        // bring in.
+        if mount.kind.as_deref() != Some("tmpfs") {
+            return Err(ContainerError::invalid_configuration(format!(
+                "config.json mount {destination} (type {}, source {}) is not supported; \
+                 only tmpfs mounts are",
+                mount.kind.as_deref().unwrap_or("none"),
+                mount
+                    .source
+                    .as_deref()
+                    .map(|source| source.display().to_string())
+                    .unwrap_or_default()
+            )));
+        }
+        let options: Vec<&str> = mount
+            .options
+            .iter()
+            .map(String::as_str)
+            .filter(|option| !IGNORED_TMPFS_OPTIONS.contains(option))
+            .collect();
+        let spec = format!("{destination}:{}", options.join(","));
+        let tmpfs = spec.parse::<TmpfsMount>().map_err(|e| {
+            ContainerError::invalid_configuration(format!(
+                "config.json tmpfs mount {destination}: {e}"
+            ))
+        })?;
+        config.tmpfs.push(tmpfs);
+        Ok(())
+    }
+}
+
+// The bundle's limits fill in what neither the command line nor --config
+// set; a different value than theirs is a conflict, not an override.
+fn apply_resources(resources: &Resources, config: &mut ContainerConfig) -> ContainerResult<()> {
+    warn_unsupported("linux.resources.", &resources.other);
+    if let Some(memory) = &resources.memory {
+        warn_unsupported("linux.resources.memory.", &memory.other);
+        // Negative means unlimited.
+        if let Some(limit) = memory.limit.filter(|limit| *limit > 0) {
+            set_limit(
+                &mut config.memory_limit,
+                limit as u64,
+                "linux.resources.memory.limit",
+                "--memory or resources.memory_mb in --config",
+            )?;
+        }
+    }
+    if let Some(cpu) = &resources.cpu {
+        warn_unsupported("linux.resources.cpu.", &cpu.other);
+        // cgroup v1 shares (2..=262144) mapped onto the v2 weight range.
+        if let Some(shares) = cpu.shares {
+            let shares = shares.clamp(2, 262_144);
+            let weight = 1 + ((shares - 2) * 9999) / 262_142;
+            set_limit(
+                &mut config.cpu_weight,
+                weight,
+                "linux.resources.cpu.shares",
+                "resources.cpu_weight in --config",
+            )?;
+        }
+        if let Some(quota) = cpu.quota.filter(|quota| *quota > 0) {
+            set_limit(
+                &mut config.cpu_quota,
+                quota as u64,
+                "linux.resources.cpu.quota",
+                "resources.cpu_quota in --config",
+            )?;
+        }
+        if let Some(period) = cpu.period {
+            set_limit(
+                &mut config.cpu_period,
+                period,
+                "linux.resources.cpu.period",
+                "resources.cpu_period in --config",
+            )?;
+        }
+    }
+    if let Some(pids) = &resources.pids
+        && pids.limit > 0
+    {
+        set_limit(
+            &mut config.pids_limit,
+            pids.limit as u64,
+            "linux.resources.pids.limit",
+            "resources.pids_limit in --config",
+        )?;
+    }
+    Ok(())
+}
+
+fn set_limit(slot: &mut Option<u64>, value: u64, field: &str, source: &str) -> ContainerResult<()> {
+    match *slot {
+        Some(set) if set != value => Err(ContainerError::invalid_configuration(format!(
+            "config.json sets {field} to {value}, which conflicts with {set} from {source}"
+        ))),
+        _ => {
+            *slot = Some(value);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(spec: Value, config: &mut ContainerConfig) -> ContainerResult<()> {
+        let spec: Spec = serde_json::from_value(spec).unwrap();
+        spec.apply(Path::new("/bundle"), config, |_| false)
+    }
+
+    fn bundle(extra: Value) -> Value {
+        let mut spec = json!({
+            "root": { "path": "rootfs" },
+            "process": { "args": ["sh"] },
+        });
+        spec.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        spec
+    }
+
+    fn config() -> ContainerConfig {
+        ContainerConfig::new(PathBuf::new(), OsString::new())
+    }
+
+    #[test]
+    fn tmpfs_mounts_are_applied_and_runtime_ones_skipped() {
+        let mut config = config();
+        let spec = bundle(json!({ "mounts": [
+            { "destination": "/proc", "type": "proc", "source": "proc" },
+            { "destination": "/run", "type": "tmpfs", "options": ["nosuid", "size=1m"] },
+        ]}));
+        apply(spec, &mut config).unwrap();
+        assert_eq!(config.tmpfs.len(), 1);
+        assert_eq!(config.tmpfs[0].path, Path::new("/run"));
+    }
+
+    #[test]
+    fn bind_mounts_are_rejected() {
+        let spec = bundle(json!({ "mounts": [
+            { "destination": "/data", "type": "bind", "source": "/srv", "options": ["rbind"] },
+        ]}));
+        let error = apply(spec, &mut config()).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("mount /data (type bind, source /srv) is not supported")
+        );
+    }
+
+    #[test]
+    fn bad_tmpfs_options_are_an_error() {
+        let spec = bundle(json!({ "mounts": [
+            { "destination": "/run", "type": "tmpfs", "options": ["size=lots"] },
+        ]}));
+        let error = apply(spec, &mut config()).unwrap_err();
+        assert!(
+            error.to_string().contains("config.json tmpfs mount /run"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn memory_limit_is_kept_to_the_byte() {
+        let mut config = config();
+        let spec =
+            bundle(json!({ "linux": { "resources": { "memory": { "limit": 1_500_000 } } } }));
+        apply(spec, &mut config).unwrap();
+        assert_eq!(config.memory_limit, Some(1_500_000));
+    }
+
+    #[test]
+    fn limits_already_set_conflict() {
+        let resources = json!({ "linux": { "resources": {
+            "cpu": { "quota": 50_000, "period": 100_000 },
+            "pids": { "limit": 64 },
+        }}});
+        // The same values are no conflict.
+        let mut same = config();
+        same.cpu_quota = Some(50_000);
+        same.pids_limit = Some(64);
+        apply(bundle(resources.clone()), &mut same).unwrap();
+        assert_eq!(same.cpu_period, Some(100_000));
+
+        let mut other = config();
+        other.pids_limit = Some(128);
+        let error = apply(bundle(resources), &mut other).unwrap_err();
+        assert!(
+            error.to_string().contains(
+                "sets linux.resources.pids.limit to 64, which conflicts with 128 from \
+                 resources.pids_limit in --config"
+            ),
+            "{error}"
+        );
+        assert_eq!(other.pids_limit, Some(128));
+    }
+}
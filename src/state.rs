@@ -0,0 +1,358 @@
+use crate::cli::ContainerConfig;
+use crate::error::{ContainerError, ContainerResult};
+use crate::health::HealthStatus;
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Per-container bookkeeping for the create/start/kill/delete workflow lives
+// under here, one directory per container id.
+pub const RUN_DIR: &str = "/run/container_rs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Creating,
+    Created,
+    Running,
+    Paused,
+    Stopped,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Status::Creating => "creating",
+            Status::Created => "created",
+            Status::Running => "running",
+            Status::Paused => "paused",
+            Status::Stopped => "stopped",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub pid: i32,
+    // The init's start time in clock ticks since boot (field 22 of
+    // /proc/<pid>/stat), which tells it apart from a later process that
+    // reuses the PID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<u64>,
+    pub status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cgroup_path: Option<PathBuf>,
+    pub rootfs: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<PathBuf>,
+    // Seconds since the Unix epoch.
+    pub created: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    // Only for containers with a --health-cmd.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    // In bytes.
+    pub memory_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_weight: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_period: Option<u64>,
+    // From --label and --label-file, for `list --filter label=...`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+// Ids become directory names, so they are restricted to a safe character set.
+pub fn validate_id(id: &str) -> Result<String, String> {
+    let valid = id.chars().next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(format!(
+            "invalid container id '{id}': use letters, digits, '_', '-' and '.', starting with a letter or digit"
+        ));
+    }
+    Ok(id.to_string())
+}
+
+pub fn container_dir(id: &str) -> PathBuf {
+    Path::new(RUN_DIR).join(id)
+}
+
+// A short random id for containers started without one.
+pub fn generate_id() -> ContainerResult<String> {
+    let mut bytes = [0u8; 6];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .map_err(|e| {
+            ContainerError::initialization(format!("Failed to generate container id: {e}"))
+        })?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+// When a process started, in clock ticks since boot.
+pub fn process_start_time(pid: i32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name may contain spaces and parentheses; the fields
+    // after it start with the state, field 3.
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl ContainerState {
+    pub fn new(id: &str, config: &ContainerConfig) -> Self {
+        Self {
+            id: id.to_string(),
+            name: config.name.clone(),
+            pid: 0,
+            start_time: None,
+            status: Status::Creating,
+            cgroup_path: None,
+            rootfs: config.rootfs.clone(),
+            bundle: config.bundle.clone(),
+            created: now_secs(),
+            exit_code: None,
+            health: None,
+            memory_limit: config.memory_limit,
+            cpu_weight: config.cpu_weight,
+            cpu_quota: config.cpu_quota,
+            cpu_period: config.cpu_period,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        container_dir(&self.id)
+    }
+
+    // `id` may also be the container's --name.
+    pub fn load(id: &str) -> ContainerResult<Self> {
+        let state = match Self::read(id) {
+            Ok(state) => state,
+            Err(e) if !container_dir(id).exists() => Self::find_by_name(id)?.ok_or(e)?,
+            Err(e) => return Err(e),
+        };
+        let dir = state.dir();
+        Ok(state.check_stale(&dir))
+    }
+
+    // Like load, for the container directory `dir`.
+    pub fn load_from(dir: &Path) -> ContainerResult<Self> {
+        Ok(Self::read_from(dir)?.check_stale(dir))
+    }
+
+    // The supervisor may have died without recording the exit (e.g. the
+    // host rebooted or it was SIGKILLed), leaving a PID that is gone or
+    // has since been given to another process.
+    fn check_stale(mut self, dir: &Path) -> Self {
+        if matches!(
+            self.status,
+            Status::Created | Status::Running | Status::Paused
+        ) && !self.is_alive()
+        {
+            log::debug!(
+                "Container {} init {} is gone, marking stopped",
+                self.id,
+                self.pid
+            );
+            self.status = Status::Stopped;
+            if let Err(e) = self.save_in(dir) {
+                log::debug!("Failed to update stale state of {}: {e}", self.id);
+            }
+        }
+        self
+    }
+
+    // Whether the recorded init is still the process with its PID. State
+    // written before start times were recorded only has the PID to go by.
+    pub fn is_alive(&self) -> bool {
+        if kill(Pid::from_raw(self.pid), None) == Err(Errno::ESRCH) {
+            return false;
+        }
+        self.start_time
+            .is_none_or(|start_time| process_start_time(self.pid) == Some(start_time))
+    }
+
+    // The state file as written, without the staleness check of load.
+    fn read(id: &str) -> ContainerResult<Self> {
+        Self::read_from(&container_dir(id))
+    }
+
+    fn read_from(dir: &Path) -> ContainerResult<Self> {
+        let path = dir.join("state.json");
+        let content = fs::read_to_string(&path).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => ContainerError::invalid_configuration(format!(
+                "No such container: {}",
+                dir.file_name().unwrap_or_default().to_string_lossy()
+            )),
+            _ => ContainerError::initialization(format!("Failed to read {}: {e}", path.display())),
+        })?;
+        serde_json::from_str(&content).map_err(|e| {
+            ContainerError::initialization(format!("Corrupt state file {}: {e}", path.display()))
+        })
+    }
+
+    pub fn find_by_name(name: &str) -> ContainerResult<Option<Self>> {
+        let entries = match fs::read_dir(RUN_DIR) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let Some(id) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if validate_id(&id).is_err() {
+                continue;
+            }
+            if let Ok(state) = Self::read(&id)
+                && state.name.as_deref() == Some(name)
+            {
+                return Ok(Some(state));
+            }
+        }
+        Ok(None)
+    }
+
+    // Written to a temporary file and renamed so readers never see a partial
+    // state.
+    pub fn save(&self) -> ContainerResult<()> {
+        self.save_in(&self.dir())
+    }
+
+    fn save_in(&self, dir: &Path) -> ContainerResult<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| {
+            ContainerError::initialization(format!("Failed to serialize state: {e}"))
+        })?;
+        let tmp = dir.join("state.json.tmp");
+        fs::write(&tmp, json + "\n")?;
+        fs::rename(&tmp, dir.join("state.json"))?;
+        Ok(())
+    }
+
+    // Polls the state file until the supervisor has recorded what `done`
+    // waits for, giving up after `timeout` if one is given.
+    pub fn wait_for(
+        id: &str,
+        timeout: Option<Duration>,
+        done: impl Fn(&ContainerState) -> bool,
+    ) -> ContainerResult<ContainerState> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let state = Self::load(id)?;
+            if done(&state) {
+                return Ok(state);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(ContainerError::initialization(format!(
+                    "Timed out waiting for container {id}, still {}",
+                    state.status
+                )));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub fn remove(&self) -> ContainerResult<()> {
+        match fs::remove_dir_all(self.dir()) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn write_state(dir: &Path, pid: i32, start_time: Option<u64>) {
+        let mut state = ContainerState::new(
+            "test",
+            &ContainerConfig::new(PathBuf::from("/"), "sh".into()),
+        );
+        state.pid = pid;
+        state.start_time = start_time;
+        state.status = Status::Running;
+        state.save_in(dir).unwrap();
+    }
+
+    #[test]
+    fn live_init_stays_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid = std::process::id() as i32;
+        write_state(dir.path(), pid, process_start_time(pid));
+        let state = ContainerState::load_from(dir.path()).unwrap();
+        assert_eq!(state.status, Status::Running);
+    }
+
+    #[test]
+    fn exited_init_is_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id() as i32;
+        let start_time = process_start_time(pid);
+        child.wait().unwrap();
+        write_state(dir.path(), pid, start_time);
+        let state = ContainerState::load_from(dir.path()).unwrap();
+        assert_eq!(state.status, Status::Stopped);
+        // And recorded, so the next reader does not have to find out again.
+        let saved = ContainerState::read_from(dir.path()).unwrap();
+        assert_eq!(saved.status, Status::Stopped);
+    }
+
+    #[test]
+    fn reused_pid_is_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid = std::process::id() as i32;
+        let start_time = process_start_time(pid).unwrap();
+        write_state(dir.path(), pid, Some(start_time + 1));
+        let state = ContainerState::load_from(dir.path()).unwrap();
+        assert_eq!(state.status, Status::Stopped);
+    }
+
+    #[test]
+    fn labels_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContainerState::new(
+            "test",
+            &ContainerConfig::new(PathBuf::from("/"), "sh".into()),
+        );
+        state.labels.insert("app".to_string(), "shop".to_string());
+        state.save_in(dir.path()).unwrap();
+        let saved = ContainerState::read_from(dir.path()).unwrap();
+        assert_eq!(saved.labels, state.labels);
+    }
+
+    #[test]
+    fn start_time_is_field_22() {
+        let pid = std::process::id() as i32;
+        let stat = fs::read_to_string(format!("/proc/{pid}/stat")).unwrap();
+        let field_22 = stat.split_whitespace().nth(21).unwrap().parse().ok();
+        assert_eq!(process_start_time(pid), field_22);
+    }
+}
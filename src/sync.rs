@@ -0,0 +1,61 @@
+use crate::error::{ContainerError, ContainerResult};
+use nix::unistd::pipe;
+use std::fs::File;
+use std::io::{Read, Write};
+
+// One-shot notification across fork: one side calls `notify`, the other
+// `wait`s. Each side drops the end it doesn't use, so a peer that dies
+// before notifying is observed as EOF instead of a hang.
+#[derive(Debug)]
+pub struct SyncPipe {
+    reader: Option<File>,
+    writer: Option<File>,
+}
+
+impl SyncPipe {
+    pub fn new() -> ContainerResult<Self> {
+        let (reader, writer) = pipe().map_err(|e| {
+            ContainerError::initialization(format!("Failed to create sync pipe: {e}"))
+        })?;
+        Ok(Self {
+            reader: Some(File::from(reader)),
+            writer: Some(File::from(writer)),
+        })
+    }
+
+    pub fn notify(&mut self) -> ContainerResult<()> {
+        self.reader.take();
+        let mut writer = self.writer.take().ok_or_else(|| {
+            ContainerError::initialization("Sync pipe write end already consumed")
+        })?;
+        writer.write_all(&[1]).map_err(|e| {
+            ContainerError::initialization(format!("Failed to notify peer process: {e}"))
+        })?;
+        Ok(())
+    }
+
+    pub fn wait(&mut self) -> ContainerResult<()> {
+        self.writer.take();
+        let mut reader = self
+            .reader
+            .take()
+            .ok_or_else(|| ContainerError::initialization("Sync pipe read end already consumed"))?;
+        let mut buf = [0u8; 1];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(1) => return Ok(()),
+                Ok(_) => {
+                    return Err(ContainerError::initialization(
+                        "Peer process exited before signalling readiness",
+                    ));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Err(ContainerError::initialization(format!(
+                        "Failed to wait for peer process: {e}"
+                    )));
+                }
+            }
+        }
+    }
+}
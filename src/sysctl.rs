@@ -0,0 +1,81 @@
+use crate::error::{ContainerError, ContainerResult};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const PROC_SYS: &str = "/proc/sys";
+
+#[derive(Debug)]
+pub struct SysctlManager;
+
+impl SysctlManager {
+    pub fn apply(sysctls: &[(String, String)]) -> ContainerResult<()> {
+        for (key, value) in sysctls {
+            Self::validate_interface(key)?;
+            let path = Self::key_to_path(key);
+            fs::write(&path, value).map_err(|e| {
+                ContainerError::invalid_configuration(match e.kind() {
+                    ErrorKind::NotFound => {
+                        format!("Unknown sysctl {key}: this kernel has no {path:?}")
+                    }
+                    ErrorKind::PermissionDenied => {
+                        format!("Not permitted to set sysctl {key}={value}: {e}")
+                    }
+                    _ => format!("Failed to set sysctl {key}={value}: {e}"),
+                })
+            })?;
+            log::info!("Set sysctl {key}={value}");
+        }
+        Ok(())
+    }
+
+    // The namespace a sysctl belongs to, for the ones that are per namespace
+    // rather than global to the host.
+    pub fn namespace(key: &str) -> Option<&'static str> {
+        if key.starts_with("net.") {
+            Some("net")
+        } else if key.starts_with("kernel.msg")
+            || key == "kernel.sem"
+            || key.starts_with("kernel.shm")
+            || key.starts_with("fs.mqueue.")
+        {
+            Some("ipc")
+        } else if key == "kernel.hostname" || key == "kernel.domainname" {
+            Some("uts")
+        } else {
+            None
+        }
+    }
+
+    fn key_to_path(key: &str) -> PathBuf {
+        Path::new(PROC_SYS).join(key.replace('.', "/"))
+    }
+
+    // Per-interface keys look like net.ipv4.conf.<iface>.forwarding; the
+    // interface only exists once the container's network has been set up.
+    fn validate_interface(key: &str) -> ContainerResult<()> {
+        let parts: Vec<&str> = key.split('.').collect();
+        let is_per_interface = parts.len() >= 5
+            && parts[0] == "net"
+            && (parts[1] == "ipv4" || parts[1] == "ipv6")
+            && (parts[2] == "conf" || parts[2] == "neigh");
+        if !is_per_interface {
+            return Ok(());
+        }
+        let iface = parts[3];
+        if iface == "all" || iface == "default" {
+            return Ok(());
+        }
+        let iface_dir = Path::new(PROC_SYS)
+            .join("net")
+            .join(parts[1])
+            .join(parts[2])
+            .join(iface);
+        if !iface_dir.exists() {
+            return Err(ContainerError::invalid_configuration(format!(
+                "Sysctl {key} refers to interface '{iface}', which does not exist in the container network namespace"
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use crate::cgroup::{CGROUP_ROOT, CgroupConfig, process_cgroup};
+use crate::error::{ContainerError, ContainerResult};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{OwnedObjectPath, Value};
+
+// Where transient scopes go unless told otherwise.
+pub const DEFAULT_SLICE: &str = "system.slice";
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The container's cgroup as a transient scope unit of the host's systemd,
+// which then owns it as it owns the rest of the hierarchy: the limits are
+// unit properties rather than files written by the runtime.
+#[derive(Debug)]
+pub struct SystemdScope {
+    unit: String,
+    // The cgroup `pid` was in before the scope took it.
+    origin: Option<PathBuf>,
+}
+
+impl SystemdScope {
+    pub fn unit_name(config: &CgroupConfig) -> String {
+        format!("{}.scope", config.name)
+    }
+
+    // Whether this host's cgroups are managed by systemd at all.
+    pub fn is_running() -> bool {
+        Path::new("/run/systemd/system").is_dir()
+    }
+
+    // Starts the scope with `pid` in it. systemd creates the cgroup and moves
+    // the process as part of the start job, which has finished once the
+    // process shows up in the scope's cgroup.
+    pub fn start(config: &CgroupConfig, pid: u32) -> ContainerResult<Self> {
+        let unit = Self::unit_name(config);
+        let origin = process_cgroup(Path::new(CGROUP_ROOT), pid);
+        let mut properties = vec![
+            (
+                "Description",
+                Value::from(format!("Container {}", config.name)),
+            ),
+            ("Slice", Value::from(DEFAULT_SLICE)),
+            ("PIDs", Value::from(vec![pid])),
+            // The init's leaf and anything nested in it are the runtime's.
+            ("Delegate", Value::from(true)),
+            // A failed scope is not kept around to block the next start.
+            ("CollectMode", Value::from("inactive-or-failed")),
+        ];
+        properties.extend(
+            Self::properties(config)
+                .into_iter()
+                .map(|(name, value)| (name, Value::from(value))),
+        );
+        if config.oom_group {
+            properties.push(("OOMPolicy", Value::from("kill")));
+        }
+        let aux: Vec<(&str, Vec<(&str, Value)>)> = Vec::new();
+        let job: OwnedObjectPath = manager_call(
+            "StartTransientUnit",
+            &(unit.as_str(), "fail", properties, aux),
+        )
+        .map_err(|e| {
+            ContainerError::cgroup_setup(format!(
+                "systemd could not start the transient unit {unit}: {e}"
+            ))
+        })?;
+        log::info!("Started transient unit {} (job {})", unit, job.as_str());
+        let scope = Self { unit, origin };
+        let start = Instant::now();
+        while process_cgroup(Path::new(CGROUP_ROOT), pid).as_deref() != Some(&scope.cgroup_path()) {
+            if start.elapsed() > START_TIMEOUT {
+                return Err(ContainerError::cgroup_setup(format!(
+                    "systemd did not move PID {pid} into {} within {START_TIMEOUT:?}",
+                    scope.unit
+                )));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(scope)
+    }
+
+    pub fn cgroup_path(&self) -> PathBuf {
+        Self::scope_path(&self.unit)
+    }
+
+    pub fn scope_path(unit: &str) -> PathBuf {
+        Path::new(CGROUP_ROOT).join(DEFAULT_SLICE).join(unit)
+    }
+
+    pub fn origin(&self) -> Option<&Path> {
+        self.origin.as_deref()
+    }
+
+    // The resource limits, as the unit properties systemd translates them from.
+    pub fn properties(config: &CgroupConfig) -> Vec<(&'static str, u64)> {
+        let mut properties = Vec::new();
+        if let Some(limit) = config.memory_limit {
+            properties.push(("MemoryMax", limit));
+        }
+        if let Some(limit) = config.memory_high {
+            properties.push(("MemoryHigh", limit));
+        }
+        if let Some(limit) = config.memory_swap_limit {
+            properties.push(("MemorySwapMax", limit));
+        }
+        // The one swappiness approximation systemd has a property for.
+        if config.swappiness_v2() == Some(("memory.swap.max", "0")) {
+            properties.push(("MemorySwapMax", 0));
+        }
+        if let Some(weight) = config.cpu_weight {
+            properties.push(("CPUWeight", weight));
+        }
+        if let (Some(quota), Some(period)) = (config.cpu_quota, config.cpu_period) {
+            // CPUQuota= is CPU time per second of wall time; u64::MAX is none.
+            let per_sec = match quota {
+                u64::MAX => u64::MAX,
+                quota => quota.saturating_mul(1_000_000) / period.max(1),
+            };
+            properties.push(("CPUQuotaPerSecUSec", per_sec));
+            properties.push(("CPUQuotaPeriodUSec", period));
+        }
+        if let Some(limit) = config.pids_limit {
+            properties.push(("TasksMax", limit));
+        }
+        properties
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    // Stopping the scope kills whatever is left in it and has systemd remove
+    // its cgroup.
+    pub fn stop(&self) -> ContainerResult<()> {
+        let job: OwnedObjectPath = manager_call("StopUnit", &(self.unit.as_str(), "replace"))
+            .map_err(|e| {
+                ContainerError::cgroup_setup(format!("systemd could not stop {}: {e}", self.unit))
+            })?;
+        log::info!(
+            "Stopped transient unit {} (job {})",
+            self.unit,
+            job.as_str()
+        );
+        Ok(())
+    }
+}
+
+fn manager_call<B, R>(method: &str, body: &B) -> zbus::Result<R>
+where
+    B: serde::Serialize + zbus::zvariant::DynamicType,
+    R: for<'d> serde::Deserialize<'d> + zbus::zvariant::Type,
+{
+    let connection = Connection::system()?;
+    let proxy = Proxy::new(
+        &connection,
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+    )?;
+    proxy.call(method, body)
+}
@@ -0,0 +1,39 @@
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Enforces --timeout: SIGTERM to the container init once the deadline
+// passes, SIGKILL if it is still around after the grace period.
+#[derive(Debug)]
+pub struct Watchdog {
+    stop: Sender<()>,
+    handle: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    pub fn spawn(pid: Pid, timeout: Duration, grace: Duration) -> Self {
+        let (stop, stop_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            if stop_rx.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                return false;
+            }
+            log::warn!("Container timed out after {timeout:?}, sending SIGTERM");
+            let _ = kill(pid, Signal::SIGTERM);
+            if stop_rx.recv_timeout(grace) == Err(RecvTimeoutError::Timeout) {
+                log::warn!("Container still running {grace:?} after SIGTERM, sending SIGKILL");
+                let _ = kill(pid, Signal::SIGKILL);
+            }
+            true
+        });
+        log::debug!("Container will be stopped after {timeout:?}");
+        Self { stop, handle }
+    }
+
+    // Cancels the deadline. True if it had already passed.
+    pub fn stop(self) -> bool {
+        let _ = self.stop.send(());
+        self.handle.join().unwrap_or(false)
+    }
+}
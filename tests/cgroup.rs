@@ -0,0 +1,45 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+// The init is in the container cgroup before it unshares its cgroup
+// namespace, so that cgroup is its root in every hierarchy.
+#[test]
+fn init_is_at_the_root_of_its_cgroup_namespace() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(
+        rootfs.path(),
+        &["--memory", "64"],
+        &["/bin/cat", "/proc/1/cgroup"],
+    );
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    let cgroups = common::stdout(&output);
+    assert!(!cgroups.is_empty(), "{output:?}");
+    for line in cgroups.lines() {
+        assert!(line.ends_with(":/"), "{line}");
+    }
+}
+
+// Only the container is moved into its cgroup, never the runtime.
+#[test]
+fn runtime_stays_out_of_the_container_cgroup() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let own = fs::read_to_string("/proc/self/cgroup").unwrap();
+    let mut runtime = Command::new(common::BIN)
+        .args(["--memory", "64", "--rootfs"])
+        .arg(rootfs.path())
+        .args(["--", "/bin/sleep", "2"])
+        .spawn()
+        .expect("run container_rs");
+    thread::sleep(Duration::from_millis(500));
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", runtime.id())).unwrap();
+    assert!(runtime.wait().unwrap().success());
+    assert_eq!(cgroups, own);
+}
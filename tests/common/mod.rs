@@ -0,0 +1,77 @@
+// Shared by the integration tests, which start real containers. They need
+// root; without it each test says so and passes. The rootfs is made of the
+// host's own binaries and the libraries they load.
+#![allow(dead_code)]
+
+use nix::unistd::Uid;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+pub const BIN: &str = env!("CARGO_BIN_EXE_container_rs");
+const BINARIES: [&str; 5] = ["sh", "cat", "sleep", "ls", "mkdir"];
+
+pub fn is_root() -> bool {
+    if Uid::current().is_root() {
+        return true;
+    }
+    eprintln!("skipped: starting containers needs root");
+    false
+}
+
+// A fresh rootfs with BINARIES in /bin, or None when not root.
+pub fn rootfs() -> Option<TempDir> {
+    if !is_root() {
+        return None;
+    }
+    let dir = tempfile::tempdir().expect("create rootfs directory");
+    for sub in ["bin", "etc", "proc", "sys", "dev", "tmp"] {
+        fs::create_dir_all(dir.path().join(sub)).unwrap();
+    }
+    for name in BINARIES {
+        install(dir.path(), name);
+    }
+    Some(dir)
+}
+
+// The host's `name` as /bin/`name` in the rootfs, with its libraries.
+pub fn install(rootfs: &Path, name: &str) {
+    let host = ["/bin", "/usr/bin", "/sbin", "/usr/sbin"]
+        .iter()
+        .map(|bin| Path::new(bin).join(name))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| panic!("{name} not found on the host"));
+    fs::copy(&host, rootfs.join("bin").join(name)).unwrap();
+    copy_libraries(&host, rootfs);
+}
+
+// Each library `ldd` lists for `binary`, at the same path in the rootfs.
+fn copy_libraries(binary: &Path, rootfs: &Path) {
+    let output = Command::new("ldd").arg(binary).output().expect("run ldd");
+    let listing = String::from_utf8_lossy(&output.stdout);
+    for library in listing
+        .split_whitespace()
+        .filter(|word| word.starts_with('/'))
+    {
+        let target = rootfs.join(library.trim_start_matches('/'));
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::copy(library, &target).unwrap();
+    }
+}
+
+// `container_rs [options] --rootfs ROOTFS -- command...`
+pub fn run(rootfs: &Path, options: &[&str], command: &[&str]) -> Output {
+    Command::new(BIN)
+        .args(options)
+        .arg("--rootfs")
+        .arg(rootfs)
+        .arg("--")
+        .args(command)
+        .output()
+        .expect("run container_rs")
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use nix::sys::stat::{Mode, SFlag, makedev, mknod};
+
+// The device cgroup has to stop the container at open(), before any driver
+// gets a say. /dev/mem is 1:1, a node for which is made in the rootfs: the
+// host may have no /dev/mem for devtmpfs to show, and without the driver an
+// allowed open fails with ENXIO instead, telling the two cases apart.
+#[test]
+fn dev_mem_is_not_permitted() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let node = rootfs.path().join("mem");
+    mknod(
+        &node,
+        SFlag::S_IFCHR,
+        Mode::from_bits_truncate(0o600),
+        makedev(1, 1),
+    )
+    .unwrap();
+    let read_mem = |options: &[&str]| {
+        let output = common::run(rootfs.path(), options, &["/bin/cat", "/mem"]);
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    let denied = read_mem(&["--cap-add", "SYS_RAWIO"]);
+    assert!(denied.contains("Operation not permitted"), "{denied}");
+
+    for options in [
+        &["--cap-add", "SYS_RAWIO", "--device-cgroup-rule", "c 1:1 r"][..],
+        &["--cap-add", "SYS_RAWIO", "--device-cgroup-rule", "c 1:* r"],
+        &["--cap-add", "SYS_RAWIO", "--no-device-cgroup"],
+    ] {
+        let allowed = read_mem(options);
+        assert!(
+            !allowed.contains("Operation not permitted"),
+            "{options:?}: {allowed}"
+        );
+    }
+}
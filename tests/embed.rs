@@ -0,0 +1,23 @@
+mod common;
+
+use container_rs::{Container, HealthListener, WaitOutcome};
+
+// Embedding the runtime through nothing but the crate's public paths.
+#[test]
+fn spawn_and_wait_through_the_public_api() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let handle = Container::new(rootfs.path())
+        .hostname("embedded")
+        .args([
+            "-c",
+            "test \"$(cat /proc/sys/kernel/hostname)\" = embedded && exit 3",
+        ])
+        .health_listener(HealthListener::new(|status| println!("health: {status}")))
+        .spawn()
+        .unwrap();
+    let outcome: WaitOutcome = handle.wait().unwrap();
+    assert_eq!(outcome, WaitOutcome::Exited(3));
+    assert_eq!(outcome.exit_code(), 3);
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use container_rs::Container;
+use container_rs::events::{Event, MemoryEventSink};
+
+#[test]
+fn lifecycle_events_in_order() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let sink = MemoryEventSink::new();
+    let handle = Container::new(rootfs.path())
+        .args(["-c", "exit 3"])
+        .with_event_sink(sink.clone())
+        .spawn()
+        .unwrap();
+    let pid = handle.pid().as_raw();
+    assert_eq!(handle.wait().unwrap().exit_code(), 3);
+    assert_eq!(
+        sink.events(),
+        [
+            Event::Created { pid },
+            Event::Running { pid },
+            Event::Exited { code: 3 },
+        ]
+    );
+}
+
+#[test]
+fn oom_is_reported_before_the_exit() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let sink = MemoryEventSink::new();
+    let handle = Container::new(rootfs.path())
+        .memory_mb(16)
+        .args(["-c", "a=x; while :; do a=$a$a; done"])
+        .with_event_sink(sink.clone())
+        .spawn()
+        .unwrap();
+    assert!(handle.wait().is_err());
+    let events = sink.events();
+    let oom = events
+        .iter()
+        .position(|event| matches!(event, Event::Oom { .. }))
+        .expect("an Oom event");
+    assert!(matches!(events[oom + 1..], [Event::Exited { .. }]));
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::path::Path;
+
+// The runtime's own failures map to the statuses documented on
+// ContainerError::exit_code; the workload's status is passed on as is.
+#[test]
+fn missing_rootfs_is_a_configuration_error() {
+    if !common::is_root() {
+        return;
+    }
+    let output = common::run(Path::new("/nonexistent/rootfs"), &[], &["/bin/sh"]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+}
+
+#[test]
+fn missing_command_is_127() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(rootfs.path(), &[], &["/bin/missing"]);
+    assert_eq!(output.status.code(), Some(127), "{output:?}");
+}
+
+#[test]
+fn command_status_is_passed_on() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(rootfs.path(), &[], &["/bin/sh", "-c", "exit 3"]);
+    assert_eq!(output.status.code(), Some(3), "{output:?}");
+}
+
+#[test]
+fn unwritable_stats_file_keeps_the_command_status() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(
+        rootfs.path(),
+        &["--stats-json=/nonexistent/stats.json"],
+        &["/bin/sh", "-c", "exit 3"],
+    );
+    assert_eq!(output.status.code(), Some(3), "{output:?}");
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Failed to write usage summary"),
+        "{output:?}"
+    );
+}
+
+#[test]
+fn hostname_without_an_own_uts_namespace_is_refused() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    for options in [
+        ["--uts", "host", "--hostname", "renamed"],
+        [
+            "--join-ns",
+            "uts=/proc/self/ns/uts",
+            "--hostname",
+            "renamed",
+        ],
+    ] {
+        let output = common::run(rootfs.path(), &options, &["/bin/true"]);
+        assert_eq!(output.status.code(), Some(2), "{output:?}");
+    }
+}
@@ -0,0 +1,52 @@
+mod common;
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+// The rootfs is chrooted into as it is: nothing is mounted, so its /proc
+// stays the empty directory it was.
+#[test]
+fn shared_mount_namespace_chroots_into_the_rootfs() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(
+        rootfs.path(),
+        &["--no-mount-ns", "--no-pivot"],
+        &["/bin/sh", "-c", "echo x > /marker && echo /proc/*"],
+    );
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert_eq!(common::stdout(&output).trim(), "/proc/*", "{output:?}");
+    assert!(rootfs.path().join("marker").exists());
+}
+
+#[test]
+fn shared_mount_namespace_needs_no_pivot() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(rootfs.path(), &["--no-mount-ns"], &["/bin/true"]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+    assert!(
+        stderr(&output).contains("--no-mount-ns needs --no-pivot"),
+        "{output:?}"
+    );
+}
+
+#[test]
+fn shared_mount_namespace_refuses_mounts() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(
+        rootfs.path(),
+        &["--no-mount-ns", "--no-pivot", "--read-only"],
+        &["/bin/true"],
+    );
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+    assert!(
+        stderr(&output).contains("--read-only cannot be used with --no-mount-ns"),
+        "{output:?}"
+    );
+}
@@ -0,0 +1,29 @@
+mod common;
+
+// The container gets eth0 with a default route through the bridge.
+#[test]
+fn bridge_sets_up_eth0_and_a_default_route() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(
+        rootfs.path(),
+        &["--network", "bridge"],
+        &["sh", "-c", "ls /sys/class/net; cat /proc/net/route"],
+    );
+    assert!(output.status.success(), "{output:?}");
+    let stdout = common::stdout(&output);
+    let interfaces: Vec<&str> = stdout
+        .lines()
+        .take_while(|line| !line.starts_with("Iface"))
+        .collect();
+    assert_eq!(interfaces, ["eth0", "lo"], "{stdout}");
+    // Destination 0.0.0.0 via 10.88.0.1, in /proc/net/route's byte order.
+    assert!(
+        stdout.lines().any(|line| line
+            .split_whitespace()
+            .take(3)
+            .eq(["eth0", "00000000", "0100580A"])),
+        "{stdout}"
+    );
+}
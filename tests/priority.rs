@@ -0,0 +1,42 @@
+mod common;
+
+use std::path::Path;
+
+// The nice value, field 19 of /proc/1/stat in the container.
+fn init_nice(rootfs: &Path, options: &[&str]) -> i32 {
+    let output = common::run(rootfs, options, &["/bin/cat", "/proc/1/stat"]);
+    assert!(output.status.success(), "{output:?}");
+    let stat = common::stdout(&output);
+    // Fields after the command name start with the state, field 3.
+    let (_, rest) = stat.rsplit_once(')').unwrap();
+    rest.split_whitespace().nth(16).unwrap().parse().unwrap()
+}
+
+// What ioprio_get reports for the init, through util-linux's ionice.
+fn init_ionice(rootfs: &Path, options: &[&str]) -> String {
+    let output = common::run(rootfs, options, &["/bin/ionice", "-p", "1"]);
+    assert!(output.status.success(), "{output:?}");
+    common::stdout(&output).trim().to_string()
+}
+
+#[test]
+fn nice_is_applied() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    assert_eq!(init_nice(rootfs.path(), &["--nice", "7"]), 7);
+    assert_eq!(init_nice(rootfs.path(), &["--nice", "-5"]), -5);
+}
+
+#[test]
+fn ionice_is_applied() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    common::install(rootfs.path(), "ionice");
+    assert_eq!(
+        init_ionice(rootfs.path(), &["--ionice", "best-effort:3"]),
+        "best-effort: prio 3"
+    );
+    assert_eq!(init_ionice(rootfs.path(), &["--ionice", "idle"]), "idle");
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use std::fs;
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+// With --read-only a write to / fails with EROFS, while the tmpfs at /tmp
+// stays writable.
+#[test]
+fn read_only_root_refuses_writes() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(
+        rootfs.path(),
+        &["--read-only"],
+        &["/bin/sh", "-c", "echo x > /tmp/file && echo x > /file"],
+    );
+    assert!(
+        stderr(&output).contains("cannot create /file: Read-only file system"),
+        "{output:?}"
+    );
+    assert_ne!(output.status.code(), Some(0));
+    assert!(!rootfs.path().join("file").exists());
+}
+
+#[test]
+fn readonly_path_refuses_writes() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    fs::create_dir_all(rootfs.path().join("data")).unwrap();
+    let output = common::run(
+        rootfs.path(),
+        &["--readonly-path", "/data"],
+        &["/bin/sh", "-c", "echo x > /file && echo x > /data/file"],
+    );
+    assert!(
+        stderr(&output).contains("cannot create /data/file: Read-only file system"),
+        "{output:?}"
+    );
+    assert!(rootfs.path().join("file").exists());
+    assert!(!rootfs.path().join("data/file").exists());
+}
@@ -0,0 +1,26 @@
+mod common;
+
+const SESSION: &str =
+    "read -r pid comm state ppid pgrp session rest < /proc/self/stat; echo $pid $session";
+
+// Without a PTY the command stays in the caller's session, which lies outside
+// the container's PID namespace and so shows up as 0.
+#[test]
+fn no_new_session_without_a_pty() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(rootfs.path(), &[], &["/bin/sh", "-c", SESSION]);
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert_eq!(common::stdout(&output).trim(), "2 0");
+}
+
+#[test]
+fn setsid_starts_a_new_session() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(rootfs.path(), &["--setsid"], &["/bin/sh", "-c", SESSION]);
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert_eq!(common::stdout(&output).trim(), "2 2");
+}
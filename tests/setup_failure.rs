@@ -0,0 +1,104 @@
+// One test, as it looks at this process's own cgroups, which a container
+// started from another test thread would move it through.
+mod common;
+
+use container_rs::Container;
+use nix::mount::{MntFlags, MsFlags, mount, umount2};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Every cgroup called `name`, in any hierarchy.
+fn cgroups_named(name: &str) -> Vec<PathBuf> {
+    let root = Path::new("/sys/fs/cgroup");
+    let mut dirs = vec![root.to_path_buf()];
+    dirs.extend(
+        fs::read_dir(root)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir()),
+    );
+    dirs.into_iter()
+        .map(|dir| dir.join(name))
+        .filter(|path| path.exists())
+        .collect()
+}
+
+fn mounts_below(dir: &Path) -> Vec<String> {
+    let dir = dir.to_string_lossy().into_owned();
+    fs::read_to_string("/proc/self/mountinfo")
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4).map(str::to_string))
+        .filter(|mount_point| mount_point.starts_with(&dir))
+        .collect()
+}
+
+fn container(rootfs: &Path, name: &str) -> Container {
+    let mut container = Container::new(rootfs).memory_mb(64).args(["-c", "exit 0"]);
+    container.config_mut().name = Some(name.to_string());
+    container
+}
+
+#[test]
+fn failed_setup_leaves_nothing_behind() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let own_cgroups = fs::read_to_string("/proc/self/cgroup").unwrap();
+
+    // Fails in the runtime, after it has created the cgroup and joined it.
+    let name = format!("setup-failure-{}", std::process::id());
+    let mut failing = container(rootfs.path(), &name);
+    failing.config_mut().join_ns = vec!["net=/nonexistent".parse().unwrap()];
+    assert!(failing.spawn().is_err());
+    assert_eq!(
+        fs::read_to_string("/proc/self/cgroup").unwrap(),
+        own_cgroups
+    );
+    assert_eq!(
+        cgroups_named(&format!("container-{name}")),
+        [] as [PathBuf; 0]
+    );
+
+    // Fails in the init, after a tmpfs below a shared mount in the rootfs has
+    // propagated back to the host.
+    let shared = rootfs.path().join("mnt");
+    fs::create_dir_all(&shared).unwrap();
+    mount(
+        Some("tmpfs"),
+        &shared,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        None::<&str>,
+    )
+    .unwrap();
+    mount(
+        None::<&str>,
+        &shared,
+        None::<&str>,
+        MsFlags::MS_SHARED,
+        None::<&str>,
+    )
+    .unwrap();
+    fs::create_dir(shared.join("x")).unwrap();
+    let name = format!("mount-failure-{}", std::process::id());
+    let mut failing = container(rootfs.path(), &name);
+    let config = failing.config_mut();
+    config.rootfs_propagation = "rshared".parse().unwrap();
+    // The second tmpfs cannot be mounted on a file.
+    config.tmpfs = vec!["/mnt/x".parse().unwrap(), "/bin/sh/x".parse().unwrap()];
+    let outcome = failing.spawn().unwrap().wait();
+    let leftover = mounts_below(rootfs.path());
+    umount2(&shared, MntFlags::MNT_DETACH).unwrap();
+    assert!(outcome.is_err() || outcome.unwrap().exit_code() != 0);
+    assert_eq!(leftover, [shared.to_string_lossy()]);
+    assert_eq!(
+        fs::read_to_string("/proc/self/cgroup").unwrap(),
+        own_cgroups
+    );
+    assert_eq!(
+        cgroups_named(&format!("container-{name}")),
+        [] as [PathBuf; 0]
+    );
+}
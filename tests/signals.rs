@@ -0,0 +1,46 @@
+mod common;
+
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// A signal sent to the runtime reaches the workload, which gets to handle
+// it: here by exiting cleanly once SIGUSR1 arrives.
+#[test]
+fn sigusr1_is_forwarded_to_the_workload() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let script = "trap 'echo got USR1; exit 0' USR1; echo ready; while :; do sleep 0.1; done";
+    let mut child = Command::new(common::BIN)
+        .arg("--rootfs")
+        .arg(rootfs.path())
+        .args(["--", "/bin/sh", "-c", script])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("run container_rs");
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line, "ready\n");
+
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGUSR1).unwrap();
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            let _ = child.kill();
+            panic!("the workload did not exit on SIGUSR1");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    line.clear();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line, "got USR1\n");
+    assert_eq!(status.code(), Some(0));
+}
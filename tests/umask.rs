@@ -0,0 +1,37 @@
+mod common;
+
+// `Umask:` in /proc/1/status, as the container's init reports it.
+fn init_umask(options: &[&str]) -> String {
+    let rootfs = common::rootfs().unwrap();
+    let output = common::run(rootfs.path(), options, &["/bin/cat", "/proc/1/status"]);
+    assert!(output.status.success(), "{output:?}");
+    common::stdout(&output)
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .expect("Umask in /proc/1/status")
+        .trim()
+        .to_string()
+}
+
+#[test]
+fn umask_is_applied() {
+    if !common::is_root() {
+        return;
+    }
+    assert_eq!(init_umask(&["--umask", "027"]), "0027");
+    assert_eq!(init_umask(&["--umask", "0"]), "0000");
+}
+
+#[test]
+fn umask_is_inherited_by_default() {
+    if !common::is_root() {
+        return;
+    }
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let own = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .unwrap()
+        .trim();
+    assert_eq!(init_umask(&[]), own);
+}
@@ -0,0 +1,47 @@
+mod common;
+
+#[test]
+fn workdir_is_entered() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(
+        rootfs.path(),
+        &["--workdir", "/tmp"],
+        &["/bin/sh", "-c", "pwd"],
+    );
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert_eq!(common::stdout(&output).trim(), "/tmp");
+}
+
+// A missing working directory is an error, never created in the rootfs.
+#[test]
+fn missing_workdir_is_refused() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let output = common::run(rootfs.path(), &["--workdir", "/nope"], &["/bin/true"]);
+    assert_eq!(output.status.code(), Some(125), "{output:?}");
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("\"/nope\" does not exist"),
+        "{output:?}"
+    );
+    assert!(!rootfs.path().join("nope").exists());
+}
+
+// Checked in the final root, where a --copy has already put the directory.
+#[test]
+fn workdir_made_by_a_copy_is_found() {
+    let Some(rootfs) = common::rootfs() else {
+        return;
+    };
+    let source = tempfile::tempdir().unwrap();
+    let copy = format!("{}:/app", source.path().display());
+    let output = common::run(
+        rootfs.path(),
+        &["--copy", &copy, "--workdir", "/app"],
+        &["/bin/sh", "-c", "pwd"],
+    );
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert_eq!(common::stdout(&output).trim(), "/app");
+}